@@ -1,3 +1,127 @@
+use std::{
+    env,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The files and directories of the crate whose changes may change the dirty state of the build.
+///
+/// Declaring any `rerun-if-changed` stops cargo rerunning the script whenever any file in the
+/// package changes, so the sources must be listed as well. Changes elsewhere in the repository
+/// aren't noticed until one of these, or git's state, changes, so the `-dirty` suffix is only a
+/// best effort.
+const WATCHED: &[&str] = &[
+    "aleph-naught.ld",
+    "Cargo.toml",
+    "README.md",
+    "assets",
+    "src",
+];
+
 fn main() {
-    println!("cargo:rerun-if-changed=aleph-naught.ld");
+    for path in WATCHED {
+        println!("cargo:rerun-if-changed={path}");
+    }
+    watch_git();
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    build_id();
+}
+
+/// Reruns the build script when a commit is made or checked out, or the index changes.
+///
+/// `HEAD` only names the current branch, which doesn't change when a commit is made, so the
+/// branch's ref is watched too, along with `packed-refs` in case it isn't a loose ref. Paths are
+/// resolved by git, so that worktrees work, and only those which exist are watched, since cargo
+/// reruns the script on every build if a watched path is missing.
+fn watch_git() {
+    let mut paths = vec![
+        "HEAD".to_owned(),
+        "index".to_owned(),
+        "packed-refs".to_owned(),
+    ];
+    // a detached `HEAD` is just `HEAD`, which is already watched
+    if let Some(head_ref) = command_output("git", &["rev-parse", "--symbolic-full-name", "HEAD"]) {
+        if head_ref.starts_with("refs/") {
+            paths.push(head_ref);
+        }
+    }
+    for path in paths {
+        if let Some(path) = command_output("git", &["rev-parse", "--git-path", &path]) {
+            if Path::new(&path).exists() {
+                println!("cargo:rerun-if-changed={path}");
+            }
+        }
+    }
+}
+
+/// Sets the environment variables used by `src/build_id.rs` to identify the build.
+fn build_id() {
+    let dirty = matches!(
+        command_output("git", &["status", "--porcelain"]),
+        Some(status) if !status.is_empty()
+    );
+    let git_hash = match command_output("git", &["rev-parse", "--short=12", "HEAD"]) {
+        Some(hash) if dirty => format!("{hash}-dirty"),
+        Some(hash) => hash,
+        None => "unknown".into(),
+    };
+
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("system time is before the unix epoch")
+                .as_secs()
+        });
+    let timestamp = utc_timestamp(timestamp);
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc_version = command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".into());
+    let target = env::var("TARGET").unwrap();
+    let profile = env::var("PROFILE").unwrap();
+
+    println!("cargo:rustc-env=ALEPH_BUILD_GIT_HASH={git_hash}");
+    println!("cargo:rustc-env=ALEPH_BUILD_TIMESTAMP={timestamp}");
+    println!("cargo:rustc-env=ALEPH_BUILD_RUSTC_VERSION={rustc_version}");
+    println!("cargo:rustc-env=ALEPH_BUILD_TARGET={target}");
+    println!("cargo:rustc-env=ALEPH_BUILD_PROFILE={profile}");
+}
+
+/// Runs `program` and returns its trimmed standard output if it succeeded.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+
+    if output.status.success() {
+        Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+    } else {
+        None
+    }
+}
+
+/// Formats seconds since the unix epoch as an ISO 8601 UTC timestamp.
+fn utc_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // civil-from-days algorithm from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    )
 }
@@ -0,0 +1,250 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Access to the ACPI tables.
+//!
+//! The loader passes the physical address of the root system description table, which is either
+//! the RSDT or the XSDT, and [`table`] and [`tables`] find other tables through it. Tables are read
+//! through the identity map, so tables above [`IDENTITY_MAPPED_LIMIT`] are ignored, as are tables
+//! with an invalid checksum.
+//!
+//! [`Srat`] parses the system resource affinity table, which assigns processors and memory to
+//! NUMA nodes, and [`Madt`] parses the multiple APIC description table, which lists the I/O APICs
+//! and how legacy ISA interrupts are connected to them.
+
+use core::{ops::Range, slice};
+
+use crate::{bootboot::BOOTBOOT, memtest::IDENTITY_MAPPED_LIMIT, util::bytes};
+
+/// The size of the header which starts every table.
+pub const HEADER_SIZE: usize = 36;
+
+/// Returns the first table with `signature`, including its header, if there is a valid one.
+pub fn table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    tables().find(|table| &table[..4] == signature)
+}
+
+/// Returns each valid table, including its header, starting with the root table.
+pub fn tables() -> impl Iterator<Item = &'static [u8]> {
+    let root = table_at(BOOTBOOT.arch.acpi_ptr);
+    let (entries, entry_size): (&[u8], _) = match root {
+        Some(root) if &root[..4] == b"RSDT" => (&root[HEADER_SIZE..], 4),
+        Some(root) if &root[..4] == b"XSDT" => (&root[HEADER_SIZE..], 8),
+        _ => (&[], 4),
+    };
+
+    root.into_iter().chain(
+        entries
+            .chunks_exact(entry_size)
+            .map(move |entry| match entry_size {
+                4 => u64::from(bytes::le::<u32>(entry, 0).unwrap_or_default()),
+                _ => bytes::le::<u64>(entry, 0).unwrap_or_default(),
+            })
+            .filter_map(table_at),
+    )
+}
+
+/// Returns the table at the physical address `addr`, if it's identity mapped and its checksum is
+/// valid.
+fn table_at(addr: u64) -> Option<&'static [u8]> {
+    let readable = |len: u64| {
+        addr != 0
+            && addr
+                .checked_add(len)
+                .is_some_and(|end| end <= IDENTITY_MAPPED_LIMIT)
+    };
+    if !readable(HEADER_SIZE as u64) {
+        return None;
+    }
+    // SAFETY: the header is identity mapped, and the firmware reserves the memory of its tables
+    let header = unsafe { slice::from_raw_parts(addr as *const u8, HEADER_SIZE) };
+    let len = bytes::le::<u32>(header, 4).ok()? as usize;
+    if len < HEADER_SIZE || !readable(len as u64) {
+        return None;
+    }
+    // SAFETY: the table is identity mapped, and the firmware reserves the memory of its tables
+    let table = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+    (bytes::sum8(table) == 0).then_some(table)
+}
+
+/// The system resource affinity table.
+#[derive(Debug, Clone, Copy)]
+pub struct Srat {
+    table: &'static [u8],
+}
+
+/// The range of physical memory in a NUMA node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryAffinity {
+    /// The node's proximity domain.
+    pub node: u32,
+    /// The physical addresses of the memory.
+    pub range: Range<u64>,
+    /// Whether the memory can be hot-plugged.
+    pub hot_pluggable: bool,
+}
+
+/// The NUMA node of a processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessorAffinity {
+    /// The node's proximity domain.
+    pub node: u32,
+    /// The processor's local APIC ID.
+    pub apic_id: u32,
+}
+
+impl Srat {
+    /// The offset of the first entry, after the header and reserved fields.
+    const ENTRIES: usize = HEADER_SIZE + 12;
+
+    /// Returns the system resource affinity table, if there is one.
+    pub fn get() -> Option<Self> {
+        table(b"SRAT").map(|table| Srat { table })
+    }
+
+    /// Returns the enabled memory affinity entries.
+    pub fn memory(&self) -> impl Iterator<Item = MemoryAffinity> {
+        self.entries().filter_map(|(kind, entry)| {
+            if kind != 1 || bytes::le::<u32>(entry, 28).ok()? & 1 == 0 {
+                return None;
+            }
+            let base = bytes::le::<u64>(entry, 8).ok()?;
+            let len = bytes::le::<u64>(entry, 16).ok()?;
+            Some(MemoryAffinity {
+                node: bytes::le(entry, 2).ok()?,
+                range: base..base.checked_add(len)?,
+                hot_pluggable: bytes::le::<u32>(entry, 28).ok()? & 2 != 0,
+            })
+        })
+    }
+
+    /// Returns the enabled processor affinity entries, for both local APIC and x2APIC IDs.
+    pub fn processors(&self) -> impl Iterator<Item = ProcessorAffinity> {
+        self.entries().filter_map(|(kind, entry)| match kind {
+            0 if bytes::le::<u32>(entry, 4).ok()? & 1 != 0 => {
+                // the low byte of the proximity domain is separate from the high bytes
+                let high = bytes::get(entry, 9, 3).ok()?;
+                Some(ProcessorAffinity {
+                    node: u32::from_le_bytes([entry[2], high[0], high[1], high[2]]),
+                    apic_id: entry[3].into(),
+                })
+            }
+            2 if bytes::le::<u32>(entry, 12).ok()? & 1 != 0 => Some(ProcessorAffinity {
+                node: bytes::le(entry, 4).ok()?,
+                apic_id: bytes::le(entry, 8).ok()?,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Returns the type and bytes of each entry.
+    fn entries(&self) -> impl Iterator<Item = (u8, &'static [u8])> {
+        entries(self.table.get(Self::ENTRIES..).unwrap_or_default())
+    }
+}
+
+/// The multiple APIC description table.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    table: &'static [u8],
+}
+
+/// An I/O APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    /// The I/O APIC's ID.
+    pub id: u8,
+    /// The physical address of its registers.
+    pub address: u32,
+    /// The global system interrupt of its first input.
+    pub gsi_base: u32,
+}
+
+/// A legacy ISA interrupt which isn't connected to the I/O APIC input with the same number, or
+/// isn't edge-triggered and active high.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    /// The ISA IRQ.
+    pub irq: u8,
+    /// The global system interrupt it is connected to.
+    pub gsi: u32,
+    /// Whether it is active low, or `None` if it conforms to the ISA bus, which is active high.
+    pub active_low: Option<bool>,
+    /// Whether it is level-triggered, or `None` if it conforms to the ISA bus, which is
+    /// edge-triggered.
+    pub level_triggered: Option<bool>,
+}
+
+impl Madt {
+    /// The offset of the first entry, after the header, local APIC address and flags.
+    const ENTRIES: usize = HEADER_SIZE + 8;
+
+    /// Returns the multiple APIC description table, if there is one.
+    pub fn get() -> Option<Self> {
+        table(b"APIC").map(|table| Madt { table })
+    }
+
+    /// Returns `true` if the system also has the legacy pair of 8259 interrupt controllers.
+    pub fn has_8259s(&self) -> bool {
+        bytes::le::<u32>(self.table, HEADER_SIZE + 4).unwrap_or_default() & 1 != 0
+    }
+
+    /// Returns the I/O APIC entries.
+    pub fn io_apics(&self) -> impl Iterator<Item = IoApic> {
+        self.entries().filter_map(|(kind, entry)| {
+            if kind != 1 {
+                return None;
+            }
+            Some(IoApic {
+                id: *entry.get(2)?,
+                address: bytes::le(entry, 4).ok()?,
+                gsi_base: bytes::le(entry, 8).ok()?,
+            })
+        })
+    }
+
+    /// Returns the interrupt source override entries for the ISA bus.
+    pub fn overrides(&self) -> impl Iterator<Item = InterruptOverride> {
+        self.entries().filter_map(|(kind, entry)| {
+            if kind != 2 || *entry.get(2)? != 0 {
+                return None;
+            }
+            let flags = bytes::le::<u16>(entry, 8).ok()?;
+            // each of the two-bit fields is 0b00 to conform to the bus, 0b01 for active high or
+            // edge-triggered, and 0b11 for active low or level-triggered
+            let field = |shift: u16| match (flags >> shift) & 0b11 {
+                0b00 => None,
+                value => Some(value == 0b11),
+            };
+            Some(InterruptOverride {
+                irq: *entry.get(3)?,
+                gsi: bytes::le(entry, 4).ok()?,
+                active_low: field(0),
+                level_triggered: field(2),
+            })
+        })
+    }
+
+    /// Returns the type and bytes of each entry.
+    fn entries(&self) -> impl Iterator<Item = (u8, &'static [u8])> {
+        entries(self.table.get(Self::ENTRIES..).unwrap_or_default())
+    }
+}
+
+/// Returns the type and bytes of each of the entries in `rest`, each of which starts with its type
+/// and length, as in the SRAT and MADT.
+fn entries(mut rest: &'static [u8]) -> impl Iterator<Item = (u8, &'static [u8])> {
+    core::iter::from_fn(move || {
+        let len = usize::from(*rest.get(1)?);
+        if len < 2 || len > rest.len() {
+            return None;
+        }
+        let (entry, next) = rest.split_at(len);
+        rest = next;
+        Some((entry[0], entry))
+    })
+}
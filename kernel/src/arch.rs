@@ -6,6 +6,11 @@
 //
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 //! Architecture-specific functionality.
+//!
+//! Each architecture's `init` only fails outright if the kernel can't run at all. Features which
+//! the kernel can do without, such as the local APIC or machine checks, are skipped if they can't
+//! be enabled, and the failures are returned as [`Degraded`]. Each architecture's `Error`
+//! documents what the kernel does without the corresponding feature.
 
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
@@ -13,9 +18,53 @@ mod x86_64;
 pub use self::x86_64::*;
 
 #[cfg(target_arch = "aarch64")]
-mod aarch64 {
-    /// Performs initialization required for `aarch64`.
-    pub fn init() {}
-}
+mod aarch64;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::*;
+
+/// The non-critical steps of `init` which failed, leaving the kernel in a degraded mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use = "the kernel may be running in a degraded mode"]
+pub struct Degraded {
+    errors: [Option<Error>; Self::CAPACITY],
+}
+
+impl Degraded {
+    /// The maximum number of failures which can be recorded. Each step of `init` fails with its own
+    /// `Error` variant, so there is room for every step to fail.
+    const CAPACITY: usize = core::mem::variant_count::<Error>();
+
+    /// Returns an empty list of failures.
+    const fn new() -> Self {
+        Degraded {
+            errors: [None; Self::CAPACITY],
+        }
+    }
+
+    /// Records `err`, or logs it if [`CAPACITY`](Self::CAPACITY) failures have already been
+    /// recorded.
+    fn push(&mut self, err: Error) {
+        match self.errors.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(err),
+            None => log::error!(
+                "too many failures to record; also degraded: {}",
+                crate::error::Report(&err)
+            ),
+        }
+    }
+
+    /// Returns `true` if every step succeeded.
+    pub fn is_empty(&self) -> bool {
+        self.errors[0].is_none()
+    }
+
+    /// Returns the steps which failed, in the order they were attempted.
+    pub fn errors(&self) -> impl Iterator<Item = &Error> {
+        self.errors.iter().map_while(Option::as_ref)
+    }
+
+    /// Returns `true` if any step failed with an error matching `f`.
+    pub fn contains(&self, f: impl FnMut(&Error) -> bool) -> bool {
+        self.errors().any(f)
+    }
+}
@@ -0,0 +1,124 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Functionality specific to the `aarch64` architecture.
+
+use core::{arch::asm, fmt};
+
+use super::Degraded;
+use crate::{error, param};
+
+param! {
+    /// The number of seconds to wait after a panic before resetting the system, or `0` to halt
+    /// instead.
+    pub static PANIC_RESET: u32 = 10, name = "panic_reset";
+}
+
+/// A step of [`init`] which failed, with the error which caused it.
+///
+/// None of these failures is fatal. Each variant describes the degraded mode the kernel runs in
+/// without the corresponding feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// PSCI is not available, so the system is [reset](reset) with the watchdog, and can't be
+    /// powered off.
+    Psci(psci::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Psci(_) => write!(f, "PSCI is not available"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Psci(err) => Some(err),
+        }
+    }
+}
+
+/// Performs initialization required for `aarch64`, returning the non-critical steps which failed.
+pub fn init() -> Degraded {
+    let mut degraded = Degraded::new();
+    match psci::version() {
+        Ok((major, minor)) => log::info!("PSCI {major}.{minor}"),
+        Err(err) => degraded.push(Error::Psci(err)),
+    }
+    degraded
+}
+
+/// Resets the system, with PSCI if it's available, or otherwise with the Raspberry Pi's watchdog.
+pub fn reset() -> ! {
+    if let Err(err) = psci::system_reset() {
+        log::warn!("cannot reset with PSCI ({err}); using the watchdog");
+    }
+    watchdog::reset()
+}
+
+/// Called by the panic handler to reset the system after [`PANIC_RESET`] seconds, so that headless
+/// boards recover from panics. Returns immediately if `PANIC_RESET` is `0`.
+pub fn panic_reset() {
+    let seconds = PANIC_RESET.get();
+    if seconds == 0 {
+        return;
+    }
+
+    log::error!("resetting in {seconds} seconds");
+    spin_for(seconds.into());
+    reset()
+}
+
+/// Switches to the stack ending at `stack_top` and calls `f(arg)` on it.
+///
+/// # Safety
+/// `stack_top` must be 16-byte aligned, and the end of a stack which nothing else is using, large
+/// enough for `f`.
+pub unsafe fn call_on_stack(stack_top: *mut u8, f: extern "C" fn(usize) -> !, arg: usize) -> ! {
+    // SAFETY: the caller guarantees that the stack is valid and unused, and `f` never returns, so
+    //         the abandoned stack is never used again by this function
+    unsafe {
+        asm!(
+            "mov sp, {stack_top}",
+            "blr {f}",
+            stack_top = in(reg) stack_top,
+            f = in(reg) f,
+            in("x0") arg,
+            options(noreturn),
+        )
+    }
+}
+
+/// Busy-waits for `seconds` seconds, using the generic timer's counter.
+fn spin_for(seconds: u64) {
+    let frequency: u64;
+    let start: u64;
+    // SAFETY: reading the counter and its frequency has no side effects, and both are accessible
+    //         at EL1
+    unsafe {
+        asm!("mrs {}, cntfrq_el0", out(reg) frequency, options(nomem, nostack));
+        asm!("isb", "mrs {}, cntpct_el0", out(reg) start, options(nomem, nostack));
+    }
+
+    let ticks = seconds.saturating_mul(frequency);
+    loop {
+        let now: u64;
+        // SAFETY: reading the counter has no side effects, and it is accessible at EL1
+        unsafe { asm!("isb", "mrs {}, cntpct_el0", out(reg) now, options(nomem, nostack)) };
+        if now.wrapping_sub(start) >= ticks {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+pub mod intc;
+pub mod psci;
+pub mod watchdog;
@@ -0,0 +1,272 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! A driver for the Raspberry Pi 3's interrupt controllers.
+//!
+//! The BCM2837 has two: the BCM2835's legacy controller for the GPU peripherals' interrupts, and
+//! the BCM2836's per-core local controller, which routes the legacy controller's interrupt to one
+//! core and adds per-core sources such as the generic timers and mailboxes.
+//!
+//! There are no exception vectors yet, so nothing takes interrupts. This driver masks and unmasks
+//! sources and reports which are pending, which is all an exception handler needs to dispatch
+//! them.
+
+use core::{fmt, ptr};
+
+use spin::Mutex;
+
+use crate::bootboot::MMIO;
+
+/// The offset of the legacy interrupt controller from the start of the peripherals.
+const LEGACY_OFFSET: usize = 0xb200;
+/// The offset of the basic pending register.
+const IRQ_BASIC_PENDING: usize = 0x00;
+/// The offsets of the two GPU pending registers.
+const IRQ_PENDING: [usize; 2] = [0x04, 0x08];
+/// The offsets of the two GPU enable registers.
+const ENABLE_IRQS: [usize; 2] = [0x10, 0x14];
+/// The offset of the basic enable register.
+const ENABLE_BASIC_IRQS: usize = 0x18;
+/// The offsets of the two GPU disable registers.
+const DISABLE_IRQS: [usize; 2] = [0x1c, 0x20];
+/// The offset of the basic disable register.
+const DISABLE_BASIC_IRQS: usize = 0x24;
+/// Set in the basic pending register when any interrupt in a GPU pending register is pending.
+const BASIC_PENDING_REG: [u32; 2] = [1 << 8, 1 << 9];
+/// The interrupts in the basic pending register which belong to the ARM, rather than the GPU.
+const BASIC_ARM_MASK: u32 = 0xff;
+
+/// The offset of the local interrupt controller from the start of the peripherals.
+const LOCAL_OFFSET: usize = 0x100_0000;
+/// The offset of the GPU interrupt routing register.
+const GPU_INT_ROUTING: usize = 0x0c;
+/// The offset of core 0's timer interrupt control register.
+const CORE_TIMER_INT_CONTROL: usize = 0x40;
+/// The offset of core 0's IRQ source register.
+const CORE_IRQ_SOURCE: usize = 0x60;
+
+/// The number of cores.
+pub const CORES: usize = 4;
+
+/// Serializes changes to the interrupt controllers' registers.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// An interrupt from the legacy interrupt controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Irq {
+    /// One of the 64 GPU peripheral interrupts, such as `29` for the mini UART.
+    Gpu(u8),
+    /// One of the 8 ARM interrupts, such as `0` for the ARM timer.
+    Arm(u8),
+}
+
+impl Irq {
+    /// Returns `true` if the interrupt is in range.
+    fn exists(self) -> bool {
+        match self {
+            Irq::Gpu(n) => n < 64,
+            Irq::Arm(n) => n < 8,
+        }
+    }
+}
+
+impl fmt::Display for Irq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Irq::Gpu(n) => write!(f, "GPU IRQ {n}"),
+            Irq::Arm(n) => write!(f, "ARM IRQ {n}"),
+        }
+    }
+}
+
+/// An interrupt source of the local interrupt controller, for a single core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LocalIrq {
+    /// The secure physical timer.
+    SecurePhysicalTimer = 0,
+    /// The non-secure physical timer.
+    PhysicalTimer = 1,
+    /// The hypervisor timer.
+    HypervisorTimer = 2,
+    /// The virtual timer.
+    VirtualTimer = 3,
+    /// Mailbox 0.
+    Mailbox0 = 4,
+    /// Mailbox 1.
+    Mailbox1 = 5,
+    /// Mailbox 2.
+    Mailbox2 = 6,
+    /// Mailbox 3.
+    Mailbox3 = 7,
+    /// The legacy interrupt controller, if it is routed to this core.
+    Legacy = 8,
+    /// The performance monitors.
+    Pmu = 9,
+    /// The AXI outstanding counter.
+    Axi = 10,
+    /// The local timer.
+    LocalTimer = 11,
+}
+
+impl LocalIrq {
+    /// All local interrupt sources, in the order of their bits in the IRQ source register.
+    const ALL: [LocalIrq; 12] = [
+        LocalIrq::SecurePhysicalTimer,
+        LocalIrq::PhysicalTimer,
+        LocalIrq::HypervisorTimer,
+        LocalIrq::VirtualTimer,
+        LocalIrq::Mailbox0,
+        LocalIrq::Mailbox1,
+        LocalIrq::Mailbox2,
+        LocalIrq::Mailbox3,
+        LocalIrq::Legacy,
+        LocalIrq::Pmu,
+        LocalIrq::Axi,
+        LocalIrq::LocalTimer,
+    ];
+}
+
+/// Masks every interrupt of the legacy controller and every timer interrupt of the local
+/// controller, and routes the legacy controller's interrupt to core 0.
+pub fn init() {
+    let _guard = LOCK.lock();
+    // SAFETY: access to the interrupt controllers is synchronized with `LOCK`
+    unsafe {
+        for reg in DISABLE_IRQS {
+            write(LEGACY_OFFSET + reg, u32::MAX);
+        }
+        write(LEGACY_OFFSET + DISABLE_BASIC_IRQS, BASIC_ARM_MASK);
+        for core in 0..CORES {
+            write(LOCAL_OFFSET + CORE_TIMER_INT_CONTROL + 4 * core, 0);
+        }
+        write(LOCAL_OFFSET + GPU_INT_ROUTING, 0);
+    }
+}
+
+/// Unmasks `irq`.
+///
+/// # Panics
+/// Panics if `irq` is out of range.
+pub fn enable(irq: Irq) {
+    assert!(irq.exists(), "{irq} doesn't exist");
+    let (reg, bit) = match irq {
+        Irq::Gpu(n) => (LEGACY_OFFSET + ENABLE_IRQS[usize::from(n / 32)], n % 32),
+        Irq::Arm(n) => (LEGACY_OFFSET + ENABLE_BASIC_IRQS, n),
+    };
+
+    // SAFETY: writing a bit to an enable register only unmasks that interrupt, so writes don't
+    //         need to be synchronized
+    unsafe { write(reg, 1 << bit) };
+}
+
+/// Masks `irq`.
+///
+/// # Panics
+/// Panics if `irq` is out of range.
+pub fn disable(irq: Irq) {
+    assert!(irq.exists(), "{irq} doesn't exist");
+    let (reg, bit) = match irq {
+        Irq::Gpu(n) => (LEGACY_OFFSET + DISABLE_IRQS[usize::from(n / 32)], n % 32),
+        Irq::Arm(n) => (LEGACY_OFFSET + DISABLE_BASIC_IRQS, n),
+    };
+
+    // SAFETY: writing a bit to a disable register only masks that interrupt, so writes don't need
+    //         to be synchronized
+    unsafe { write(reg, 1 << bit) };
+}
+
+/// Returns the lowest-numbered pending, unmasked interrupt of the legacy controller, with ARM
+/// interrupts first.
+pub fn pending() -> Option<Irq> {
+    // SAFETY: reading the pending registers has no side effects
+    let basic = unsafe { read(LEGACY_OFFSET + IRQ_BASIC_PENDING) };
+    if basic & BASIC_ARM_MASK != 0 {
+        return Some(Irq::Arm(basic.trailing_zeros() as u8));
+    }
+
+    for (i, (reg, flag)) in IRQ_PENDING.into_iter().zip(BASIC_PENDING_REG).enumerate() {
+        if basic & flag != 0 {
+            // SAFETY: reading the pending registers has no side effects
+            let pending = unsafe { read(LEGACY_OFFSET + reg) };
+            if pending != 0 {
+                return Some(Irq::Gpu((32 * i) as u8 + pending.trailing_zeros() as u8));
+            }
+        }
+    }
+    None
+}
+
+/// Routes the legacy controller's interrupt to `core`.
+///
+/// # Panics
+/// Panics if `core` doesn't exist.
+pub fn route_legacy_to(core: usize) {
+    assert!(core < CORES, "core {core} doesn't exist");
+
+    let _guard = LOCK.lock();
+    // SAFETY: access to the interrupt controllers is synchronized with `LOCK`
+    unsafe { write(LOCAL_OFFSET + GPU_INT_ROUTING, core as u32) };
+}
+
+/// Unmasks or masks the generic timer interrupt `timer` for `core`.
+///
+/// # Panics
+/// Panics if `core` doesn't exist or `timer` isn't one of the generic timers.
+pub fn set_timer_enabled(core: usize, timer: LocalIrq, enabled: bool) {
+    assert!(core < CORES, "core {core} doesn't exist");
+    assert!(
+        timer <= LocalIrq::VirtualTimer,
+        "{timer:?} isn't a generic timer"
+    );
+
+    let reg = LOCAL_OFFSET + CORE_TIMER_INT_CONTROL + 4 * core;
+    let _guard = LOCK.lock();
+    // SAFETY: access to the interrupt controllers is synchronized with `LOCK`
+    unsafe {
+        let mut control = read(reg);
+        if enabled {
+            control |= 1 << timer as u32;
+        } else {
+            control &= !(1 << timer as u32);
+        }
+        write(reg, control);
+    }
+}
+
+/// Returns the pending interrupt sources of `core`'s local controller.
+///
+/// # Panics
+/// Panics if `core` doesn't exist.
+pub fn local_pending(core: usize) -> impl Iterator<Item = LocalIrq> {
+    assert!(core < CORES, "core {core} doesn't exist");
+
+    // SAFETY: reading the IRQ source register has no side effects
+    let source = unsafe { read(LOCAL_OFFSET + CORE_IRQ_SOURCE + 4 * core) };
+    LocalIrq::ALL
+        .into_iter()
+        .filter(move |&irq| source & (1 << irq as u32) != 0)
+}
+
+/// Reads the register at offset `reg` from the start of the peripherals.
+///
+/// # Safety
+/// Reading the register must not interfere with other users of the interrupt controllers.
+unsafe fn read(reg: usize) -> u32 {
+    // SAFETY: BOOTBOOT maps the peripherals at `MMIO`, which include both interrupt controllers,
+    //         and the caller guarantees that reading doesn't interfere with other users
+    unsafe { ptr::read_volatile(MMIO.as_ptr().add(reg).cast::<u32>()) }
+}
+
+/// Writes `value` to the register at offset `reg` from the start of the peripherals.
+///
+/// # Safety
+/// Writing the register must not interfere with other users of the interrupt controllers.
+unsafe fn write(reg: usize, value: u32) {
+    // SAFETY: BOOTBOOT maps the peripherals at `MMIO`, which include both interrupt controllers,
+    //         and the caller guarantees that writing doesn't interfere with other users
+    unsafe { ptr::write_volatile(MMIO.as_ptr().add(reg).cast::<u32>() as *mut u32, value) }
+}
@@ -0,0 +1,123 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! The Power State Coordination Interface, which firmware or a hypervisor may provide for power
+//! management.
+//!
+//! There is no device tree parser to find out whether PSCI is available, and calling it when it
+//! isn't is an undefined instruction, so it must be enabled with the [`PSCI_CONDUIT`] parameter.
+//! The Raspberry Pi's default firmware doesn't provide PSCI, but QEMU's `virt` machine does.
+
+use core::{arch::asm, convert::Infallible, fmt};
+
+use crate::param;
+
+param! {
+    /// How to call PSCI: `hvc` or `smc`, or empty if there is no PSCI implementation.
+    pub static PSCI_CONDUIT: &'static str = "", name = "psci_conduit";
+}
+
+/// The function ID of `PSCI_VERSION`.
+const PSCI_VERSION: u32 = 0x8400_0000;
+/// The function ID of `SYSTEM_OFF`.
+const SYSTEM_OFF: u32 = 0x8400_0008;
+/// The function ID of `SYSTEM_RESET`.
+const SYSTEM_RESET: u32 = 0x8400_0009;
+
+/// An error returned by a PSCI call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// PSCI is not enabled by [`PSCI_CONDUIT`].
+    NotAvailable,
+    /// The call returned, with the given PSCI error code.
+    Failed(i32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotAvailable => write!(f, "psci_conduit is not set"),
+            Error::Failed(code) => write!(f, "PSCI call failed with error {code}"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// The instruction used to call PSCI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Conduit {
+    /// Hypervisor call, for PSCI provided by a hypervisor.
+    Hvc,
+    /// Secure monitor call, for PSCI provided by firmware.
+    Smc,
+}
+
+/// Returns the conduit selected by [`PSCI_CONDUIT`].
+fn conduit() -> Result<Conduit, Error> {
+    match PSCI_CONDUIT.get() {
+        "hvc" => Ok(Conduit::Hvc),
+        "smc" => Ok(Conduit::Smc),
+        "" => Err(Error::NotAvailable),
+        other => {
+            log::warn!("invalid value for psci_conduit: {other}");
+            Err(Error::NotAvailable)
+        }
+    }
+}
+
+/// Calls the PSCI function `function` with arguments `args`, returning its result.
+fn call(function: u32, args: [u64; 3]) -> Result<i64, Error> {
+    let result: i64;
+    // SAFETY: `PSCI_CONDUIT` says that this conduit reaches a PSCI implementation, and the
+    //         registers which SMCCC allows the call to change are marked as clobbered
+    unsafe {
+        match conduit()? {
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inlateout("x0") u64::from(function) => result,
+                inlateout("x1") args[0] => _,
+                inlateout("x2") args[1] => _,
+                inlateout("x3") args[2] => _,
+                out("x4") _, out("x5") _, out("x6") _, out("x7") _,
+                out("x8") _, out("x9") _, out("x10") _, out("x11") _,
+                out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                out("x16") _, out("x17") _,
+                options(nostack),
+            ),
+            Conduit::Smc => asm!(
+                "smc #0",
+                inlateout("x0") u64::from(function) => result,
+                inlateout("x1") args[0] => _,
+                inlateout("x2") args[1] => _,
+                inlateout("x3") args[2] => _,
+                out("x4") _, out("x5") _, out("x6") _, out("x7") _,
+                out("x8") _, out("x9") _, out("x10") _, out("x11") _,
+                out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                out("x16") _, out("x17") _,
+                options(nostack),
+            ),
+        }
+    }
+    Ok(result)
+}
+
+/// Returns the major and minor version of PSCI.
+pub fn version() -> Result<(u16, u16), Error> {
+    let version = call(PSCI_VERSION, [0; 3])? as u32;
+    Ok(((version >> 16) as u16, version as u16))
+}
+
+/// Resets the system. Only returns if the reset fails.
+pub fn system_reset() -> Result<Infallible, Error> {
+    Err(Error::Failed(call(SYSTEM_RESET, [0; 3])? as i32))
+}
+
+/// Powers off the system. Only returns if powering off fails.
+pub fn system_off() -> Result<Infallible, Error> {
+    Err(Error::Failed(call(SYSTEM_OFF, [0; 3])? as i32))
+}
@@ -0,0 +1,114 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! The Raspberry Pi's power management watchdog.
+//!
+//! Once [`start`]ed, the watchdog resets the system unless it is restarted before the timeout
+//! expires. It is also the only way to reset a Raspberry Pi without PSCI, which [`reset`] does by
+//! starting the watchdog with a very short timeout.
+
+use core::ptr;
+
+use spin::Mutex;
+
+use crate::bootboot::MMIO;
+
+/// The offset of the power management registers from the start of the peripherals.
+const PM_OFFSET: usize = 0x10_0000;
+/// The offset of the reset control register.
+const PM_RSTC: usize = 0x1c;
+/// The offset of the watchdog register, which counts down to a reset.
+const PM_WDOG: usize = 0x24;
+
+/// Must be included in every write to the power management registers.
+const PM_PASSWORD: u32 = 0x5a00_0000;
+/// The watchdog's configuration, in the reset control register.
+const PM_RSTC_WRCFG_MASK: u32 = 0x30;
+/// Configures the watchdog to do a full reset, in the reset control register.
+const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x20;
+/// Stops the watchdog, in the reset control register.
+const PM_RSTC_RESET: u32 = 0x102;
+/// The watchdog's timeout, in ticks, in the watchdog register.
+const PM_WDOG_TIME_MASK: u32 = 0x000f_ffff;
+
+/// The number of watchdog ticks in a second.
+const TICKS_PER_SECOND: u32 = 1 << 16;
+/// The longest timeout, in seconds.
+pub const MAX_TIMEOUT: u32 = PM_WDOG_TIME_MASK / TICKS_PER_SECOND;
+
+/// Serializes access to the power management registers.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Starts or restarts the watchdog, which resets the system after `seconds` seconds, up to
+/// [`MAX_TIMEOUT`].
+pub fn start(seconds: u32) {
+    start_ticks(seconds.clamp(1, MAX_TIMEOUT) * TICKS_PER_SECOND);
+}
+
+/// Stops the watchdog.
+pub fn stop() {
+    let _guard = LOCK.lock();
+    // SAFETY: access to the power management registers is synchronized with `LOCK`
+    unsafe { write(PM_RSTC, PM_PASSWORD | PM_RSTC_RESET) };
+}
+
+/// Resets the system with the watchdog.
+///
+/// This doesn't wait for other users of the watchdog, so that it works even if a panic interrupted
+/// one of them.
+pub fn reset() -> ! {
+    // SAFETY: the system is being reset, so racing with other users of the registers is harmless
+    unsafe { arm(10) };
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Starts the watchdog with a timeout of `ticks` ticks.
+fn start_ticks(ticks: u32) {
+    let _guard = LOCK.lock();
+    // SAFETY: access to the power management registers is synchronized with `LOCK`
+    unsafe { arm(ticks) };
+}
+
+/// Starts the watchdog with a timeout of `ticks` ticks.
+///
+/// # Safety
+/// Access to the power management registers must be synchronized.
+unsafe fn arm(ticks: u32) {
+    // SAFETY: the caller guarantees that access is synchronized
+    unsafe {
+        write(PM_WDOG, PM_PASSWORD | (ticks & PM_WDOG_TIME_MASK));
+        let rstc = read(PM_RSTC) & !PM_RSTC_WRCFG_MASK;
+        write(PM_RSTC, PM_PASSWORD | rstc | PM_RSTC_WRCFG_FULL_RESET);
+    }
+}
+
+/// Reads the power management register at offset `reg`.
+///
+/// # Safety
+/// Access to the power management registers must be synchronized.
+unsafe fn read(reg: usize) -> u32 {
+    // SAFETY: BOOTBOOT maps the peripherals at `MMIO`, which include the power management
+    //         registers, and the caller guarantees that access is synchronized
+    unsafe { ptr::read_volatile(MMIO.as_ptr().add(PM_OFFSET + reg).cast::<u32>()) }
+}
+
+/// Writes `value` to the power management register at offset `reg`.
+///
+/// # Safety
+/// Access to the power management registers must be synchronized.
+unsafe fn write(reg: usize, value: u32) {
+    // SAFETY: BOOTBOOT maps the peripherals at `MMIO`, which include the power management
+    //         registers, and the caller guarantees that access is synchronized
+    unsafe {
+        ptr::write_volatile(
+            MMIO.as_ptr().add(PM_OFFSET + reg).cast::<u32>() as *mut u32,
+            value,
+        )
+    }
+}
@@ -7,39 +7,219 @@
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 //! Functionality specific to the `x86_64` architecture.
 
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
+use spin::Mutex;
 use x86_64::{
     structures::{idt::InterruptDescriptorTable, DescriptorTablePointer},
     VirtAddr,
 };
 
-use interrupt::IntVec;
+use super::Degraded;
+use crate::error;
+use hypervisor::Hypervisor;
+use interrupt::{Attributes, IntVec};
+
+/// The interrupt descriptor table, in a page of its own so that it can be made read-only.
+#[repr(C, align(4096))]
+struct Idt(InterruptDescriptorTable);
+
+/// The interrupt descriptor table, which is read-only once [`init`] completes.
+static mut IDT: Idt = Idt(InterruptDescriptorTable::new());
+/// Serializes changes to [`IDT`] after [`init`] completes.
+static IDT_LOCK: Mutex<()> = Mutex::new(());
+
+/// A step of [`init`] which failed, with the error which caused it.
+///
+/// None of these failures is fatal. Each variant describes the degraded mode the kernel runs in
+/// without the corresponding feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The local APIC could not be enabled, so it is left as the firmware configured it, and
+    /// nothing which depends on it, such as corrected machine check interrupts, is available.
+    Apic(apic::Error),
+    /// APIC error interrupts could not be enabled, so errors such as illegal vectors go unreported.
+    ApicErrors(apic::Error),
+    /// Machine checks could not be enabled, so hardware errors are neither reported nor
+    /// quarantined, and an uncorrected error shuts down the processor.
+    MachineCheck(mce::Error),
+    /// Corrected machine check interrupts could not be enabled, so corrected errors are only found
+    /// when the banks are [polled](mce::poll).
+    Cmci(mce::Error),
+    /// The null page could not be unmapped, so null pointer dereferences aren't caught.
+    NullPage(paging::Error),
+    /// The kernel's code and read-only data could not be made read-only, so stray writes to them
+    /// aren't caught.
+    KernelImage(paging::Error),
+    /// The interrupt descriptor table could not be made read-only, so stray writes to it aren't
+    /// caught.
+    Idt(paging::Error),
+    /// The local APIC timer could not be set up, so there is no periodic tick.
+    Timer(timer::Error),
+    /// The serial console's interrupt could not be enabled, so its input is only read from the
+    /// UART's receive FIFO, and is lost if more arrives than the FIFO holds before it's read.
+    SerialInterrupt(serial::Error),
+    /// Running as an SEV-ES guest, but the GHCB page could not be shared with the hypervisor, so
+    /// any MSR access or port I/O which the hypervisor intercepts panics.
+    Ghcb(sev::Error),
+    /// Running as an SEV-SNP guest with restricted injection, but the `#HV` doorbell page could
+    /// not be registered, so no interrupts are delivered.
+    Doorbell(sev::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Apic(_) => write!(f, "cannot enable the local APIC"),
+            Error::ApicErrors(_) => write!(f, "cannot enable APIC error interrupts"),
+            Error::MachineCheck(_) => write!(f, "cannot enable machine checks"),
+            Error::Cmci(_) => write!(f, "cannot enable corrected machine check interrupts"),
+            Error::NullPage(_) => write!(f, "cannot unmap the null page"),
+            Error::KernelImage(_) => write!(f, "cannot make the kernel image read-only"),
+            Error::Idt(_) => write!(f, "cannot make the IDT read-only"),
+            Error::Timer(_) => write!(f, "cannot set up the local APIC timer"),
+            Error::SerialInterrupt(_) => write!(f, "cannot enable the serial console's interrupt"),
+            Error::Ghcb(_) => write!(f, "cannot share the GHCB page"),
+            Error::Doorbell(_) => write!(f, "cannot register the #HV doorbell page"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Apic(err) | Error::ApicErrors(err) => Some(err),
+            Error::MachineCheck(err) | Error::Cmci(err) => Some(err),
+            Error::NullPage(err) | Error::KernelImage(err) | Error::Idt(err) => Some(err),
+            Error::Timer(err) => Some(err),
+            Error::SerialInterrupt(err) => Some(err),
+            Error::Ghcb(err) | Error::Doorbell(err) => Some(err),
+        }
+    }
+}
 
-/// Performs initialization required for `x86_64`.
-pub fn init() {
+/// Performs initialization required for `x86_64`, returning the non-critical steps which failed.
+/// Calls after the first do nothing, and return no failures.
+///
+/// Once initialization completes, the kernel's code and read-only data, and the interrupt
+/// descriptor table, are read-only. Use [`update_idt`] to change the interrupt descriptor table.
+pub fn init() -> Degraded {
     static INITIALIZED: AtomicBool = AtomicBool::new(false);
-    static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
+    let mut degraded = Degraded::new();
     if INITIALIZED.swap(true, Ordering::Acquire) {
-        return;
+        return degraded;
     }
 
+    let debug = VirtAddr::from_ptr(interrupt::trampoline::<{ IntVec::DEBUG.0 }> as *const ());
+    let breakpoint =
+        VirtAddr::from_ptr(interrupt::trampoline::<{ IntVec::BREAKPOINT.0 }> as *const ());
+    let nmi = VirtAddr::from_ptr(
+        interrupt::trampoline::<{ IntVec::NON_MASKABLE_INTERRUPT.0 }> as *const (),
+    );
     let double_fault =
         VirtAddr::from_ptr(interrupt::trampoline::<{ IntVec::DOUBLE_FAULT.0 }> as *const ());
+    let vmm_communication =
+        VirtAddr::from_ptr(interrupt::trampoline::<{ IntVec::VMM_COMMUNICATION.0 }> as *const ());
+    let hypervisor_injection = VirtAddr::from_ptr(
+        interrupt::trampoline::<{ IntVec::HYPERVISOR_INJECTION.0 }> as *const (),
+    );
+    let invalid_opcode =
+        VirtAddr::from_ptr(interrupt::trampoline::<{ IntVec::INVALID_OPCODE.0 }> as *const ());
     let segment_not_present =
         VirtAddr::from_ptr(interrupt::trampoline::<{ IntVec::SEGMENT_NOT_PRESENT.0 }> as *const ());
+    let general_protection =
+        VirtAddr::from_ptr(interrupt::trampoline::<{ IntVec::GENERAL_PROTECTION.0 }> as *const ());
+    let page_fault =
+        VirtAddr::from_ptr(interrupt::trampoline::<{ IntVec::PAGE_FAULT.0 }> as *const ());
+    let machine_check =
+        VirtAddr::from_ptr(interrupt::trampoline::<{ IntVec::MACHINE_CHECK.0 }> as *const ());
+
+    // SAFETY: this is the only call, since it's synchronized with `INITIALIZED`, and no IDT entry
+    //         uses an IST stack yet
+    unsafe { tss::load() };
 
+    // SAFETY: `trampoline` can handle interrupts with or without error codes
+    //         access to `IDT` is synchronized with `INITIALIZED`
+    unsafe { IDT.0.debug.set_handler_addr(debug) };
+    // SAFETY: `trampoline` can handle interrupts with or without error codes, and it's safe for
+    //         user mode to execute `int3`, which only calls the debugger hook
+    //         access to `IDT` is synchronized with `INITIALIZED`
+    Attributes::INTERRUPT_GATE
+        .user()
+        .apply(unsafe { IDT.0.breakpoint.set_handler_addr(breakpoint) });
+    // SAFETY: `trampoline` can handle interrupts with or without error codes
+    //         `NMI_IST` is only used for NMIs, and is backed by the TSS loaded above
+    //         access to `IDT` is synchronized with `INITIALIZED`
+    unsafe {
+        IDT.0
+            .non_maskable_interrupt
+            .set_handler_addr(nmi)
+            .set_stack_index(tss::NMI_IST)
+    };
+    // SAFETY: `trampoline` can handle interrupts with or without error codes
+    //         access to `IDT` is synchronized with `INITIALIZED`
+    unsafe { IDT.0.invalid_opcode.set_handler_addr(invalid_opcode) };
     // SAFETY: `trampoline` can handle interrupts with or without error codes
     //         `trampoline<8>` does not return
+    //         `DOUBLE_FAULT_IST` is only used for double faults, and is backed by the TSS loaded
+    //         above
     //         access to `IDT` is synchronized with `INITIALIZED`
-    unsafe { IDT.double_fault.set_handler_addr(double_fault) };
+    unsafe {
+        IDT.0
+            .double_fault
+            .set_handler_addr(double_fault)
+            .set_stack_index(tss::DOUBLE_FAULT_IST)
+    };
     // SAFETY: `trampoline` can handle interrupts with or without error codes
     //         access to `IDT` is synchronized with `INITIALIZED`
     unsafe {
-        IDT.segment_not_present
+        IDT.0
+            .segment_not_present
             .set_handler_addr(segment_not_present)
     };
+    // SAFETY: `trampoline` can handle interrupts with or without error codes
+    //         access to `IDT` is synchronized with `INITIALIZED`
+    unsafe {
+        IDT.0
+            .general_protection_fault
+            .set_handler_addr(general_protection)
+    };
+    // SAFETY: `trampoline` can handle interrupts with or without error codes
+    //         access to `IDT` is synchronized with `INITIALIZED`
+    unsafe { IDT.0.page_fault.set_handler_addr(page_fault) };
+    // SAFETY: `trampoline` can handle interrupts with or without error codes
+    //         `MACHINE_CHECK_IST` is only used for machine checks, and is backed by the TSS loaded
+    //         above
+    //         access to `IDT` is synchronized with `INITIALIZED`
+    unsafe {
+        IDT.0
+            .machine_check
+            .set_handler_addr(machine_check)
+            .set_stack_index(tss::MACHINE_CHECK_IST)
+    };
+    for (vec, trampoline) in (32..=255).zip(interrupt::USER_TRAMPOLINES) {
+        // SAFETY: `trampoline` can handle interrupts with or without error codes
+        //         access to `IDT` is synchronized with `INITIALIZED`
+        unsafe { IDT.0[vec].set_handler_addr(VirtAddr::from_ptr(trampoline as *const ())) };
+    }
+    // SAFETY: `trampoline` can handle interrupts with or without error codes
+    //         access to `IDT` is synchronized with `INITIALIZED`
+    unsafe {
+        IDT.0
+            .vmm_communication_exception
+            .set_handler_addr(vmm_communication)
+    };
+    // SAFETY: `trampoline` can handle interrupts with or without error codes
+    //         access to `IDT` is synchronized with `INITIALIZED`
+    unsafe {
+        IDT.0
+            .hv_injection_exception
+            .set_handler_addr(hypervisor_injection)
+    };
 
     let idt_ptr = DescriptorTablePointer {
         limit: (core::mem::size_of::<InterruptDescriptorTable>() - 1)
@@ -47,235 +227,155 @@ pub fn init() {
             .unwrap(),
         base: VirtAddr::from_ptr(
             // SAFETY: access to `IDT` is synchronized with `INITIALIZED`
-            unsafe { &IDT } as *const _,
+            unsafe { &IDT.0 } as *const _,
         ),
     };
 
     // SAFETY: `idt_ptr` is a valid pointer to `IDT`
     unsafe { x86_64::instructions::tables::lidt(&idt_ptr) };
-}
-
-pub mod interrupt {
-    //! Interrupt handlers.
-
-    use x86_64::structures::idt::{DescriptorTable, SelectorErrorCode};
-
-    #[cfg(doc)]
-    use x86_64::structures::idt::InterruptDescriptorTable;
-
-    /// An interrupt vector.
-    ///
-    /// Vectors `0..32` are reserved for system exceptions. All others are available for use as
-    /// user interrupts.
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-    #[repr(transparent)]
-    pub struct IntVec(pub u8);
-
-    impl IntVec {
-        /// Divide-by-zero-error exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::divide_error`] for details.
-        pub const DIVIDE_BY_ZERO_ERROR: Self = Self(0);
-
-        /// Debug exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::debug`] for details.
-        pub const DEBUG: Self = Self(1);
-
-        /// Non-maskable interrupt.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::non_maskable_interrupt`] for details.
-        pub const NON_MASKABLE_INTERRUPT: Self = Self(2);
-
-        /// Breakpoint exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::breakpoint`] for details.
-        pub const BREAKPOINT: Self = Self(3);
-
-        /// Overflow exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::overflow`] for details.
-        pub const OVERFLOW: Self = Self(4);
-
-        /// Boundr-range exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::bound_range_exceeded`] for details.
-        pub const BOUND_RANGE: Self = Self(5);
-
-        /// Invalid-opcode exception
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::invalid_opcode`] for details.
-        pub const INVALID_OPCODE: Self = Self(6);
-
-        /// Device-not-available exeption.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::device_not_available`] for details.
-        pub const DEVICE_NOT_AVAILABLE: Self = Self(7);
-
-        /// Double-fault exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::double_fault`] for details.
-        pub const DOUBLE_FAULT: Self = Self(8);
-
-        /// Invalid-TSS exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::invalid_tss`] for details.
-        pub const INVALID_TSS: Self = Self(10);
-
-        /// Segment-not-present exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::segment_not_present`] for details.
-        pub const SEGMENT_NOT_PRESENT: Self = Self(11);
-
-        /// Stack exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::stack_segment_fault`] for details.
-        pub const STACK: Self = Self(12);
-
-        /// General-protection exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::general_protection_fault`] for details.
-        pub const GENERAL_PROTECTION: Self = Self(13);
-
-        /// Page-fault exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::page_fault`] for details.
-        pub const PAGE_FAULT: Self = Self(14);
-
-        /// x87 floating-point exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::x87_floating_point`] for details.
-        pub const X87_FLOATING_POINT: Self = Self(16);
-
-        /// Alignment-check exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::alignment_check`] for details.
-        pub const ALIGNMENT_CHECK: Self = Self(17);
-
-        /// Machine-check exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::machine_check`] for details.
-        pub const MACHINE_CHECK: Self = Self(18);
-
-        /// SIMD floating-point exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::simd_floating_point`] for details.
-        pub const SIMD_FLOATING_POINT: Self = Self(19);
-
-        /// Control-protection exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::divide_error`] for details.
-        pub const CONTROL_PROTECTION: Self = Self(21);
-
-        /// Hypervisor-injection exception.
-        pub const HYPERVISOR_INJECTION: Self = Self(28);
-
-        /// VMM-communication exception.
-        pub const VMM_COMMUNICATION: Self = Self(29);
-
-        /// Security exception.
-        ///
-        /// See [`x86_64`]'s [`InterruptDescriptorTable::security_exception`] for details.
-        pub const SECURITY: Self = Self(30);
+    // SAFETY: this is the processor which calls `init`, interrupts are still disabled, and nothing
+    //         else changes the GS base
+    if !unsafe { percpu::init() } {
+        log::warn!(
+            "no per-processor data for local APIC ID {}",
+            apic::current_id()
+        );
+    }
+    // SAFETY: this is the only call, since it's synchronized with `INITIALIZED`, and the IDT is
+    //         loaded
+    if let Err(err) = unsafe { sev::init() } {
+        degraded.push(Error::Ghcb(err));
+    }
 
-        /// Returns true if the interrupt vector is in the range (`0..32`) reserved for exceptions
-        /// (even if the vector isn't currently used).
-        pub fn is_exception(self) -> bool {
-            self.0 < 32
+    if let Some(hypervisor) = Hypervisor::detect() {
+        log::info!(
+            "running under {hypervisor} ({:?})",
+            hypervisor.enlightenments()
+        );
+    }
+    log::info!("hardware virtualization: {:?}", virtualization::detect());
+    log::info!("performance monitoring: {:?}", perf::Pmu::detect());
+    if sev::restricted_injection() {
+        if let Err(err) = sev::register_doorbell() {
+            degraded.push(Error::Doorbell(err));
         }
-
-        /// Returns true if the interrupt vector is in the range (`32..=255`) available for user
-        /// interrupts.
-        pub fn is_user_interrupt(self) -> bool {
-            self.0 >= 32
+    }
+    match apic::enable() {
+        Ok(()) => {
+            if let Err(err) = apic::enable_error_interrupt() {
+                degraded.push(Error::ApicErrors(err));
+            }
         }
+        Err(err) => degraded.push(Error::Apic(err)),
+    }
+    match mce::init() {
+        Ok(banks) => log::info!("machine checks enabled with {banks} banks"),
+        Err(err) => degraded.push(Error::MachineCheck(err)),
+    }
+    emulate::init();
+    log::info!("clocks: {}", clock::init());
+    match timer::init() {
+        Ok(hz) => log::info!("local APIC timer: {hz} Hz"),
+        Err(err) => degraded.push(Error::Timer(err)),
+    }
+    storm::init();
+    match serial::enable_interrupt() {
+        Ok(()) | Err(serial::Error::NotPresent) => {}
+        Err(err) => degraded.push(Error::SerialInterrupt(err)),
+    }
+    // SAFETY: the handlers for all user interrupt vectors, including `CMCI_VECTOR`, are installed
+    //         above
+    match unsafe { mce::enable_cmci() } {
+        Ok(banks) => log::info!("corrected machine check interrupts enabled for {banks} banks"),
+        Err(err) => degraded.push(Error::Cmci(err)),
     }
 
-    /// Interrupt handler trampoline.
-    ///
-    /// # Safety
-    /// This function is not safe to call directly, but it can be used as an x86_64 interrupt
-    /// handler, whether or not the interrupt has an error code. If no error code is passed by the
-    /// CPU, then `0` is pushed as the error code.
-    #[naked]
-    pub unsafe extern "C" fn trampoline<const VEC: u8>() {
-        // SAFETY: see comments below
-        unsafe {
-            core::arch::asm!(
-                // push error code if not present, which ensures a consistent stack layout
-                "bt rsp, 3",
-                "jnc 1f",
-                "push 0",
-
-                // preserves necessary registers for C calling convention
-                "1:",
-                "push rdi",
-                "push rsi",
-                "push rdx",
-                "push rcx",
-                "push rax",
-                "push r8",
-                "push r9",
-                "push r10",
-                "push r11",
-                "cld",
-
-                // SAFETY: this points to the interrupt stack frame
-                // CAUTION: modifying the stack layout may invalidate this pointer
-                "lea rdi, [rsp+0x50]",
-                "mov rsi, {vec}",
-                // SAFETY: this points to the error code
-                // CAUTION: modifying the stack layout may invalidate this pointer
-                "mov rdx, [rsp+0x48]",
-
-                // SAFETY: `handler` uses the C calling convention so any of the callee-saved
-                //         registers are preserved by `handler`. Caller-saved registers have been
-                //         saved and are restored below
-                "call {handler}",
-
-                // restore registers previously preserved
-                "pop r11",
-                "pop r10",
-                "pop r9",
-                "pop r8",
-                "pop rax",
-                "pop rcx",
-                "pop rdx",
-                "pop rsi",
-                "pop rdi",
-                // remove error code
-                "add rsp, 8",
+    if let Err(err) = paging::init() {
+        degraded.push(Error::NullPage(err));
+    }
+    // SAFETY: nothing writes to the kernel's code or read-only data
+    if let Err(err) = unsafe { paging::protect_kernel_image() } {
+        degraded.push(Error::KernelImage(err));
+    }
+    // SAFETY: from now on, the IDT is only changed by `update_idt`, which makes it writable first
+    if let Err(err) = unsafe { paging::set_writable(idt_range(), false) } {
+        degraded.push(Error::Idt(err));
+    }
 
-                // SAFETY: rsp now points to the interrupt stack frame, without the error code
-                // CAUTION: when making changes to the stack, care must be taken to ensure
-                //          the safety statement above remains true
-                "iretq",
+    degraded
+}
 
-                vec = const VEC,
-                handler = sym handler,
-                options(noreturn),
-            );
-        }
+/// Changes the interrupt descriptor table, which is otherwise read-only once [`init`] completes.
+///
+/// # Safety
+/// Any handlers set by `f` must be valid interrupt handlers.
+pub unsafe fn update_idt(f: impl FnOnce(&mut InterruptDescriptorTable)) {
+    let _guard = IDT_LOCK.lock();
+
+    // SAFETY: the IDT's page is only made writable while `IDT_LOCK` is held
+    let writable = unsafe { paging::set_writable(idt_range(), true) };
+    // SAFETY: access to `IDT` is synchronized with `IDT_LOCK`, and the caller guarantees that any
+    //         handlers are valid
+    f(unsafe { &mut IDT.0 });
+    if writable.is_ok() {
+        // making the IDT read-only again can't fail, since making it writable succeeded
+        // SAFETY: the IDT is no longer being written
+        let _ = unsafe { paging::set_writable(idt_range(), false) };
     }
+}
 
-    unsafe extern "C" fn handler(stack_frame: &[usize; 5], vec: IntVec, error_code: u64) {
-        let stack_frame_ptr = stack_frame as *const _;
-        log::info!("stack_frame_ptr = {stack_frame_ptr:?}");
-        log::info!("stack_frame = {stack_frame:x?}");
-        log::info!("vec = {vec:?}");
-        log::info!("error_code = {error_code:x}");
-
-        match vec {
-            IntVec::SEGMENT_NOT_PRESENT => {
-                let err = SelectorErrorCode::new_truncate(error_code);
-                match err.descriptor_table() {
-                    DescriptorTable::Idt => {
-                        panic!("handler not present: interrupt vector {}", err.index() / 2)
-                    }
-                    _ => panic!("segment not present: {err:?}"),
-                }
-            }
-            vec => unimplemented!("handler for interrupt vector {vec:?}"),
-        }
+/// Switches to the stack ending at `stack_top` and calls `f(arg)` on it.
+///
+/// # Safety
+/// `stack_top` must be 16-byte aligned, and the end of a stack which nothing else is using, large
+/// enough for `f`.
+pub unsafe fn call_on_stack(stack_top: *mut u8, f: extern "C" fn(usize) -> !, arg: usize) -> ! {
+    // SAFETY: the caller guarantees that the stack is valid and unused, and `f` never returns, so
+    //         the abandoned stack is never used again by this function
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {stack_top}",
+            "call {f}",
+            stack_top = in(reg) stack_top,
+            f = in(reg) f,
+            in("rdi") arg,
+            options(noreturn),
+        )
     }
 }
+
+/// Returns the addresses of the interrupt descriptor table.
+fn idt_range() -> core::ops::Range<VirtAddr> {
+    // SAFETY: only the address of `IDT` is taken
+    let start = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(IDT) });
+    start..(start + core::mem::size_of::<Idt>())
+}
+
+pub mod apic;
+pub mod clock;
+pub mod debug;
+pub mod decode;
+pub mod emulate;
+pub mod error_code;
+pub mod fault;
+pub mod frame;
+pub mod hypervisor;
+pub mod inject;
+pub mod interrupt;
+pub mod ioapic;
+pub mod kmap;
+pub mod mce;
+pub mod paging;
+pub mod percpu;
+pub mod perf;
+pub mod ps2;
+pub mod serial;
+pub mod sev;
+pub mod single_step;
+pub mod softirq;
+pub mod storm;
+pub mod threaded;
+pub mod timer;
+pub mod tss;
+pub mod vector;
+pub mod virtualization;
@@ -0,0 +1,301 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! The local APIC, in x2APIC mode.
+//!
+//! Only x2APIC mode is supported, since its registers are accessed with MSRs, so it doesn't need
+//! its MMIO page to be mapped uncacheable. On processors without x2APIC, [`enable`] returns
+//! [`Error::X2ApicUnsupported`] and the local APIC is left as the firmware configured it.
+//!
+//! Spurious interrupts and [APIC errors](enable_error_interrupt) are counted, which
+//! [`spurious_interrupts`] and [`errors`] report, and logged.
+
+use core::{
+    arch::x86_64::__cpuid,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use x86_64::registers::model_specific::Msr;
+
+use super::{
+    interrupt::{self, Context, IntVec},
+    storm, vector,
+};
+
+/// `IA32_APIC_BASE`, which enables the local APIC and selects its mode.
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Enables the local APIC, in `IA32_APIC_BASE`.
+const APIC_BASE_EN: u64 = 1 << 11;
+/// Enables x2APIC mode, in `IA32_APIC_BASE`.
+const APIC_BASE_EXTD: u64 = 1 << 10;
+
+/// The x2APIC ID register.
+const X2APIC_ID: u32 = 0x802;
+/// The x2APIC end-of-interrupt register.
+const X2APIC_EOI: u32 = 0x80b;
+/// The x2APIC error status register.
+const X2APIC_ESR: u32 = 0x828;
+/// The x2APIC spurious interrupt vector register.
+const X2APIC_SVR: u32 = 0x80f;
+/// The x2APIC self IPI register.
+const X2APIC_SELF_IPI: u32 = 0x83f;
+
+/// Enables the APIC, in the spurious interrupt vector register.
+const SVR_APIC_ENABLE: u64 = 1 << 8;
+/// Masks the interrupt, in a local vector table register.
+const LVT_MASKED: u64 = 1 << 16;
+
+/// The vector used for spurious interrupts, which need no handling and no end-of-interrupt.
+pub const SPURIOUS_VECTOR: IntVec = IntVec(0xff);
+
+/// Whether the local APIC has been enabled in x2APIC mode.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The number of spurious interrupts since boot.
+static SPURIOUS_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+/// The number of APIC error interrupts since boot.
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// An error which prevented the local APIC from being enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The processor doesn't support x2APIC mode.
+    X2ApicUnsupported,
+    /// The local APIC hasn't been enabled.
+    NotEnabled,
+    /// There is no free interrupt vector.
+    NoFreeVector,
+    /// An interrupt handler could not be registered.
+    Register(interrupt::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::X2ApicUnsupported => write!(f, "x2APIC mode is not supported"),
+            Error::NotEnabled => write!(f, "the local APIC is not enabled"),
+            Error::NoFreeVector => write!(f, "no free interrupt vector"),
+            Error::Register(_) => write!(f, "cannot register the interrupt handler"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {
+    fn source(&self) -> Option<&(dyn crate::error::Error + 'static)> {
+        match self {
+            Error::Register(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The contents of the error status register, which has a bit set for each kind of error the local
+/// APIC detected.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ErrorStatus(pub u32);
+
+impl ErrorStatus {
+    /// The name of each error bit, in order of bit, as `(bit, name)`.
+    const ERRORS: [(u32, &'static str); 8] = [
+        (0, "send checksum error"),
+        (1, "receive checksum error"),
+        (2, "send accept error"),
+        (3, "receive accept error"),
+        (4, "redirectable IPI"),
+        (5, "send illegal vector"),
+        (6, "received illegal vector"),
+        (7, "illegal register address"),
+    ];
+}
+
+impl fmt::Display for ErrorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        for (bit, name) in Self::ERRORS {
+            if self.0 & (1 << bit) != 0 {
+                write!(f, "{separator}{name}")?;
+                separator = ", ";
+            }
+        }
+        if separator.is_empty() {
+            write!(f, "no error")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ErrorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ErrorStatus({:#x}: {self})", self.0)
+    }
+}
+
+/// A local vector table register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Lvt {
+    /// Corrected machine check interrupts.
+    Cmci = 0x82f,
+    /// The local APIC timer.
+    Timer = 0x832,
+    /// Thermal sensor interrupts.
+    Thermal = 0x833,
+    /// Performance monitoring counter overflow interrupts.
+    PerformanceCounter = 0x834,
+    /// The `LINT0` pin.
+    Lint0 = 0x835,
+    /// The `LINT1` pin.
+    Lint1 = 0x836,
+    /// Internal APIC errors.
+    Error = 0x837,
+}
+
+/// Enables the local APIC in x2APIC mode.
+pub fn enable() -> Result<(), Error> {
+    // SAFETY: CPUID is available on all x86_64 processors
+    if unsafe { __cpuid(1) }.ecx & (1 << 21) == 0 {
+        return Err(Error::X2ApicUnsupported);
+    }
+
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    // SAFETY: x2APIC is supported, and the local APIC must be enabled in xAPIC mode before
+    //         switching to x2APIC mode
+    unsafe {
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_EN);
+        apic_base.write(value | APIC_BASE_EN | APIC_BASE_EXTD);
+        Msr::new(X2APIC_SVR).write(SVR_APIC_ENABLE | u64::from(SPURIOUS_VECTOR.0));
+    }
+
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Allocates a vector for APIC error interrupts, registers their handler, and unmasks them,
+/// returning the vector.
+pub fn enable_error_interrupt() -> Result<IntVec, Error> {
+    if !is_enabled() {
+        return Err(Error::NotEnabled);
+    }
+    let vec = vector::alloc().ok_or(Error::NoFreeVector)?;
+    if let Err(err) = interrupt::register(vec, handle_error) {
+        vector::free(vec);
+        return Err(Error::Register(err));
+    }
+    // SAFETY: x2APIC mode is enabled, and writing the error status register clears any errors
+    //         detected before the handler was registered
+    unsafe { Msr::new(X2APIC_ESR).write(0) };
+    storm::register(vec, mask_error_interrupt);
+    // SAFETY: `handle_error` is registered for `vec`, and signals end-of-interrupt
+    unsafe { set_lvt(Lvt::Error, Some(vec)) };
+    Ok(vec)
+}
+
+/// Returns the number of spurious interrupts since boot.
+pub fn spurious_interrupts() -> u64 {
+    SPURIOUS_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of APIC error interrupts since boot.
+pub fn errors() -> u64 {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if the local APIC has been [enabled](enable).
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Returns the local APIC ID of the current processor.
+///
+/// # Panics
+/// Panics if the local APIC has not been [enabled](enable).
+pub fn id() -> u32 {
+    assert!(is_enabled(), "the local APIC is not enabled");
+    // SAFETY: x2APIC mode is enabled
+    unsafe { Msr::new(X2APIC_ID).read() as u32 }
+}
+
+/// Returns the local APIC ID of the current processor, from the local APIC if it has been
+/// [enabled](enable), or from CPUID otherwise, in which case it is at most 255.
+pub fn current_id() -> u32 {
+    if is_enabled() {
+        id()
+    } else {
+        // SAFETY: CPUID is available on all x86_64 processors
+        unsafe { __cpuid(1) }.ebx >> 24
+    }
+}
+
+/// Routes the local interrupt `lvt` to `vec` with fixed delivery, or masks it if `vec` is `None`.
+///
+/// # Panics
+/// Panics if the local APIC has not been [enabled](enable).
+///
+/// # Safety
+/// There must be a handler for `vec` which signals [end-of-interrupt](eoi).
+pub unsafe fn set_lvt(lvt: Lvt, vec: Option<IntVec>) {
+    assert!(is_enabled(), "the local APIC is not enabled");
+    let value = match vec {
+        Some(vec) => u64::from(vec.0),
+        None => LVT_MASKED,
+    };
+    // SAFETY: x2APIC mode is enabled, and the caller guarantees that `vec` is handled
+    unsafe { Msr::new(lvt as u32).write(value) };
+}
+
+/// Sends an interrupt on `vec` to the current processor, which is delivered once interrupts are
+/// enabled.
+///
+/// # Panics
+/// Panics if the local APIC has not been [enabled](enable), or if `vec` is an exception vector,
+/// which the local APIC can't deliver.
+///
+/// # Safety
+/// There must be a handler for `vec` which signals [end-of-interrupt](eoi).
+pub unsafe fn send_self(vec: IntVec) {
+    assert!(is_enabled(), "the local APIC is not enabled");
+    assert!(vec.0 >= 16, "{vec:?} cannot be sent as an IPI");
+    // SAFETY: x2APIC mode is enabled, and the caller guarantees that `vec` is handled
+    unsafe { Msr::new(X2APIC_SELF_IPI).write(u64::from(vec.0)) };
+}
+
+/// Signals the end of the interrupt currently being handled.
+pub fn eoi() {
+    if is_enabled() {
+        // SAFETY: x2APIC mode is enabled, and writing zero to the EOI register is always valid
+        unsafe { Msr::new(X2APIC_EOI).write(0) };
+    }
+}
+
+/// Handles a spurious interrupt, which needs no end-of-interrupt.
+pub(super) fn handle_spurious(context: &Context) {
+    SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    log::debug!("spurious interrupt at {:#x}", context.rip);
+    storm::record(SPURIOUS_VECTOR, false);
+}
+
+/// Handles an APIC error interrupt.
+fn handle_error(_context: &mut Context) {
+    // SAFETY: this interrupt is only delivered in x2APIC mode, and the error status register is
+    //         updated by writing to it before it's read
+    let status = unsafe {
+        let mut esr = Msr::new(X2APIC_ESR);
+        esr.write(0);
+        ErrorStatus(esr.read() as u32)
+    };
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+    log::warn!("local APIC error: {status}");
+    eoi();
+}
+
+/// Masks the error interrupt during an interrupt storm. Errors are still recorded in the error
+/// status register.
+fn mask_error_interrupt() {
+    // SAFETY: masking an interrupt doesn't require a handler
+    unsafe { set_lvt(Lvt::Error, None) };
+}
@@ -0,0 +1,318 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Calibration and validation of the time stamp counter.
+//!
+//! [`init`] measures the time stamp counter (TSC) against the programmable interval timer (PIT)
+//! several times, and compares the results with each other and with the frequency reported by
+//! CPUID. The TSC is only selected as the clock source if it is invariant and the measurements
+//! agree; otherwise the PIT is. If [`CLOCK_CHECK`] is set, the TSC is also measured against the
+//! real-time clock (RTC) over a whole second, which takes up to two seconds.
+//!
+//! The HPET isn't checked, since finding it needs the ACPI tables.
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+use crate::param;
+
+param! {
+    /// Whether to also check the time stamp counter against the real-time clock at boot, which
+    /// takes up to two seconds.
+    pub static CLOCK_CHECK: bool = false, name = "clock_check";
+}
+
+/// The PIT's input frequency.
+const PIT_HZ: u64 = 1_193_182;
+/// The length of each PIT measurement, in milliseconds.
+const PIT_SAMPLE_MS: u64 = 20;
+/// The number of PIT measurements.
+const PIT_SAMPLES: usize = 3;
+/// The largest difference between measurements, in parts per thousand, for them to agree.
+const TOLERANCE_PPT: u64 = 5;
+/// The time stamp counter frequency assumed if it can't be measured or reported.
+const DEFAULT_TSC_HZ: u64 = 2_000_000_000;
+/// The number of TSC ticks to wait for the PIT or RTC before assuming that it isn't there.
+const TIMEOUT_TICKS: u64 = 5_000_000_000;
+
+/// The PIT's channel 2 data port.
+const PIT_CHANNEL_2: u16 = 0x42;
+/// The PIT's mode/command port.
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2, low then high byte, mode 0 (interrupt on terminal count), in the PIT command.
+const PIT_CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+/// The port controlling channel 2's gate and the speaker.
+const NMI_STATUS_CONTROL: u16 = 0x61;
+/// Channel 2's gate, in [`NMI_STATUS_CONTROL`].
+const GATE_2: u8 = 1 << 0;
+/// Connects channel 2 to the speaker, in [`NMI_STATUS_CONTROL`].
+const SPEAKER: u8 = 1 << 1;
+/// Channel 2's output, in [`NMI_STATUS_CONTROL`].
+const OUT_2: u8 = 1 << 5;
+
+/// The CMOS register select port, which also disables NMIs when bit 7 is set.
+const CMOS_SELECT: u16 = 0x70;
+/// The CMOS data port.
+const CMOS_DATA: u16 = 0x71;
+/// The RTC's seconds register.
+const RTC_SECONDS: u8 = 0x00;
+/// The RTC's status register A.
+const RTC_STATUS_A: u8 = 0x0a;
+/// An update is in progress, in status register A.
+const RTC_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+
+/// The measured, or reported, time stamp counter frequency.
+static TSC_HZ: AtomicU64 = AtomicU64::new(DEFAULT_TSC_HZ);
+/// Whether the TSC was selected as the clock source.
+static TSC_SELECTED: AtomicBool = AtomicBool::new(false);
+
+/// A source of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The time stamp counter.
+    Tsc,
+    /// The programmable interval timer.
+    Pit,
+}
+
+/// The results of [`init`]'s measurements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    /// Whether CPUID reports that the TSC runs at a constant rate in all power states.
+    pub invariant: bool,
+    /// The TSC frequency reported by CPUID, if any.
+    pub cpuid_hz: Option<u64>,
+    /// Each measurement of the TSC frequency against the PIT, or `None` if the PIT didn't count.
+    pub pit_hz: [Option<u64>; PIT_SAMPLES],
+    /// The measurement of the TSC frequency against the RTC, if [`CLOCK_CHECK`] is set and the RTC
+    /// ticked.
+    pub rtc_hz: Option<u64>,
+    /// The selected clock source.
+    pub source: Source,
+}
+
+impl Report {
+    /// Returns `true` if every measurement agrees with the others, and with CPUID, within
+    /// [`TOLERANCE_PPT`] parts per thousand.
+    pub fn consistent(&self) -> bool {
+        let mut measured = self
+            .pit_hz
+            .iter()
+            .chain([&self.rtc_hz, &self.cpuid_hz])
+            .flatten();
+        match measured.next() {
+            Some(&first) => {
+                self.pit_hz.iter().all(Option::is_some) && measured.all(|&hz| agree(hz, first))
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TSC ")?;
+        if !self.invariant {
+            write!(f, "not ")?;
+        }
+        write!(f, "invariant; CPUID: ")?;
+        write_hz(f, self.cpuid_hz)?;
+        write!(f, "; PIT:")?;
+        for hz in self.pit_hz {
+            write!(f, " ")?;
+            write_hz(f, hz)?;
+        }
+        if CLOCK_CHECK.get() {
+            write!(f, "; RTC: ")?;
+            write_hz(f, self.rtc_hz)?;
+        }
+        write!(f, "; using {:?}", self.source)
+    }
+}
+
+/// Writes a frequency in MHz, or `none`.
+fn write_hz(f: &mut fmt::Formatter<'_>, hz: Option<u64>) -> fmt::Result {
+    match hz {
+        Some(hz) => write!(f, "{}.{:03} MHz", hz / 1_000_000, hz / 1_000 % 1_000),
+        None => write!(f, "none"),
+    }
+}
+
+/// Measures the time stamp counter, and selects the clock source.
+pub fn init() -> Report {
+    // SAFETY: CPUID is available on all x86_64 processors
+    let invariant = unsafe { __cpuid(0x8000_0000) }.eax >= 0x8000_0007
+        // SAFETY: CPUID is available on all x86_64 processors, and this leaf is supported
+        && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0;
+    let cpuid_hz = cpuid_tsc_hz();
+
+    let mut pit_hz = [None; PIT_SAMPLES];
+    for hz in &mut pit_hz {
+        *hz = measure_with_pit();
+    }
+    let rtc_hz = if CLOCK_CHECK.get() {
+        measure_with_rtc()
+    } else {
+        None
+    };
+
+    let mut report = Report {
+        invariant,
+        cpuid_hz,
+        pit_hz,
+        rtc_hz,
+        source: Source::Pit,
+    };
+    if invariant && report.consistent() {
+        report.source = Source::Tsc;
+    }
+
+    let hz = match (pit_hz[0], cpuid_hz) {
+        (Some(hz), _) | (None, Some(hz)) => hz,
+        (None, None) => DEFAULT_TSC_HZ,
+    };
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    TSC_SELECTED.store(report.source == Source::Tsc, Ordering::Relaxed);
+
+    report
+}
+
+/// Returns the time stamp counter's frequency, as measured by [`init`] or reported by CPUID, or a
+/// guess if neither is available or `init` hasn't been called.
+pub fn tsc_hz() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// Returns the clock source selected by [`init`].
+pub fn source() -> Source {
+    if TSC_SELECTED.load(Ordering::Relaxed) {
+        Source::Tsc
+    } else {
+        Source::Pit
+    }
+}
+
+/// Returns `true` if `a` and `b` differ by at most [`TOLERANCE_PPT`] parts per thousand.
+fn agree(a: u64, b: u64) -> bool {
+    a.abs_diff(b) <= a.max(b) / 1000 * TOLERANCE_PPT
+}
+
+/// Returns the time stamp counter's frequency, if CPUID reports it.
+fn cpuid_tsc_hz() -> Option<u64> {
+    // SAFETY: CPUID is available on all x86_64 processors
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+
+    if max_leaf >= 0x15 {
+        // SAFETY: CPUID is available on all x86_64 processors, and this leaf is supported
+        let tsc = unsafe { __cpuid(0x15) };
+        if tsc.eax != 0 && tsc.ebx != 0 && tsc.ecx != 0 {
+            return Some(u64::from(tsc.ecx) * u64::from(tsc.ebx) / u64::from(tsc.eax));
+        }
+    }
+    if max_leaf >= 0x16 {
+        // SAFETY: CPUID is available on all x86_64 processors, and this leaf is supported
+        let base_mhz = unsafe { __cpuid(0x16) }.eax & 0xffff;
+        if base_mhz != 0 {
+            return Some(u64::from(base_mhz) * 1_000_000);
+        }
+    }
+    None
+}
+
+/// Returns the time stamp counter.
+fn rdtsc() -> u64 {
+    // SAFETY: the time stamp counter is available on all x86_64 processors
+    unsafe { _rdtsc() }
+}
+
+/// Measures the time stamp counter's frequency over [`PIT_SAMPLE_MS`] milliseconds of the PIT's
+/// channel 2, returning `None` if the PIT doesn't count.
+fn measure_with_pit() -> Option<u64> {
+    time_with_pit(PIT_SAMPLE_MS, rdtsc).map(|start| (rdtsc() - start) * 1000 / PIT_SAMPLE_MS)
+}
+
+/// Counts `ms` milliseconds with the PIT's channel 2, calling `start` as soon as it's counting, and
+/// returns `start`'s result once they've passed, or `None` if the PIT doesn't count.
+///
+/// # Panics
+/// Panics if `ms` is too long for channel 2's count, which is about 54 milliseconds.
+pub(super) fn time_with_pit<T>(ms: u64, start: impl FnOnce() -> T) -> Option<T> {
+    let mut control = Port::<u8>::new(NMI_STATUS_CONTROL);
+    let mut command = PortWriteOnly::<u8>::new(PIT_COMMAND);
+    let mut channel_2 = PortWriteOnly::<u8>::new(PIT_CHANNEL_2);
+    let count = u16::try_from(PIT_HZ * ms / 1000).expect("too long for the PIT");
+
+    // SAFETY: these are the PIT's standard ports, and channel 2 is only used here, with the
+    //         speaker disconnected
+    let previous = unsafe {
+        let previous = control.read();
+        control.write((previous & !SPEAKER) | GATE_2);
+        command.write(PIT_CHANNEL_2_ONE_SHOT);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+        previous
+    };
+    let started = rdtsc();
+    let result = start();
+
+    let mut done = false;
+    while rdtsc() - started < TIMEOUT_TICKS {
+        // SAFETY: reading channel 2's output has no side effects
+        if unsafe { control.read() } & OUT_2 != 0 {
+            done = true;
+            break;
+        }
+    }
+    // SAFETY: restoring the gate and speaker as they were
+    unsafe { control.write(previous) };
+
+    done.then_some(result)
+}
+
+/// Measures the time stamp counter's frequency over one second of the RTC, returning `None` if
+/// the RTC doesn't tick.
+fn measure_with_rtc() -> Option<u64> {
+    let first = wait_for_rtc_tick(rdtsc())?;
+    let second = wait_for_rtc_tick(first)?;
+    Some(second - first)
+}
+
+/// Waits for the RTC's seconds to change, returning the time stamp counter when they did, or
+/// `None` if they don't change within [`TIMEOUT_TICKS`] of `start`.
+fn wait_for_rtc_tick(start: u64) -> Option<u64> {
+    let mut seconds = None;
+    while rdtsc() - start < TIMEOUT_TICKS {
+        match (seconds, read_rtc_seconds()) {
+            (None, now) => seconds = now,
+            (Some(seconds), Some(now)) if now != seconds => return Some(rdtsc()),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Reads the RTC's seconds register, or returns `None` if an update is in progress.
+fn read_rtc_seconds() -> Option<u8> {
+    let mut select = PortWriteOnly::<u8>::new(CMOS_SELECT);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+
+    // SAFETY: these are the CMOS's standard ports, and reading the RTC's registers has no side
+    //         effects; NMIs stay enabled, since bit 7 of the register index is clear
+    unsafe {
+        select.write(RTC_STATUS_A);
+        if data.read() & RTC_UPDATE_IN_PROGRESS != 0 {
+            return None;
+        }
+        select.write(RTC_SECONDS);
+        Some(data.read())
+    }
+}
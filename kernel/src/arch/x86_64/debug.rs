@@ -0,0 +1,66 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Software breakpoints, and the hook for a kernel debugger.
+//!
+//! [`breakpoint`] executes `int3`, which causes a [breakpoint exception](IntVec::BREAKPOINT). If a
+//! debugger has been registered with [`set_debugger`], the exception handler calls it with the
+//! interrupted [`Context`], which it may inspect and change before execution continues. Otherwise
+//! the breakpoint is logged and execution continues after it.
+//!
+//! `int3` is a trap, so the context's `rip` is the address of the instruction after it.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::interrupt::Context;
+#[cfg(doc)]
+use super::interrupt::IntVec;
+
+/// A kernel debugger, which is called with the context of each breakpoint.
+pub type Debugger = fn(&mut Context);
+
+/// The registered debugger, as a [`Debugger`], or zero if there isn't one.
+static DEBUGGER: AtomicUsize = AtomicUsize::new(0);
+/// The number of breakpoints hit since boot.
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Executes a breakpoint, which calls the registered debugger, if there is one.
+#[inline(always)]
+pub fn breakpoint() {
+    // SAFETY: the breakpoint exception's handler returns to the next instruction, after calling
+    //         the debugger, which may only change the context in ways the debugger expects
+    unsafe { core::arch::asm!("int3", options(nostack)) };
+}
+
+/// Registers `debugger` to be called on every breakpoint, replacing any debugger already
+/// registered.
+pub fn set_debugger(debugger: Debugger) {
+    DEBUGGER.store(debugger as usize, Ordering::Release);
+}
+
+/// Removes the registered debugger, so breakpoints are only logged.
+pub fn clear_debugger() {
+    DEBUGGER.store(0, Ordering::Release);
+}
+
+/// Returns the number of breakpoints hit since boot.
+pub fn hits() -> u64 {
+    HITS.load(Ordering::Relaxed)
+}
+
+/// Handles a breakpoint exception.
+pub(super) fn handle_breakpoint(context: &mut Context) {
+    HITS.fetch_add(1, Ordering::Relaxed);
+    match DEBUGGER.load(Ordering::Acquire) {
+        0 => log::warn!("breakpoint at {:#x}", context.rip.wrapping_sub(1)),
+        debugger => {
+            // SAFETY: only `Debugger` pointers are stored in `DEBUGGER`
+            let debugger = unsafe { core::mem::transmute::<usize, Debugger>(debugger) };
+            debugger(context);
+        }
+    }
+}
@@ -0,0 +1,744 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! A minimal decoder for 64-bit mode x86 instructions.
+//!
+//! The decoder determines the length of an instruction and the layout of its operands, which is
+//! enough to print the faulting instruction in fault diagnostics and to locate its memory operand.
+//! It does not determine which operation the instruction performs, nor does it check whether the
+//! instruction is valid.
+
+use core::fmt;
+
+/// The maximum length of an x86 instruction in bytes.
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// An error which occurred while decoding an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes ended before the end of the instruction.
+    Truncated,
+    /// The instruction is longer than [`MAX_INSTRUCTION_LEN`].
+    TooLong,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "truncated instruction"),
+            DecodeError::TooLong => write!(f, "instruction exceeds {MAX_INSTRUCTION_LEN} bytes"),
+        }
+    }
+}
+
+impl crate::error::Error for DecodeError {}
+
+/// A segment register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    /// The `ES` segment register.
+    Es,
+    /// The `CS` segment register.
+    Cs,
+    /// The `SS` segment register.
+    Ss,
+    /// The `DS` segment register.
+    Ds,
+    /// The `FS` segment register.
+    Fs,
+    /// The `GS` segment register.
+    Gs,
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Segment::Es => "es",
+            Segment::Cs => "cs",
+            Segment::Ss => "ss",
+            Segment::Ds => "ds",
+            Segment::Fs => "fs",
+            Segment::Gs => "gs",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// The opcode map an instruction's opcode belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpcodeMap {
+    /// The one-byte opcode map.
+    Primary,
+    /// The two-byte opcode map (escape `0f`).
+    Secondary,
+    /// The three-byte opcode map with escape `0f 38`.
+    Map0f38,
+    /// The three-byte opcode map with escape `0f 3a`.
+    Map0f3a,
+    /// An opcode map which is not supported by the decoder, selected by a VEX or EVEX prefix.
+    Other(u8),
+}
+
+/// The size of an immediate operand, before operand-size prefixes are taken into account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Immediate {
+    None,
+    /// One byte.
+    Byte,
+    /// Two bytes.
+    Word,
+    /// A two-byte immediate followed by a one-byte immediate (`enter`).
+    WordByte,
+    /// Two bytes with an operand-size prefix, otherwise four.
+    Z,
+    /// Two bytes with an operand-size prefix, eight with `REX.W`, otherwise four.
+    V,
+    /// A memory offset: four bytes with an address-size prefix, otherwise eight.
+    Offset,
+}
+
+/// The memory operand of an instruction, specified using a ModR/M byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryOperand {
+    /// The segment override, if any.
+    pub segment: Option<Segment>,
+    /// The number (`0..16`) of the base register, if any.
+    pub base: Option<u8>,
+    /// The number (`0..16`) of the index register, if any.
+    pub index: Option<u8>,
+    /// The scale applied to the index register.
+    pub scale: u8,
+    /// The displacement. One-byte displacements of EVEX-encoded instructions are not scaled.
+    pub displacement: i32,
+    /// True if the address is relative to the end of the instruction.
+    pub rip_relative: bool,
+    /// True if an address-size prefix selects 32-bit addressing.
+    pub address_32: bool,
+}
+
+impl fmt::Display for MemoryOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const REG64: [&str; 16] = [
+            "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11",
+            "r12", "r13", "r14", "r15",
+        ];
+        const REG32: [&str; 16] = [
+            "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d",
+            "r12d", "r13d", "r14d", "r15d",
+        ];
+        let regs = if self.address_32 { &REG32 } else { &REG64 };
+
+        if let Some(segment) = self.segment {
+            write!(f, "{segment}:")?;
+        }
+        f.write_str("[")?;
+
+        let mut empty = true;
+        if self.rip_relative {
+            f.write_str(if self.address_32 { "eip" } else { "rip" })?;
+            empty = false;
+        }
+        if let Some(base) = self.base {
+            f.write_str(regs[base as usize])?;
+            empty = false;
+        }
+        if let Some(index) = self.index {
+            if !empty {
+                f.write_str("+")?;
+            }
+            write!(f, "{}*{}", regs[index as usize], self.scale)?;
+            empty = false;
+        }
+        if empty {
+            write!(f, "{:#x}", self.displacement as u32)?;
+        } else if self.displacement < 0 {
+            write!(f, "-{:#x}", self.displacement.unsigned_abs())?;
+        } else if self.displacement > 0 {
+            write!(f, "+{:#x}", self.displacement)?;
+        }
+
+        f.write_str("]")
+    }
+}
+
+/// A decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    bytes: [u8; MAX_INSTRUCTION_LEN],
+    len: u8,
+    opcode_map: OpcodeMap,
+    opcode: u8,
+    modrm: Option<u8>,
+    register_operand: Option<u8>,
+    memory_operand: Option<MemoryOperand>,
+    operand_size: u8,
+}
+
+impl Instruction {
+    /// Decodes the instruction at the beginning of `bytes`.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Decoder::new(bytes).decode()
+    }
+
+    /// Decodes the instruction at `addr`, reading no further than the end of the page containing
+    /// `addr`.
+    ///
+    /// # Safety
+    /// The bytes from `addr` to the end of its 4-KiB page must be readable.
+    pub unsafe fn read(addr: u64) -> Result<Self, DecodeError> {
+        let len = (0x1000 - (addr & 0xfff) as usize).min(MAX_INSTRUCTION_LEN);
+
+        // SAFETY: the caller guarantees the memory is readable
+        let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+
+        Self::decode(bytes)
+    }
+
+    /// Returns the bytes of the instruction.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// Returns the length of the instruction in bytes.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Returns the opcode map the instruction's opcode belongs to.
+    pub fn opcode_map(&self) -> OpcodeMap {
+        self.opcode_map
+    }
+
+    /// Returns the opcode of the instruction, excluding any escape bytes.
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    /// Returns the ModR/M byte, if the instruction has one.
+    pub fn modrm(&self) -> Option<u8> {
+        self.modrm
+    }
+
+    /// Returns the number (`0..16`) of the register specified by the ModR/M byte's `r/m` field, if
+    /// the instruction has a ModR/M byte which specifies a register rather than a memory operand.
+    pub fn register_operand(&self) -> Option<u8> {
+        self.register_operand
+    }
+
+    /// Returns the memory operand, if the instruction has one.
+    pub fn memory_operand(&self) -> Option<MemoryOperand> {
+        self.memory_operand
+    }
+
+    /// Returns the operand size in bytes selected by the prefixes: `8` with `REX.W`, `2` with an
+    /// operand-size prefix, and otherwise `4`, which is the default for most instructions.
+    pub fn operand_size(&self) -> u8 {
+        self.operand_size
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.bytes().iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+
+        if let Some(operand) = self.memory_operand {
+            write!(f, " (memory operand {operand})")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The state of an instruction being decoded.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    operand_16: bool,
+    address_32: bool,
+    segment: Option<Segment>,
+    /// The `REX` prefix bits `W`, `R`, `X` and `B`, which are also found in VEX and EVEX prefixes.
+    rex: u8,
+}
+
+impl<'a> Decoder<'a> {
+    const REX_W: u8 = 0b1000;
+    const REX_X: u8 = 0b0010;
+    const REX_B: u8 = 0b0001;
+
+    fn new(bytes: &'a [u8]) -> Self {
+        Decoder {
+            bytes,
+            pos: 0,
+            operand_16: false,
+            address_32: false,
+            segment: None,
+            rex: 0,
+        }
+    }
+
+    fn next(&mut self) -> Result<u8, DecodeError> {
+        if self.pos >= MAX_INSTRUCTION_LEN {
+            return Err(DecodeError::TooLong);
+        }
+        let byte = *self.bytes.get(self.pos).ok_or(DecodeError::Truncated)?;
+        self.pos += 1;
+
+        Ok(byte)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), DecodeError> {
+        for _ in 0..len {
+            self.next()?;
+        }
+
+        Ok(())
+    }
+
+    fn decode(mut self) -> Result<Instruction, DecodeError> {
+        // legacy prefixes, and a REX prefix, which only counts if it immediately precedes the opcode
+        let mut byte = self.next()?;
+        loop {
+            match byte {
+                0x40..=0x4f => {
+                    self.rex = byte & 0xf;
+                    byte = self.next()?;
+                    continue;
+                }
+                0x26 => self.segment = Some(Segment::Es),
+                0x2e => self.segment = Some(Segment::Cs),
+                0x36 => self.segment = Some(Segment::Ss),
+                0x3e => self.segment = Some(Segment::Ds),
+                0x64 => self.segment = Some(Segment::Fs),
+                0x65 => self.segment = Some(Segment::Gs),
+                0x66 => self.operand_16 = true,
+                0x67 => self.address_32 = true,
+                0xf0 | 0xf2 | 0xf3 => {}
+                _ => break,
+            }
+            self.rex = 0;
+            byte = self.next()?;
+        }
+
+        let (opcode_map, opcode, has_modrm, immediate) = match byte {
+            0x0f => {
+                let byte = self.next()?;
+                match byte {
+                    0x38 => (OpcodeMap::Map0f38, self.next()?, true, Immediate::None),
+                    0x3a => (OpcodeMap::Map0f3a, self.next()?, true, Immediate::Byte),
+                    _ => {
+                        let (has_modrm, immediate) = secondary_operands(byte);
+                        (OpcodeMap::Secondary, byte, has_modrm, immediate)
+                    }
+                }
+            }
+            // two-byte VEX prefix
+            0xc5 => {
+                let vex = self.next()?;
+                self.rex = (!vex >> 5) & 0b100;
+
+                let opcode = self.next()?;
+                let (has_modrm, immediate) = vex_secondary_operands(opcode);
+                (OpcodeMap::Secondary, opcode, has_modrm, immediate)
+            }
+            // three-byte VEX prefix (`0xc4`) or EVEX prefix (`0x62`)
+            0xc4 | 0x62 => {
+                let map_select = self.next()?;
+                let w = self.next()? >> 7;
+                if byte == 0x62 {
+                    self.skip(1)?;
+                }
+                self.rex = (w << 3) | ((!map_select >> 5) & 0b111);
+
+                let opcode = self.next()?;
+                match map_select & if byte == 0x62 { 0b111 } else { 0b11111 } {
+                    1 => {
+                        let (has_modrm, immediate) = vex_secondary_operands(opcode);
+                        (OpcodeMap::Secondary, opcode, has_modrm, immediate)
+                    }
+                    2 => (OpcodeMap::Map0f38, opcode, true, Immediate::None),
+                    3 => (OpcodeMap::Map0f3a, opcode, true, Immediate::Byte),
+                    map => (OpcodeMap::Other(map), opcode, true, Immediate::None),
+                }
+            }
+            _ => {
+                let (has_modrm, immediate) = primary_operands(byte);
+                (OpcodeMap::Primary, byte, has_modrm, immediate)
+            }
+        };
+
+        let mut memory_operand = None;
+        let mut register_operand = None;
+        let modrm = if has_modrm {
+            let modrm = self.next()?;
+            if modrm >> 6 != 0b11 {
+                memory_operand = Some(self.memory_operand(modrm)?);
+            } else {
+                register_operand = Some((modrm & 0b111) | ((self.rex & Self::REX_B) << 3));
+            }
+            Some(modrm)
+        } else {
+            None
+        };
+
+        // group 3 (`test`) is the only group where an immediate depends on the ModR/M byte
+        let immediate = match (opcode_map, opcode, modrm) {
+            (OpcodeMap::Primary, 0xf6, Some(modrm)) if (modrm >> 3) & 0b110 == 0 => Immediate::Byte,
+            (OpcodeMap::Primary, 0xf7, Some(modrm)) if (modrm >> 3) & 0b110 == 0 => Immediate::Z,
+            _ => immediate,
+        };
+        let immediate_len = match immediate {
+            Immediate::None => 0,
+            Immediate::Byte => 1,
+            Immediate::Word => 2,
+            Immediate::WordByte => 3,
+            Immediate::Z if self.operand_16 => 2,
+            Immediate::Z => 4,
+            Immediate::V if self.rex & Self::REX_W != 0 => 8,
+            Immediate::V if self.operand_16 => 2,
+            Immediate::V => 4,
+            Immediate::Offset if self.address_32 => 4,
+            Immediate::Offset => 8,
+        };
+        self.skip(immediate_len)?;
+
+        let mut bytes = [0; MAX_INSTRUCTION_LEN];
+        bytes[..self.pos].copy_from_slice(&self.bytes[..self.pos]);
+
+        Ok(Instruction {
+            bytes,
+            len: self.pos as u8,
+            opcode_map,
+            opcode,
+            modrm,
+            register_operand,
+            memory_operand,
+            operand_size: if self.rex & Self::REX_W != 0 {
+                8
+            } else if self.operand_16 {
+                2
+            } else {
+                4
+            },
+        })
+    }
+
+    /// Decodes the memory operand specified by `modrm`, along with any SIB byte and displacement.
+    fn memory_operand(&mut self, modrm: u8) -> Result<MemoryOperand, DecodeError> {
+        let mode = modrm >> 6;
+        let rm = modrm & 0b111;
+        let b = (self.rex & Self::REX_B) << 3;
+        let x = (self.rex & Self::REX_X) << 2;
+
+        let mut operand = MemoryOperand {
+            segment: self.segment,
+            base: Some(rm | b),
+            index: None,
+            scale: 1,
+            displacement: 0,
+            rip_relative: false,
+            address_32: self.address_32,
+        };
+
+        let mut displacement_len = match mode {
+            0b00 => 0,
+            0b01 => 1,
+            _ => 4,
+        };
+
+        if rm == 0b100 {
+            let sib = self.next()?;
+            let base = sib & 0b111;
+            let index = ((sib >> 3) & 0b111) | x;
+
+            operand.scale = 1 << (sib >> 6);
+            // an index of `rsp` means there is no index register
+            operand.index = if index == 0b100 { None } else { Some(index) };
+            if mode == 0b00 && base == 0b101 {
+                operand.base = None;
+                displacement_len = 4;
+            } else {
+                operand.base = Some(base | b);
+            }
+        } else if mode == 0b00 && rm == 0b101 {
+            operand.base = None;
+            operand.rip_relative = true;
+            displacement_len = 4;
+        }
+
+        operand.displacement = match displacement_len {
+            1 => self.next()? as i8 as i32,
+            4 => i32::from_le_bytes([self.next()?, self.next()?, self.next()?, self.next()?]),
+            _ => 0,
+        };
+
+        Ok(operand)
+    }
+}
+
+/// Returns whether an opcode in the one-byte opcode map has a ModR/M byte, and the size of its
+/// immediate operand.
+fn primary_operands(opcode: u8) -> (bool, Immediate) {
+    match opcode {
+        // arithmetic and logic instructions
+        0x00..=0x3f => match opcode & 0b111 {
+            0..=3 => (true, Immediate::None),
+            4 => (false, Immediate::Byte),
+            5 => (false, Immediate::Z),
+            _ => (false, Immediate::None),
+        },
+        0x63 => (true, Immediate::None),
+        0x68 => (false, Immediate::Z),
+        0x69 => (true, Immediate::Z),
+        0x6a => (false, Immediate::Byte),
+        0x6b => (true, Immediate::Byte),
+        0x70..=0x7f => (false, Immediate::Byte),
+        0x80 | 0x82 | 0x83 => (true, Immediate::Byte),
+        0x81 => (true, Immediate::Z),
+        0x84..=0x8f => (true, Immediate::None),
+        0xa0..=0xa3 => (false, Immediate::Offset),
+        0xa8 => (false, Immediate::Byte),
+        0xa9 => (false, Immediate::Z),
+        0xb0..=0xb7 => (false, Immediate::Byte),
+        0xb8..=0xbf => (false, Immediate::V),
+        0xc0 | 0xc1 | 0xc6 => (true, Immediate::Byte),
+        0xc2 | 0xca => (false, Immediate::Word),
+        0xc7 => (true, Immediate::Z),
+        0xc8 => (false, Immediate::WordByte),
+        0xcd => (false, Immediate::Byte),
+        0xd0..=0xd3 | 0xd8..=0xdf => (true, Immediate::None),
+        0xe0..=0xe7 | 0xeb => (false, Immediate::Byte),
+        0xe8 | 0xe9 => (false, Immediate::Z),
+        0xf6 | 0xf7 | 0xfe | 0xff => (true, Immediate::None),
+        _ => (false, Immediate::None),
+    }
+}
+
+/// Returns whether an opcode in the two-byte opcode map has a ModR/M byte, and the size of its
+/// immediate operand.
+fn secondary_operands(opcode: u8) -> (bool, Immediate) {
+    match opcode {
+        0x05..=0x09 | 0x0b | 0x0e | 0x30..=0x37 | 0x77 | 0xa0..=0xa2 | 0xa8..=0xaa => {
+            (false, Immediate::None)
+        }
+        0x0f | 0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 => (true, Immediate::Byte),
+        0x80..=0x8f => (false, Immediate::Z),
+        0xc8..=0xcf => (false, Immediate::None),
+        _ => (true, Immediate::None),
+    }
+}
+
+/// Returns whether an opcode in the two-byte opcode map, selected by a VEX or EVEX prefix, has a
+/// ModR/M byte, and the size of its immediate operand.
+fn vex_secondary_operands(opcode: u8) -> (bool, Immediate) {
+    match opcode {
+        // `vzeroupper` and `vzeroall`
+        0x77 => (false, Immediate::None),
+        _ => match secondary_operands(opcode) {
+            (true, Immediate::Byte) => (true, Immediate::Byte),
+            _ => (true, Immediate::None),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes `bytes`, which must be exactly one instruction, checking that a following byte isn't
+    /// included.
+    fn decode(bytes: &[u8]) -> Instruction {
+        let instruction = Instruction::decode(bytes).unwrap();
+        assert_eq!(instruction.len(), bytes.len(), "length of {bytes:02x?}");
+        assert_eq!(instruction.bytes(), bytes);
+
+        let mut padded = [0x90; MAX_INSTRUCTION_LEN + 1];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        assert_eq!(Instruction::decode(&padded), Ok(instruction));
+        instruction
+    }
+
+    fn memory_operand(bytes: &[u8]) -> MemoryOperand {
+        decode(bytes).memory_operand().unwrap()
+    }
+
+    #[test]
+    fn prefixes() {
+        // lock add word [r12], 0x1234
+        let add = decode(&[0xf0, 0x66, 0x41, 0x81, 0x04, 0x24, 0x34, 0x12]);
+        assert_eq!(add.operand_size(), 2);
+        assert_eq!(add.memory_operand().unwrap().base, Some(12));
+        // mov rax, fs:[0x28]
+        let operand = memory_operand(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]);
+        assert_eq!(operand.segment, Some(Segment::Fs));
+        assert_eq!((operand.base, operand.index), (None, None));
+        assert_eq!(operand.displacement, 0x28);
+        // rep movsq
+        assert_eq!(decode(&[0xf3, 0x48, 0xa5]).operand_size(), 8);
+        // mov r11, imm64
+        decode(&[0x49, 0xbb, 1, 2, 3, 4, 5, 6, 7, 8]);
+        // mov ax, imm16
+        decode(&[0x66, 0xb8, 0x34, 0x12]);
+
+        // a REX prefix followed by a legacy prefix is ignored
+        let mov = decode(&[0x48, 0x66, 0x89, 0xc0]);
+        assert_eq!(mov.operand_size(), 2);
+        let mov = decode(&[0x66, 0x48, 0x89, 0xc0]);
+        assert_eq!(mov.operand_size(), 8);
+        // mov r11, rax
+        assert_eq!(decode(&[0x49, 0x89, 0xc3]).register_operand(), Some(11));
+    }
+
+    #[test]
+    fn modrm_and_sib() {
+        // mov r9, [rbp-8]
+        let operand = memory_operand(&[0x4c, 0x8b, 0x4d, 0xf8]);
+        assert_eq!((operand.base, operand.displacement), (Some(5), -8));
+        // mov eax, [rsp+0x100]
+        let operand = memory_operand(&[0x8b, 0x84, 0x24, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!((operand.base, operand.index), (Some(4), None));
+        assert_eq!(operand.displacement, 0x100);
+        // mov eax, [eax+ecx*4+8]
+        let operand = memory_operand(&[0x67, 0x8b, 0x44, 0x88, 0x08]);
+        assert!(operand.address_32);
+        assert_eq!(
+            (operand.base, operand.index, operand.scale),
+            (Some(0), Some(1), 4)
+        );
+        assert_eq!(operand.displacement, 8);
+        // mov rax, [r13+r14*8], where r13 as a base needs a displacement
+        let operand = memory_operand(&[0x4b, 0x8b, 0x44, 0xf5, 0x00]);
+        assert_eq!(
+            (operand.base, operand.index, operand.scale),
+            (Some(13), Some(14), 8)
+        );
+        // lea rax, [rbx*2+0x10], which has no base
+        let operand = memory_operand(&[0x48, 0x8d, 0x04, 0x5d, 0x10, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            (operand.base, operand.index, operand.scale),
+            (None, Some(3), 2)
+        );
+        // add byte [rax], 1
+        decode(&[0x80, 0x00, 0x01]);
+        // movzx eax, byte [rdi]
+        decode(&[0x0f, 0xb6, 0x07]);
+        // bt dword [rdi], 3
+        decode(&[0x0f, 0xba, 0x27, 0x03]);
+    }
+
+    #[test]
+    fn rip_relative() {
+        // mov rax, [rip+0x10]
+        let operand = memory_operand(&[0x48, 0x8b, 0x05, 0x10, 0x00, 0x00, 0x00]);
+        assert!(operand.rip_relative);
+        assert_eq!((operand.base, operand.displacement), (None, 0x10));
+        // mov dword [rip-4], 0x12345678, which has an immediate after the displacement
+        let operand = memory_operand(&[0xc7, 0x05, 0xfc, 0xff, 0xff, 0xff, 0x78, 0x56, 0x34, 0x12]);
+        assert!(operand.rip_relative);
+        assert_eq!(operand.displacement, -4);
+        // cmp byte [rip], 0
+        decode(&[0x80, 0x3d, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_immediates() {
+        // test al, 1
+        decode(&[0xf6, 0xc0, 0x01]);
+        // not al
+        decode(&[0xf6, 0xd0]);
+        // test byte [rdi], 0x80
+        decode(&[0xf6, 0x07, 0x80]);
+        // test eax, 0x12345678
+        decode(&[0xf7, 0xc0, 0x78, 0x56, 0x34, 0x12]);
+        // test ax, 0x1234
+        decode(&[0x66, 0xf7, 0xc0, 0x34, 0x12]);
+        // test rax, 0x12345678, whose immediate is sign-extended rather than eight bytes
+        decode(&[0x48, 0xf7, 0xc0, 0x78, 0x56, 0x34, 0x12]);
+        // test dword [rsp+8], 1
+        decode(&[0xf7, 0x44, 0x24, 0x08, 0x01, 0x00, 0x00, 0x00]);
+        // neg eax, and div qword [rcx], which have no immediate
+        decode(&[0xf7, 0xd8]);
+        decode(&[0x48, 0xf7, 0x31]);
+    }
+
+    #[test]
+    fn memory_offsets() {
+        // mov al, [moffs64]
+        decode(&[0xa0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        // mov eax, [moffs32]
+        decode(&[0x67, 0xa1, 1, 2, 3, 4]);
+        // mov [moffs64], al
+        decode(&[0xa2, 1, 2, 3, 4, 5, 6, 7, 8]);
+        // mov [moffs64], rax, where REX.W doesn't change the offset's size
+        decode(&[0x48, 0xa3, 1, 2, 3, 4, 5, 6, 7, 8]);
+        // mov [moffs32], ax, where the operand-size prefix doesn't either
+        decode(&[0x66, 0x67, 0xa3, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn vex() {
+        // vzeroupper, which has no ModR/M byte
+        decode(&[0xc5, 0xf8, 0x77]);
+        // vmovdqa ymm0, [rcx]
+        let instruction = decode(&[0xc5, 0xfd, 0x6f, 0x01]);
+        assert_eq!(instruction.opcode_map(), OpcodeMap::Secondary);
+        // vmovaps xmm0, [r9], with VEX.B set
+        assert_eq!(
+            memory_operand(&[0xc4, 0xc1, 0x78, 0x28, 0x01]).base,
+            Some(9)
+        );
+        // vpshufd xmm0, xmm1, 0x1b
+        decode(&[0xc5, 0xf9, 0x70, 0xc1, 0x1b]);
+        // vpbroadcastd ymm0, [rip]
+        let instruction = decode(&[0xc4, 0xe2, 0x7d, 0x58, 0x05, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(instruction.opcode_map(), OpcodeMap::Map0f38);
+        assert!(instruction.memory_operand().unwrap().rip_relative);
+        // vinsertf128 ymm0, ymm0, xmm1, 1
+        let instruction = decode(&[0xc4, 0xe3, 0x7d, 0x18, 0xc1, 0x01]);
+        assert_eq!(instruction.opcode_map(), OpcodeMap::Map0f3a);
+    }
+
+    #[test]
+    fn evex() {
+        // vmovaps zmm0, [rcx]
+        decode(&[0x62, 0xf1, 0x7c, 0x48, 0x28, 0x01]);
+        // vmovdqa64 zmm0, [rsp+0x80], whose one-byte displacement is scaled by the hardware
+        let operand = memory_operand(&[0x62, 0xf1, 0xfd, 0x48, 0x6f, 0x44, 0x24, 0x02]);
+        assert_eq!((operand.base, operand.displacement), (Some(4), 2));
+        // vpaddd zmm0, zmm1, [rax]
+        decode(&[0x62, 0xf1, 0x75, 0x48, 0xfe, 0x00]);
+        // vextracti64x4 ymm1, zmm0, 1
+        let instruction = decode(&[0x62, 0xf3, 0xfd, 0x48, 0x3b, 0xc1, 0x01]);
+        assert_eq!(instruction.opcode_map(), OpcodeMap::Map0f3a);
+        // vpermb zmm0, zmm1, zmm2
+        let instruction = decode(&[0x62, 0xf2, 0x75, 0x48, 0x8d, 0xc2]);
+        assert_eq!(instruction.opcode_map(), OpcodeMap::Map0f38);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Instruction::decode(&[]), Err(DecodeError::Truncated));
+        assert_eq!(Instruction::decode(&[0x48]), Err(DecodeError::Truncated));
+        assert_eq!(
+            Instruction::decode(&[0x48, 0x8b, 0x05, 0x10]),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(Instruction::decode(&[0x66; 16]), Err(DecodeError::TooLong));
+        // fourteen prefixes and a one-byte instruction is the longest allowed
+        let mut bytes = [0x66; MAX_INSTRUCTION_LEN];
+        bytes[MAX_INSTRUCTION_LEN - 1] = 0x90;
+        assert_eq!(decode(&bytes).len(), MAX_INSTRUCTION_LEN);
+        bytes[MAX_INSTRUCTION_LEN - 1] = 0x04;
+        assert_eq!(Instruction::decode(&bytes), Err(DecodeError::TooLong));
+    }
+}
@@ -0,0 +1,201 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Emulation of instructions which the processor doesn't support.
+//!
+//! When an invalid-opcode exception occurs, the faulting instruction is decoded and offered to each
+//! [registered](register) [`Hook`] in turn. If one of them emulates it, execution resumes after the
+//! instruction; otherwise the exception is fatal, as it would be without any hooks. This lets the
+//! same kernel run, in a degraded mode, on processors which lack some instructions.
+//!
+//! [`init`] registers [`rdrand`], which emulates `rdrand` and `rdseed` on processors without them.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count, _rdtsc},
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::registers::rflags::RFlags;
+
+use super::{
+    decode::{Instruction, OpcodeMap},
+    interrupt::Context,
+};
+
+/// The maximum number of hooks which can be registered.
+pub const MAX_HOOKS: usize = 8;
+
+/// A function which emulates an instruction.
+///
+/// A hook returns `true` if it emulated the instruction, after updating the context with its
+/// effects, except for the instruction pointer, which is then advanced past the instruction. It
+/// returns `false`, without changing the context, if it doesn't emulate the instruction.
+pub type Hook = fn(&Instruction, &mut Context) -> bool;
+
+/// The registered hooks, as `Hook` pointers, or zero for unused slots.
+static HOOKS: [AtomicUsize; MAX_HOOKS] = [const { AtomicUsize::new(0) }; MAX_HOOKS];
+
+/// The state of the pseudorandom number generator used by [`rdrand`], or zero if it hasn't been
+/// seeded.
+static PRNG_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// An error registering a hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// [`MAX_HOOKS`] hooks are already registered.
+    Full,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Full => write!(f, "{MAX_HOOKS} emulation hooks are already registered"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// Registers [`rdrand`] if the processor lacks `rdrand` or `rdseed`.
+pub fn init() {
+    // SAFETY: CPUID is available on all x86_64 processors
+    let has_rdrand = unsafe { __cpuid(1) }.ecx & (1 << 30) != 0;
+    // SAFETY: CPUID is available on all x86_64 processors
+    let has_rdseed = unsafe { __cpuid(0) }.eax >= 7
+        // SAFETY: CPUID is available on all x86_64 processors, and this leaf is supported
+        && unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0;
+
+    if !(has_rdrand && has_rdseed) {
+        match register(rdrand) {
+            Ok(()) => {
+                log::warn!("emulating rdrand and rdseed, which are not cryptographically secure")
+            }
+            Err(err) => log::warn!("cannot emulate rdrand and rdseed: {err}"),
+        }
+    }
+}
+
+/// Registers `hook`, which is offered each instruction raising an invalid-opcode exception after
+/// the hooks registered before it.
+pub fn register(hook: Hook) -> Result<(), Error> {
+    HOOKS
+        .iter()
+        .find(|slot| {
+            slot.compare_exchange(0, hook as usize, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })
+        .map(drop)
+        .ok_or(Error::Full)
+}
+
+/// Offers the instruction which raised an invalid-opcode exception in `context` to each hook,
+/// returning `true` if one of them emulated it.
+pub(super) fn emulate(context: &mut Context) -> bool {
+    if HOOKS[0].load(Ordering::Acquire) == 0 {
+        return false;
+    }
+    // SAFETY: the processor fetched the instruction from this address, so its page is readable
+    let instruction = match unsafe { Instruction::read(context.rip) } {
+        Ok(instruction) => instruction,
+        Err(_) => return false,
+    };
+
+    for slot in &HOOKS {
+        let hook = match slot.load(Ordering::Acquire) {
+            0 => break,
+            // SAFETY: only `Hook` pointers are stored in `HOOKS`
+            hook => unsafe { core::mem::transmute::<usize, Hook>(hook) },
+        };
+        if hook(&instruction, context) {
+            context.rip += instruction.len() as u64;
+            return true;
+        }
+    }
+    false
+}
+
+/// Emulates `rdrand` and `rdseed` with a pseudorandom number generator seeded from the time stamp
+/// counter.
+///
+/// The numbers are **not** cryptographically secure, so this is only suitable for code which
+/// uses these instructions opportunistically, such as to randomize hash tables.
+pub fn rdrand(instruction: &Instruction, context: &mut Context) -> bool {
+    let (reg, modrm) = match (instruction.register_operand(), instruction.modrm()) {
+        (Some(reg), Some(modrm)) => (reg, modrm),
+        _ => return false,
+    };
+    // `rdrand` is `0f c7 /6` and `rdseed` is `0f c7 /7`, with a register operand
+    if instruction.opcode_map() != OpcodeMap::Secondary
+        || instruction.opcode() != 0xc7
+        || (modrm >> 3) & 0b110 != 0b110
+    {
+        return false;
+    }
+
+    let value = next_random();
+    let dest = gpr(context, reg);
+    *dest = match instruction.operand_size() {
+        2 => (*dest & !0xffff) | (value & 0xffff),
+        4 => value & 0xffff_ffff,
+        _ => value,
+    };
+
+    // the carry flag is set when a random number is returned, and the other status flags cleared
+    let status = RFlags::CARRY_FLAG
+        | RFlags::PARITY_FLAG
+        | RFlags::AUXILIARY_CARRY_FLAG
+        | RFlags::ZERO_FLAG
+        | RFlags::SIGN_FLAG
+        | RFlags::OVERFLOW_FLAG;
+    context.rflags = (context.rflags & !status.bits()) | RFlags::CARRY_FLAG.bits();
+    true
+}
+
+/// Returns the next number from an xorshift64* generator.
+fn next_random() -> u64 {
+    let step = |mut x: u64| {
+        if x == 0 {
+            // SAFETY: the time stamp counter is available on all x86_64 processors
+            x = unsafe { _rdtsc() } | 1;
+        }
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        x
+    };
+    let mut next = 0;
+    // the closure always returns `Some`, so this can't fail
+    let _ = PRNG_STATE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+        next = step(x);
+        Some(next)
+    });
+    next.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+/// Returns the general-purpose register numbered `reg` (`0..16`) in `context`.
+fn gpr(context: &mut Context, reg: u8) -> &mut u64 {
+    let registers = &mut context.registers;
+    match reg {
+        0 => &mut registers.rax,
+        1 => &mut registers.rcx,
+        2 => &mut registers.rdx,
+        3 => &mut registers.rbx,
+        4 => &mut context.rsp,
+        5 => &mut registers.rbp,
+        6 => &mut registers.rsi,
+        7 => &mut registers.rdi,
+        8 => &mut registers.r8,
+        9 => &mut registers.r9,
+        10 => &mut registers.r10,
+        11 => &mut registers.r11,
+        12 => &mut registers.r12,
+        13 => &mut registers.r13,
+        14 => &mut registers.r14,
+        _ => &mut registers.r15,
+    }
+}
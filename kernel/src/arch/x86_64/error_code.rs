@@ -0,0 +1,340 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Decoding of the error codes which the processor pushes for exceptions.
+//!
+//! Each error code type wraps the raw value from [`Context::error_code`](super::interrupt::Context)
+//! and has an accessor for each architecturally defined field. Its `Display` output explains the
+//! fault in words, for fault handlers and panic messages, and its `Debug` output shows the decoded
+//! fields.
+
+use core::fmt;
+
+/// The error code of a page fault.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultErrorCode(pub u64);
+
+impl PageFaultErrorCode {
+    /// Returns `true` if the page was present, so the fault was caused by a protection violation,
+    /// or `false` if the page wasn't present.
+    pub const fn protection_violation(self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    /// Returns `true` if the access was a write, or `false` if it was a read.
+    pub const fn caused_by_write(self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    /// Returns `true` if the access was made in user mode.
+    pub const fn user_mode(self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+
+    /// Returns `true` if a reserved bit was set in a paging structure entry.
+    pub const fn malformed_table(self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+
+    /// Returns `true` if the access was an instruction fetch.
+    pub const fn instruction_fetch(self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+
+    /// Returns `true` if the access violated the page's protection key.
+    pub const fn protection_key(self) -> bool {
+        self.0 & (1 << 5) != 0
+    }
+
+    /// Returns `true` if the access was a shadow stack access.
+    pub const fn shadow_stack(self) -> bool {
+        self.0 & (1 << 6) != 0
+    }
+
+    /// Returns `true` if the fault was caused by an SGX access-control violation.
+    pub const fn sgx(self) -> bool {
+        self.0 & (1 << 15) != 0
+    }
+
+    /// Returns `true` if the fault was caused by an SEV-SNP reverse map table check.
+    pub const fn rmp(self) -> bool {
+        self.0 & (1 << 31) != 0
+    }
+}
+
+impl fmt::Display for PageFaultErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.user_mode() { "user" } else { "kernel" };
+        let access = if self.instruction_fetch() {
+            "instruction fetch"
+        } else if self.shadow_stack() {
+            "shadow stack access"
+        } else if self.caused_by_write() {
+            "write"
+        } else {
+            "read"
+        };
+        let cause = if self.protection_violation() {
+            "violating the page's protection"
+        } else {
+            "to a page which isn't present"
+        };
+        write!(f, "{mode}-mode {access} {cause}")?;
+
+        let details = [
+            (self.malformed_table(), "reserved bit set in a page table"),
+            (self.protection_key(), "protection key violation"),
+            (self.sgx(), "SGX violation"),
+            (self.rmp(), "RMP violation"),
+        ];
+        for (_, detail) in details.iter().filter(|(set, _)| *set) {
+            write!(f, ", {detail}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PageFaultErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        debug_flags(
+            f,
+            "PageFaultErrorCode",
+            self.0,
+            &[
+                (self.protection_violation(), "PROTECTION_VIOLATION"),
+                (self.caused_by_write(), "CAUSED_BY_WRITE"),
+                (self.user_mode(), "USER_MODE"),
+                (self.malformed_table(), "MALFORMED_TABLE"),
+                (self.instruction_fetch(), "INSTRUCTION_FETCH"),
+                (self.protection_key(), "PROTECTION_KEY"),
+                (self.shadow_stack(), "SHADOW_STACK"),
+                (self.sgx(), "SGX"),
+                (self.rmp(), "RMP"),
+            ],
+        )
+    }
+}
+
+/// The error code of a control-protection exception.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ControlProtectionErrorCode(pub u64);
+
+/// The kind of control-flow violation which caused a control-protection exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlProtectionKind {
+    /// A near `RET` returned to an address other than the one on the shadow stack.
+    NearRet,
+    /// A far `RET` or `IRET` returned to an address other than the one on the shadow stack.
+    FarRetOrIret,
+    /// An indirect `CALL` or `JMP` targeted an instruction other than `ENDBRANCH`.
+    Endbranch,
+    /// `RSTORSSP` found an invalid shadow stack restore token.
+    Rstorssp,
+    /// `SETSSBSY` found an invalid supervisor shadow stack token.
+    Setssbsy,
+    /// An undefined kind, with the given code.
+    Unknown(u16),
+}
+
+impl ControlProtectionErrorCode {
+    /// Returns the kind of violation.
+    pub const fn kind(self) -> ControlProtectionKind {
+        match self.0 & 0x7fff {
+            1 => ControlProtectionKind::NearRet,
+            2 => ControlProtectionKind::FarRetOrIret,
+            3 => ControlProtectionKind::Endbranch,
+            4 => ControlProtectionKind::Rstorssp,
+            5 => ControlProtectionKind::Setssbsy,
+            code => ControlProtectionKind::Unknown(code as u16),
+        }
+    }
+
+    /// Returns `true` if the violation occurred in an SGX enclave.
+    pub const fn enclave(self) -> bool {
+        self.0 & (1 << 15) != 0
+    }
+}
+
+impl fmt::Display for ControlProtectionErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind() {
+            ControlProtectionKind::NearRet => write!(f, "near return address mismatch")?,
+            ControlProtectionKind::FarRetOrIret => write!(f, "far return address mismatch")?,
+            ControlProtectionKind::Endbranch => write!(f, "indirect branch without ENDBRANCH")?,
+            ControlProtectionKind::Rstorssp => write!(f, "invalid shadow stack restore token")?,
+            ControlProtectionKind::Setssbsy => write!(f, "invalid supervisor shadow stack token")?,
+            ControlProtectionKind::Unknown(code) => write!(f, "unknown violation {code:#x}")?,
+        }
+        if self.enclave() {
+            write!(f, " in an SGX enclave")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ControlProtectionErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ControlProtectionErrorCode")
+            .field("kind", &self.kind())
+            .field("enclave", &self.enclave())
+            .finish()
+    }
+}
+
+/// The error code of a VMM-communication exception, which is the SVM exit code of the intercept.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VmExitCode(pub u64);
+
+/// An SVM intercept which can cause a VMM-communication exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExit {
+    /// A read of the given control register.
+    ReadCr(u8),
+    /// A write to the given control register.
+    WriteCr(u8),
+    /// A read of the given debug register.
+    ReadDr(u8),
+    /// A write to the given debug register.
+    WriteDr(u8),
+    /// An exception with the given vector.
+    Exception(u8),
+    /// `RDTSC`.
+    Rdtsc,
+    /// `RDPMC`.
+    Rdpmc,
+    /// `CPUID`.
+    Cpuid,
+    /// `INVD`.
+    Invd,
+    /// `IN`, `OUT`, `INS` or `OUTS`.
+    IoIo,
+    /// `RDMSR` or `WRMSR`.
+    Msr,
+    /// `VMMCALL`.
+    Vmmcall,
+    /// `RDTSCP`.
+    Rdtscp,
+    /// `WBINVD`.
+    Wbinvd,
+    /// `MONITOR` or `MONITORX`.
+    Monitor,
+    /// `MWAIT` or `MWAITX`.
+    Mwait,
+    /// `XSETBV`.
+    Xsetbv,
+    /// A nested page fault, such as an access to an MMIO range.
+    NestedPageFault,
+    /// Any other exit code.
+    Other(u64),
+}
+
+impl VmExitCode {
+    /// Returns the intercept.
+    pub const fn exit(self) -> VmExit {
+        match self.0 {
+            0x00..=0x0f => VmExit::ReadCr(self.0 as u8),
+            0x10..=0x1f => VmExit::WriteCr((self.0 - 0x10) as u8),
+            0x20..=0x2f => VmExit::ReadDr((self.0 - 0x20) as u8),
+            0x30..=0x3f => VmExit::WriteDr((self.0 - 0x30) as u8),
+            0x40..=0x5f => VmExit::Exception((self.0 - 0x40) as u8),
+            0x6e => VmExit::Rdtsc,
+            0x6f => VmExit::Rdpmc,
+            0x72 => VmExit::Cpuid,
+            0x76 => VmExit::Invd,
+            0x7b => VmExit::IoIo,
+            0x7c => VmExit::Msr,
+            0x81 => VmExit::Vmmcall,
+            0x87 => VmExit::Rdtscp,
+            0x89 => VmExit::Wbinvd,
+            0x8a => VmExit::Monitor,
+            0x8b | 0x8c => VmExit::Mwait,
+            0x8d => VmExit::Xsetbv,
+            0x400 => VmExit::NestedPageFault,
+            code => VmExit::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for VmExitCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.exit() {
+            VmExit::ReadCr(n) => write!(f, "read of CR{n}"),
+            VmExit::WriteCr(n) => write!(f, "write to CR{n}"),
+            VmExit::ReadDr(n) => write!(f, "read of DR{n}"),
+            VmExit::WriteDr(n) => write!(f, "write to DR{n}"),
+            VmExit::Exception(vector) => write!(f, "exception {vector}"),
+            VmExit::Rdtsc => write!(f, "RDTSC"),
+            VmExit::Rdpmc => write!(f, "RDPMC"),
+            VmExit::Cpuid => write!(f, "CPUID"),
+            VmExit::Invd => write!(f, "INVD"),
+            VmExit::IoIo => write!(f, "port I/O"),
+            VmExit::Msr => write!(f, "MSR access"),
+            VmExit::Vmmcall => write!(f, "VMMCALL"),
+            VmExit::Rdtscp => write!(f, "RDTSCP"),
+            VmExit::Wbinvd => write!(f, "WBINVD"),
+            VmExit::Monitor => write!(f, "MONITOR"),
+            VmExit::Mwait => write!(f, "MWAIT"),
+            VmExit::Xsetbv => write!(f, "XSETBV"),
+            VmExit::NestedPageFault => write!(f, "nested page fault"),
+            VmExit::Other(code) => write!(f, "exit code {code:#x}"),
+        }
+    }
+}
+
+impl fmt::Debug for VmExitCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VmExitCode({:#x}: {:?})", self.0, self.exit())
+    }
+}
+
+/// The error code of a security exception.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SecurityErrorCode(pub u64);
+
+impl SecurityErrorCode {
+    /// Returns `true` if the exception is a redirected `INIT` signal.
+    pub const fn init_redirected(self) -> bool {
+        self.0 == 1
+    }
+}
+
+impl fmt::Display for SecurityErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.init_redirected() {
+            write!(f, "redirected INIT signal")
+        } else {
+            write!(f, "unknown security event {:#x}", self.0)
+        }
+    }
+}
+
+impl fmt::Debug for SecurityErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        debug_flags(
+            f,
+            "SecurityErrorCode",
+            self.0,
+            &[(self.init_redirected(), "INIT_REDIRECTED")],
+        )
+    }
+}
+
+/// Writes `name(raw: FLAG | FLAG)` for the `flags` which are set, as a `Debug` representation.
+fn debug_flags(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    raw: u64,
+    flags: &[(bool, &str)],
+) -> fmt::Result {
+    write!(f, "{name}({raw:#x}")?;
+    let mut separator = ": ";
+    for (_, flag) in flags.iter().filter(|(set, _)| *set) {
+        write!(f, "{separator}{flag}")?;
+        separator = " | ";
+    }
+    write!(f, ")")
+}
@@ -0,0 +1,233 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Recovery from faults.
+//!
+//! [`catch_fault`] runs a closure, and if it causes a fault, such as a page fault or general
+//! protection fault, returns the [`Fault`] instead of panicking. This is useful for probing
+//! hardware or parsing untrusted structures, where a fault is an expected outcome.
+//!
+//! Like `longjmp`, recovery abandons the closure's stack frames without running any destructors.
+//!
+//! Only faults raised by the closure itself are caught: the fault must interrupt kernel code running
+//! on the closure's stack, below `catch_fault`'s frame, and must not be raised by an interrupt
+//! handler which interrupted the closure, so that no other code's frames are abandoned.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
+
+use x86_64::registers::control::Cr2;
+
+use super::{
+    interrupt::{self, Context, IntVec},
+    percpu,
+};
+use crate::bootboot::INIT_STACK_SIZE;
+
+/// Set while [`catch_fault`] is running a closure.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// The stack pointer to restore when recovering from a fault.
+static RECOVERY_RSP: AtomicU64 = AtomicU64::new(0);
+/// The [interrupt nesting depth](interrupt::depth) when [`catch_fault`] was called.
+static ENTRY_DEPTH: AtomicU32 = AtomicU32::new(0);
+/// The vector of the fault which was recovered from.
+static FAULT_VECTOR: AtomicU64 = AtomicU64::new(0);
+/// The error code of the fault which was recovered from.
+static FAULT_ERROR_CODE: AtomicU64 = AtomicU64::new(0);
+/// The address of the instruction which caused the fault which was recovered from.
+static FAULT_RIP: AtomicU64 = AtomicU64::new(0);
+/// The value of `CR2` when the fault which was recovered from occurred.
+static FAULT_ADDRESS: AtomicU64 = AtomicU64::new(0);
+
+/// A fault caught by [`catch_fault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    /// The fault's interrupt vector.
+    pub vector: IntVec,
+    /// The fault's error code, or `0` if it doesn't have one.
+    pub error_code: u64,
+    /// The address of the faulting instruction.
+    pub rip: u64,
+    /// The address being accessed, for page faults.
+    pub address: Option<u64>,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "fault {:?} at {:#x} (error code {:#x})",
+            self.vector, self.rip, self.error_code
+        )?;
+        if let Some(address) = self.address {
+            write!(f, " accessing {address:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `f`, returning its result, or the [`Fault`] if it faults.
+///
+/// Only faults which would otherwise be fatal are caught: invalid opcodes, segment-not-present
+/// faults, general protection faults and page faults, raised by `f` itself rather than by an
+/// interrupt handler which interrupted it.
+///
+/// # Panics
+/// Panics if called from within the closure of another call to `catch_fault`.
+///
+/// # Safety
+/// If `f` faults, it is abandoned mid-execution without running destructors, so `f` must not
+/// leave anything in an inconsistent state at any point where it might fault, and must not hold
+/// any locks or other values whose destructors must run.
+pub unsafe fn catch_fault<R>(f: impl FnOnce() -> R) -> Result<R, Fault> {
+    assert!(
+        !ACTIVE.swap(true, Ordering::Acquire),
+        "catch_fault cannot be nested"
+    );
+
+    ENTRY_DEPTH.store(interrupt::depth(), Ordering::Relaxed);
+
+    let mut f = Some(f);
+    let mut result = None;
+    let mut call = || result = f.take().map(|f| f());
+
+    // SAFETY: `call_closure` is instantiated for the type of `call`, which outlives the call
+    let faulted = unsafe { protected_call(&mut call as *mut _ as *mut (), call_closure(&call)) };
+    ACTIVE.store(false, Ordering::Release);
+
+    match result {
+        Some(result) if faulted == 0 => Ok(result),
+        _ => {
+            let vector = IntVec(FAULT_VECTOR.load(Ordering::Relaxed) as u8);
+            Err(Fault {
+                vector,
+                error_code: FAULT_ERROR_CODE.load(Ordering::Relaxed),
+                rip: FAULT_RIP.load(Ordering::Relaxed),
+                address: (vector == IntVec::PAGE_FAULT)
+                    .then(|| FAULT_ADDRESS.load(Ordering::Relaxed)),
+            })
+        }
+    }
+}
+
+/// Recovers from a fault inside [`catch_fault`], by changing `context` to resume at
+/// [`recover_landing`].
+/// Returns `false` if the fault can't be recovered from, including if it wasn't raised by the
+/// closure itself.
+pub(super) fn recover(context: &mut Context, vec: IntVec) -> bool {
+    let catchable = matches!(
+        vec,
+        IntVec::INVALID_OPCODE
+            | IntVec::SEGMENT_NOT_PRESENT
+            | IntVec::GENERAL_PROTECTION
+            | IntVec::PAGE_FAULT
+    );
+    if !catchable || !ACTIVE.load(Ordering::Acquire) {
+        return false;
+    }
+
+    // the fault must interrupt kernel code on the closure's part of the stack, below the frame of
+    // `protected_call`, and this fault must be the only interrupt since `catch_fault` was called
+    let recovery_rsp = RECOVERY_RSP.load(Ordering::Acquire);
+    let stack_bottom = percpu::stack_top().wrapping_sub(INIT_STACK_SIZE);
+    let on_closure_stack = (stack_bottom..recovery_rsp).contains(&context.rsp);
+    let depth = ENTRY_DEPTH.load(Ordering::Relaxed) + 1;
+    if context.cs & 3 != 0 || !on_closure_stack || interrupt::depth() != depth {
+        return false;
+    }
+
+    FAULT_VECTOR.store(vec.0.into(), Ordering::Relaxed);
+    FAULT_ERROR_CODE.store(context.error_code, Ordering::Relaxed);
+    FAULT_RIP.store(context.rip, Ordering::Relaxed);
+    FAULT_ADDRESS.store(Cr2::read().as_u64(), Ordering::Relaxed);
+
+    context.rsp = recovery_rsp;
+    context.rip = recover_landing as *const () as u64;
+    true
+}
+
+/// Returns `call_closure` instantiated for the type of `_closure`.
+fn call_closure<F: FnMut()>(_closure: &F) -> unsafe extern "C" fn(*mut ()) {
+    /// Calls the closure pointed to by `closure`.
+    ///
+    /// # Safety
+    /// `closure` must point to a valid `F`.
+    unsafe extern "C" fn call<F: FnMut()>(closure: *mut ()) {
+        // SAFETY: the caller guarantees that `closure` points to a valid `F`
+        unsafe { (*closure.cast::<F>())() }
+    }
+
+    call::<F>
+}
+
+/// Calls `f(data)`, returning `0`, or `1` if [`recover`] redirects a fault to [`recover_landing`].
+///
+/// # Safety
+/// `f` must be safe to call with `data`.
+#[naked]
+unsafe extern "C" fn protected_call(data: *mut (), f: unsafe extern "C" fn(*mut ())) -> u64 {
+    // SAFETY: see comments below
+    unsafe {
+        core::arch::asm!(
+            // save the callee-saved registers, which `recover_landing` restores after a fault
+            "push rbx",
+            "push rbp",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            // realign the stack for the C calling convention
+            "sub rsp, 8",
+
+            // CAUTION: `recover_landing` depends on this stack layout
+            "mov [rip + {recovery_rsp}], rsp",
+            // SAFETY: the caller guarantees that `f` is safe to call with `data`, which is already
+            //         in `rdi`
+            "call rsi",
+            "xor eax, eax",
+
+            "add rsp, 8",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbp",
+            "pop rbx",
+            "ret",
+
+            recovery_rsp = sym RECOVERY_RSP,
+            options(noreturn),
+        );
+    }
+}
+
+/// Where a fault inside [`protected_call`] resumes, with the stack pointer it saved, returning `1`
+/// from `protected_call`.
+///
+/// # Safety
+/// This function is not safe to call directly. It may only be used by [`recover`].
+#[naked]
+unsafe extern "C" fn recover_landing() {
+    // SAFETY: `recover` sets `rsp` to the value `protected_call` saved, so this restores the
+    //         registers `protected_call` saved and returns to its caller
+    unsafe {
+        core::arch::asm!(
+            "mov eax, 1",
+            "add rsp, 8",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbp",
+            "pop rbx",
+            "ret",
+            options(noreturn),
+        );
+    }
+}
@@ -0,0 +1,565 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Allocation of physical frames.
+//!
+//! Free frames are tracked with a bitmap, which is filled from the
+//! [free frames](crate::bootboot::Bootboot::free_frames) which BOOTBOOT reports, excluding
+//! quarantined and reserved memory, on the first allocation. Memory which is
+//! [quarantined](crate::quarantine) later is [withdrawn](withdraw) from the free frames, and frames
+//! in it which are in use are dropped rather than freed. Only frames below
+//! [`IDENTITY_MAPPED_LIMIT`] are used, so that every frame can be accessed through its physical
+//! address, such as to zero it or to use it as a page table. Frame `0` is never used, since the null page is unmapped.
+//!
+//! [`alloc_contiguous`] allocates physically contiguous frames below a given address, such as for
+//! DMA buffers.
+//!
+//! Each allocated frame has a reference count and [`FrameFlags`], so that a frame can be mapped in
+//! more than one place, such as for shared memory or copy-on-write. A frame starts with one
+//! reference, [`share`] adds another, and [`release`] removes one, only freeing the frame once the
+//! last is gone. The metadata is kept in a table allocated from the first free frames large enough
+//! to hold it, with an entry for each frame up to the highest free one.
+//!
+//! If the firmware provides a [system resource affinity table](Srat), each range of memory is
+//! tagged with its NUMA node, and [`alloc`] prefers frames on the current processor's node, falling
+//! back to any other node. [`alloc_on_node`] prefers a given node instead, such as for the
+//! structures of another processor.
+//!
+//! With the `poison` feature, frames are filled with [`FREE_POISON`] when they're freed, and with
+//! [`ALLOC_POISON`] when they're allocated. Before a freed frame is allocated again, it is checked
+//! for anything other than [`FREE_POISON`], which panics, since something wrote to the frame after
+//! it was freed, such as through a mapping which should have been removed.
+
+use core::{
+    fmt,
+    ops::{BitOr, Range},
+};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::{
+    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use crate::{
+    acpi::Srat, bootboot::BOOTBOOT, memtest::IDENTITY_MAPPED_LIMIT, quarantine, util::Bitmap,
+};
+
+use super::{apic, paging::PAGE_SIZE};
+
+/// The pattern freed frames are filled with when the `poison` feature is enabled.
+pub const FREE_POISON: u64 = 0x6b6b_6b6b_6b6b_6b6b;
+/// The pattern newly allocated frames are filled with when the `poison` feature is enabled, so that
+/// reads of memory which was never written stand out.
+pub const ALLOC_POISON: u64 = 0xa5a5_a5a5_a5a5_a5a5;
+
+/// The maximum number of ranges of memory whose NUMA node is recorded.
+pub const MAX_NODE_RANGES: usize = 32;
+
+/// The number of frames which can be tracked.
+const FRAMES: usize = (IDENTITY_MAPPED_LIMIT / PAGE_SIZE) as usize;
+/// The number of processors whose NUMA node is recorded, which is one for each local APIC ID which
+/// fits in a byte.
+const MAX_CPUS: usize = 256;
+
+/// The free frames.
+static FREE: Mutex<Free> = Mutex::new(Free {
+    frames: Bitmap::new(),
+    metadata: &mut [],
+    nodes: [const { NodeRange::EMPTY }; MAX_NODE_RANGES],
+    cpu_nodes: [None; MAX_CPUS],
+    initialized: false,
+    hint: 0,
+});
+
+/// The free frames, the metadata of the allocated ones, and where to start looking for a free one.
+struct Free {
+    /// A set bit for each free frame.
+    frames: Bitmap<{ FRAMES / 64 }>,
+    /// The metadata of each frame, indexed by frame number.
+    metadata: &'static mut [Metadata],
+    /// The NUMA node of each range of frames, with empty ranges unused.
+    nodes: [NodeRange; MAX_NODE_RANGES],
+    /// The NUMA node of each processor, indexed by local APIC ID.
+    cpu_nodes: [Option<u32>; MAX_CPUS],
+    /// Whether `frames` has been filled from the memory map.
+    initialized: bool,
+    /// The frame number to start searching from.
+    hint: usize,
+}
+
+/// A range of frames in a NUMA node.
+#[derive(Debug, Clone)]
+struct NodeRange {
+    /// The frame numbers in the range.
+    frames: Range<usize>,
+    /// The node's proximity domain.
+    node: u32,
+}
+
+impl NodeRange {
+    /// An unused entry.
+    const EMPTY: Self = NodeRange {
+        frames: 0..0,
+        node: 0,
+    };
+}
+
+/// The metadata of a frame, for which all zeroes is the metadata of a free frame.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Metadata {
+    /// The number of references to the frame, which is zero if it's free or wasn't allocated by
+    /// this module.
+    references: u32,
+    flags: FrameFlags,
+}
+
+/// Flags describing how an allocated frame is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameFlags(u32);
+
+impl FrameFlags {
+    /// No flags.
+    pub const NONE: Self = FrameFlags(0);
+    /// The frame's physical address has been given to a device, so it must not be moved.
+    pub const PINNED: Self = FrameFlags(1 << 0);
+    /// The frame is shared read-only, and is copied when one of its mappings is written to.
+    pub const COPY_ON_WRITE: Self = FrameFlags(1 << 1);
+    /// The frame is free, and was filled with [`FREE_POISON`] when it was freed.
+    const POISONED: Self = FrameFlags(1 << 31);
+
+    /// Returns `true` if all of the flags in `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for FrameFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        FrameFlags(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Display for FrameFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Self::PINNED, "pinned"),
+            (Self::COPY_ON_WRITE, "copy-on-write"),
+        ];
+        let mut first = true;
+        for (flag, name) in names {
+            if self.contains(flag) {
+                write!(f, "{}{name}", if first { "" } else { ", " })?;
+                first = false;
+            }
+        }
+        if first {
+            write!(f, "none")?;
+        }
+        Ok(())
+    }
+}
+
+/// Allocates a frame, with one reference, preferring the current processor's NUMA node. Returns
+/// `None` if there are no free frames left.
+///
+/// The frame's contents are unspecified.
+pub fn alloc() -> Option<PhysFrame> {
+    let mut free = lock();
+    let node = free
+        .cpu_nodes
+        .get(apic::current_id() as usize)
+        .copied()
+        .flatten();
+    free.alloc(node)
+}
+
+/// Allocates a frame, with one reference, preferring NUMA node `node`. Returns `None` if there are
+/// no free frames left.
+///
+/// The frame's contents are unspecified.
+pub fn alloc_on_node(node: u32) -> Option<PhysFrame> {
+    lock().alloc(Some(node))
+}
+
+/// Returns the NUMA node of `frame`, or `None` if it isn't known.
+pub fn node_of(frame: PhysFrame) -> Option<u32> {
+    let address = frame.start_address().as_u64();
+    let index = (address / PAGE_SIZE) as usize;
+    lock()
+        .nodes
+        .iter()
+        .find(|range| range.frames.contains(&index))
+        .map(|range| range.node)
+}
+
+/// Returns the NUMA node of the current processor, or `None` if it isn't known.
+pub fn current_node() -> Option<u32> {
+    let free = lock();
+    free.cpu_nodes
+        .get(apic::current_id() as usize)
+        .copied()
+        .flatten()
+}
+
+/// Allocates a frame and fills it with zeroes, returning `None` if there are no free frames left.
+pub fn alloc_zeroed() -> Option<PhysFrame> {
+    let frame = alloc()?;
+    // SAFETY: the frame was just allocated, so nothing else is using it
+    unsafe { zero(frame, 1) };
+    Some(frame)
+}
+
+/// Allocates `count` physically contiguous frames, each with one reference, starting at a multiple
+/// of `align` bytes and ending at or below the physical address `limit`, and returns the first.
+/// Returns `None` if there is no such run of free frames.
+///
+/// The frames' contents are unspecified.
+///
+/// # Panics
+/// Panics if `align` isn't a power of two.
+pub fn alloc_contiguous(count: usize, align: u64, limit: u64) -> Option<PhysFrame> {
+    assert!(align.is_power_of_two(), "alignment must be a power of two");
+    let align = (align / PAGE_SIZE).max(1) as usize;
+    let limit = (limit.min(IDENTITY_MAPPED_LIMIT) / PAGE_SIZE) as usize;
+
+    let mut free = lock();
+    let first = free.find_contiguous(count, align, limit)?;
+    free.take(first..first + count);
+    Some(frame(first))
+}
+
+/// Returns `frame`, which has one reference, to the allocator.
+///
+/// # Panics
+/// Panics if `frame` is already free, or is shared.
+///
+/// # Safety
+/// `frame` must have been allocated by this module, and nothing may use it afterwards.
+pub unsafe fn free(frame: PhysFrame) {
+    let mut free = lock();
+    let index = free.index_of(frame);
+    assert!(
+        free.metadata[index].references <= 1,
+        "frame {:#x} freed while shared",
+        frame.start_address()
+    );
+    free.give_back(index);
+}
+
+/// Removes the free frames overlapping the physical addresses in `range`, so they're never handed
+/// out again, such as once they've been [quarantined](crate::quarantine). Frames in `range` which
+/// are in use are dropped when they're freed.
+pub fn withdraw(range: Range<u64>) {
+    let mut free = FREE.lock();
+    // until then, quarantined memory is excluded when the free frames are filled
+    if !free.initialized {
+        return;
+    }
+    let start = (range.start / PAGE_SIZE).min(FRAMES as u64) as usize;
+    let end = range.end.div_ceil(PAGE_SIZE).min(FRAMES as u64) as usize;
+    if start < end {
+        free.frames.set_range(start..end, false);
+    }
+}
+
+/// Adds a reference to `frame`, and returns the number of references it now has.
+///
+/// # Panics
+/// Panics if `frame` isn't allocated, or has `u32::MAX` references.
+pub fn share(frame: PhysFrame) -> u32 {
+    let mut free = lock();
+    let index = free.index_of(frame);
+    let metadata = &mut free.metadata[index];
+    assert!(
+        metadata.references > 0,
+        "cannot share unallocated frame {:#x}",
+        frame.start_address()
+    );
+    metadata.references = metadata
+        .references
+        .checked_add(1)
+        .expect("too many references to a frame");
+    metadata.references
+}
+
+/// Removes a reference to `frame`, returning it to the allocator if it was the last. Returns `true`
+/// if the frame was freed.
+///
+/// Frames which weren't allocated by this module, such as those of the kernel image, are ignored.
+///
+/// # Panics
+/// Panics if `frame` is already free.
+///
+/// # Safety
+/// The caller must own the reference, and must not use the frame through it afterwards.
+pub unsafe fn release(frame: PhysFrame) -> bool {
+    let mut free = lock();
+    let index = free.index_of(frame);
+    match free.metadata.get(index).map(|metadata| metadata.references) {
+        Some(1) => {
+            free.give_back(index);
+            true
+        }
+        Some(0) => {
+            assert!(
+                !free.frames.get(index),
+                "frame {:#x} released while free",
+                frame.start_address()
+            );
+            false
+        }
+        Some(_) => {
+            free.metadata[index].references -= 1;
+            false
+        }
+        None => false,
+    }
+}
+
+/// Returns the number of references to `frame`, which is zero if it isn't allocated.
+pub fn references(frame: PhysFrame) -> u32 {
+    let free = lock();
+    free.metadata
+        .get(free.index_of(frame))
+        .map_or(0, |metadata| metadata.references)
+}
+
+/// Returns the flags of `frame`, which are empty if it isn't allocated.
+pub fn flags(frame: PhysFrame) -> FrameFlags {
+    let free = lock();
+    free.metadata
+        .get(free.index_of(frame))
+        .filter(|metadata| metadata.references > 0)
+        .map_or(FrameFlags::NONE, |metadata| metadata.flags)
+}
+
+/// Sets the flags of `frame` to `flags`.
+///
+/// # Panics
+/// Panics if `frame` isn't allocated.
+pub fn set_flags(frame: PhysFrame, flags: FrameFlags) {
+    let mut free = lock();
+    let index = free.index_of(frame);
+    match free.metadata.get_mut(index) {
+        Some(metadata) if metadata.references > 0 => metadata.flags = flags,
+        _ => panic!(
+            "cannot set flags of unallocated frame {:#x}",
+            frame.start_address()
+        ),
+    }
+}
+
+/// Fills `count` frames, starting at `frame`, with zeroes.
+///
+/// # Safety
+/// The frames must be allocated, and nothing else may be using them.
+pub unsafe fn zero(frame: PhysFrame, count: usize) {
+    let start = frame.start_address().as_u64() as *mut u8;
+    // SAFETY: the frames are identity mapped, and the caller guarantees nothing else uses them
+    unsafe { core::ptr::write_bytes(start, 0, count * PAGE_SIZE as usize) };
+}
+
+/// A [`FrameAllocator`] which allocates with [`alloc`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Allocator;
+
+// SAFETY: `alloc` only returns free frames, and never the same frame twice unless it was freed
+unsafe impl FrameAllocator<Size4KiB> for Allocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        alloc()
+    }
+}
+
+impl Free {
+    /// Allocates a frame, preferring NUMA node `node`, if any.
+    fn alloc(&mut self, node: Option<u32>) -> Option<PhysFrame> {
+        let preferred = node.and_then(|node| {
+            self.nodes
+                .iter()
+                .filter(|range| range.node == node)
+                .find_map(|range| {
+                    self.frames
+                        .next_set(range.frames.start)
+                        .filter(|&index| index < range.frames.end)
+                })
+        });
+        let index = match preferred {
+            Some(index) => index,
+            None => {
+                let index = self
+                    .frames
+                    .next_set(self.hint)
+                    .or_else(|| self.frames.next_set(0))?;
+                self.hint = index;
+                index
+            }
+        };
+        self.take(index..index + 1);
+        Some(frame(index))
+    }
+
+    /// Returns the first of `count` free frames, starting at a multiple of `align` frames and
+    /// ending at or below frame `limit`.
+    fn find_contiguous(&self, count: usize, align: usize, limit: usize) -> Option<usize> {
+        let mut start = 0;
+        while let Some(index) = self.frames.next_set(start) {
+            let first = index.next_multiple_of(align);
+            let end = first.checked_add(count).filter(|&end| end <= limit)?;
+            match self.frames.next_clear(first) {
+                Some(used) if used < end => start = used + 1,
+                _ => return Some(first),
+            }
+        }
+        None
+    }
+
+    /// Marks the free frames in `range` as allocated, with one reference and no flags.
+    ///
+    /// # Panics
+    /// With the `poison` feature, panics if a frame was written to after it was freed.
+    fn take(&mut self, range: Range<usize>) {
+        self.frames.set_range(range.clone(), false);
+        for index in range {
+            let metadata = &mut self.metadata[index];
+            if cfg!(feature = "poison") {
+                let frame = frame(index);
+                // SAFETY: the frame was free, so nothing else should be using it
+                let words = unsafe { words(frame) };
+                if metadata.flags.contains(FrameFlags::POISONED) {
+                    if let Some(offset) = words.iter().position(|&word| word != FREE_POISON) {
+                        panic!(
+                            "frame {:#x} was modified at offset {:#x} after it was freed",
+                            frame.start_address(),
+                            offset * 8
+                        );
+                    }
+                }
+                words.fill(ALLOC_POISON);
+            }
+            metadata.references = 1;
+            metadata.flags = FrameFlags::NONE;
+        }
+    }
+
+    /// Marks the frame numbered `index` as free, or drops it if it has been
+    /// [quarantined](crate::quarantine).
+    ///
+    /// # Panics
+    /// Panics if the frame is already free.
+    fn give_back(&mut self, index: usize) {
+        assert!(
+            !self.frames.get(index),
+            "frame {:#x} freed twice",
+            frame(index).start_address()
+        );
+        self.metadata[index].references = 0;
+        self.metadata[index].flags = FrameFlags::NONE;
+        let start = frame(index).start_address().as_u64();
+        if quarantine::overlaps(start..start + PAGE_SIZE) {
+            return;
+        }
+        self.frames.set(index, true);
+        if cfg!(feature = "poison") {
+            // SAFETY: the frame is free, so nothing else may use it
+            unsafe { words(frame(index)) }.fill(FREE_POISON);
+            self.metadata[index].flags = FrameFlags::POISONED;
+        }
+    }
+
+    /// Records the NUMA nodes of memory and processors from `srat`.
+    fn read_nodes(&mut self, srat: &Srat) {
+        let mut ranges = self.nodes.iter_mut();
+        for memory in srat.memory() {
+            let start = memory.range.start.div_ceil(PAGE_SIZE).min(FRAMES as u64) as usize;
+            let end = (memory.range.end / PAGE_SIZE).min(FRAMES as u64) as usize;
+            if start >= end {
+                continue;
+            }
+            match ranges.next() {
+                Some(range) => {
+                    *range = NodeRange {
+                        frames: start..end,
+                        node: memory.node,
+                    }
+                }
+                None => {
+                    log::warn!("more than {MAX_NODE_RANGES} NUMA memory ranges; ignoring the rest");
+                    break;
+                }
+            }
+        }
+        for processor in srat.processors() {
+            if let Some(node) = self.cpu_nodes.get_mut(processor.apic_id as usize) {
+                *node = Some(processor.node);
+            }
+        }
+    }
+
+    /// Returns the number of `frame`.
+    fn index_of(&self, frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / PAGE_SIZE) as usize
+    }
+}
+
+/// Locks the free frames, filling them from the memory map and allocating the metadata table the
+/// first time.
+///
+/// # Panics
+/// Panics if there isn't enough contiguous free memory for the metadata table.
+fn lock() -> MutexGuard<'static, Free> {
+    let mut free = FREE.lock();
+    if !free.initialized {
+        let mut frames = 0;
+        for address in BOOTBOOT.free_frames::<PAGE_SIZE>() {
+            if (PAGE_SIZE..IDENTITY_MAPPED_LIMIT).contains(&address) {
+                let index = (address / PAGE_SIZE) as usize;
+                free.frames.set(index, true);
+                frames = frames.max(index + 1);
+            }
+        }
+
+        let size = frames * core::mem::size_of::<Metadata>();
+        let count = size.div_ceil(PAGE_SIZE as usize);
+        let first = free
+            .find_contiguous(count, 1, FRAMES)
+            .expect("not enough memory for the frame metadata");
+        free.frames.set_range(first..first + count, false);
+        // SAFETY: the frames were just removed from the free frames, so nothing else uses them,
+        //         they're identity mapped, and all zeroes is valid metadata
+        free.metadata = unsafe {
+            zero(frame(first), count);
+            core::slice::from_raw_parts_mut(
+                frame(first).start_address().as_u64() as *mut Metadata,
+                frames,
+            )
+        };
+        if let Some(srat) = Srat::get() {
+            free.read_nodes(&srat);
+        }
+        free.initialized = true;
+    }
+    free
+}
+
+/// Returns the contents of `frame` as words.
+///
+/// # Safety
+/// Nothing else may use the frame while the words are in use.
+unsafe fn words(frame: PhysFrame) -> &'static mut [u64] {
+    let start = frame.start_address().as_u64() as *mut u64;
+    // SAFETY: the frame is identity mapped, and the caller guarantees nothing else uses it
+    unsafe { core::slice::from_raw_parts_mut(start, PAGE_SIZE as usize / 8) }
+}
+
+/// Returns the frame numbered `index`.
+fn frame(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * PAGE_SIZE))
+}
@@ -0,0 +1,145 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Detection of the hypervisor, if any, the kernel is running under, and of the paravirtual
+//! interfaces ("enlightenments") it provides.
+
+use core::{arch::x86_64::__cpuid, fmt};
+
+/// The first CPUID leaf reserved for hypervisors.
+const HYPERVISOR_LEAF: u32 = 0x4000_0000;
+
+/// A hypervisor, as identified by its CPUID vendor signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    /// Linux KVM.
+    Kvm,
+    /// Microsoft Hyper-V, or another hypervisor implementing the Hyper-V interface.
+    HyperV,
+    /// VMware.
+    Vmware,
+    /// Xen.
+    Xen,
+    /// QEMU's tiny code generator, when not using hardware acceleration.
+    QemuTcg,
+    /// Oracle VirtualBox.
+    VirtualBox,
+    /// An unrecognized hypervisor with the given vendor signature.
+    Other([u8; 12]),
+}
+
+impl Hypervisor {
+    /// Returns the hypervisor the kernel is running under, or `None` if it is running on bare
+    /// metal.
+    pub fn detect() -> Option<Self> {
+        // SAFETY: CPUID is available on all x86_64 processors
+        let cpuid = unsafe { __cpuid(1) };
+        if cpuid.ecx & (1 << 31) == 0 {
+            return None;
+        }
+
+        // SAFETY: CPUID is available on all x86_64 processors
+        let cpuid = unsafe { __cpuid(HYPERVISOR_LEAF) };
+        let mut signature = [0; 12];
+        signature[0..4].copy_from_slice(&cpuid.ebx.to_le_bytes());
+        signature[4..8].copy_from_slice(&cpuid.ecx.to_le_bytes());
+        signature[8..12].copy_from_slice(&cpuid.edx.to_le_bytes());
+
+        Some(match &signature {
+            b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+            b"Microsoft Hv" => Hypervisor::HyperV,
+            b"VMwareVMware" => Hypervisor::Vmware,
+            b"XenVMMXenVMM" => Hypervisor::Xen,
+            b"TCGTCGTCGTCG" => Hypervisor::QemuTcg,
+            b"VBoxVBoxVBox" => Hypervisor::VirtualBox,
+            _ => Hypervisor::Other(signature),
+        })
+    }
+
+    /// Returns the paravirtual interfaces provided by the hypervisor.
+    pub fn enlightenments(&self) -> Enlightenments {
+        let max_leaf = hypervisor_cpuid(0);
+        let mut enlightenments = Enlightenments::default();
+
+        match self {
+            Hypervisor::Kvm if max_leaf > HYPERVISOR_LEAF => {
+                let features = hypervisor_cpuid(1);
+                enlightenments.pv_clock =
+                    features & (KVM_FEATURE_CLOCKSOURCE2 | KVM_FEATURE_CLOCKSOURCE) != 0;
+                enlightenments.pv_eoi = features & KVM_FEATURE_PV_EOI != 0;
+                enlightenments.pv_ipi = features & KVM_FEATURE_PV_SEND_IPI != 0;
+            }
+            Hypervisor::HyperV if max_leaf >= HYPERVISOR_LEAF + 4 => {
+                let features = hypervisor_cpuid(3);
+                let recommendations = hypervisor_cpuid(4);
+                enlightenments.pv_clock = features & HV_ACCESS_PARTITION_REFERENCE_TSC != 0;
+                enlightenments.pv_eoi = features & HV_ACCESS_APIC_MSRS != 0
+                    && recommendations & HV_APIC_ACCESS_RECOMMENDED != 0;
+                enlightenments.pv_ipi = recommendations & HV_CLUSTER_IPI_RECOMMENDED != 0;
+            }
+            _ => {}
+        }
+
+        enlightenments
+    }
+}
+
+/// `kvmclock` using the original MSRs.
+const KVM_FEATURE_CLOCKSOURCE: u32 = 1 << 0;
+/// `kvmclock` using the new MSRs.
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+/// Paravirtual end-of-interrupt.
+const KVM_FEATURE_PV_EOI: u32 = 1 << 6;
+/// Paravirtual IPIs using a hypercall.
+const KVM_FEATURE_PV_SEND_IPI: u32 = 1 << 11;
+
+/// The partition reference TSC page is available.
+const HV_ACCESS_PARTITION_REFERENCE_TSC: u32 = 1 << 9;
+/// The APIC registers can be accessed through synthetic MSRs.
+const HV_ACCESS_APIC_MSRS: u32 = 1 << 4;
+/// Using the synthetic MSRs for EOI, ICR and TPR is recommended.
+const HV_APIC_ACCESS_RECOMMENDED: u32 = 1 << 3;
+/// Using a hypercall for cluster IPIs is recommended.
+const HV_CLUSTER_IPI_RECOMMENDED: u32 = 1 << 10;
+
+/// Returns `EAX` for the given offset from the first hypervisor CPUID leaf.
+///
+/// Must only be used when running under a hypervisor.
+fn hypervisor_cpuid(offset: u32) -> u32 {
+    // SAFETY: CPUID is available on all x86_64 processors
+    unsafe { __cpuid(HYPERVISOR_LEAF + offset) }.eax
+}
+
+impl fmt::Display for Hypervisor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Hypervisor::Kvm => f.write_str("KVM"),
+            Hypervisor::HyperV => f.write_str("Hyper-V"),
+            Hypervisor::Vmware => f.write_str("VMware"),
+            Hypervisor::Xen => f.write_str("Xen"),
+            Hypervisor::QemuTcg => f.write_str("QEMU TCG"),
+            Hypervisor::VirtualBox => f.write_str("VirtualBox"),
+            Hypervisor::Other(signature) => {
+                write!(f, "unknown hypervisor \"{}\"", signature.escape_ascii())
+            }
+        }
+    }
+}
+
+/// The paravirtual interfaces provided by a hypervisor.
+///
+/// These are not used yet, but are detected so the timer and interrupt code can make use of them
+/// as it is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Enlightenments {
+    /// A paravirtual clock (`kvmclock` or the Hyper-V reference TSC page) is available.
+    pub pv_clock: bool,
+    /// Paravirtual end-of-interrupt signaling is available.
+    pub pv_eoi: bool,
+    /// Paravirtual inter-processor interrupts are available.
+    pub pv_ipi: bool,
+}
@@ -0,0 +1,228 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Injection of interrupts, for testing the interrupt dispatch path.
+//!
+//! [`inject`] raises a real interrupt, either with an `int` instruction or a self-IPI, so that it
+//! goes through the interrupt descriptor table and [`trampoline`], but it is intercepted before
+//! reaching the vector's handler, and reported as a [`Delivery`]. This checks that every vector is
+//! routed correctly, even vectors whose handlers would panic.
+//!
+//! [`simulate`] instead calls the dispatcher directly with a synthesized [`Context`], such as one
+//! with an error code, so that the handlers themselves can be tested. Both are counted by
+//! [`interrupt::count`](super::interrupt::count).
+//!
+//! This is only intended for tests. Nothing else should inject interrupts.
+//!
+//! [`trampoline`]: super::interrupt::trampoline
+
+use core::{
+    arch::asm,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
+};
+
+use x86_64::{
+    instructions::{
+        interrupts,
+        segmentation::{Segment, CS, SS},
+    },
+    registers::rflags,
+};
+
+use super::{
+    apic,
+    interrupt::{self, Context, IntVec, Registers},
+};
+
+/// The value of [`PENDING`] when no interrupt is being injected.
+const NONE: u16 = u16::MAX;
+/// The number of times to check whether a self-IPI has been delivered before giving up.
+const SELF_IPI_ATTEMPTS: u32 = 1000;
+/// The size of each stub in [`int_stubs`].
+const INT_STUB_LEN: usize = 3;
+
+/// The vector of the interrupt being injected, or [`NONE`].
+static PENDING: AtomicU16 = AtomicU16::new(NONE);
+/// Set if the interrupt being injected is a self-IPI, which needs an end-of-interrupt.
+static SELF_IPI: AtomicBool = AtomicBool::new(false);
+/// Set once the interrupt being injected has been intercepted.
+static DELIVERED: AtomicBool = AtomicBool::new(false);
+/// The error code of the intercepted interrupt.
+static ERROR_CODE: AtomicU64 = AtomicU64::new(0);
+/// The interrupted instruction pointer of the intercepted interrupt.
+static RIP: AtomicU64 = AtomicU64::new(0);
+
+/// How to raise an injected interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// The `int` instruction, which works for any vector with a handler, but never pushes an error
+    /// code.
+    Software,
+    /// A self-IPI from the local APIC, which only works for vectors `16` and up.
+    SelfIpi,
+}
+
+/// An error which prevented an interrupt from being injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Another interrupt is already being injected.
+    Busy,
+    /// The local APIC isn't enabled, so self-IPIs can't be sent.
+    ApicNotEnabled,
+    /// The vector can't be raised with the given method.
+    Unsupported(IntVec, Method),
+    /// The interrupt was raised, but never reached the dispatcher.
+    NotDelivered(IntVec),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Busy => write!(f, "another interrupt is being injected"),
+            Error::ApicNotEnabled => write!(f, "the local APIC is not enabled"),
+            Error::Unsupported(vec, method) => {
+                write!(f, "{vec:?} cannot be raised with {method:?}")
+            }
+            Error::NotDelivered(vec) => write!(f, "{vec:?} was not delivered"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// An injected interrupt, as it reached the dispatcher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delivery {
+    /// The vector the dispatcher was called with.
+    pub vector: IntVec,
+    /// The error code the dispatcher received, which is always `0` for injected interrupts.
+    pub error_code: u64,
+    /// The instruction pointer of the interrupted code.
+    pub rip: u64,
+}
+
+/// Raises an interrupt on `vec` with `method`, and returns how it reached the dispatcher, which
+/// doesn't pass it on to the vector's handler.
+///
+/// If `vec` has no handler in the interrupt descriptor table, the processor raises a
+/// segment-not-present exception instead, which panics.
+pub fn inject(vec: IntVec, method: Method) -> Result<Delivery, Error> {
+    match method {
+        Method::Software => {}
+        Method::SelfIpi if !apic::is_enabled() => return Err(Error::ApicNotEnabled),
+        Method::SelfIpi if vec.0 < 16 => return Err(Error::Unsupported(vec, method)),
+        Method::SelfIpi => {}
+    }
+    if PENDING
+        .compare_exchange(NONE, vec.0.into(), Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return Err(Error::Busy);
+    }
+    DELIVERED.store(false, Ordering::Relaxed);
+    SELF_IPI.store(method == Method::SelfIpi, Ordering::Relaxed);
+
+    match method {
+        Method::Software => {
+            let stub = int_stubs as *const () as usize + usize::from(vec.0) * INT_STUB_LEN;
+            // SAFETY: each stub is an `int` instruction followed by `ret`, and the interrupt is
+            //         intercepted without running the vector's handler
+            unsafe {
+                let stub = core::mem::transmute::<usize, unsafe extern "C" fn()>(stub);
+                stub();
+            }
+        }
+        Method::SelfIpi => {
+            // SAFETY: the interrupt is intercepted, which signals end-of-interrupt
+            unsafe { apic::send_self(vec) };
+            let enabled = interrupts::are_enabled();
+            for _ in 0..SELF_IPI_ATTEMPTS {
+                // the interrupt is delivered after the instruction following `sti`
+                interrupts::enable();
+                core::hint::spin_loop();
+                if !enabled {
+                    interrupts::disable();
+                }
+                if DELIVERED.load(Ordering::Acquire) {
+                    break;
+                }
+            }
+        }
+    }
+
+    let delivered = DELIVERED.load(Ordering::Acquire);
+    let delivery = Delivery {
+        vector: vec,
+        error_code: ERROR_CODE.load(Ordering::Relaxed),
+        rip: RIP.load(Ordering::Relaxed),
+    };
+    PENDING.store(NONE, Ordering::Release);
+
+    delivered
+        .then_some(delivery)
+        .ok_or(Error::NotDelivered(vec))
+}
+
+/// Calls the dispatcher for `vec`, as though an interrupt with `error_code` had occurred at `rip`,
+/// and returns the context as the handler left it.
+///
+/// Unlike [`inject`], this runs the vector's handler, which may panic.
+///
+/// # Safety
+/// The vector's handler must not depend on the interrupt having actually occurred, for instance by
+/// reading hardware state describing it, or by resuming the context.
+pub unsafe fn simulate(vec: IntVec, error_code: u64, rip: u64) -> Context {
+    let rsp: u64;
+    // SAFETY: reading the stack pointer has no side effects
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+
+    let mut context = Context {
+        registers: Registers::default(),
+        error_code,
+        rip,
+        cs: CS::get_reg().0.into(),
+        rflags: rflags::read_raw(),
+        rsp,
+        ss: SS::get_reg().0.into(),
+    };
+    // SAFETY: the caller guarantees that the handler works with a synthesized context
+    unsafe { interrupt::handler(&mut context, vec) };
+    context
+}
+
+/// Intercepts an interrupt on `vec` if it is being injected, returning `true` if it was.
+pub(super) fn intercept(context: &Context, vec: IntVec) -> bool {
+    if PENDING.load(Ordering::Acquire) != u16::from(vec.0) || DELIVERED.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    ERROR_CODE.store(context.error_code, Ordering::Relaxed);
+    RIP.store(context.rip, Ordering::Relaxed);
+    DELIVERED.store(true, Ordering::Release);
+    if SELF_IPI.load(Ordering::Relaxed) {
+        apic::eoi();
+    }
+    true
+}
+
+/// An `int` instruction for each vector, each followed by `ret`, [`INT_STUB_LEN`] bytes apart.
+#[naked]
+unsafe extern "C" fn int_stubs() {
+    // SAFETY: this is only called at the start of a stub, each of which returns
+    unsafe {
+        core::arch::asm!(
+            ".set inject_vector, 0",
+            ".rept 256",
+            // `int imm8` followed by `ret`, encoded by hand so that `int 3` isn't shortened
+            ".byte 0xcd, inject_vector, 0xc3",
+            ".set inject_vector, inject_vector + 1",
+            ".endr",
+            options(noreturn),
+        );
+    }
+}
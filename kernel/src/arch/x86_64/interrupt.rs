@@ -0,0 +1,820 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Interrupt handlers.
+//!
+//! Every interrupt enters through [`trampoline`], which clears the interrupted code's registers
+//! before dispatching, so that values it controls can't reach a handler, even speculatively. The
+//! dispatcher then checks that the stack pointer is within the current processor's stack, an
+//! [IST stack](super::tss), or the [emergency stack](crash::on_emergency_stack), and panics
+//! otherwise, since a handler running on any other stack may corrupt whatever is there.
+//!
+//! Every interrupt handled is counted by vector, which [`count`] reports, and by processor and
+//! vector, which [`stats`] reports.
+//!
+//! Every user interrupt vector has a trampoline in the IDT, so a driver can claim one at any time
+//! by [registering](register) a handler for it, without changing the IDT. Vectors the kernel handles
+//! itself, such as [`CMCI_VECTOR`](super::mce::CMCI_VECTOR), can't be registered.
+
+use core::{
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr2,
+    structures::idt::{DescriptorTable, EntryOptions, SelectorErrorCode},
+    PrivilegeLevel,
+};
+
+use super::{
+    decode::Instruction,
+    error_code::{ControlProtectionErrorCode, PageFaultErrorCode, SecurityErrorCode},
+};
+use crate::{bootboot::INIT_STACK_SIZE, crash};
+
+#[cfg(doc)]
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+/// An interrupt vector.
+///
+/// Vectors `0..32` are reserved for system exceptions. All others are available for use as
+/// user interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct IntVec(pub u8);
+
+impl IntVec {
+    /// Divide-by-zero-error exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::divide_error`] for details.
+    pub const DIVIDE_BY_ZERO_ERROR: Self = Self(0);
+
+    /// Debug exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::debug`] for details.
+    pub const DEBUG: Self = Self(1);
+
+    /// Non-maskable interrupt.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::non_maskable_interrupt`] for details.
+    pub const NON_MASKABLE_INTERRUPT: Self = Self(2);
+
+    /// Breakpoint exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::breakpoint`] for details.
+    pub const BREAKPOINT: Self = Self(3);
+
+    /// Overflow exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::overflow`] for details.
+    pub const OVERFLOW: Self = Self(4);
+
+    /// Boundr-range exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::bound_range_exceeded`] for details.
+    pub const BOUND_RANGE: Self = Self(5);
+
+    /// Invalid-opcode exception
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::invalid_opcode`] for details.
+    pub const INVALID_OPCODE: Self = Self(6);
+
+    /// Device-not-available exeption.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::device_not_available`] for details.
+    pub const DEVICE_NOT_AVAILABLE: Self = Self(7);
+
+    /// Double-fault exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::double_fault`] for details.
+    pub const DOUBLE_FAULT: Self = Self(8);
+
+    /// Invalid-TSS exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::invalid_tss`] for details.
+    pub const INVALID_TSS: Self = Self(10);
+
+    /// Segment-not-present exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::segment_not_present`] for details.
+    pub const SEGMENT_NOT_PRESENT: Self = Self(11);
+
+    /// Stack exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::stack_segment_fault`] for details.
+    pub const STACK: Self = Self(12);
+
+    /// General-protection exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::general_protection_fault`] for details.
+    pub const GENERAL_PROTECTION: Self = Self(13);
+
+    /// Page-fault exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::page_fault`] for details.
+    pub const PAGE_FAULT: Self = Self(14);
+
+    /// x87 floating-point exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::x87_floating_point`] for details.
+    pub const X87_FLOATING_POINT: Self = Self(16);
+
+    /// Alignment-check exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::alignment_check`] for details.
+    pub const ALIGNMENT_CHECK: Self = Self(17);
+
+    /// Machine-check exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::machine_check`] for details.
+    pub const MACHINE_CHECK: Self = Self(18);
+
+    /// SIMD floating-point exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::simd_floating_point`] for details.
+    pub const SIMD_FLOATING_POINT: Self = Self(19);
+
+    /// Control-protection exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::divide_error`] for details.
+    pub const CONTROL_PROTECTION: Self = Self(21);
+
+    /// Hypervisor-injection exception.
+    pub const HYPERVISOR_INJECTION: Self = Self(28);
+
+    /// VMM-communication exception.
+    pub const VMM_COMMUNICATION: Self = Self(29);
+
+    /// Security exception.
+    ///
+    /// See [`x86_64`]'s [`InterruptDescriptorTable::security_exception`] for details.
+    pub const SECURITY: Self = Self(30);
+
+    /// Returns true if the interrupt vector is in the range (`0..32`) reserved for exceptions
+    /// (even if the vector isn't currently used).
+    pub fn is_exception(self) -> bool {
+        self.0 < 32
+    }
+
+    /// Returns true if the interrupt vector is in the range (`32..=255`) available for user
+    /// interrupts.
+    pub fn is_user_interrupt(self) -> bool {
+        self.0 >= 32
+    }
+}
+
+/// The number of processors, by local APIC ID, whose interrupts are counted separately.
+/// Interrupts on processors with higher IDs, or before the processor's
+/// [per-processor data](super::percpu) is set up, are only included in the totals.
+pub const STATS_CPUS: usize = 64;
+
+/// The number of interrupts handled on each vector.
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+/// The number of interrupts handled on each vector by each of the first [`STATS_CPUS`] processors.
+static CPU_COUNTS: [[AtomicU64; 256]; STATS_CPUS] =
+    [const { [const { AtomicU64::new(0) }; 256] }; STATS_CPUS];
+
+/// The interrupt nesting depth of each processor, by local APIC ID.
+static DEPTHS: [AtomicU32; super::percpu::MAX_CPUS] =
+    [const { AtomicU32::new(0) }; super::percpu::MAX_CPUS];
+
+/// Returns the number of interrupts the current processor is handling, including those whose
+/// handlers have been interrupted, or zero outside of interrupt handlers. Interrupts taken before
+/// the processor's [per-processor data](super::percpu) is set up aren't counted.
+///
+/// [Deferred work](super::softirq) run as an interrupt returns is counted as part of the interrupt.
+pub fn depth() -> u32 {
+    depth_counter().map_or(0, |depth| depth.load(Ordering::Relaxed))
+}
+
+/// Returns the current processor's interrupt nesting depth, if its ID is cached.
+fn depth_counter() -> Option<&'static AtomicU32> {
+    super::percpu::cached_id().and_then(|id| DEPTHS.get(id as usize))
+}
+
+/// Returns the number of interrupts on `vec` handled since boot.
+pub fn count(vec: IntVec) -> u64 {
+    COUNTS[usize::from(vec.0)].load(Ordering::Relaxed)
+}
+
+/// Returns the interrupt statistics, whose `Display` output is a table of the vectors which have
+/// been used, with their counts on each processor.
+pub fn stats() -> Stats {
+    Stats(())
+}
+
+/// The number of interrupts handled since boot, by processor and vector.
+///
+/// Counts are read when they're requested, so they may change between calls.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats(());
+
+impl Stats {
+    /// Returns the number of interrupts on `vec` handled by all processors.
+    pub fn total(&self, vec: IntVec) -> u64 {
+        count(vec)
+    }
+
+    /// Returns the number of interrupts on `vec` handled by the processor with local APIC ID
+    /// `cpu`, or `None` if it isn't counted separately.
+    pub fn on_cpu(&self, cpu: u32, vec: IntVec) -> Option<u64> {
+        let counts = CPU_COUNTS.get(cpu as usize)?;
+        Some(counts[usize::from(vec.0)].load(Ordering::Relaxed))
+    }
+
+    /// Returns each vector which has been used, with its total.
+    pub fn vectors(&self) -> impl Iterator<Item = (IntVec, u64)> {
+        (0..=u8::MAX)
+            .map(|vec| (IntVec(vec), count(IntVec(vec))))
+            .filter(|&(_, count)| count != 0)
+    }
+
+    /// Returns the local APIC IDs of the separately counted processors which have handled
+    /// interrupts.
+    fn cpus(&self) -> impl Iterator<Item = u32> {
+        (0..STATS_CPUS as u32).filter(|&cpu| {
+            CPU_COUNTS[cpu as usize]
+                .iter()
+                .any(|count| count.load(Ordering::Relaxed) != 0)
+        })
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vector {:>12}", "total")?;
+        for cpu in self.cpus() {
+            let width = if cpu < 10 { 11 } else { 10 };
+            write!(f, " {:>width$}{cpu}", "cpu")?;
+        }
+        for (vec, total) in self.vectors() {
+            write!(f, "\n{:>6} {total:>12}", vec.0)?;
+            for cpu in self.cpus() {
+                write!(f, " {:>12}", self.on_cpu(cpu, vec).unwrap_or_default())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Disables interrupts on the current processor until it's dropped, when they're restored to the
+/// state they were in when it was created.
+///
+/// Code which takes a lock that interrupt handlers also take must hold one of these, so that a
+/// handler can't interrupt it and wait for the lock forever. Guards may be nested.
+#[derive(Debug)]
+#[must_use = "interrupts are restored as soon as the guard is dropped"]
+pub struct InterruptGuard {
+    /// Whether interrupts were enabled when the guard was created.
+    enabled: bool,
+    /// The guard restores the state of the processor it was created on, so it can't be sent to
+    /// another thread.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl InterruptGuard {
+    /// Disables interrupts, saving whether they were enabled.
+    pub fn new() -> Self {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        InterruptGuard {
+            enabled,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for InterruptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Calls `f` with interrupts disabled on the current processor, and then restores them to their
+/// previous state.
+pub fn disabled<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = InterruptGuard::new();
+    f()
+}
+
+/// A handler for a user interrupt vector.
+///
+/// Handlers run with interrupts disabled, and must signal end-of-interrupt themselves if their
+/// source needs it. A handler which finds work to do reports [progress](super::storm::progress);
+/// otherwise the interrupt counts towards an [interrupt storm](super::storm).
+pub type Handler = fn(&mut Context);
+
+/// The registered handler of each vector, as a [`Handler`], or zero if there isn't one.
+static HANDLERS: [AtomicUsize; 256] = [const { AtomicUsize::new(0) }; 256];
+
+/// The trampoline of each user interrupt vector, starting at vector 32.
+pub(super) static USER_TRAMPOLINES: [unsafe extern "C" fn(); 224] =
+    trampolines!(2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+
+/// The type and privilege level of an IDT gate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    /// Whether the gate is a trap gate, which leaves interrupts enabled, rather than an interrupt
+    /// gate, which disables them.
+    trap: bool,
+    /// The most privileged level from which the gate can be invoked with `int`.
+    dpl: PrivilegeLevel,
+}
+
+impl Attributes {
+    /// An interrupt gate, which disables interrupts while its handler runs, and can only be invoked
+    /// with `int` by the kernel. This is the default.
+    pub const INTERRUPT_GATE: Self = Attributes {
+        trap: false,
+        dpl: PrivilegeLevel::Ring0,
+    };
+
+    /// A trap gate, which leaves interrupts enabled while its handler runs, and can only be invoked
+    /// with `int` by the kernel.
+    pub const TRAP_GATE: Self = Attributes {
+        trap: true,
+        dpl: PrivilegeLevel::Ring0,
+    };
+
+    /// Returns the same type of gate, which can be invoked with `int` from `dpl` or more
+    /// privileged levels.
+    pub const fn with_dpl(self, dpl: PrivilegeLevel) -> Self {
+        Attributes { dpl, ..self }
+    }
+
+    /// Returns the same type of gate, which can be invoked with `int` from user mode, such as for
+    /// breakpoints or system calls.
+    pub const fn user(self) -> Self {
+        self.with_dpl(PrivilegeLevel::Ring3)
+    }
+
+    /// Returns `true` if this is a trap gate.
+    pub const fn is_trap_gate(self) -> bool {
+        self.trap
+    }
+
+    /// Returns the most privileged level from which the gate can be invoked with `int`.
+    pub const fn dpl(self) -> PrivilegeLevel {
+        self.dpl
+    }
+
+    /// Applies the attributes to an IDT entry's options.
+    pub(super) fn apply(self, options: &mut EntryOptions) {
+        options
+            .disable_interrupts(!self.trap)
+            .set_privilege_level(self.dpl);
+    }
+}
+
+impl Default for Attributes {
+    fn default() -> Self {
+        Self::INTERRUPT_GATE
+    }
+}
+
+/// Changes the attributes of the gate of the user interrupt vector `vec`.
+///
+/// # Safety
+/// The handler of a [trap gate](Attributes::TRAP_GATE) must be able to run with interrupts enabled,
+/// and the handler of a gate which user mode can invoke must be safe for it to invoke.
+pub unsafe fn set_attributes(vec: IntVec, attributes: Attributes) -> Result<(), Error> {
+    if !vec.is_user_interrupt() {
+        return Err(Error::Reserved(vec));
+    }
+    // SAFETY: the entry's handler is unchanged, and the caller guarantees it can be used with
+    //         `attributes`
+    unsafe {
+        super::update_idt(|idt| {
+            let entry = &mut idt[usize::from(vec.0)];
+            attributes.apply(entry.set_handler_addr(entry.handler_addr()));
+        })
+    };
+    Ok(())
+}
+
+/// An error registering an interrupt handler, or changing its gate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The vector is reserved for exceptions, or the kernel handles it itself.
+    Reserved(IntVec),
+    /// The vector already has a handler.
+    InUse(IntVec),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Reserved(vec) => write!(f, "interrupt vector {} is reserved", vec.0),
+            Error::InUse(vec) => write!(f, "interrupt vector {} already has a handler", vec.0),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// Registers `handler` as the handler of the user interrupt vector `vec`, which should have been
+/// [allocated](super::vector::alloc) by the caller.
+pub fn register(vec: IntVec, handler: Handler) -> Result<(), Error> {
+    if !vec.is_user_interrupt()
+        || vec == super::mce::CMCI_VECTOR
+        || vec == super::apic::SPURIOUS_VECTOR
+    {
+        return Err(Error::Reserved(vec));
+    }
+    HANDLERS[usize::from(vec.0)]
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| Error::InUse(vec))
+}
+
+/// Removes the handler of `vec`, returning `true` if it had one.
+///
+/// The source of the interrupt must be disabled first. Interrupts already being handled on other
+/// processors may still call the old handler.
+pub fn unregister(vec: IntVec) -> bool {
+    HANDLERS[usize::from(vec.0)].swap(0, Ordering::AcqRel) != 0
+}
+
+/// Calls the registered handler of `vec`, and records the interrupt for storm detection, returning
+/// `false` if it doesn't have a handler.
+fn dispatch(context: &mut Context, vec: IntVec) -> bool {
+    match HANDLERS[usize::from(vec.0)].load(Ordering::Acquire) {
+        0 => false,
+        handler => {
+            // SAFETY: only `Handler` pointers are stored in `HANDLERS`
+            let handler = unsafe { core::mem::transmute::<usize, Handler>(handler) };
+            handler(context);
+            super::threaded::wake(vec);
+            super::storm::record_handled(vec);
+            true
+        }
+    }
+}
+
+/// The general-purpose registers of interrupted code, as saved by [`trampoline`].
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+#[allow(missing_docs)] // each field is named after its register
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// The state of interrupted code, as saved on the stack by the processor and [`trampoline`].
+///
+/// Any changes made by an interrupt handler take effect when the interrupted code resumes.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Context {
+    /// The general-purpose registers.
+    pub registers: Registers,
+    /// The error code, or `0` if the interrupt doesn't have one.
+    pub error_code: u64,
+    /// The instruction pointer.
+    pub rip: u64,
+    /// The code segment selector.
+    pub cs: u64,
+    /// The `RFLAGS` register.
+    pub rflags: u64,
+    /// The stack pointer.
+    pub rsp: u64,
+    /// The stack segment selector.
+    pub ss: u64,
+}
+
+/// Interrupt handler trampoline.
+///
+/// # Safety
+/// This function is not safe to call directly, but it can be used as an x86_64 interrupt
+/// handler, whether or not the interrupt has an error code. If no error code is passed by the
+/// CPU, then `0` is pushed as the error code.
+#[naked]
+pub unsafe extern "C" fn trampoline<const VEC: u8>() {
+    // SAFETY: see comments below
+    unsafe {
+        core::arch::asm!(
+            // push error code if not present, which ensures a consistent stack layout
+            "bt rsp, 3",
+            "jnc 1f",
+            "push 0",
+
+            // save all general-purpose registers, in the order of `Registers`' fields
+            "1:",
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "cld",
+
+            // clear the interrupted code's registers, which are only accessed through the
+            // `Context` from now on
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+
+            // SAFETY: this points to a `Context`
+            // CAUTION: modifying the stack layout may invalidate this pointer
+            "mov rdi, rsp",
+            "mov rsi, {vec}",
+
+            // the stack was 16-byte aligned before the processor pushed the interrupt stack frame,
+            // so after 21 pushes it must be realigned for the C calling convention
+            "sub rsp, 8",
+
+            // SAFETY: `entry` uses the C calling convention so any of the callee-saved
+            //         registers are preserved by `entry`. All registers are restored below,
+            //         including any changes `entry` made to the `Context`
+            "call {entry}",
+            "add rsp, 8",
+
+            // restore registers previously saved
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            // remove error code
+            "add rsp, 8",
+
+            // SAFETY: rsp now points to the interrupt stack frame, without the error code
+            // CAUTION: when making changes to the stack, care must be taken to ensure
+            //          the safety statement above remains true
+            "iretq",
+
+            vec = const VEC,
+            entry = sym entry,
+            options(noreturn),
+        );
+    }
+}
+
+/// Expands to the trampolines of the vectors `16 * hi..16 * (hi + 1)` for each `hi`, in order.
+macro_rules! trampolines {
+    ($($hi:literal)*) => {
+        [$(
+            trampoline::<{ $hi * 16 }>,
+            trampoline::<{ $hi * 16 + 1 }>,
+            trampoline::<{ $hi * 16 + 2 }>,
+            trampoline::<{ $hi * 16 + 3 }>,
+            trampoline::<{ $hi * 16 + 4 }>,
+            trampoline::<{ $hi * 16 + 5 }>,
+            trampoline::<{ $hi * 16 + 6 }>,
+            trampoline::<{ $hi * 16 + 7 }>,
+            trampoline::<{ $hi * 16 + 8 }>,
+            trampoline::<{ $hi * 16 + 9 }>,
+            trampoline::<{ $hi * 16 + 10 }>,
+            trampoline::<{ $hi * 16 + 11 }>,
+            trampoline::<{ $hi * 16 + 12 }>,
+            trampoline::<{ $hi * 16 + 13 }>,
+            trampoline::<{ $hi * 16 + 14 }>,
+            trampoline::<{ $hi * 16 + 15 }>,
+        )*]
+    };
+}
+use trampolines;
+
+/// Dispatches an interrupt on `vec` from [`trampoline`] to its handler, and then, for a user
+/// interrupt, runs [deferred work](super::softirq).
+unsafe extern "C" fn entry(context: &mut Context, vec: IntVec) {
+    // the same counter is decremented, even if the per-processor data is set up in between
+    let depth = depth_counter();
+    if let Some(depth) = depth {
+        depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // SAFETY: `context` was saved by `trampoline` for an interrupt on `vec`
+    unsafe { handler(context, vec) };
+    if vec.is_user_interrupt() {
+        super::softirq::run_on_exit(context);
+    }
+
+    if let Some(depth) = depth {
+        depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Dispatches an interrupt on `vec` to its handler.
+///
+/// This is called by [`trampoline`], through [`entry`], or with a synthesized context by
+/// [`inject::simulate`](super::inject::simulate), or for events from the `#HV` doorbell.
+pub(super) unsafe extern "C" fn handler(context: &mut Context, vec: IntVec) {
+    // a #VC must be handled before anything which may raise another, such as CPUID or reading an
+    // MSR, or its handler would recurse
+    if vec == IntVec::VMM_COMMUNICATION {
+        super::sev::handle_vmm_communication(context);
+        count_interrupt(vec);
+        return;
+    }
+
+    let stack_top = super::percpu::stack_top();
+    let rsp = context as *const Context as u64;
+    if !on_stack(stack_top, rsp) || (context.cs & 3 == 0 && !on_stack(stack_top, context.rsp)) {
+        panic!(
+            "{vec:?} at {:#x} with the stack pointer {rsp:#x} ({:#x} when interrupted), outside \
+            the stack ending at {stack_top:#x}",
+            context.rip, context.rsp,
+        );
+    }
+
+    count_interrupt(vec);
+
+    // single-stepping must be handled before anything is logged, since the code being traced may
+    // hold the logger's lock
+    if vec == IntVec::DEBUG && super::single_step::record(context.rip) {
+        return;
+    }
+
+    if super::inject::intercept(context, vec) {
+        return;
+    }
+
+    if vec == IntVec::BREAKPOINT {
+        super::debug::handle_breakpoint(context);
+        return;
+    }
+
+    if vec == IntVec::HYPERVISOR_INJECTION {
+        super::sev::handle_hypervisor_injection(context);
+        return;
+    }
+
+    if vec == IntVec::MACHINE_CHECK {
+        super::mce::handle_machine_check(context);
+        return;
+    }
+
+    if vec == super::mce::CMCI_VECTOR {
+        super::mce::handle_cmci();
+        return;
+    }
+
+    if vec == super::apic::SPURIOUS_VECTOR {
+        super::apic::handle_spurious(context);
+        return;
+    }
+
+    if vec.is_user_interrupt() && dispatch(context, vec) {
+        return;
+    }
+
+    if vec == IntVec::INVALID_OPCODE && super::emulate::emulate(context) {
+        return;
+    }
+
+    if vec == IntVec::PAGE_FAULT
+        && super::paging::handle_page_fault(PageFaultErrorCode(context.error_code))
+    {
+        return;
+    }
+
+    if super::fault::recover(context, vec) {
+        return;
+    }
+
+    let context_ptr = context as *const _;
+    log::info!("context_ptr = {context_ptr:?}");
+    log::info!("context = {context:x?}");
+    log::info!("vec = {vec:?}");
+
+    let rip = context.rip;
+    let error_code = context.error_code;
+
+    match vec {
+        IntVec::INVALID_OPCODE => {
+            panic!("invalid opcode at {rip:#x}: {}", FaultingInstruction(rip))
+        }
+        IntVec::SEGMENT_NOT_PRESENT => {
+            let err = SelectorErrorCode::new_truncate(error_code);
+            match err.descriptor_table() {
+                DescriptorTable::Idt => {
+                    panic!("handler not present: interrupt vector {}", err.index() / 2)
+                }
+                _ => panic!("segment not present: {err:?}"),
+            }
+        }
+        IntVec::GENERAL_PROTECTION if error_code != 0 => panic!(
+            "general protection fault at {rip:#x}: {} ({:?})",
+            FaultingInstruction(rip),
+            SelectorErrorCode::new_truncate(error_code),
+        ),
+        IntVec::GENERAL_PROTECTION => {
+            panic!(
+                "general protection fault at {rip:#x}: {}",
+                FaultingInstruction(rip)
+            )
+        }
+        IntVec::PAGE_FAULT => {
+            let err = PageFaultErrorCode(error_code);
+            let addr = Cr2::read();
+            if err.instruction_fetch() {
+                panic!("page fault fetching instruction at {addr:#x}: {err}");
+            } else {
+                panic!(
+                    "page fault accessing {addr:#x} at {rip:#x}: {err}: {}",
+                    FaultingInstruction(rip),
+                );
+            }
+        }
+        IntVec::CONTROL_PROTECTION => panic!(
+            "control-protection fault at {rip:#x}: {}",
+            ControlProtectionErrorCode(error_code)
+        ),
+        IntVec::SECURITY => panic!(
+            "security exception at {rip:#x}: {}",
+            SecurityErrorCode(error_code)
+        ),
+        vec => unimplemented!("handler for interrupt vector {vec:?}"),
+    }
+}
+
+/// Counts an interrupt on `vec`, in total and, if the current processor's ID is
+/// [cached](super::percpu::cached_id), for the current processor. This never raises a #VC.
+fn count_interrupt(vec: IntVec) {
+    COUNTS[usize::from(vec.0)].fetch_add(1, Ordering::Relaxed);
+    if let Some(counts) = super::percpu::cached_id().and_then(|id| CPU_COUNTS.get(id as usize)) {
+        counts[usize::from(vec.0)].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns `true` if `rsp` is within the stack ending at `stack_top`, an IST stack, or the
+/// emergency stack used while reporting a panic.
+fn on_stack(stack_top: u64, rsp: u64) -> bool {
+    stack_top.wrapping_sub(rsp) <= INIT_STACK_SIZE
+        || super::tss::is_ist_stack(rsp)
+        || crash::is_emergency_stack(rsp as usize)
+}
+
+/// Displays the instruction at the given address for fault diagnostics.
+///
+/// This must only be used for an address from which the processor successfully fetched an
+/// instruction.
+struct FaultingInstruction(u64);
+
+impl fmt::Display for FaultingInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // SAFETY: the processor fetched an instruction from this address, so its page is readable
+        match unsafe { Instruction::read(self.0) } {
+            Ok(instruction) => write!(f, "{instruction}"),
+            Err(err) => write!(f, "<{err}>"),
+        }
+    }
+}
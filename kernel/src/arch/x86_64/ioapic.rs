@@ -0,0 +1,201 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! The I/O APICs, which route interrupts from devices to local APICs.
+//!
+//! [`route_isa_irq`] finds the I/O APIC input a legacy ISA IRQ is connected to from the ACPI
+//! [MADT](Madt), including any interrupt source override, and sends it to a vector on the current
+//! processor. The first time, it also masks every input of the legacy 8259 interrupt controllers,
+//! if there are any, so that the IRQ isn't delivered through them as well.
+//!
+//! The registers are mapped with [`kmap`] only while they're being changed, so, like [`kmap`],
+//! routing must not be done by interrupt handlers. Interrupts which are routed are never masked
+//! here again, so handlers don't need the registers.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{instructions::port::PortWriteOnly, structures::paging::PhysFrame, PhysAddr};
+
+use super::{
+    interrupt::IntVec,
+    kmap::{self, kmap, kunmap},
+    paging::{Caching, PAGE_SIZE},
+    percpu,
+};
+use crate::acpi::Madt;
+
+/// The offset of the register select register, which selects the register accessed through the
+/// window.
+const IOREGSEL: usize = 0x00;
+/// The offset of the register window.
+const IOWIN: usize = 0x10;
+/// The version register, which includes the index of the last redirection entry.
+const IOAPICVER: u32 = 0x01;
+/// The low half of the first redirection entry. Each entry is two registers.
+const IOREDTBL: u32 = 0x10;
+
+/// Active low, in the low half of a redirection entry.
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+/// Level-triggered, in the low half of a redirection entry.
+const REDIRECT_LEVEL: u32 = 1 << 15;
+/// Masked, in the low half of a redirection entry.
+const REDIRECT_MASKED: u32 = 1 << 16;
+/// The shift of the destination local APIC ID, in the high half of a redirection entry.
+const REDIRECT_DESTINATION_SHIFT: u32 = 24;
+
+/// The data ports of the primary and secondary 8259 interrupt controllers, which set the mask of
+/// their inputs.
+const PIC_DATA_PORTS: [u16; 2] = [0x21, 0xa1];
+
+/// Serializes changes to the redirection entries.
+static LOCK: Mutex<()> = Mutex::new(());
+/// Whether the legacy 8259 interrupt controllers have been masked.
+static PICS_MASKED: AtomicBool = AtomicBool::new(false);
+
+/// An error routing an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There is no MADT, so the I/O APICs can't be found.
+    NoMadt,
+    /// No I/O APIC has an input for the global system interrupt.
+    NoInput(u32),
+    /// The current processor's local APIC ID doesn't fit in a redirection entry.
+    Unreachable(u32),
+    /// The I/O APIC's registers could not be mapped.
+    Map(kmap::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoMadt => write!(f, "there is no MADT"),
+            Error::NoInput(gsi) => write!(f, "no I/O APIC handles GSI {gsi}"),
+            Error::Unreachable(id) => {
+                write!(f, "local APIC ID {id} is out of the I/O APIC's range")
+            }
+            Error::Map(_) => write!(f, "cannot map the I/O APIC's registers"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {
+    fn source(&self) -> Option<&(dyn crate::error::Error + 'static)> {
+        match self {
+            Error::Map(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Routes the legacy ISA IRQ `irq` to `vec` on the current processor, and unmasks it.
+///
+/// # Safety
+/// There must be a handler for `vec` which signals [end-of-interrupt](super::apic::eoi), and
+/// nothing else may be routed to the same I/O APIC input.
+pub unsafe fn route_isa_irq(irq: u8, vec: IntVec) -> Result<(), Error> {
+    let madt = Madt::get().ok_or(Error::NoMadt)?;
+    if !PICS_MASKED.swap(true, Ordering::Relaxed) && madt.has_8259s() {
+        mask_pics();
+    }
+
+    let source = madt.overrides().find(|source| source.irq == irq);
+    let gsi = source.map_or(irq.into(), |source| source.gsi);
+    let mut low = u32::from(vec.0);
+    if source.and_then(|source| source.active_low) == Some(true) {
+        low |= REDIRECT_ACTIVE_LOW;
+    }
+    if source.and_then(|source| source.level_triggered) == Some(true) {
+        low |= REDIRECT_LEVEL;
+    }
+    let id = percpu::id();
+    let high = u8::try_from(id).map_err(|_| Error::Unreachable(id))?;
+
+    let _guard = LOCK.lock();
+    for io_apic in madt.io_apics() {
+        // SAFETY: the MADT gives the address of the I/O APIC's registers, and access to them is
+        //         synchronized with `LOCK`
+        let registers = unsafe { Registers::map(io_apic.address) }?;
+        let inputs = (registers.read(IOAPICVER) >> 16 & 0xff) + 1;
+        if let Some(input) = gsi
+            .checked_sub(io_apic.gsi_base)
+            .filter(|&input| input < inputs)
+        {
+            let entry = IOREDTBL + 2 * input;
+            // the entry stays masked until its destination is set
+            registers.write(entry, low | REDIRECT_MASKED);
+            registers.write(entry + 1, u32::from(high) << REDIRECT_DESTINATION_SHIFT);
+            registers.write(entry, low);
+            return Ok(());
+        }
+    }
+    Err(Error::NoInput(gsi))
+}
+
+/// An I/O APIC's registers, mapped with [`kmap`] until dropped.
+struct Registers {
+    base: *mut u8,
+    /// The start of the mapped frame.
+    page: *mut u8,
+}
+
+impl Registers {
+    /// Maps the registers at the physical address `address`.
+    ///
+    /// # Safety
+    /// `address` must be the address of an I/O APIC's registers, and nothing else may access them
+    /// while they're mapped.
+    unsafe fn map(address: u32) -> Result<Self, Error> {
+        let address = u64::from(address);
+        let frame = PhysFrame::containing_address(PhysAddr::new(address));
+        // SAFETY: the caller guarantees that this is an I/O APIC's registers, which are mapped
+        //         uncached
+        let page = unsafe { kmap(frame, Caching::Uncached) }.map_err(Error::Map)?;
+        Ok(Registers {
+            // SAFETY: the registers are within the mapped frame
+            base: unsafe { page.add((address % PAGE_SIZE) as usize) },
+            page,
+        })
+    }
+
+    /// Reads the register `index`.
+    fn read(&self, index: u32) -> u32 {
+        // SAFETY: `base` is mapped to the I/O APIC's registers, which only `self` accesses
+        unsafe {
+            self.base.add(IOREGSEL).cast::<u32>().write_volatile(index);
+            self.base.add(IOWIN).cast::<u32>().read_volatile()
+        }
+    }
+
+    /// Writes `value` to the register `index`.
+    fn write(&self, index: u32, value: u32) {
+        // SAFETY: `base` is mapped to the I/O APIC's registers, which only `self` accesses
+        unsafe {
+            self.base.add(IOREGSEL).cast::<u32>().write_volatile(index);
+            self.base.add(IOWIN).cast::<u32>().write_volatile(value);
+        }
+    }
+}
+
+impl Drop for Registers {
+    fn drop(&mut self) {
+        // SAFETY: `page` was mapped by `kmap`, and isn't used afterwards
+        unsafe { kunmap(self.page) };
+    }
+}
+
+/// Masks every input of the legacy 8259 interrupt controllers.
+fn mask_pics() {
+    for port in PIC_DATA_PORTS {
+        // SAFETY: these are the 8259s' standard data ports, and masking every input only stops
+        //         them from raising interrupts
+        unsafe { PortWriteOnly::<u8>::new(port).write(0xff) };
+    }
+}
@@ -0,0 +1,157 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Temporary mappings of arbitrary physical frames.
+//!
+//! Only memory below [`IDENTITY_MAPPED_LIMIT`](crate::memtest::IDENTITY_MAPPED_LIMIT) can be
+//! accessed through its physical address. [`kmap`] maps any other frame, such as one above the
+//! limit or in a device's MMIO range, at one of [`SLOTS_PER_CPU`] pages reserved for the current
+//! processor, until it is unmapped with [`kunmap`]. Device registers must be mapped
+//! [uncached](Caching::Uncached), and normal memory [write-back](Caching::WriteBack).
+//!
+//! The slots are in a window of [`KERNEL_SPACE`](crate::mem::KERNEL_SPACE), which is reserved the
+//! first time [`kmap`] is called. Since each processor has slots of its own, a mapping is only
+//! flushed from the current processor's TLB, and must only be used on the processor which created
+//! it. Like other changes to the page tables, [`kmap`] must not be used by interrupt handlers, which
+//! may have interrupted code holding the page table lock.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Page, PhysFrame},
+    VirtAddr,
+};
+
+use super::{
+    apic, paging,
+    paging::{Caching, PAGE_SIZE},
+};
+use crate::mem::{self, KERNEL_SPACE};
+
+/// The number of frames each processor can have mapped at once.
+pub const SLOTS_PER_CPU: usize = 4;
+
+/// The number of processors with slots, which is one for each local APIC ID which fits in a byte.
+const MAX_CPUS: usize = 256;
+/// The size of the window of slots.
+const WINDOW_SIZE: u64 = (MAX_CPUS * SLOTS_PER_CPU) as u64 * PAGE_SIZE;
+
+/// The start of the window of slots, or zero if it hasn't been reserved yet.
+static WINDOW: AtomicU64 = AtomicU64::new(0);
+/// Serializes reserving the window.
+static WINDOW_LOCK: Mutex<()> = Mutex::new(());
+/// A set bit for each slot each processor is using.
+static IN_USE: [AtomicU8; MAX_CPUS] = [const { AtomicU8::new(0) }; MAX_CPUS];
+
+/// An error mapping a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The window of slots could not be reserved.
+    Window(mem::Error),
+    /// All of the current processor's slots are in use, or it has no slots.
+    NoFreeSlot,
+    /// The frame could not be mapped.
+    Paging(paging::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Window(_) => write!(f, "cannot reserve the temporary mapping window"),
+            Error::NoFreeSlot => write!(f, "no free temporary mapping slot"),
+            Error::Paging(_) => write!(f, "cannot map the frame"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {
+    fn source(&self) -> Option<&(dyn crate::error::Error + 'static)> {
+        match self {
+            Error::Window(err) => Some(err),
+            Error::NoFreeSlot => None,
+            Error::Paging(err) => Some(err),
+        }
+    }
+}
+
+/// Maps `frame` at a free slot of the current processor with `caching`, writable but not
+/// executable, and returns a pointer to the start of it.
+///
+/// # Safety
+/// Nothing else may use `frame` in a way which conflicts with the mapping, and `caching` must suit
+/// the frame: [`Caching::Uncached`] for device registers, and [`Caching::WriteBack`] for memory.
+pub unsafe fn kmap(frame: PhysFrame, caching: Caching) -> Result<*mut u8, Error> {
+    let cpu = apic::current_id() as usize;
+    let in_use = IN_USE.get(cpu).ok_or(Error::NoFreeSlot)?;
+    let window = window()?;
+
+    // only this processor changes its own slots, so they can't be claimed in the meantime
+    let slot = in_use.load(Ordering::Relaxed).trailing_ones() as usize;
+    if slot >= SLOTS_PER_CPU {
+        return Err(Error::NoFreeSlot);
+    }
+    in_use.fetch_or(1 << slot, Ordering::Acquire);
+
+    let addr = VirtAddr::new(window + (cpu * SLOTS_PER_CPU + slot) as u64 * PAGE_SIZE);
+    // SAFETY: the slot is only used for this mapping until it's unmapped, and the caller
+    //         guarantees the mapping and its caching don't conflict with other uses of the frame
+    match unsafe { paging::map_frame(Page::containing_address(addr), frame, caching) } {
+        Ok(()) => Ok(addr.as_mut_ptr()),
+        Err(err) => {
+            in_use.fetch_and(!(1 << slot), Ordering::Release);
+            Err(Error::Paging(err))
+        }
+    }
+}
+
+/// Unmaps the frame which [`kmap`] mapped at `ptr`.
+///
+/// # Panics
+/// Panics if `ptr` isn't the start of one of the current processor's slots which is in use.
+///
+/// # Safety
+/// Nothing may use the mapping afterwards.
+pub unsafe fn kunmap(ptr: *mut u8) {
+    let cpu = apic::current_id() as usize;
+    let window = WINDOW.load(Ordering::Acquire);
+    let index = (ptr as u64).wrapping_sub(window) / PAGE_SIZE;
+    let slot = index.wrapping_sub((cpu * SLOTS_PER_CPU) as u64) as usize;
+    assert!(
+        window != 0 && ptr as u64 % PAGE_SIZE == 0 && slot < SLOTS_PER_CPU,
+        "{ptr:p} is not a temporary mapping slot of this processor"
+    );
+    let in_use = &IN_USE[cpu];
+    assert!(
+        in_use.load(Ordering::Relaxed) & 1 << slot != 0,
+        "temporary mapping slot {ptr:p} is not in use"
+    );
+
+    let page = Page::containing_address(VirtAddr::from_ptr(ptr));
+    // SAFETY: the caller guarantees nothing uses the mapping afterwards
+    unsafe { paging::unmap_frame(page) }.expect("unmap a temporary mapping slot");
+    in_use.fetch_and(!(1 << slot), Ordering::Release);
+}
+
+/// Returns the start of the window of slots, reserving it if necessary.
+fn window() -> Result<u64, Error> {
+    let _guard = WINDOW_LOCK.lock();
+    let mut window = WINDOW.load(Ordering::Acquire);
+    if window == 0 {
+        // aligning to 2 MiB keeps the slots in as few page tables as possible
+        window = KERNEL_SPACE
+            .lock()
+            .allocate(WINDOW_SIZE, 0x20_0000, "temporary mappings")
+            .map_err(Error::Window)?
+            .start;
+        WINDOW.store(window, Ordering::Release);
+    }
+    Ok(window)
+}
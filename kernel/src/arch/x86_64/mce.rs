@@ -0,0 +1,482 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Machine check architecture.
+//!
+//! Hardware errors are logged in the machine check banks. Uncorrected errors raise a
+//! [machine check exception](IntVec::MACHINE_CHECK) (`#MC`), while corrected errors are only
+//! logged, and must be found with [`poll`]. If the processor supports corrected machine check
+//! interrupts (CMCI) and the [local APIC](super::apic) is enabled, [`enable_cmci`] has the banks
+//! raise an interrupt once [`CMCI_THRESHOLD`] corrected errors have been logged, which polls them.
+//!
+//! Errors with a physical address in a memory or cache error are
+//! [quarantined](crate::quarantine) by frame, so the affected memory is never handed out again.
+//! Memory which is already in use can't be migrated yet, so a machine check is only survived if
+//! the processor reports that it can restart the interrupted code and that no immediate action is
+//! required.
+//!
+//! A machine check or CMCI can interrupt code holding the logger's or the quarantine's lock, so
+//! their handlers only queue the errors they find, without locking, and the errors are reported
+//! and quarantined later, as [deferred work](super::softirq).
+
+use core::{
+    arch::x86_64::__cpuid,
+    fmt,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+
+use x86_64::registers::{
+    control::{Cr4, Cr4Flags},
+    model_specific::Msr,
+};
+
+use super::{
+    apic::{self, Lvt},
+    interrupt::{Context, IntVec},
+    softirq,
+};
+use crate::{
+    param,
+    quarantine::{self, Reason},
+    util::ring::MpscQueue,
+};
+
+param! {
+    /// The number of corrected errors a bank logs before raising a corrected machine check
+    /// interrupt.
+    pub static CMCI_THRESHOLD: u16 = 1, name = "cmci_threshold";
+}
+
+/// An error which prevented machine checks, or corrected machine check interrupts, from being
+/// enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The processor doesn't support the machine check architecture.
+    Unsupported,
+    /// Machine checks haven't been enabled with [`init`].
+    NotEnabled,
+    /// The processor doesn't support corrected machine check interrupts.
+    CmciUnsupported,
+    /// The local APIC, which delivers corrected machine check interrupts, isn't enabled.
+    ApicNotEnabled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unsupported => write!(f, "the machine check architecture is not supported"),
+            Error::NotEnabled => write!(f, "machine checks are not enabled"),
+            Error::CmciUnsupported => {
+                write!(f, "corrected machine check interrupts are not supported")
+            }
+            Error::ApicNotEnabled => write!(f, "the local APIC is not enabled"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// The vector used for corrected machine check interrupts.
+pub const CMCI_VECTOR: IntVec = IntVec(0xf0);
+
+/// The number of errors which can wait to be reported.
+const PENDING_CAPACITY: usize = 32;
+
+/// The number of machine check banks, or zero if machine checks haven't been enabled.
+static BANKS: AtomicU8 = AtomicU8::new(0);
+
+/// The number of corrected errors found since boot.
+static CORRECTED_ERRORS: AtomicU64 = AtomicU64::new(0);
+/// Errors found in interrupt context, waiting to be reported and have their frames quarantined.
+static PENDING: MpscQueue<BankError, PENDING_CAPACITY> = MpscQueue::new();
+/// The number of errors which couldn't be reported because [`PENDING`] was full.
+static LOST: AtomicU64 = AtomicU64::new(0);
+
+/// `IA32_MCG_CAP`, which describes the machine check architecture.
+const IA32_MCG_CAP: u32 = 0x179;
+/// `IA32_MCG_STATUS`, which describes the state of the processor after a machine check.
+const IA32_MCG_STATUS: u32 = 0x17a;
+/// `IA32_MCG_CTL`, which enables machine check features, if present.
+const IA32_MCG_CTL: u32 = 0x17b;
+/// `IA32_MC0_CTL`; each bank has four MSRs: `CTL`, `STATUS`, `ADDR` and `MISC`.
+const IA32_MC0_CTL: u32 = 0x400;
+/// `IA32_MC0_CTL2`, which configures CMCI for bank 0; the other banks' follow consecutively.
+const IA32_MC0_CTL2: u32 = 0x280;
+
+/// The number of banks, in `IA32_MCG_CAP`.
+const MCG_CAP_COUNT: u64 = 0xff;
+/// Whether `IA32_MCG_CTL` is present, in `IA32_MCG_CAP`.
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+/// Whether CMCI is supported, in `IA32_MCG_CAP`.
+const MCG_CAP_CMCI_P: u64 = 1 << 10;
+
+/// Enables CMCI for the bank, in `IA32_MCi_CTL2`.
+const MCI_CTL2_CMCI_EN: u64 = 1 << 30;
+/// The corrected error count threshold, in `IA32_MCi_CTL2`.
+const MCI_CTL2_THRESHOLD: u64 = 0x7fff;
+
+/// Restart IP valid, in `IA32_MCG_STATUS`.
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+
+/// The error is valid, in `IA32_MCi_STATUS`.
+const MCI_STATUS_VAL: u64 = 1 << 63;
+/// A previous error was overwritten, in `IA32_MCi_STATUS`.
+const MCI_STATUS_OVER: u64 = 1 << 62;
+/// The error was not corrected, in `IA32_MCi_STATUS`.
+const MCI_STATUS_UC: u64 = 1 << 61;
+/// `IA32_MCi_MISC` is valid, in `IA32_MCi_STATUS`.
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+/// `IA32_MCi_ADDR` is valid, in `IA32_MCi_STATUS`.
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+/// The processor context is corrupt, in `IA32_MCi_STATUS`.
+const MCI_STATUS_PCC: u64 = 1 << 57;
+/// Recovery action is required before continuing, in `IA32_MCi_STATUS`.
+const MCI_STATUS_AR: u64 = 1 << 55;
+
+/// The size of the frames which are quarantined.
+const FRAME_SIZE: u64 = 4096;
+
+/// An error logged in a machine check bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankError {
+    /// The bank which logged the error.
+    pub bank: u8,
+    /// The value of `IA32_MCi_STATUS`.
+    pub status: u64,
+    /// The value of `IA32_MCi_ADDR`, if valid.
+    pub address: Option<u64>,
+    /// The value of `IA32_MCi_MISC`, if valid.
+    pub misc: Option<u64>,
+}
+
+impl BankError {
+    /// Returns `true` if the error was corrected.
+    pub fn is_corrected(&self) -> bool {
+        self.status & MCI_STATUS_UC == 0
+    }
+
+    /// Returns `true` if the processor context was corrupted.
+    pub fn is_context_corrupt(&self) -> bool {
+        self.status & MCI_STATUS_PCC != 0
+    }
+
+    /// Returns `true` if recovery action is required before the interrupted code can continue.
+    pub fn is_action_required(&self) -> bool {
+        self.status & MCI_STATUS_AR != 0
+    }
+
+    /// Returns the architectural machine check error code.
+    pub fn error_code(&self) -> u16 {
+        self.status as u16
+    }
+
+    /// Returns `true` if the error code is a memory controller or cache hierarchy error.
+    pub fn is_memory_error(&self) -> bool {
+        let code = self.error_code();
+        // ignoring the filtering bit (12), memory controller errors are `0000_0000_1MMM_CCCC` and
+        // cache hierarchy errors are `0000_0001_RRRR_TTLL`
+        code & 0xef80 == 0x0080 || code & 0xef00 == 0x0100
+    }
+
+    /// Returns the physical address of the frame containing the error, if it is a memory error
+    /// with a valid address.
+    pub fn frame(&self) -> Option<u64> {
+        match self.address {
+            Some(address) if self.is_memory_error() => Some(address & !(FRAME_SIZE - 1)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = if self.is_corrected() {
+            "corrected"
+        } else {
+            "uncorrected"
+        };
+        write!(
+            f,
+            "{severity} error in bank {}: status {:#018x}",
+            self.bank, self.status
+        )?;
+        if let Some(address) = self.address {
+            write!(f, ", address {address:#x}")?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, ", misc {misc:#x}")?;
+        }
+        if self.status & MCI_STATUS_OVER != 0 {
+            write!(f, " (previous errors lost)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Enables machine checks, first reporting any errors logged before boot. Returns the number of
+/// banks.
+pub fn init() -> Result<u8, Error> {
+    // SAFETY: CPUID is available on all x86_64 processors
+    let cpuid = unsafe { __cpuid(1) };
+    let (mce, mca) = (cpuid.edx & (1 << 7) != 0, cpuid.edx & (1 << 14) != 0);
+    if !mce || !mca {
+        return Err(Error::Unsupported);
+    }
+
+    // SAFETY: `IA32_MCG_CAP` exists on all processors supporting the machine check architecture
+    let mcg_cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+    let banks = (mcg_cap & MCG_CAP_COUNT) as u8;
+
+    // report and clear errors left over from before boot, such as those which caused a reset
+    for error in (0..banks).filter_map(read_bank) {
+        log::warn!("machine check logged before boot: {error}");
+        quarantine_frame(&error);
+        clear_bank(error.bank);
+    }
+
+    if mcg_cap & MCG_CAP_CTL_P != 0 {
+        // SAFETY: `IA32_MCG_CTL` is present, and enabling all features is always valid
+        unsafe { Msr::new(IA32_MCG_CTL).write(u64::MAX) };
+    }
+    for bank in first_configurable_bank()..banks {
+        // SAFETY: the bank exists, and enabling reporting of all errors is always valid
+        unsafe { Msr::new(bank_msr(bank, 0)).write(u64::MAX) };
+    }
+
+    // SAFETY: machine checks are handled by the interrupt handler
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+    BANKS.store(banks, Ordering::Release);
+
+    Ok(banks)
+}
+
+/// Reports, quarantines and clears any corrected errors in the machine check banks, returning the
+/// number of errors found.
+///
+/// Uncorrected errors are left for the machine check exception handler.
+pub fn poll() -> usize {
+    let count = collect_corrected();
+    report_pending(0);
+    count
+}
+
+/// Returns the number of corrected errors found by [`poll`] since boot.
+pub fn corrected_errors() -> u64 {
+    CORRECTED_ERRORS.load(Ordering::Relaxed)
+}
+
+/// Enables corrected machine check interrupts for every bank which supports them, returning the
+/// number of such banks.
+///
+/// # Safety
+/// There must be a handler for [`CMCI_VECTOR`].
+pub unsafe fn enable_cmci() -> Result<u8, Error> {
+    if bank_count() == 0 {
+        return Err(Error::NotEnabled);
+    }
+    // SAFETY: `IA32_MCG_CAP` exists, since machine checks have been enabled
+    let mcg_cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+    if mcg_cap & MCG_CAP_CMCI_P == 0 {
+        return Err(Error::CmciUnsupported);
+    }
+    if !apic::is_enabled() {
+        return Err(Error::ApicNotEnabled);
+    }
+
+    let threshold = u64::from(CMCI_THRESHOLD.get()).clamp(1, MCI_CTL2_THRESHOLD);
+    let mut enabled = 0;
+    for bank in 0..bank_count() {
+        let mut ctl2 = Msr::new(IA32_MC0_CTL2 + u32::from(bank));
+        // SAFETY: CMCI is supported, so each bank has `IA32_MCi_CTL2`, and `CMCI_EN` only sticks
+        //         in banks which support CMCI
+        let supported = unsafe {
+            let value = ctl2.read() & !MCI_CTL2_THRESHOLD;
+            ctl2.write(value | MCI_CTL2_CMCI_EN | threshold);
+            ctl2.read() & MCI_CTL2_CMCI_EN != 0
+        };
+        if supported {
+            enabled += 1;
+        }
+    }
+
+    super::storm::register(CMCI_VECTOR, mask_cmci);
+    // SAFETY: the caller guarantees that there is a handler for `CMCI_VECTOR`
+    unsafe { apic::set_lvt(Lvt::Cmci, Some(CMCI_VECTOR)) };
+
+    Ok(enabled)
+}
+
+/// Handles a corrected machine check interrupt.
+pub(super) fn handle_cmci() {
+    let found = collect_corrected();
+    if found != 0 {
+        defer_report();
+    }
+    super::storm::record(CMCI_VECTOR, found != 0);
+    apic::eoi();
+}
+
+/// Masks corrected machine check interrupts, leaving corrected errors to be found by [`poll`].
+fn mask_cmci() {
+    // SAFETY: masking an interrupt doesn't require a handler
+    unsafe { apic::set_lvt(Lvt::Cmci, None) };
+}
+
+/// Handles a machine check exception.
+///
+/// # Panics
+/// Panics if the interrupted code can't be safely restarted.
+pub(super) fn handle_machine_check(context: &Context) {
+    // SAFETY: a machine check exception only occurs if machine checks are supported
+    let mcg_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+    let mut recoverable = mcg_status & MCG_STATUS_RIPV != 0;
+
+    let mut fatal = None;
+    for error in (0..bank_count()).filter_map(read_bank) {
+        if !error.is_corrected() && (error.is_context_corrupt() || error.is_action_required()) {
+            recoverable = false;
+            fatal = fatal.or(Some(error));
+        }
+        queue(error);
+        clear_bank(error.bank);
+    }
+
+    if !recoverable {
+        match fatal {
+            Some(error) => panic!("unrecoverable machine check at {:#x}: {error}", context.rip),
+            None => panic!("unrecoverable machine check at {:#x}", context.rip),
+        }
+    }
+
+    // SAFETY: clearing `MCIP` signals that the machine check has been handled
+    unsafe { Msr::new(IA32_MCG_STATUS).write(0) };
+    defer_report();
+}
+
+/// Queues any corrected errors in the machine check banks to be reported, and clears them,
+/// returning the number of errors found.
+fn collect_corrected() -> usize {
+    let mut count = 0;
+
+    for error in (0..bank_count()).filter_map(read_bank) {
+        if !error.is_corrected() {
+            continue;
+        }
+
+        count += 1;
+        CORRECTED_ERRORS.fetch_add(1, Ordering::Relaxed);
+        queue(error);
+        clear_bank(error.bank);
+    }
+
+    count
+}
+
+/// Queues `error` to be reported, and its frame quarantined, by [`report_pending`]. This doesn't
+/// lock anything, so it can be used from any interrupt handler.
+fn queue(error: BankError) {
+    if PENDING.push(error).is_err() {
+        LOST.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Has the queued errors reported as deferred work. If the deferred work queue is full, they wait
+/// for the next report.
+fn defer_report() {
+    let _ = softirq::defer(report_pending, 0);
+}
+
+/// Reports the errors queued by interrupt handlers, and quarantines their frames. Does nothing if
+/// another processor is already reporting them.
+fn report_pending(_: usize) {
+    let consumer = match PENDING.consumer() {
+        Some(consumer) => consumer,
+        None => return,
+    };
+    for error in consumer {
+        if error.is_corrected() {
+            log::warn!("machine check: {error}");
+        } else {
+            log::error!("machine check: {error}");
+        }
+        quarantine_frame(&error);
+    }
+
+    let lost = LOST.swap(0, Ordering::Relaxed);
+    if lost != 0 {
+        log::error!("machine check: {lost} errors lost before they could be reported");
+    }
+}
+
+/// Quarantines the frame containing a memory error, if any.
+fn quarantine_frame(error: &BankError) {
+    if let Some(frame) = error.frame() {
+        match quarantine::add(frame..(frame + FRAME_SIZE), Reason::MachineCheck) {
+            Ok(()) => log::warn!("quarantined frame {frame:#x} after a machine check"),
+            Err(err) => log::error!("cannot quarantine frame {frame:#x}: {err}"),
+        }
+    }
+}
+
+/// Returns the number of machine check banks, or zero if machine checks haven't been enabled.
+fn bank_count() -> u8 {
+    BANKS.load(Ordering::Acquire)
+}
+
+/// Returns the first bank whose `IA32_MCi_CTL` may be written.
+///
+/// Bank 0's control register must not be written on Intel family 6 processors before Nehalem.
+fn first_configurable_bank() -> u8 {
+    // SAFETY: CPUID is available on all x86_64 processors
+    let vendor = unsafe { __cpuid(0) };
+    // SAFETY: CPUID is available on all x86_64 processors
+    let signature = unsafe { __cpuid(1) }.eax;
+    let family = (signature >> 8) & 0xf;
+    let model = (signature >> 4) & 0xf | (signature >> 12) & 0xf0;
+    let intel = (vendor.ebx, vendor.edx, vendor.ecx) == (0x756e_6547, 0x4965_6e69, 0x6c65_746e);
+
+    if intel && family == 6 && model < 0x1a {
+        1
+    } else {
+        0
+    }
+}
+
+/// Returns MSR `offset` of `bank`, where `offset` is `0`, `1`, `2` or `3` for `CTL`, `STATUS`,
+/// `ADDR` or `MISC`, respectively.
+fn bank_msr(bank: u8, offset: u32) -> u32 {
+    IA32_MC0_CTL + 4 * u32::from(bank) + offset
+}
+
+/// Returns the error logged in `bank`, if any.
+fn read_bank(bank: u8) -> Option<BankError> {
+    // SAFETY: the bank exists, and reading its status has no side effects
+    let status = unsafe { Msr::new(bank_msr(bank, 1)).read() };
+    if status & MCI_STATUS_VAL == 0 {
+        return None;
+    }
+
+    // SAFETY: the bank exists, and the status indicates that the address register is valid
+    let address =
+        (status & MCI_STATUS_ADDRV != 0).then(|| unsafe { Msr::new(bank_msr(bank, 2)).read() });
+    // SAFETY: the bank exists, and the status indicates that the misc register is valid
+    let misc =
+        (status & MCI_STATUS_MISCV != 0).then(|| unsafe { Msr::new(bank_msr(bank, 3)).read() });
+
+    Some(BankError {
+        bank,
+        status,
+        address,
+        misc,
+    })
+}
+
+/// Clears the error logged in `bank`.
+fn clear_bank(bank: u8) {
+    // SAFETY: the bank exists, and clearing its status only discards the logged error
+    unsafe { Msr::new(bank_msr(bank, 1)).write(0) };
+}
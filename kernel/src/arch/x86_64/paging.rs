@@ -0,0 +1,685 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Management of the active page tables.
+//!
+//! The page tables are the ones BOOTBOOT set up. Since BOOTBOOT identity maps the low physical
+//! memory they are allocated from, they are accessed through their physical addresses.
+//!
+//! The null page is unmapped by [`init`], so that null pointer dereferences fault even though low
+//! memory is identity mapped, and the functions in this module refuse to change the null page or
+//! any range spanning the non-canonical hole.
+//!
+//! [`new_kernel_page`] and [`new_user_page`] map new pages, allocating the frames for them and for
+//! any page tables needed with the [frame allocator](super::frame). Ranges added with
+//! [`add_demand_region`] are instead mapped a page at a time, when a page is first accessed.
+//!
+//! [`map_shared`] maps an allocated frame again, read-only, and [`unmap`] drops a mapping's
+//! reference to its frame, so that a shared frame is only freed once its last mapping is gone.
+//! [`protect`] changes the [`Permissions`] of pages which are already mapped.
+
+use core::{arch::x86_64::__cpuid, fmt, ops::Range};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr2, Cr3},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+        Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{error_code::PageFaultErrorCode, frame};
+
+/// The size of a page.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// The maximum number of regions which can be added with [`add_demand_region`].
+pub const MAX_DEMAND_REGIONS: usize = 16;
+
+/// The start of the upper half of the address space, which ends the non-canonical hole.
+const UPPER_HALF: u64 = 0xffff_8000_0000_0000;
+
+/// Serializes changes to the page tables.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// The page table used to map the first 2 MiB of memory with 4 KiB pages, if BOOTBOOT mapped it
+/// with a huge page, so that the null page can be unmapped.
+static mut LOW_MEMORY_TABLE: PageTable = PageTable::new();
+
+/// The regions which are mapped on demand.
+static DEMAND_REGIONS: Mutex<[Option<DemandRegion>; MAX_DEMAND_REGIONS]> =
+    Mutex::new([const { None }; MAX_DEMAND_REGIONS]);
+
+/// A range of pages which are mapped when first accessed.
+#[derive(Debug, Clone)]
+struct DemandRegion {
+    /// The addresses in the region, which are page aligned.
+    range: Range<VirtAddr>,
+    /// Whether the pages are accessible from user mode.
+    user: bool,
+}
+
+/// An error which prevented the page tables from being changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The page at the given address is not mapped.
+    NotMapped(VirtAddr),
+    /// The page at the given address is part of a huge page, so it can't be changed on its own.
+    HugePage(VirtAddr),
+    /// The range includes the null page, which must never be mapped.
+    NullPage,
+    /// The range spans the non-canonical hole in the address space.
+    NonCanonical,
+    /// The page at the given address is already mapped.
+    AlreadyMapped(VirtAddr),
+    /// There were no free frames for a page or page table.
+    OutOfMemory,
+    /// The range overlaps a region which is already mapped on demand.
+    Overlaps,
+    /// [`MAX_DEMAND_REGIONS`] regions are already mapped on demand.
+    TooManyRegions,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotMapped(addr) => write!(f, "page at {addr:#x} is not mapped"),
+            Error::HugePage(addr) => write!(f, "page at {addr:#x} is part of a huge page"),
+            Error::NullPage => write!(f, "the null page must never be mapped"),
+            Error::NonCanonical => write!(f, "the range spans the non-canonical hole"),
+            Error::AlreadyMapped(addr) => write!(f, "page at {addr:#x} is already mapped"),
+            Error::OutOfMemory => write!(f, "out of memory"),
+            Error::Overlaps => write!(f, "the range overlaps a demand-paged region"),
+            Error::TooManyRegions => {
+                write!(f, "{MAX_DEMAND_REGIONS} demand-paged regions already exist")
+            }
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// Enables write protection in supervisor mode, so the kernel can't write to read-only pages, and
+/// the no-execute bit, if it's supported, and unmaps the null page.
+pub fn init() -> Result<(), Error> {
+    // SAFETY: the kernel doesn't intentionally write to read-only pages
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+    // SAFETY: CPUID is available on all x86_64 processors
+    if unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0 {
+        // SAFETY: the no-execute bit is supported, and no pages are mapped with it yet
+        unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    }
+
+    unmap_null_page()
+}
+
+/// Returns an error if `range` includes the null page or spans the non-canonical hole.
+pub fn check_range(range: &Range<VirtAddr>) -> Result<(), Error> {
+    if range.is_empty() {
+        Ok(())
+    } else if range.start.as_u64() < PAGE_SIZE {
+        Err(Error::NullPage)
+    } else if range.start.as_u64() < UPPER_HALF && range.end.as_u64() > UPPER_HALF {
+        Err(Error::NonCanonical)
+    } else {
+        Ok(())
+    }
+}
+
+/// The access allowed to a page, other than whether it's accessible from user mode.
+///
+/// There is no writable and executable combination, so that no page can be both written to and
+/// executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permissions {
+    /// The page can be read, but not written to or executed.
+    ReadOnly,
+    /// The page can be read and written to, but not executed.
+    ReadWrite,
+    /// The page can be read and executed, but not written to.
+    ReadExecute,
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permissions::ReadOnly => write!(f, "r--"),
+            Permissions::ReadWrite => write!(f, "rw-"),
+            Permissions::ReadExecute => write!(f, "r-x"),
+        }
+    }
+}
+
+/// How accesses to a mapped page are cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Caching {
+    /// Reads and writes are cached, which is only correct for normal memory.
+    WriteBack,
+    /// Nothing is cached, and accesses are neither combined nor reordered, as device registers
+    /// require.
+    Uncached,
+}
+
+impl Caching {
+    /// Returns the page table flags selecting this caching, with the default page attribute table.
+    fn flags(self) -> PageTableFlags {
+        match self {
+            Caching::WriteBack => PageTableFlags::empty(),
+            Caching::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Returns the physical address `addr` is mapped to, if it's mapped.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    with_page_table(|page_table| page_table.translate_addr(addr))
+}
+
+/// Makes the pages overlapping `range` writable or read-only, without changing whether they are
+/// executable.
+///
+/// # Safety
+/// Nothing may write to the pages while they're read-only, and the pages must not be used for
+/// anything else which depends on their mapping.
+pub unsafe fn set_writable(range: Range<VirtAddr>, writable: bool) -> Result<(), Error> {
+    // SAFETY: the caller's guarantees are the same
+    unsafe { update_flags(range, |flags| flags.set(PageTableFlags::WRITABLE, writable)) }
+}
+
+/// Changes the pages overlapping the `len` bytes at `addr` to allow only the access in
+/// `permissions`, such as to make data read-only once it's initialized, or to make a guard page.
+///
+/// If the no-execute bit isn't supported, every page can be executed, whatever its permissions.
+///
+/// # Safety
+/// Nothing may access the pages in a way `permissions` doesn't allow, and the pages must not be
+/// used for anything else which depends on their mapping.
+pub unsafe fn protect(addr: VirtAddr, len: u64, permissions: Permissions) -> Result<(), Error> {
+    let end = addr
+        .as_u64()
+        .checked_add(len)
+        .and_then(|end| VirtAddr::try_new(end).ok())
+        .ok_or(Error::NonCanonical)?;
+    let (writable, executable) = match permissions {
+        Permissions::ReadOnly => (false, false),
+        Permissions::ReadWrite => (true, false),
+        Permissions::ReadExecute => (false, true),
+    };
+    let no_execute = no_execute();
+
+    // SAFETY: the caller's guarantees are the same
+    unsafe {
+        update_flags(addr..end, |flags| {
+            flags.set(PageTableFlags::WRITABLE, writable);
+            flags.set(no_execute, !executable);
+        })
+    }
+}
+
+/// Changes the flags of the pages overlapping `range` with `f`.
+///
+/// # Safety
+/// The new flags must not allow any access which is in use to be made, and the pages must not be
+/// used for anything else which depends on their mapping.
+unsafe fn update_flags(
+    range: Range<VirtAddr>,
+    f: impl Fn(&mut PageTableFlags),
+) -> Result<(), Error> {
+    check_range(&range)?;
+    if range.is_empty() {
+        return Ok(());
+    }
+
+    with_page_table(|page_table| {
+        let start = Page::<Size4KiB>::containing_address(range.start);
+        let end = Page::<Size4KiB>::containing_address(range.end - 1u64);
+
+        for page in Page::range_inclusive(start, end) {
+            let addr = page.start_address();
+            let mut flags = match page_table.translate(addr) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(_),
+                    flags,
+                    ..
+                } => flags,
+                TranslateResult::Mapped { .. } => return Err(Error::HugePage(addr)),
+                _ => return Err(Error::NotMapped(addr)),
+            };
+            f(&mut flags);
+
+            // SAFETY: the caller guarantees that changing the page's flags is safe, and the page is
+            //         known to be mapped with a 4 KiB page
+            unsafe { page_table.update_flags(page, flags) }
+                .map_err(|_| Error::NotMapped(addr))?
+                .flush();
+        }
+
+        Ok(())
+    })
+}
+
+/// Maps `page` to a new, zeroed frame, which is writable but not executable, and only accessible
+/// to the kernel. Returns the frame.
+pub fn new_kernel_page(page: Page) -> Result<PhysFrame, Error> {
+    new_page(page, PageTableFlags::empty())
+}
+
+/// Maps `page` to a new, zeroed frame, which is writable but not executable, and accessible from
+/// user mode. Returns the frame.
+pub fn new_user_page(page: Page) -> Result<PhysFrame, Error> {
+    new_page(page, PageTableFlags::USER_ACCESSIBLE)
+}
+
+/// Maps `page` to `frame`, which is already allocated and mapped elsewhere, adding a reference to
+/// it. The page is read-only and not executable, and only accessible to the kernel unless `user`
+/// is set.
+///
+/// # Panics
+/// Panics if `frame` wasn't allocated by the [frame allocator](super::frame).
+pub fn map_shared(page: Page, frame: PhysFrame, user: bool) -> Result<(), Error> {
+    let addr = page.start_address();
+    check_range(&(addr..addr + PAGE_SIZE))?;
+
+    let mut flags = PageTableFlags::PRESENT | no_execute();
+    if user {
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+
+    with_page_table(|page_table| {
+        if page_table.translate_addr(addr).is_some() {
+            return Err(Error::AlreadyMapped(addr));
+        }
+        frame::share(frame);
+
+        // SAFETY: the frame is allocated, and only mapped read-only, so mapping it again can't
+        //         violate memory safety, and the page wasn't mapped
+        match unsafe { page_table.map_to(page, frame, flags, &mut frame::Allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(err) => {
+                // SAFETY: the reference added above was never used
+                unsafe { frame::release(frame) };
+                Err(map_error(err, addr))
+            }
+        }
+    })
+}
+
+/// Unmaps `page`, and removes the mapping's reference to its frame, which frees the frame if it was
+/// the last. Frames which weren't allocated by the [frame allocator](super::frame) are left alone.
+///
+/// # Safety
+/// Nothing may use the page afterwards.
+pub unsafe fn unmap(page: Page) -> Result<(), Error> {
+    // SAFETY: the caller guarantees nothing uses the page
+    let frame = unsafe { unmap_frame(page) }?;
+    // SAFETY: the mapping's reference is gone, and the caller guarantees nothing uses the page
+    unsafe { frame::release(frame) };
+    Ok(())
+}
+
+/// Maps `page` to `frame` with `caching`, which is writable but not executable, and only
+/// accessible to the kernel, without adding a reference to the frame.
+///
+/// # Safety
+/// Nothing else may use `frame` in a way which conflicts with the mapping, including by accessing it
+/// with different caching.
+pub(super) unsafe fn map_frame(
+    page: Page,
+    frame: PhysFrame,
+    caching: Caching,
+) -> Result<(), Error> {
+    let addr = page.start_address();
+    check_range(&(addr..addr + PAGE_SIZE))?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute() | caching.flags();
+
+    with_page_table(|page_table| {
+        // SAFETY: the caller guarantees the mapping doesn't conflict with other uses of the frame
+        unsafe { page_table.map_to(page, frame, flags, &mut frame::Allocator) }
+            .map_err(|err| map_error(err, addr))?
+            .flush();
+        Ok(())
+    })
+}
+
+/// Remaps `page` to the same frame with the memory encryption bit `c_bit` set if `encrypted`, or
+/// cleared otherwise, without changing its flags. Under SEV, a page mapped without the encryption
+/// bit is shared with the hypervisor.
+///
+/// # Safety
+/// The page's contents are unpredictable afterwards, so nothing may depend on them, and the frame
+/// must be in the matching state with the hypervisor.
+pub(super) unsafe fn set_encrypted(page: Page, c_bit: u64, encrypted: bool) -> Result<(), Error> {
+    let addr = page.start_address();
+    check_range(&(addr..addr + PAGE_SIZE))?;
+
+    with_page_table(|page_table| {
+        let (frame, flags) = match page_table.translate(addr) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } => (frame, flags),
+            TranslateResult::Mapped { .. } => return Err(Error::HugePage(addr)),
+            _ => return Err(Error::NotMapped(addr)),
+        };
+        let mut phys = frame.start_address().as_u64() & !c_bit;
+        if encrypted {
+            phys |= c_bit;
+        }
+
+        // SAFETY: the page is mapped again immediately, to the same frame, and the caller
+        //         guarantees that nothing depends on its contents
+        unsafe {
+            let (_, flush) = page_table.unmap(page).map_err(|_| Error::NotMapped(addr))?;
+            flush.ignore();
+            let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+            page_table
+                .map_to(page, frame, flags, &mut frame::Allocator)
+                .map_err(|err| map_error(err, addr))?
+                .flush();
+        }
+        Ok(())
+    })
+}
+
+/// Unmaps `page`, without removing a reference to its frame, and returns the frame.
+///
+/// # Safety
+/// Nothing may use the page afterwards.
+pub(super) unsafe fn unmap_frame(page: Page) -> Result<PhysFrame, Error> {
+    let addr = page.start_address();
+    check_range(&(addr..addr + PAGE_SIZE))?;
+
+    with_page_table(|page_table| {
+        let (frame, flush) = page_table.unmap(page).map_err(|err| match err {
+            UnmapError::ParentEntryHugePage => Error::HugePage(addr),
+            _ => Error::NotMapped(addr),
+        })?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/// Maps the pages overlapping `range` on demand: each page is mapped to a new, zeroed frame, as
+/// with [`new_kernel_page`], or [`new_user_page`] if `user` is set, when it is first accessed.
+///
+/// Pages in the range which are already mapped are left as they are.
+pub fn add_demand_region(range: Range<VirtAddr>, user: bool) -> Result<(), Error> {
+    check_range(&range)?;
+    if range.is_empty() {
+        return Ok(());
+    }
+    let range = range.start.align_down(PAGE_SIZE)..range.end.align_up(PAGE_SIZE);
+
+    let mut regions = DEMAND_REGIONS.lock();
+    if regions
+        .iter()
+        .flatten()
+        .any(|region| region.range.start < range.end && range.start < region.range.end)
+    {
+        return Err(Error::Overlaps);
+    }
+    let slot = regions
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(Error::TooManyRegions)?;
+    *slot = Some(DemandRegion { range, user });
+    Ok(())
+}
+
+/// Handles a page fault at the address in `CR2` with `error_code`, by mapping the page if it's in a
+/// region added with [`add_demand_region`]. Returns `true` if the page was mapped, so the faulting
+/// instruction can be retried.
+pub(super) fn handle_page_fault(error_code: PageFaultErrorCode) -> bool {
+    // a fault while the page tables are locked can't be handled without deadlocking
+    if error_code.protection_violation() || LOCK.is_locked() {
+        return false;
+    }
+    let addr = Cr2::read();
+    let user = match DEMAND_REGIONS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|region| region.range.contains(&addr))
+    {
+        Some(region) => region.user,
+        None => return false,
+    };
+    if error_code.user_mode() && !user {
+        return false;
+    }
+
+    let page = Page::containing_address(addr);
+    let flags = if user {
+        PageTableFlags::USER_ACCESSIBLE
+    } else {
+        PageTableFlags::empty()
+    };
+    match new_page(page, flags) {
+        // another processor may have mapped the page first
+        Ok(_) | Err(Error::AlreadyMapped(_)) => true,
+        Err(err) => {
+            log::error!("cannot map demand-paged page at {addr:#x}: {err}");
+            false
+        }
+    }
+}
+
+/// Maps `page` to a new, zeroed frame, which is writable and not executable, with any additional
+/// `flags`.
+fn new_page(page: Page, flags: PageTableFlags) -> Result<PhysFrame, Error> {
+    let addr = page.start_address();
+    check_range(&(addr..addr + PAGE_SIZE))?;
+
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute();
+
+    with_page_table(|page_table| {
+        if page_table.translate_addr(addr).is_some() {
+            return Err(Error::AlreadyMapped(addr));
+        }
+        let frame = frame::alloc_zeroed().ok_or(Error::OutOfMemory)?;
+
+        // SAFETY: the frame was just allocated, so nothing else uses it, and the page wasn't mapped
+        match unsafe { page_table.map_to(page, frame, flags, &mut frame::Allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(frame)
+            }
+            Err(err) => {
+                // SAFETY: the frame was never mapped
+                unsafe { frame::free(frame) };
+                Err(map_error(err, addr))
+            }
+        }
+    })
+}
+
+/// Returns the no-execute flag if it's enabled, or no flags otherwise.
+fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Converts an error mapping the page at `addr`.
+fn map_error(err: MapToError<Size4KiB>, addr: VirtAddr) -> Error {
+    match err {
+        MapToError::FrameAllocationFailed => Error::OutOfMemory,
+        MapToError::ParentEntryHugePage => Error::HugePage(addr),
+        MapToError::PageAlreadyMapped(_) => Error::AlreadyMapped(addr),
+    }
+}
+
+/// Makes the kernel's code read-only, and its read-only data read-only and not executable.
+///
+/// # Safety
+/// Nothing may write to the kernel's code or read-only data, or execute its read-only data.
+pub unsafe fn protect_kernel_image() -> Result<(), Error> {
+    extern "C" {
+        static __text_start: [u8; 0];
+        static __text_end: [u8; 0];
+        static __build_id_end: [u8; 0];
+    }
+
+    // SAFETY: the linker script places the kernel's code from `__text_start` to `__text_end`, and
+    //         its read-only data from there to `__build_id_end`, each ending on a page boundary
+    let (text_start, text_end, rodata_end) = unsafe {
+        (
+            VirtAddr::from_ptr(__text_start.as_ptr()),
+            VirtAddr::from_ptr(__text_end.as_ptr()),
+            VirtAddr::from_ptr(__build_id_end.as_ptr()),
+        )
+    };
+    // SAFETY: the caller guarantees that nothing writes to the code
+    unsafe { protect(text_start, text_end - text_start, Permissions::ReadExecute) }?;
+    // SAFETY: the caller guarantees that nothing writes to or executes the read-only data
+    unsafe { protect(text_end, rodata_end - text_end, Permissions::ReadOnly) }
+}
+
+/// Unmaps the null page, first splitting the huge page containing it, if necessary.
+fn unmap_null_page() -> Result<(), Error> {
+    let null = VirtAddr::zero();
+
+    with_page_table(|page_table| {
+        let low_memory_table = match page_table.translate(null) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                ..
+            } => None,
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                flags,
+                ..
+            } => {
+                // SAFETY: only the address of `LOW_MEMORY_TABLE` is taken
+                let table = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(LOW_MEMORY_TABLE) });
+                let phys = page_table
+                    .translate_addr(table)
+                    .ok_or(Error::NotMapped(table))?;
+                Some((phys, flags))
+            }
+            TranslateResult::Mapped { .. } => return Err(Error::HugePage(null)),
+            _ => return Ok(()),
+        };
+
+        let level_2 = level_2_table_for_null(page_table).ok_or(Error::HugePage(null))?;
+        match low_memory_table {
+            Some((phys, flags)) => {
+                let flags = flags - PageTableFlags::HUGE_PAGE;
+                // SAFETY: access to `LOW_MEMORY_TABLE` is synchronized with `LOCK`, and it is only
+                //         used once, since the null page is no longer part of a huge page after
+                //         this
+                let table = unsafe { &mut *core::ptr::addr_of_mut!(LOW_MEMORY_TABLE) };
+                for (i, entry) in table.iter_mut().enumerate().skip(1) {
+                    entry.set_addr(PhysAddr::new(i as u64 * PAGE_SIZE), flags);
+                }
+                table[0].set_unused();
+
+                // the new table maps the same memory with the same flags, except the null page
+                let table_flags = PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | (flags & PageTableFlags::USER_ACCESSIBLE);
+                level_2[0].set_addr(phys, table_flags);
+                tlb::flush_all();
+            }
+            None => {
+                // SAFETY: BOOTBOOT identity maps the page tables, and access is synchronized with
+                //         `LOCK`
+                let level_1 = unsafe { &mut *(level_2[0].addr().as_u64() as *mut PageTable) };
+                level_1[0].set_unused();
+                tlb::flush(null);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Returns the level 2 page table which maps the null page, or `None` if it's mapped by a 1 GiB
+/// page.
+fn level_2_table_for_null<'a>(
+    page_table: &'a mut OffsetPageTable<'_>,
+) -> Option<&'a mut PageTable> {
+    let level_4 = page_table.level_4_table();
+    if level_4[0].flags().contains(PageTableFlags::HUGE_PAGE) {
+        return None;
+    }
+    // SAFETY: BOOTBOOT identity maps the page tables, and access is synchronized with `LOCK`
+    let level_3 = unsafe { &mut *(level_4[0].addr().as_u64() as *mut PageTable) };
+    if level_3[0].flags().contains(PageTableFlags::HUGE_PAGE) {
+        return None;
+    }
+    // SAFETY: BOOTBOOT identity maps the page tables, and access is synchronized with `LOCK`
+    Some(unsafe { &mut *(level_3[0].addr().as_u64() as *mut PageTable) })
+}
+
+/// Runs `f` with the active level 4 page table.
+fn with_page_table<R>(f: impl FnOnce(&mut OffsetPageTable<'_>) -> R) -> R {
+    let _guard = LOCK.lock();
+    let (frame, _) = Cr3::read();
+
+    // SAFETY: BOOTBOOT identity maps the page tables, and access is synchronized with `LOCK`
+    let mut page_table = unsafe {
+        let level_4 = &mut *(frame.start_address().as_u64() as *mut PageTable);
+        OffsetPageTable::new(level_4, VirtAddr::zero())
+    };
+
+    f(&mut page_table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> Range<VirtAddr> {
+        VirtAddr::new_truncate(start)..VirtAddr::new_truncate(end)
+    }
+
+    #[test]
+    fn null_page() {
+        assert_eq!(check_range(&range(0, PAGE_SIZE)), Err(Error::NullPage));
+        assert_eq!(
+            check_range(&range(PAGE_SIZE - 1, 2 * PAGE_SIZE)),
+            Err(Error::NullPage)
+        );
+        assert_eq!(check_range(&range(PAGE_SIZE, 2 * PAGE_SIZE)), Ok(()));
+        // an empty range maps nothing, wherever it is
+        assert_eq!(check_range(&range(0, 0)), Ok(()));
+    }
+
+    #[test]
+    fn non_canonical_hole() {
+        let lower_end = 0x0000_8000_0000_0000;
+        assert_eq!(
+            check_range(&range(lower_end - PAGE_SIZE, lower_end)),
+            Ok(())
+        );
+        assert_eq!(
+            check_range(&range(UPPER_HALF, UPPER_HALF + PAGE_SIZE)),
+            Ok(())
+        );
+        assert_eq!(
+            check_range(&range(lower_end - PAGE_SIZE, UPPER_HALF + PAGE_SIZE)),
+            Err(Error::NonCanonical)
+        );
+        assert_eq!(
+            check_range(&range(PAGE_SIZE, !(PAGE_SIZE - 1))),
+            Err(Error::NonCanonical)
+        );
+    }
+}
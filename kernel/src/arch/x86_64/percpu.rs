@@ -0,0 +1,120 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Per-processor data, found through the GS base.
+//!
+//! [`init`] records the current processor's local APIC ID and the end of its stack, and points the
+//! GS base at them, so that [`id`] and [`stack_top`] are a single memory access. Interrupt handlers
+//! use them rather than CPUID or the local APIC's ID register, since as an SEV-ES guest, executing
+//! CPUID or reading an MSR raises a VMM-communication exception, which a handler can't do while
+//! that exception is being handled.
+//!
+//! Until [`init`] has been called, [`id`] and [`stack_top`] fall back to
+//! [`apic::current_id`](super::apic::current_id).
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
+
+use x86_64::{registers::model_specific::GsBase, VirtAddr};
+
+use super::apic;
+use crate::bootboot::INIT_STACK_SIZE;
+
+/// The number of processors, by local APIC ID, which can have per-processor data.
+pub const MAX_CPUS: usize = 256;
+
+/// The data of one processor.
+#[repr(C)]
+struct PerCpu {
+    /// The address of this structure, which is read through the GS base.
+    this: AtomicU64,
+    /// The processor's local APIC ID.
+    id: AtomicU32,
+    /// The address of the end of the processor's stack.
+    stack_top: AtomicU64,
+}
+
+/// The data of each processor, by local APIC ID.
+static CPUS: [PerCpu; MAX_CPUS] = [const {
+    PerCpu {
+        this: AtomicU64::new(0),
+        id: AtomicU32::new(0),
+        stack_top: AtomicU64::new(0),
+    }
+}; MAX_CPUS];
+/// Whether [`init`] has been called, and so whether the GS base points to an element of [`CPUS`].
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Records the current processor's data, and points its GS base to them. Returns `false`, leaving
+/// the GS base unchanged, if the processor's local APIC ID is at least [`MAX_CPUS`].
+///
+/// # Safety
+/// Must be called by the processor which calls [`init`](super::init), before interrupts are
+/// enabled, and nothing else may change the GS base.
+pub(super) unsafe fn init() -> bool {
+    let id = apic::current_id();
+    let cpu = match CPUS.get(id as usize) {
+        Some(cpu) => cpu,
+        None => return false,
+    };
+    cpu.id.store(id, Ordering::Relaxed);
+    cpu.stack_top.store(boot_stack_top(id), Ordering::Relaxed);
+    cpu.this
+        .store(cpu as *const PerCpu as u64, Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(cpu));
+    READY.store(true, Ordering::Release);
+    true
+}
+
+/// Returns the local APIC ID of the current processor.
+pub fn id() -> u32 {
+    match current() {
+        Some(cpu) => cpu.id.load(Ordering::Relaxed),
+        None => apic::current_id(),
+    }
+}
+
+/// Returns the local APIC ID of the current processor, or `None` if [`init`] hasn't been called.
+/// Unlike [`id`], this never executes CPUID or reads an MSR.
+pub fn cached_id() -> Option<u32> {
+    current().map(|cpu| cpu.id.load(Ordering::Relaxed))
+}
+
+/// Returns the address of the end of the current processor's stack.
+pub fn stack_top() -> u64 {
+    match current() {
+        Some(cpu) => cpu.stack_top.load(Ordering::Relaxed),
+        None => boot_stack_top(apic::current_id()),
+    }
+}
+
+/// Returns the current processor's data, if [`init`] has been called.
+fn current() -> Option<&'static PerCpu> {
+    if !READY.load(Ordering::Acquire) {
+        return None;
+    }
+    let this: u64;
+    // SAFETY: `READY` is set, so the GS base points to an element of `CPUS`, whose first field is
+    //         its own address
+    unsafe {
+        asm!(
+            "mov {this}, gs:[0]",
+            this = out(reg) this,
+            options(nostack, readonly, preserves_flags),
+        );
+    }
+    // SAFETY: `this` is the address of an element of `CPUS`, which is static
+    Some(unsafe { &*(this as *const PerCpu) })
+}
+
+/// Returns the address of the end of the stack the loader gave the processor with local APIC ID
+/// `id`.
+fn boot_stack_top(id: u32) -> u64 {
+    0u64.wrapping_sub(u64::from(id) * INIT_STACK_SIZE)
+}
@@ -0,0 +1,360 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Hardware performance counters, using the Intel architectural performance monitoring unit.
+//!
+//! A [`Counter`] counts a single [`Event`] on the current CPU, in both kernel and user mode, from
+//! when it is started until it is dropped. Fixed-function counters are used where possible, and
+//! programmable counters otherwise.
+//!
+//! Counters are per-CPU, since there are no threads to scope them to yet, and there is no
+//! sampling, since there is no timer interrupt or trace buffer to sample into. Each processor's
+//! counters are claimed separately, by [local APIC ID](percpu::id), and a [`Counter`] can't be sent
+//! to another processor.
+
+use core::{
+    arch::x86_64::__cpuid,
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use x86_64::registers::model_specific::Msr;
+
+use super::percpu::{self, MAX_CPUS};
+
+/// The CPUID leaf describing the architectural performance monitoring unit.
+const PMU_LEAF: u32 = 0x0a;
+
+/// `IA32_PMC0`; the remaining programmable counters follow consecutively.
+const IA32_PMC0: u32 = 0xc1;
+/// `IA32_PERFEVTSEL0`; the remaining event select registers follow consecutively.
+const IA32_PERFEVTSEL0: u32 = 0x186;
+/// `IA32_FIXED_CTR0`; the remaining fixed-function counters follow consecutively.
+const IA32_FIXED_CTR0: u32 = 0x309;
+/// `IA32_FIXED_CTR_CTRL`, which has a 4-bit control field for each fixed-function counter.
+const IA32_FIXED_CTR_CTRL: u32 = 0x38d;
+/// `IA32_PERF_GLOBAL_CTRL`, which enables each counter (version 2 and later).
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+
+/// Counts in kernel mode, in `IA32_PERFEVTSELx`.
+const PERFEVTSEL_OS: u64 = 1 << 17;
+/// Counts in user mode, in `IA32_PERFEVTSELx`.
+const PERFEVTSEL_USR: u64 = 1 << 16;
+/// Enables the counter, in `IA32_PERFEVTSELx`.
+const PERFEVTSEL_EN: u64 = 1 << 22;
+/// Counts in kernel and user mode, in a fixed-function counter's field of `IA32_FIXED_CTR_CTRL`.
+const FIXED_CTR_CTRL_OS_USR: u64 = 0b11;
+
+/// The counters of one processor which are in use.
+#[derive(Debug)]
+struct InUse {
+    /// The programmable counters which are in use.
+    programmable: AtomicU32,
+    /// The fixed-function counters which are in use.
+    fixed: AtomicU32,
+}
+
+/// The counters in use on each processor, by local APIC ID.
+static IN_USE: [InUse; MAX_CPUS] = [const {
+    InUse {
+        programmable: AtomicU32::new(0),
+        fixed: AtomicU32::new(0),
+    }
+}; MAX_CPUS];
+
+/// A hardware event which can be counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Instructions retired.
+    Instructions,
+    /// Core cycles while not halted.
+    Cycles,
+    /// Reference cycles while not halted, which count at a fixed frequency.
+    ReferenceCycles,
+    /// Last level cache misses.
+    CacheMisses,
+    /// Mispredicted branches retired.
+    BranchMisses,
+}
+
+impl Event {
+    /// Returns the bit in CPUID.0AH:EBX which is set if the event is *not* available.
+    fn unavailable_bit(self) -> u32 {
+        match self {
+            Event::Cycles => 0,
+            Event::Instructions => 1,
+            Event::ReferenceCycles => 2,
+            Event::CacheMisses => 4,
+            Event::BranchMisses => 6,
+        }
+    }
+
+    /// Returns the fixed-function counter which counts the event, if any.
+    fn fixed_counter(self) -> Option<u32> {
+        match self {
+            Event::Instructions => Some(0),
+            Event::Cycles => Some(1),
+            Event::ReferenceCycles => Some(2),
+            Event::CacheMisses | Event::BranchMisses => None,
+        }
+    }
+
+    /// Returns the event select and unit mask for a programmable counter.
+    fn encoding(self) -> u64 {
+        let (event, umask) = match self {
+            Event::Instructions => (0xc0, 0x00),
+            Event::Cycles => (0x3c, 0x00),
+            Event::ReferenceCycles => (0x3c, 0x01),
+            Event::CacheMisses => (0x2e, 0x41),
+            Event::BranchMisses => (0xc5, 0x00),
+        };
+        umask << 8 | event
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Instructions => write!(f, "instructions"),
+            Event::Cycles => write!(f, "cycles"),
+            Event::ReferenceCycles => write!(f, "reference cycles"),
+            Event::CacheMisses => write!(f, "cache misses"),
+            Event::BranchMisses => write!(f, "branch misses"),
+        }
+    }
+}
+
+/// The capabilities of the performance monitoring unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pmu {
+    /// The architectural performance monitoring version.
+    pub version: u8,
+    /// The number of programmable counters.
+    pub programmable_counters: u8,
+    /// The width of the programmable counters in bits.
+    pub programmable_width: u8,
+    /// The number of fixed-function counters.
+    pub fixed_counters: u8,
+    /// The width of the fixed-function counters in bits.
+    pub fixed_width: u8,
+    /// CPUID.0AH:EBX, in which a set bit means an architectural event is *not* available.
+    unavailable_events: u32,
+}
+
+impl Pmu {
+    /// Returns the capabilities of the performance monitoring unit, or `None` if there is no
+    /// architectural performance monitoring unit.
+    pub fn detect() -> Option<Self> {
+        // SAFETY: CPUID is available on all x86_64 processors
+        let max_leaf = unsafe { __cpuid(0) }.eax;
+        if max_leaf < PMU_LEAF {
+            return None;
+        }
+
+        // SAFETY: CPUID is available on all x86_64 processors, and the leaf is checked above
+        let cpuid = unsafe { __cpuid(PMU_LEAF) };
+        let version = cpuid.eax as u8;
+        if version == 0 {
+            return None;
+        }
+
+        // the length of the EBX bit vector; bits beyond it are unavailable events
+        let event_vector_len = (cpuid.eax >> 24) as u8;
+        let unavailable_events =
+            cpuid.ebx | u32::MAX.checked_shl(event_vector_len.into()).unwrap_or(0);
+
+        let (fixed_counters, fixed_width) = if version >= 2 {
+            ((cpuid.edx & 0x1f) as u8, (cpuid.edx >> 5) as u8)
+        } else {
+            (0, 0)
+        };
+
+        Some(Pmu {
+            version,
+            programmable_counters: (cpuid.eax >> 8) as u8,
+            programmable_width: (cpuid.eax >> 16) as u8,
+            fixed_counters,
+            fixed_width,
+            unavailable_events,
+        })
+    }
+
+    /// Returns `true` if `event` can be counted.
+    pub fn supports(&self, event: Event) -> bool {
+        self.unavailable_events & (1 << event.unavailable_bit()) == 0
+    }
+}
+
+/// An error which prevented a counter from being started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There is no architectural performance monitoring unit.
+    NoPmu,
+    /// The event is not supported by this processor.
+    Unsupported(Event),
+    /// All suitable counters are already in use.
+    Busy(Event),
+    /// The current processor's local APIC ID is too large for its counters to be tracked.
+    UnknownCpu(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoPmu => write!(f, "no architectural performance monitoring unit"),
+            Error::Unsupported(event) => write!(f, "counting {event} is not supported"),
+            Error::Busy(event) => write!(f, "no free counter for {event}"),
+            Error::UnknownCpu(id) => write!(f, "cannot track the counters of CPU {id}"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// A hardware counter of the current CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    /// A fixed-function counter.
+    Fixed(u32),
+    /// A programmable counter.
+    Programmable(u32),
+}
+
+/// A running hardware performance counter, which is stopped when dropped.
+///
+/// It counts on the processor which started it, so it can't be sent to another one.
+#[derive(Debug)]
+pub struct Counter {
+    event: Event,
+    slot: Slot,
+    /// The counters in use on the processor which started the counter.
+    in_use: &'static InUse,
+    /// Whether `IA32_PERF_GLOBAL_CTRL` exists.
+    global_ctrl: bool,
+    /// Keeps the counter on the processor which started it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Counter {
+    /// Starts counting `event` on the current CPU, from zero.
+    pub fn start(event: Event) -> Result<Self, Error> {
+        let pmu = Pmu::detect().ok_or(Error::NoPmu)?;
+        if !pmu.supports(event) {
+            return Err(Error::Unsupported(event));
+        }
+
+        let id = percpu::id();
+        let in_use = IN_USE.get(id as usize).ok_or(Error::UnknownCpu(id))?;
+        let slot = match event.fixed_counter() {
+            Some(index) if index < pmu.fixed_counters.into() && claim(&in_use.fixed, index) => {
+                Slot::Fixed(index)
+            }
+            _ => (0..pmu.programmable_counters.into())
+                .find(|&index| claim(&in_use.programmable, index))
+                .map(Slot::Programmable)
+                .ok_or(Error::Busy(event))?,
+        };
+
+        let counter = Counter {
+            event,
+            slot,
+            in_use,
+            global_ctrl: pmu.version >= 2,
+            _not_send: PhantomData,
+        };
+        // SAFETY: the counter exists and has been claimed, so nothing else is using it
+        unsafe { counter.program(true) };
+        Ok(counter)
+    }
+
+    /// Returns the event being counted.
+    pub fn event(&self) -> Event {
+        self.event
+    }
+
+    /// Returns the number of events counted since the counter was started.
+    pub fn read(&self) -> u64 {
+        let msr = match self.slot {
+            Slot::Fixed(index) => IA32_FIXED_CTR0 + index,
+            Slot::Programmable(index) => IA32_PMC0 + index,
+        };
+        // SAFETY: the counter exists, since it was started
+        unsafe { Msr::new(msr).read() }
+    }
+
+    /// Enables or disables the counter, resetting it to zero when enabling.
+    ///
+    /// # Safety
+    /// The counter must exist, and must not be used by anything else.
+    unsafe fn program(&self, enable: bool) {
+        let global_bit = match self.slot {
+            Slot::Fixed(index) => {
+                let mut fixed_ctrl = Msr::new(IA32_FIXED_CTR_CTRL);
+                let shift = index * 4;
+                // SAFETY: the caller guarantees that the counter exists and isn't used by
+                //         anything else, so it is safe to change its control field
+                unsafe {
+                    let mut value = fixed_ctrl.read() & !(0xf << shift);
+                    if enable {
+                        Msr::new(IA32_FIXED_CTR0 + index).write(0);
+                        value |= FIXED_CTR_CTRL_OS_USR << shift;
+                    }
+                    fixed_ctrl.write(value);
+                }
+                1 << (32 + index)
+            }
+            Slot::Programmable(index) => {
+                let value = if enable {
+                    self.event.encoding() | PERFEVTSEL_OS | PERFEVTSEL_USR | PERFEVTSEL_EN
+                } else {
+                    0
+                };
+                // SAFETY: the caller guarantees that the counter exists and isn't used by
+                //         anything else
+                unsafe {
+                    Msr::new(IA32_PERFEVTSEL0 + index).write(0);
+                    Msr::new(IA32_PMC0 + index).write(0);
+                    Msr::new(IA32_PERFEVTSEL0 + index).write(value);
+                }
+                1 << index
+            }
+        };
+
+        if self.global_ctrl {
+            let mut global_ctrl = Msr::new(IA32_PERF_GLOBAL_CTRL);
+            // SAFETY: `IA32_PERF_GLOBAL_CTRL` exists in version 2 and later, and only this
+            //         counter's bit is changed
+            unsafe {
+                let value = global_ctrl.read();
+                global_ctrl.write(if enable {
+                    value | global_bit
+                } else {
+                    value & !global_bit
+                });
+            }
+        }
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        // SAFETY: the counter exists and was claimed when it was started
+        unsafe { self.program(false) };
+
+        let (in_use, index) = match self.slot {
+            Slot::Fixed(index) => (&self.in_use.fixed, index),
+            Slot::Programmable(index) => (&self.in_use.programmable, index),
+        };
+        in_use.fetch_and(!(1 << index), Ordering::Release);
+    }
+}
+
+/// Marks counter `index` as in use, returning `false` if it already was.
+fn claim(in_use: &AtomicU32, index: u32) -> bool {
+    index < 32 && in_use.fetch_or(1 << index, Ordering::Acquire) & (1 << index) == 0
+}
@@ -0,0 +1,66 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Polled input from a PS/2 keyboard.
+//!
+//! The keyboard is left as the firmware configured it, which normally has the controller
+//! translate its scancodes to scancode set 1, for [`Set1Decoder`]. Input is polled, since there is
+//! no interrupt controller driver to route the keyboard's interrupt yet.
+
+use spin::Mutex;
+use x86_64::instructions::port::PortReadOnly;
+
+use crate::input::{scancode::Set1Decoder, InputEvent};
+
+/// The controller's data port.
+const DATA_PORT: u16 = 0x60;
+/// The controller's status port.
+const STATUS_PORT: u16 = 0x64;
+/// Data is waiting to be read, in the status register.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// The waiting data is from the second port, normally a mouse, in the status register.
+const STATUS_AUX: u8 = 1 << 5;
+
+/// The keyboard's decoder, which also serializes access to the controller.
+static KEYBOARD: Mutex<Set1Decoder> = Mutex::new(Set1Decoder::new());
+
+/// Returns the next key event from the keyboard, if a whole scancode is waiting.
+///
+/// Timestamps are always `0`, since there is no clock yet.
+pub fn read_event() -> Option<InputEvent> {
+    let mut keyboard = KEYBOARD.lock();
+    // SAFETY: reading the controller's data is synchronized with `KEYBOARD`
+    while let Some(byte) = unsafe { read() } {
+        if let Some(event) = keyboard.decode(byte, 0) {
+            return Some(event);
+        }
+    }
+    None
+}
+
+/// Reads a byte from the keyboard, if there is one, discarding any bytes from a mouse.
+///
+/// # Safety
+/// Access to the controller must be synchronized.
+unsafe fn read() -> Option<u8> {
+    let mut status = PortReadOnly::<u8>::new(STATUS_PORT);
+    let mut data = PortReadOnly::<u8>::new(DATA_PORT);
+    loop {
+        // SAFETY: these are the PS/2 controller's standard ports, and the caller guarantees that
+        //         access is synchronized
+        let (status, byte) = unsafe {
+            let status = status.read();
+            if status & STATUS_OUTPUT_FULL == 0 {
+                return None;
+            }
+            (status, data.read())
+        };
+        if status & STATUS_AUX == 0 {
+            return Some(byte);
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! A driver for the 16550 UART, used as a serial console on `COM1`.
+//!
+//! Once [`init`] finds a UART, log messages are mirrored to it, and input can be read with
+//! [`read_byte`]. Until [`enable_interrupt`] routes the UART's IRQ through the
+//! [I/O APIC](super::ioapic), input stays in the UART's receive FIFO until it's read. Afterwards,
+//! the interrupt handler moves input to a larger buffer as it arrives, so that it isn't lost while
+//! nothing is reading.
+//!
+//! The handler only [tries](Mutex::try_lock) to lock the UART, since the code it interrupted may
+//! be holding the lock to write a log message. Input it can't move then stays in the FIFO, and
+//! [`read_byte`] reads it from there once the buffer is empty.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+use super::{
+    apic,
+    interrupt::{self, Context, IntVec},
+    ioapic, storm, vector,
+};
+use crate::{param, util::ring::SpscRing};
+
+param! {
+    /// The baud rate of the serial console.
+    pub static SERIAL_BAUD: u32 = 115_200, name = "serial_baud";
+}
+
+/// The I/O port base of `COM1`.
+const COM1_BASE: u16 = 0x3f8;
+/// The ISA IRQ of `COM1`.
+const COM1_IRQ: u8 = 4;
+/// The number of bytes of input the interrupt handler can hold until they're read.
+const INPUT_CAPACITY: usize = 256;
+
+/// The UART's input clock divided by 16, which is the maximum baud rate.
+const MAX_BAUD: u32 = 115_200;
+
+/// Divisor latch access, in the line control register.
+const LCR_DLAB: u8 = 1 << 7;
+/// 8 data bits, no parity and 1 stop bit, in the line control register.
+const LCR_8N1: u8 = 0b11;
+/// Enables and clears the FIFOs, with a 14-byte receive threshold, in the FIFO control register.
+const FCR_ENABLE_CLEAR_14: u8 = 0xc7;
+/// Data terminal ready, request to send, `OUT1` and `OUT2`, in the modem control register.
+const MCR_NORMAL: u8 = 0x0f;
+/// Loopback mode, in the modem control register.
+const MCR_LOOPBACK: u8 = 1 << 4;
+/// Interrupts when data is received, in the interrupt enable register.
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+/// Data is ready to be read, in the line status register.
+const LSR_DATA_READY: u8 = 1 << 0;
+/// The transmitter holding register is empty, in the line status register.
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// The serial console on `COM1`.
+// SAFETY: `COM1_BASE` is the standard I/O port base for `COM1`, and all access is synchronized
+//         through the mutex
+static COM1: Mutex<Uart16550> = Mutex::new(unsafe { Uart16550::new(COM1_BASE) });
+
+/// Whether a UART was found on `COM1`.
+static PRESENT: AtomicBool = AtomicBool::new(false);
+/// Input moved from the UART by the interrupt handler, which is only accessed with [`COM1`]
+/// locked.
+static INPUT: SpscRing<u8, INPUT_CAPACITY> = SpscRing::new();
+/// The UART's interrupt vector, or zero if its interrupt isn't enabled.
+static VECTOR: AtomicU8 = AtomicU8::new(0);
+/// The number of bytes of input dropped because [`INPUT`] was full.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// An error enabling the UART's interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There is no UART on `COM1`.
+    NotPresent,
+    /// There is no free interrupt vector for the UART.
+    NoFreeVector,
+    /// The UART's handler could not be registered.
+    Register(interrupt::Error),
+    /// The UART's IRQ could not be routed to its vector.
+    Route(ioapic::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotPresent => write!(f, "there is no serial port"),
+            Error::NoFreeVector => write!(f, "no free interrupt vector for the serial port"),
+            Error::Register(_) => write!(f, "cannot register the serial port's handler"),
+            Error::Route(_) => write!(f, "cannot route the serial port's IRQ"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {
+    fn source(&self) -> Option<&(dyn crate::error::Error + 'static)> {
+        match self {
+            Error::Register(err) => Some(err),
+            Error::Route(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// A 16550 UART.
+#[derive(Debug)]
+pub struct Uart16550 {
+    data: Port<u8>,
+    interrupt_enable: PortWriteOnly<u8>,
+    fifo_control: PortWriteOnly<u8>,
+    line_control: PortWriteOnly<u8>,
+    modem_control: PortWriteOnly<u8>,
+    line_status: PortReadOnly<u8>,
+}
+
+impl Uart16550 {
+    /// Returns a UART with registers at I/O port `base`.
+    ///
+    /// # Safety
+    /// Only a 16550-compatible UART, or nothing, may be at `base`, and nothing else may access the
+    /// UART's registers.
+    pub const unsafe fn new(base: u16) -> Self {
+        Uart16550 {
+            data: Port::new(base),
+            interrupt_enable: PortWriteOnly::new(base + 1),
+            fifo_control: PortWriteOnly::new(base + 2),
+            line_control: PortWriteOnly::new(base + 3),
+            modem_control: PortWriteOnly::new(base + 4),
+            line_status: PortReadOnly::new(base + 5),
+        }
+    }
+
+    /// Initializes the UART with 8 data bits, no parity and 1 stop bit at `baud` bits per second,
+    /// returning `false` if it fails a loopback test, which usually means there is no UART.
+    pub fn init(&mut self, baud: u32) -> bool {
+        let divisor = (MAX_BAUD / baud.clamp(1, MAX_BAUD)) as u16;
+
+        // SAFETY: `new`'s caller guarantees that the ports belong to the UART
+        unsafe {
+            self.interrupt_enable.write(0);
+            self.line_control.write(LCR_DLAB);
+            self.data.write(divisor as u8);
+            self.interrupt_enable.write((divisor >> 8) as u8);
+            self.line_control.write(LCR_8N1);
+            self.fifo_control.write(FCR_ENABLE_CLEAR_14);
+
+            self.modem_control.write(MCR_NORMAL | MCR_LOOPBACK);
+            self.data.write(0xae);
+            let present = self.try_receive() == Some(0xae);
+            self.modem_control.write(MCR_NORMAL);
+
+            present
+        }
+    }
+
+    /// Sends a byte, waiting for the transmitter to be ready.
+    pub fn send(&mut self, byte: u8) {
+        // SAFETY: `new`'s caller guarantees that the ports belong to the UART
+        unsafe {
+            while self.line_status.read() & LSR_THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            self.data.write(byte);
+        }
+    }
+
+    /// Enables or disables the interrupt raised when data is received.
+    pub fn set_receive_interrupt(&mut self, enabled: bool) {
+        let value = if enabled { IER_RECEIVED_DATA } else { 0 };
+        // SAFETY: `new`'s caller guarantees that the ports belong to the UART
+        unsafe { self.interrupt_enable.write(value) };
+    }
+
+    /// Returns the next received byte, if any.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        // SAFETY: `new`'s caller guarantees that the ports belong to the UART
+        unsafe {
+            if self.line_status.read() & LSR_DATA_READY == 0 {
+                None
+            } else {
+                Some(self.data.read())
+            }
+        }
+    }
+}
+
+impl fmt::Write for Uart16550 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Initializes the serial console on `COM1` at [`SERIAL_BAUD`], returning `false` if there is no
+/// UART.
+pub fn init() -> bool {
+    let present = COM1.lock().init(SERIAL_BAUD.get());
+    PRESENT.store(present, Ordering::Release);
+    present
+}
+
+/// Writes to the serial console, if present.
+pub fn write_fmt(args: fmt::Arguments<'_>) {
+    if PRESENT.load(Ordering::Acquire) {
+        // writing to the UART never fails
+        let _ = fmt::Write::write_fmt(&mut *COM1.lock(), args);
+    }
+}
+
+/// Writes raw bytes to the serial console, if present, without translating line endings.
+pub fn write_bytes(bytes: &[u8]) {
+    if PRESENT.load(Ordering::Acquire) {
+        let mut com1 = COM1.lock();
+        for &byte in bytes {
+            com1.send(byte);
+        }
+    }
+}
+
+/// Routes the serial console's IRQ to a vector of its own on the current processor, and enables
+/// its interrupt when data is received.
+pub fn enable_interrupt() -> Result<(), Error> {
+    if !PRESENT.load(Ordering::Acquire) {
+        return Err(Error::NotPresent);
+    }
+    if VECTOR.load(Ordering::Acquire) != 0 {
+        return Ok(());
+    }
+
+    let vec = vector::alloc().ok_or(Error::NoFreeVector)?;
+    if let Err(err) = interrupt::register(vec, handle) {
+        vector::free(vec);
+        return Err(Error::Register(err));
+    }
+    // SAFETY: `handle` signals end-of-interrupt, and `COM1_IRQ` is only used by `COM1`
+    if let Err(err) = unsafe { ioapic::route_isa_irq(COM1_IRQ, vec) } {
+        interrupt::unregister(vec);
+        vector::free(vec);
+        return Err(Error::Route(err));
+    }
+    storm::register(vec, mask);
+    VECTOR.store(vec.0, Ordering::Release);
+    COM1.lock().set_receive_interrupt(true);
+    Ok(())
+}
+
+/// Returns the next byte of input from the serial console, if any.
+pub fn read_byte() -> Option<u8> {
+    if !PRESENT.load(Ordering::Acquire) {
+        return None;
+    }
+    // while `COM1` is locked, the interrupt handler can't move newer input into `INPUT`
+    let mut com1 = COM1.lock();
+    INPUT
+        .consumer()
+        .and_then(|mut input| input.pop())
+        .or_else(|| com1.try_receive())
+}
+
+/// Returns the number of bytes of input dropped because they arrived faster than they were read.
+pub fn dropped_input() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Handles the UART's interrupt, by moving its input to [`INPUT`].
+fn handle(_context: &mut Context) {
+    let mut received = 0;
+    if let Some(mut com1) = COM1.try_lock() {
+        if let Some(mut input) = INPUT.producer() {
+            while let Some(byte) = com1.try_receive() {
+                if input.push(byte).is_err() {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+                received += 1;
+            }
+        }
+    }
+    if received != 0 {
+        storm::progress(IntVec(VECTOR.load(Ordering::Relaxed)));
+    }
+    apic::eoi();
+}
+
+/// Disables the UART's interrupt during an interrupt storm, if it isn't locked.
+fn mask() {
+    if let Some(mut com1) = COM1.try_lock() {
+        com1.set_receive_interrupt(false);
+    }
+}
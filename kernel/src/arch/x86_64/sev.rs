@@ -0,0 +1,593 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Support for running as an AMD SEV-ES guest.
+//!
+//! Under SEV-ES, the hypervisor can't read or modify the guest's registers, so instructions which
+//! would normally be intercepted by the hypervisor instead cause a
+//! [VMM-communication exception](IntVec::VMM_COMMUNICATION) (`#VC`). The guest must emulate the
+//! instruction, requesting any information it needs from the hypervisor using the Guest-Hypervisor
+//! Communication Block (GHCB) protocol.
+//!
+//! `CPUID` is emulated using the GHCB MSR protocol, which doesn't require a GHCB page to be shared
+//! with the hypervisor, so it works from the start. MSR accesses and non-string port I/O are
+//! emulated with the bootstrap processor's GHCB page, once [`init`] has shared it with the
+//! hypervisor. Until then, and for any other intercept, a `#VC` panics.
+//!
+//! The GHCB page holds one request at a time, so a `#VC` which needs it while another request is
+//! in progress, such as one raised by an NMI handler, panics rather than corrupting the request.
+//!
+//! Under SEV-SNP with restricted injection, the hypervisor may only inject the
+//! [hypervisor-injection exception](IntVec::HYPERVISOR_INJECTION) (`#HV`). The events it wants to
+//! deliver, such as interrupts, are instead queued in a `#HV` doorbell page, one per processor,
+//! which the `#HV` handler drains and dispatches as though they had been delivered normally.
+//! Registering the doorbell page with the hypervisor isn't implemented yet, so
+//! [`register_doorbell`] can't succeed, and a guest with restricted injection receives no
+//! interrupts.
+
+use core::{
+    arch::x86_64::__cpuid,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicU8, Ordering},
+};
+
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::paging::{Page, Size4KiB},
+    VirtAddr,
+};
+
+use super::{
+    decode::{Instruction, OpcodeMap},
+    error_code::{VmExit, VmExitCode},
+    interrupt::{self, Context, IntVec},
+    paging,
+};
+
+/// The GHCB MSR, used for the GHCB MSR protocol, and to tell the hypervisor where the GHCB page is.
+const GHCB_MSR: u32 = 0xc001_0130;
+
+/// The GHCB MSR protocol's SEV information response.
+const GHCB_MSR_SEV_INFO_RESPONSE: u64 = 0x001;
+/// The GHCB MSR protocol's SEV information request.
+const GHCB_MSR_SEV_INFO_REQUEST: u64 = 0x002;
+/// The GHCB MSR protocol's `CPUID` request.
+const GHCB_MSR_CPUID_REQUEST: u64 = 0x004;
+/// The GHCB MSR protocol's `CPUID` response.
+const GHCB_MSR_CPUID_RESPONSE: u64 = 0x005;
+/// The GHCB MSR protocol's GHCB registration request, used by SEV-SNP guests.
+const GHCB_MSR_REGISTER_REQUEST: u64 = 0x012;
+/// The GHCB MSR protocol's GHCB registration response.
+const GHCB_MSR_REGISTER_RESPONSE: u64 = 0x013;
+/// The GHCB MSR protocol's page state change request, used by SEV-SNP guests.
+const GHCB_MSR_PAGE_STATE_REQUEST: u64 = 0x014;
+/// The GHCB MSR protocol's page state change response.
+const GHCB_MSR_PAGE_STATE_RESPONSE: u64 = 0x015;
+/// The page state change operation which makes a page shared.
+const PAGE_STATE_SHARED: u64 = 2;
+/// The mask for the GHCB MSR protocol's request and response codes.
+const GHCB_MSR_INFO_MASK: u64 = 0xfff;
+
+/// The highest version of the GHCB protocol which is supported.
+const GHCB_PROTOCOL_MAX: u16 = 2;
+
+/// The offset of `RAX` in the GHCB page.
+const GHCB_RAX: usize = 0x1f8;
+/// The offset of `RCX` in the GHCB page.
+const GHCB_RCX: usize = 0x308;
+/// The offset of `RDX` in the GHCB page.
+const GHCB_RDX: usize = 0x310;
+/// The offset of the exit code in the GHCB page.
+const GHCB_SW_EXIT_CODE: usize = 0x390;
+/// The offset of the first exit information field in the GHCB page.
+const GHCB_SW_EXIT_INFO_1: usize = 0x398;
+/// The offset of the second exit information field in the GHCB page.
+const GHCB_SW_EXIT_INFO_2: usize = 0x3a0;
+/// The offset of the bitmap of valid fields in the GHCB page, with a bit for each 8 bytes.
+const GHCB_VALID_BITMAP: usize = 0x3f0;
+/// The offset of the 8 bytes in the GHCB page ending with the protocol version, at `0xffa`, and
+/// the usage, at `0xffc`.
+const GHCB_VERSION_AND_USAGE: usize = 0xff8;
+
+/// The exit code for port I/O.
+const EXIT_IOIO: u64 = 0x7b;
+/// The exit code for MSR accesses.
+const EXIT_MSR: u64 = 0x7c;
+
+/// Set in the port I/O exit information for input.
+const IOIO_IN: u64 = 1 << 0;
+/// Set in the port I/O exit information for 8-bit data.
+const IOIO_DATA_8: u64 = 1 << 4;
+/// Set in the port I/O exit information for 16-bit data.
+const IOIO_DATA_16: u64 = 1 << 5;
+/// Set in the port I/O exit information for 32-bit data.
+const IOIO_DATA_32: u64 = 1 << 6;
+/// Set in the port I/O exit information for a 64-bit address size.
+const IOIO_ADDR_64: u64 = 1 << 9;
+
+/// `SEV_STATUS`, which reports which SEV features are active in the guest.
+const SEV_STATUS_MSR: u32 = 0xc001_0131;
+/// Set in `SEV_STATUS` if SEV-ES is active.
+const SEV_STATUS_ES: u64 = 1 << 1;
+/// Set in `SEV_STATUS` if SEV-SNP is active.
+const SEV_STATUS_SNP: u64 = 1 << 2;
+/// Set in `SEV_STATUS` if restricted injection is active.
+const SEV_STATUS_RESTRICTED_INJECTION: u64 = 1 << 5;
+
+/// The vector of a pending interrupt, in the doorbell's pending events.
+const PENDING_VECTOR: u16 = 0xff;
+/// Set in the doorbell's pending events if an NMI is pending.
+const PENDING_NMI: u16 = 1 << 14;
+/// Set in the doorbell's pending events if a machine check is pending.
+const PENDING_MC: u16 = 1 << 15;
+
+/// The bootstrap processor's `#HV` doorbell page. Other processors will need their own once they
+/// are started.
+static DOORBELL: Doorbell = Doorbell::new();
+/// The bootstrap processor's GHCB page, which is shared with the hypervisor by [`init`]. Other
+/// processors will need their own once they are started.
+static GHCB: Ghcb = Ghcb::new();
+/// The GHCB protocol version negotiated by [`init`], or zero if the GHCB page isn't shared.
+static GHCB_VERSION: AtomicU16 = AtomicU16::new(0);
+/// The guest physical address of [`GHCB`], once it's shared.
+static GHCB_GPA: AtomicU64 = AtomicU64::new(0);
+/// Set while a request is using [`GHCB`].
+static GHCB_BUSY: AtomicBool = AtomicBool::new(false);
+
+/// An error in SEV guest support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The request needs the `SNP_HV_DOORBELL_PAGE` GHCB request, which isn't supported yet.
+    GhcbRequired,
+    /// The hypervisor doesn't support a version of the GHCB protocol which the kernel supports.
+    UnsupportedProtocol,
+    /// The GHCB page could not be mapped without encryption.
+    Paging(paging::Error),
+    /// The GHCB page could not be made shared with the hypervisor.
+    PageState,
+    /// The hypervisor refused to register the GHCB page.
+    Registration,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::GhcbRequired => write!(f, "a GHCB request is not supported"),
+            Error::UnsupportedProtocol => write!(f, "no supported GHCB protocol version"),
+            Error::Paging(_) => write!(f, "cannot map the GHCB page as shared"),
+            Error::PageState => write!(f, "cannot make the GHCB page shared"),
+            Error::Registration => write!(f, "the hypervisor refused to register the GHCB page"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {
+    fn source(&self) -> Option<&(dyn crate::error::Error + 'static)> {
+        match self {
+            Error::Paging(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// A Guest-Hypervisor Communication Block page, through which requests are passed to the
+/// hypervisor, as laid out in the GHCB specification.
+#[repr(C, align(4096))]
+struct Ghcb([AtomicU64; 512]);
+
+impl Ghcb {
+    /// Returns a GHCB page with no valid fields.
+    const fn new() -> Self {
+        Ghcb([const { AtomicU64::new(0) }; 512])
+    }
+
+    /// Marks every field as invalid, so that only those set afterwards are passed to the hypervisor.
+    fn clear(&self) {
+        self.0[GHCB_SW_EXIT_CODE / 8].store(0, Ordering::Relaxed);
+        self.0[GHCB_VALID_BITMAP / 8].store(0, Ordering::Relaxed);
+        self.0[GHCB_VALID_BITMAP / 8 + 1].store(0, Ordering::Relaxed);
+    }
+
+    /// Sets the field at `offset` to `value`, and marks it as valid.
+    fn set(&self, offset: usize, value: u64) {
+        let bit = offset / 8;
+        self.0[bit].store(value, Ordering::Relaxed);
+        self.0[GHCB_VALID_BITMAP / 8 + bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+    }
+
+    /// Returns the field at `offset`, or `None` if the hypervisor didn't mark it as valid.
+    fn get(&self, offset: usize) -> Option<u64> {
+        let bit = offset / 8;
+        let valid = self.0[GHCB_VALID_BITMAP / 8 + bit / 64].load(Ordering::Relaxed);
+        (valid & 1 << (bit % 64) != 0).then(|| self.0[bit].load(Ordering::Relaxed))
+    }
+
+    /// Passes a request with `exit_code` and its exit information to the hypervisor, along with the
+    /// fields which have been [set](Self::set). Returns the second exit information field, which
+    /// describes the error, if the hypervisor reports one.
+    fn call(&self, exit_code: u64, info_1: u64, info_2: u64) -> Result<(), u64> {
+        self.set(GHCB_SW_EXIT_CODE, exit_code);
+        self.set(GHCB_SW_EXIT_INFO_1, info_1);
+        self.set(GHCB_SW_EXIT_INFO_2, info_2);
+        let version = u64::from(GHCB_VERSION.load(Ordering::Relaxed));
+        // the usage, in the upper half, is zero for the standard layout
+        self.0[GHCB_VERSION_AND_USAGE / 8].store(version << 16, Ordering::Relaxed);
+
+        // SAFETY: the GHCB MSR exists on all SEV-ES guests, and the GHCB page is shared, so
+        //         writing its address and exiting passes the request to the hypervisor, which
+        //         only changes the GHCB page
+        unsafe {
+            Msr::new(GHCB_MSR).write(GHCB_GPA.load(Ordering::Relaxed));
+            vmgexit();
+        }
+
+        match self.0[GHCB_SW_EXIT_INFO_1 / 8].load(Ordering::Relaxed) as u32 {
+            0 => Ok(()),
+            _ => Err(self.0[GHCB_SW_EXIT_INFO_2 / 8].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A `#HV` doorbell page, through which the hypervisor queues events for a processor under
+/// restricted injection, as laid out in the GHCB specification.
+#[repr(C, align(4096))]
+struct Doorbell {
+    /// The pending events: an interrupt vector in the low byte, or zero if there isn't one, and
+    /// the [`PENDING_NMI`] and [`PENDING_MC`] flags.
+    pending: AtomicU16,
+    /// Set by the guest to tell the hypervisor not to raise `#HV` for new events, because it
+    /// will check the doorbell anyway.
+    no_further_signal: AtomicU8,
+    /// Set by the hypervisor if the pending interrupt doesn't need an end-of-interrupt.
+    no_eoi_required: AtomicU8,
+}
+
+impl Doorbell {
+    /// Returns a doorbell page with no pending events.
+    const fn new() -> Self {
+        Doorbell {
+            pending: AtomicU16::new(0),
+            no_further_signal: AtomicU8::new(0),
+            no_eoi_required: AtomicU8::new(0),
+        }
+    }
+}
+
+/// Returns the value of `SEV_STATUS`, or `0` if the processor doesn't support SEV.
+fn status() -> u64 {
+    // SAFETY: CPUID is available on all x86_64 processors
+    if unsafe { __cpuid(0x8000_0000) }.eax < 0x8000_001f {
+        return 0;
+    }
+    // SAFETY: CPUID is available on all x86_64 processors, and this leaf is supported
+    if unsafe { __cpuid(0x8000_001f) }.eax & (1 << 1) == 0 {
+        return 0;
+    }
+    // SAFETY: `SEV_STATUS` exists on all processors supporting SEV
+    unsafe { Msr::new(SEV_STATUS_MSR).read() }
+}
+
+/// Returns `true` if running as an SEV-SNP guest with restricted injection, so that interrupts are
+/// only delivered through the `#HV` doorbell page.
+pub fn restricted_injection() -> bool {
+    let status = status();
+    status & SEV_STATUS_SNP != 0 && status & SEV_STATUS_RESTRICTED_INJECTION != 0
+}
+
+/// Shares the bootstrap processor's GHCB page with the hypervisor, if running as an SEV-ES guest,
+/// so that MSR accesses and port I/O can be emulated. Does nothing otherwise.
+///
+/// # Safety
+/// Must only be called once, by the bootstrap processor, with the IDT loaded, so that the `CPUID`
+/// it executes can be emulated.
+pub(super) unsafe fn init() -> Result<(), Error> {
+    let status = status();
+    if status & SEV_STATUS_ES == 0 {
+        return Ok(());
+    }
+
+    let response = msr_protocol(GHCB_MSR_SEV_INFO_REQUEST);
+    if response & GHCB_MSR_INFO_MASK != GHCB_MSR_SEV_INFO_RESPONSE {
+        return Err(Error::UnsupportedProtocol);
+    }
+    let (max, min) = ((response >> 48) as u16, (response >> 32) as u16);
+    let version = max.min(GHCB_PROTOCOL_MAX);
+    if version < min.max(1) || (status & SEV_STATUS_SNP != 0 && version < 2) {
+        return Err(Error::UnsupportedProtocol);
+    }
+
+    // SAFETY: CPUID is available on all x86_64 processors, and this leaf is supported by SEV guests
+    let c_bit = 1 << (unsafe { __cpuid(0x8000_001f) }.ebx & 0x3f);
+    let addr = VirtAddr::from_ptr(&GHCB);
+    let gpa = match paging::translate(addr) {
+        Some(phys) => phys.as_u64() & !c_bit,
+        None => return Err(Error::Paging(paging::Error::NotMapped(addr))),
+    };
+
+    if status & SEV_STATUS_SNP != 0 {
+        // SAFETY: nothing uses `GHCB` until it's shared
+        unsafe { rescind_validation(addr) }?;
+        let response = msr_protocol(gpa | PAGE_STATE_SHARED << 52 | GHCB_MSR_PAGE_STATE_REQUEST);
+        if response & GHCB_MSR_INFO_MASK != GHCB_MSR_PAGE_STATE_RESPONSE || response >> 32 != 0 {
+            return Err(Error::PageState);
+        }
+    }
+    // SAFETY: nothing depends on the contents of `GHCB`, which are cleared below, and the frame is
+    //         shared with the hypervisor, if it needed to be made shared
+    unsafe { paging::set_encrypted(Page::<Size4KiB>::containing_address(addr), c_bit, false) }
+        .map_err(Error::Paging)?;
+    for field in &GHCB.0 {
+        field.store(0, Ordering::Relaxed);
+    }
+
+    if status & SEV_STATUS_SNP != 0 {
+        let response = msr_protocol(gpa | GHCB_MSR_REGISTER_REQUEST);
+        if response != gpa | GHCB_MSR_REGISTER_RESPONSE {
+            return Err(Error::Registration);
+        }
+    }
+    GHCB_GPA.store(gpa, Ordering::Relaxed);
+    GHCB_VERSION.store(version, Ordering::Release);
+    Ok(())
+}
+
+/// Registers the bootstrap processor's `#HV` doorbell page with the hypervisor.
+///
+/// This needs the GHCB `SNP_HV_DOORBELL_PAGE` request, which isn't implemented yet, so it
+/// currently always fails.
+pub fn register_doorbell() -> Result<(), Error> {
+    Err(Error::GhcbRequired)
+}
+
+/// Handles a hypervisor-injection exception by dispatching every event pending in the doorbell
+/// page, until none are left.
+pub(super) fn handle_hypervisor_injection(context: &mut Context) {
+    loop {
+        let pending = DOORBELL.pending.swap(0, Ordering::AcqRel);
+        if pending == 0 {
+            break;
+        }
+
+        // machine checks take priority over NMIs, which take priority over interrupts
+        if pending & PENDING_MC != 0 {
+            // SAFETY: the pending event is handled as though it had been delivered directly
+            unsafe { interrupt::handler(context, IntVec::MACHINE_CHECK) };
+        }
+        if pending & PENDING_NMI != 0 {
+            // SAFETY: the pending event is handled as though it had been delivered directly
+            unsafe { interrupt::handler(context, IntVec::NON_MASKABLE_INTERRUPT) };
+        }
+        let vector = (pending & PENDING_VECTOR) as u8;
+        if vector != 0 {
+            // handlers signal end-of-interrupt themselves, even when `no_eoi_required` says it
+            // isn't needed, which is harmless
+            // SAFETY: the pending event is handled as though it had been delivered directly
+            unsafe { interrupt::handler(context, IntVec(vector)) };
+        }
+    }
+}
+
+/// Handles a VMM-communication exception.
+///
+/// # Panics
+/// Panics if the intercepted instruction can't be emulated.
+pub(super) fn handle_vmm_communication(context: &mut Context) {
+    let exit_code = VmExitCode(context.error_code);
+    match exit_code.exit() {
+        VmExit::Cpuid => {
+            let leaf = context.registers.rax as u32;
+            let regs = &mut context.registers;
+
+            regs.rax = cpuid_request(leaf, 0).into();
+            regs.rbx = cpuid_request(leaf, 1).into();
+            regs.rcx = cpuid_request(leaf, 2).into();
+            regs.rdx = cpuid_request(leaf, 3).into();
+
+            // `cpuid` is a two-byte instruction
+            context.rip += 2;
+        }
+        VmExit::Msr => emulate_msr(context),
+        VmExit::IoIo => emulate_port_io(context),
+        _ => panic!(
+            "unsupported VMM-communication exception at {:#x}: {exit_code}",
+            context.rip
+        ),
+    }
+}
+
+/// Emulates `RDMSR` or `WRMSR` with the GHCB page.
+///
+/// # Panics
+/// Panics if the GHCB page isn't available, or the hypervisor doesn't complete the access.
+fn emulate_msr(context: &mut Context) {
+    let instruction = intercepted_instruction(context);
+    let write = match (instruction.opcode_map(), instruction.opcode()) {
+        (OpcodeMap::Secondary, 0x30) => true,
+        (OpcodeMap::Secondary, 0x32) => false,
+        _ => panic!(
+            "MSR access intercepted at {:#x} for {instruction}",
+            context.rip
+        ),
+    };
+
+    let regs = &mut context.registers;
+    let msr = regs.rcx as u32;
+    with_ghcb(|ghcb| {
+        ghcb.set(GHCB_RCX, msr.into());
+        if write {
+            ghcb.set(GHCB_RAX, regs.rax & 0xffff_ffff);
+            ghcb.set(GHCB_RDX, regs.rdx & 0xffff_ffff);
+        }
+        if let Err(info) = ghcb.call(EXIT_MSR, write.into(), 0) {
+            panic!("the hypervisor failed to emulate an access to MSR {msr:#x}: {info:#x}");
+        }
+        if !write {
+            match (ghcb.get(GHCB_RAX), ghcb.get(GHCB_RDX)) {
+                (Some(rax), Some(rdx)) => {
+                    regs.rax = rax & 0xffff_ffff;
+                    regs.rdx = rdx & 0xffff_ffff;
+                }
+                _ => panic!("the hypervisor didn't return the value of MSR {msr:#x}"),
+            }
+        }
+    });
+
+    context.rip += instruction.len() as u64;
+}
+
+/// Emulates `IN` or `OUT` with the GHCB page. String instructions (`INS` and `OUTS`) aren't
+/// supported.
+///
+/// # Panics
+/// Panics if the instruction isn't supported, the GHCB page isn't available, or the hypervisor
+/// doesn't complete the access.
+fn emulate_port_io(context: &mut Context) {
+    let instruction = intercepted_instruction(context);
+    let regs = &mut context.registers;
+    let (input, port) = match (instruction.opcode_map(), instruction.opcode()) {
+        // the port is an immediate byte, which ends the instruction
+        (OpcodeMap::Primary, opcode @ 0xe4..=0xe7) => (
+            opcode < 0xe6,
+            u16::from(instruction.bytes()[instruction.len() - 1]),
+        ),
+        (OpcodeMap::Primary, opcode @ 0xec..=0xef) => (opcode < 0xee, regs.rdx as u16),
+        _ => panic!(
+            "port I/O intercepted at {:#x} for unsupported {instruction}",
+            context.rip
+        ),
+    };
+    let (size, mask) = match (instruction.opcode() & 1, instruction.operand_size()) {
+        (0, _) => (IOIO_DATA_8, 0xff),
+        (_, 2) => (IOIO_DATA_16, 0xffff),
+        _ => (IOIO_DATA_32, 0xffff_ffff),
+    };
+    let info = u64::from(port) << 16 | IOIO_ADDR_64 | size | if input { IOIO_IN } else { 0 };
+
+    with_ghcb(|ghcb| {
+        if !input {
+            ghcb.set(GHCB_RAX, regs.rax & mask);
+        }
+        if let Err(err) = ghcb.call(EXIT_IOIO, info, 0) {
+            panic!("the hypervisor failed to emulate port I/O on {port:#x}: {err:#x}");
+        }
+        if input {
+            let value = match ghcb.get(GHCB_RAX) {
+                Some(value) => value & mask,
+                None => panic!("the hypervisor didn't return the input from port {port:#x}"),
+            };
+            // 32-bit results are zero-extended, while smaller ones leave the upper bits alone
+            regs.rax = match size {
+                IOIO_DATA_32 => value,
+                _ => regs.rax & !mask | value,
+            };
+        }
+    });
+
+    context.rip += instruction.len() as u64;
+}
+
+/// Decodes the instruction which raised a VMM-communication exception.
+///
+/// # Panics
+/// Panics if the instruction can't be decoded.
+fn intercepted_instruction(context: &Context) -> Instruction {
+    // SAFETY: the processor fetched the instruction at `rip`, so its page is readable
+    match unsafe { Instruction::read(context.rip) } {
+        Ok(instruction) => instruction,
+        Err(err) => panic!(
+            "cannot decode the instruction at {:#x} which raised #VC: {err}",
+            context.rip
+        ),
+    }
+}
+
+/// Runs `f` with exclusive use of the GHCB page, cleared of any previous request.
+///
+/// # Panics
+/// Panics if the GHCB page isn't shared with the hypervisor, or is already in use.
+fn with_ghcb<R>(f: impl FnOnce(&Ghcb) -> R) -> R {
+    if GHCB_VERSION.load(Ordering::Acquire) == 0 {
+        panic!("the GHCB page is not shared with the hypervisor");
+    }
+    if GHCB_BUSY.swap(true, Ordering::Acquire) {
+        panic!("the GHCB page is already in use by an interrupted request");
+    }
+    GHCB.clear();
+    let result = f(&GHCB);
+    GHCB_BUSY.store(false, Ordering::Release);
+    result
+}
+
+/// Rescinds the validation of the 4-KiB page at `addr` with `PVALIDATE`, as an SEV-SNP guest must
+/// before the page is made shared.
+///
+/// # Safety
+/// Nothing may access the page until it is validated again, or remapped as shared.
+unsafe fn rescind_validation(addr: VirtAddr) -> Result<(), Error> {
+    let result: u64;
+    // SAFETY: `PVALIDATE` is available to SEV-SNP guests, and the caller guarantees nothing
+    //         accesses the page
+    unsafe {
+        core::arch::asm!(
+            ".byte 0xf2, 0x0f, 0x01, 0xff",
+            inout("rax") addr.as_u64() => result,
+            in("ecx") 0,
+            in("edx") 0,
+            options(nostack),
+        );
+    }
+    match result as u32 {
+        0 => Ok(()),
+        _ => Err(Error::PageState),
+    }
+}
+
+/// Passes `request` to the hypervisor with the GHCB MSR protocol, and returns its response. The
+/// GHCB MSR's previous value, which may be the GHCB page's address, is restored afterwards.
+fn msr_protocol(request: u64) -> u64 {
+    let mut msr = Msr::new(GHCB_MSR);
+    // SAFETY: the GHCB MSR exists on all SEV-ES guests, and this is only used by SEV-ES guests
+    //         exiting to the hypervisor is how the GHCB protocol passes control to it, and it then
+    //         returns with the response in the MSR
+    unsafe {
+        let saved = msr.read();
+        msr.write(request);
+        vmgexit();
+        let response = msr.read();
+        msr.write(saved);
+        response
+    }
+}
+
+/// Exits to the hypervisor with `VMGEXIT` (`rep vmmcall`).
+///
+/// # Safety
+/// The request in the GHCB MSR, or the GHCB page it points to, must be valid.
+unsafe fn vmgexit() {
+    // SAFETY: the caller guarantees the request is valid, and the hypervisor only changes the GHCB
+    //         MSR and page
+    unsafe { core::arch::asm!(".byte 0xf3, 0x0f, 0x01, 0xd9", options(nostack)) };
+}
+
+/// Requests a single register of the result of `CPUID` leaf `leaf`, where `register` is `0`, `1`,
+/// `2` or `3` for `EAX`, `EBX`, `ECX` or `EDX`, respectively.
+///
+/// The GHCB MSR protocol doesn't provide a way to pass the sub-leaf in `ECX`, so leaves with
+/// sub-leaves return the result for sub-leaf `0`.
+///
+/// # Panics
+/// Panics if the hypervisor doesn't respond to the request correctly.
+fn cpuid_request(leaf: u32, register: u64) -> u32 {
+    let request = u64::from(leaf) << 32 | register << 30 | GHCB_MSR_CPUID_REQUEST;
+    let response = msr_protocol(request);
+
+    if response & GHCB_MSR_INFO_MASK != GHCB_MSR_CPUID_RESPONSE {
+        panic!("invalid GHCB MSR protocol response to CPUID request: {response:#x}");
+    }
+
+    (response >> 32) as u32
+}
@@ -0,0 +1,150 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Instruction-level single-step tracing.
+//!
+//! [`trace`] runs a function with the trap flag (`TF`) set in `RFLAGS`, which causes a
+//! [debug exception](IntVec::DEBUG) after every instruction. The exception handler records the
+//! address of each instruction in a trace buffer, which can be inspected once the function
+//! returns.
+//!
+//! This is very slow, and is only intended as a last resort for debugging problems which can't be
+//! reproduced under a debugger.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(doc)]
+use super::interrupt::IntVec;
+
+/// The maximum number of instruction addresses that can be recorded in a single [`Trace`].
+pub const TRACE_CAPACITY: usize = 1024;
+
+/// The trap flag in `RFLAGS`.
+const TRAP_FLAG_BIT: u8 = 8;
+/// The single-step flag (`BS`) in `DR6`.
+const SINGLE_STEP_BIT: u8 = 14;
+
+/// Set while a [`Trace`] exists, since there is only one trace buffer.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Set while single-stepping, so that debug exceptions are recorded in the trace buffer.
+static STEPPING: AtomicBool = AtomicBool::new(false);
+/// The trace buffer.
+static BUFFER: [AtomicU64; TRACE_CAPACITY] = [const { AtomicU64::new(0) }; TRACE_CAPACITY];
+/// The number of instructions executed while single-stepping, including any which didn't fit in
+/// [`BUFFER`].
+static STEPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs `f` one instruction at a time, recording the address of each instruction executed.
+///
+/// The trace includes a few instructions from `trace` itself, before and after the call to `f`.
+/// Any code called by `f` is also traced, except for interrupt and exception handlers.
+///
+/// Returns `None`, without calling `f`, if a previous [`Trace`] still exists.
+pub fn trace(f: impl FnOnce()) -> Option<Trace> {
+    if ACTIVE.swap(true, Ordering::Acquire) {
+        return None;
+    }
+
+    STEPS.store(0, Ordering::Relaxed);
+    STEPPING.store(true, Ordering::Release);
+
+    // SAFETY: setting the trap flag only causes debug exceptions, which are handled by `record`
+    //         while `STEPPING` is set
+    unsafe {
+        core::arch::asm!(
+            "pushfq",
+            "bts qword ptr [rsp], {tf}",
+            "popfq",
+            tf = const TRAP_FLAG_BIT,
+        );
+    }
+
+    f();
+
+    // SAFETY: clearing the trap flag restores normal execution
+    unsafe {
+        core::arch::asm!(
+            "pushfq",
+            "btr qword ptr [rsp], {tf}",
+            "popfq",
+            tf = const TRAP_FLAG_BIT,
+        );
+    }
+
+    STEPPING.store(false, Ordering::Release);
+
+    Some(Trace {
+        steps: STEPS.load(Ordering::Relaxed),
+    })
+}
+
+/// Records a single step, where `rip` is the address of the next instruction to be executed.
+///
+/// Returns `false` if the debug exception was not caused by single-stepping during a [`trace`].
+pub(super) fn record(rip: u64) -> bool {
+    let dr6 = x86_64::registers::debug::Dr6::read_raw();
+
+    if !STEPPING.load(Ordering::Acquire) || dr6 & (1 << SINGLE_STEP_BIT) == 0 {
+        return false;
+    }
+
+    // SAFETY: `DR6` is not cleared by the processor, so the single-step flag must be cleared here
+    //         to avoid misinterpreting later debug exceptions
+    unsafe {
+        core::arch::asm!(
+            "mov dr6, {dr6}",
+            dr6 = in(reg) dr6 & !(1 << SINGLE_STEP_BIT),
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+
+    let step = STEPS.fetch_add(1, Ordering::Relaxed);
+    if let Some(entry) = BUFFER.get(step) {
+        entry.store(rip, Ordering::Relaxed);
+    }
+
+    true
+}
+
+/// The instruction addresses recorded by [`trace`].
+///
+/// No new trace can be started until this is dropped.
+#[derive(Debug)]
+pub struct Trace {
+    steps: usize,
+}
+
+impl Trace {
+    /// Returns an iterator over the recorded instruction addresses, in execution order.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        BUFFER[..self.len()]
+            .iter()
+            .map(|entry| entry.load(Ordering::Relaxed))
+    }
+
+    /// Returns the number of recorded instruction addresses.
+    pub fn len(&self) -> usize {
+        self.steps.min(TRACE_CAPACITY)
+    }
+
+    /// Returns `true` if no instruction addresses were recorded.
+    pub fn is_empty(&self) -> bool {
+        self.steps == 0
+    }
+
+    /// Returns the number of instructions which were executed but not recorded, because the
+    /// trace buffer was full.
+    pub fn dropped(&self) -> usize {
+        self.steps - self.len()
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        ACTIVE.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,126 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Deferred interrupt work.
+//!
+//! An interrupt handler should do as little as possible with interrupts disabled, such as
+//! acknowledging its device and taking its data, and [`defer`] the rest. Each processor has a queue
+//! of deferred work, which is run with interrupts enabled when a user interrupt returns to code
+//! which had interrupts enabled, or when [`run`] is called, such as by an idle loop or a kernel
+//! thread.
+//!
+//! At most [`BUDGET`] items are run each time an interrupt returns, so that a stream of deferred
+//! work can't starve the interrupted code; the rest waits for the next interrupt or call to
+//! [`run`]. Deferred work is never nested: an interrupt which arrives while work is running doesn't
+//! run any more itself.
+
+use core::fmt;
+
+use x86_64::{instructions::interrupts, registers::rflags::RFlags};
+
+use super::{interrupt::Context, percpu};
+use crate::util::ring::MpscQueue;
+
+/// The number of items each processor's queue can hold.
+pub const QUEUE_CAPACITY: usize = 64;
+/// The maximum number of items run each time an interrupt returns.
+pub const BUDGET: usize = 16;
+
+/// The number of processors, by local APIC ID, which have queues.
+const MAX_CPUS: usize = 64;
+
+/// The queue of deferred work of each processor.
+static QUEUES: [MpscQueue<Work, QUEUE_CAPACITY>; MAX_CPUS] = [const { MpscQueue::new() }; MAX_CPUS];
+
+/// An item of deferred work, which is a function and its argument.
+#[derive(Debug, Clone, Copy)]
+struct Work {
+    f: fn(usize),
+    arg: usize,
+}
+
+/// An error deferring work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The processor, with the given local APIC ID, has no queue.
+    NoQueue(u32),
+    /// The processor's queue is full.
+    QueueFull,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoQueue(cpu) => write!(f, "processor {cpu} has no deferred work queue"),
+            Error::QueueFull => write!(f, "the deferred work queue is full"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// Queues `f(arg)` to run on the current processor with interrupts enabled.
+pub fn defer(f: fn(usize), arg: usize) -> Result<(), Error> {
+    defer_on(percpu::id(), f, arg)
+}
+
+/// Queues `f(arg)` to run with interrupts enabled on the processor with local APIC ID `cpu`, the
+/// next time it runs deferred work.
+pub fn defer_on(cpu: u32, f: fn(usize), arg: usize) -> Result<(), Error> {
+    let queue = QUEUES.get(cpu as usize).ok_or(Error::NoQueue(cpu))?;
+    queue.push(Work { f, arg }).map_err(|_| Error::QueueFull)
+}
+
+/// Returns the number of items waiting in the current processor's queue.
+pub fn pending() -> usize {
+    QUEUES.get(percpu::id() as usize).map_or(0, MpscQueue::len)
+}
+
+/// Runs the current processor's deferred work, including any deferred while it runs, with
+/// interrupts as they are, returning the number of items run.
+///
+/// Returns zero without running anything if the current processor is already running deferred
+/// work.
+pub fn run() -> usize {
+    run_up_to(usize::MAX)
+}
+
+/// Runs up to [`BUDGET`] items of deferred work with interrupts enabled, if `context` is an
+/// interrupt which will return to code which had interrupts enabled.
+pub(super) fn run_on_exit(context: &Context) {
+    if context.rflags & RFlags::INTERRUPT_FLAG.bits() == 0 {
+        return;
+    }
+    let has_work = QUEUES
+        .get(percpu::id() as usize)
+        .is_some_and(|queue| !queue.is_empty());
+    if has_work {
+        interrupts::enable();
+        run_up_to(BUDGET);
+        interrupts::disable();
+    }
+}
+
+/// Runs up to `budget` items of the current processor's deferred work, returning the number run.
+fn run_up_to(budget: usize) -> usize {
+    let queue = match QUEUES.get(percpu::id() as usize) {
+        Some(queue) => queue,
+        None => return 0,
+    };
+    // the consumer is already claimed if this processor is running deferred work, which this
+    // interrupted
+    let consumer = match queue.consumer() {
+        Some(consumer) => consumer,
+        None => return 0,
+    };
+    let mut count = 0;
+    for work in consumer.take(budget) {
+        (work.f)(work.arg);
+        count += 1;
+    }
+    count
+}
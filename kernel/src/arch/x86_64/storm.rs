@@ -0,0 +1,164 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Detection of interrupt storms.
+//!
+//! Each interrupt is [recorded](record), along with whether its handler made progress, such as
+//! finding work to do. If a vector fires more than [`IRQ_STORM_LIMIT`] times within about a second
+//! without progress, its source is masked with the function registered by [`register`], and a
+//! warning is logged, so that a misbehaving device can't keep the processor busy handling
+//! interrupts. Storms are detected in interrupt context, which can interrupt code holding the
+//! logger's lock, so the warning is logged later, as [deferred work](super::softirq).
+//!
+//! Interrupts on vectors with a [registered handler](super::interrupt::register) are recorded once
+//! the handler returns, so those handlers only report [`progress`]. Handlers which the kernel calls
+//! itself, such as the one for corrected machine check interrupts, call [`record`] directly.
+//!
+//! Time is measured with the time stamp counter, whose frequency is taken from
+//! [`clock::tsc_hz`], so the window is only approximate if the TSC isn't stable.
+
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use super::{clock, interrupt::IntVec, softirq};
+use crate::param;
+
+param! {
+    /// The number of interrupts on a vector, without progress, within about a second, above which
+    /// the vector's source is masked, or `0` to never mask sources.
+    pub static IRQ_STORM_LIMIT: u32 = 10_000, name = "irq_storm_limit";
+}
+
+/// The length of the window, in time stamp counter ticks, before [`init`] is called.
+const DEFAULT_WINDOW: u64 = 2_000_000_000;
+
+/// A storm whose source was masked, in [`Vector::storm`].
+const STORM_MASKED: u8 = 1 << 0;
+/// A storm whose source couldn't be masked, in [`Vector::storm`].
+const STORM_UNMASKABLE: u8 = 1 << 1;
+
+/// The length of the window, in time stamp counter ticks.
+static WINDOW: AtomicU64 = AtomicU64::new(DEFAULT_WINDOW);
+
+/// The interrupt counts of each vector.
+static VECTORS: [Vector; 256] = [const { Vector::new() }; 256];
+
+/// The interrupt count, and the function to mask the source, of a vector.
+struct Vector {
+    /// The time stamp counter at the start of the current window.
+    window_start: AtomicU64,
+    /// The number of interrupts without progress in the current window.
+    count: AtomicU32,
+    /// The function to mask the vector's source, as a `fn()`, or zero if there isn't one.
+    mask: AtomicUsize,
+    /// Whether the handler of the interrupt being handled has reported [`progress`].
+    progress: AtomicBool,
+    /// The storms which haven't been reported yet, as [`STORM_MASKED`] and [`STORM_UNMASKABLE`].
+    storm: AtomicU8,
+}
+
+impl Vector {
+    /// Returns a vector with no interrupts and no mask function.
+    const fn new() -> Self {
+        Vector {
+            window_start: AtomicU64::new(0),
+            count: AtomicU32::new(0),
+            mask: AtomicUsize::new(0),
+            progress: AtomicBool::new(false),
+            storm: AtomicU8::new(0),
+        }
+    }
+}
+
+/// Sets the length of the window from the time stamp counter's frequency, which must already have
+/// been measured by [`clock::init`].
+pub fn init() {
+    WINDOW.store(clock::tsc_hz(), Ordering::Relaxed);
+}
+
+/// Registers `mask` as the function which masks the source of `vec` during a storm.
+pub fn register(vec: IntVec, mask: fn()) {
+    VECTORS[usize::from(vec.0)]
+        .mask
+        .store(mask as usize, Ordering::Release);
+}
+
+/// Reports that the handler of the interrupt on `vec` found work to do, so that the interrupt isn't
+/// counted towards a storm when it's recorded after the handler returns.
+pub fn progress(vec: IntVec) {
+    VECTORS[usize::from(vec.0)]
+        .progress
+        .store(true, Ordering::Relaxed);
+}
+
+/// Records an interrupt on `vec` whose registered handler has returned, using the [`progress`] it
+/// reported. Returns `true` if the interrupt was part of a storm, and the source was masked.
+pub(super) fn record_handled(vec: IntVec) -> bool {
+    let progress = VECTORS[usize::from(vec.0)]
+        .progress
+        .swap(false, Ordering::Relaxed);
+    record(vec, progress)
+}
+
+/// Records an interrupt on `vec`, where `progress` says whether the handler found work to do.
+/// Returns `true` if the interrupt was part of a storm, and the source was masked.
+pub fn record(vec: IntVec, progress: bool) -> bool {
+    let limit = IRQ_STORM_LIMIT.get();
+    let vector = &VECTORS[usize::from(vec.0)];
+    if progress || limit == 0 {
+        vector.count.store(0, Ordering::Relaxed);
+        return false;
+    }
+
+    // SAFETY: the time stamp counter is available on all x86_64 processors
+    let now = unsafe { _rdtsc() };
+    if now.wrapping_sub(vector.window_start.load(Ordering::Relaxed))
+        > WINDOW.load(Ordering::Relaxed)
+    {
+        vector.window_start.store(now, Ordering::Relaxed);
+        vector.count.store(0, Ordering::Relaxed);
+    }
+    if vector.count.fetch_add(1, Ordering::Relaxed) < limit {
+        return false;
+    }
+
+    vector.count.store(0, Ordering::Relaxed);
+    let masked = match vector.mask.load(Ordering::Acquire) {
+        0 => false,
+        mask => {
+            // SAFETY: only `fn()` pointers are stored in `mask`
+            let mask = unsafe { core::mem::transmute::<usize, fn()>(mask) };
+            mask();
+            true
+        }
+    };
+    let storm = if masked {
+        STORM_MASKED
+    } else {
+        STORM_UNMASKABLE
+    };
+    vector.storm.fetch_or(storm, Ordering::Relaxed);
+    // if the queue is full, the storm is reported with the next one
+    let _ = softirq::defer(report, 0);
+    masked
+}
+
+/// Logs the storms which have been detected since the last report.
+fn report(_: usize) {
+    for (vec, vector) in VECTORS.iter().enumerate() {
+        let vec = IntVec(vec as u8);
+        let storm = vector.storm.swap(0, Ordering::Relaxed);
+        if storm & STORM_MASKED != 0 {
+            log::warn!("interrupt storm on vector {vec:?}; the source has been masked");
+        }
+        if storm & STORM_UNMASKABLE != 0 {
+            log::warn!("interrupt storm on vector {vec:?}, which cannot be masked");
+        }
+    }
+}
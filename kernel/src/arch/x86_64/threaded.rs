@@ -0,0 +1,95 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Threaded interrupt handlers.
+//!
+//! A threaded interrupt is [registered](register) with two handlers. The hard handler runs when the
+//! interrupt arrives, with interrupts disabled, and only masks and acknowledges its source. The
+//! thread handler then does the real work with interrupts enabled, and unmasks the source when it's
+//! done. Since the source stays masked in between, the thread handler is never run for an
+//! interrupt more than once at a time. Queuing the thread handler counts as
+//! [progress](super::storm::progress), so the hard handler doesn't report it itself.
+//!
+//! There is no scheduler yet, so the thread handler runs as [deferred work](super::softirq) on the
+//! processor which took the interrupt, rather than in a kernel thread of its own. It must not
+//! block. If the deferred work queue is full, the thread handler isn't run, and the source stays
+//! masked. That is counted, and reported by the next thread handler to run, since logging from the
+//! hard handler could deadlock on the logger's lock.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use super::{
+    interrupt::{self, Handler, IntVec},
+    softirq, storm,
+};
+
+/// The thread handler of each vector, as a `fn(IntVec)`, or zero if it isn't threaded.
+static THREADS: [AtomicUsize; 256] = [const { AtomicUsize::new(0) }; 256];
+/// Set for each vector whose thread handler is waiting to run.
+static PENDING: [AtomicBool; 256] = [const { AtomicBool::new(false) }; 256];
+/// The number of times a thread handler couldn't be queued since it was last reported.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Registers a threaded interrupt on the user interrupt vector `vec`, with the hard handler `hard`,
+/// which must mask and acknowledge the interrupt's source, and the thread handler `thread`, which
+/// must unmask it.
+///
+/// The source must not be enabled until this returns, since the thread handler isn't run for
+/// interrupts before then.
+pub fn register(vec: IntVec, hard: Handler, thread: fn(IntVec)) -> Result<(), interrupt::Error> {
+    interrupt::register(vec, hard)?;
+    THREADS[usize::from(vec.0)].store(thread as usize, Ordering::Release);
+    Ok(())
+}
+
+/// Removes the handlers of the threaded interrupt on `vec`, returning `true` if it was threaded.
+///
+/// The source of the interrupt must be disabled first. A thread handler which is already waiting
+/// to run is skipped.
+pub fn unregister(vec: IntVec) -> bool {
+    let threaded = THREADS[usize::from(vec.0)].swap(0, Ordering::AcqRel) != 0;
+    if threaded {
+        interrupt::unregister(vec);
+    }
+    threaded
+}
+
+/// Queues the thread handler of `vec`, if it's threaded and isn't already waiting to run.
+///
+/// This is called after the hard handler returns, and before the interrupt is
+/// [recorded](super::storm::record_handled).
+pub(super) fn wake(vec: IntVec) {
+    let index = usize::from(vec.0);
+    if THREADS[index].load(Ordering::Acquire) == 0 || PENDING[index].swap(true, Ordering::AcqRel) {
+        return;
+    }
+    match softirq::defer(run, index) {
+        Ok(()) => storm::progress(vec),
+        Err(_) => {
+            PENDING[index].store(false, Ordering::Release);
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Runs the thread handler of the vector `index`.
+fn run(index: usize) {
+    // cleared first, so that an interrupt which arrives once the source is unmasked queues it again
+    PENDING[index].store(false, Ordering::Release);
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped != 0 {
+        log::warn!("{dropped} thread handlers could not be queued, so their sources stay masked");
+    }
+    match THREADS[index].load(Ordering::Acquire) {
+        0 => {}
+        thread => {
+            // SAFETY: only `fn(IntVec)` pointers are stored in `THREADS`
+            let thread = unsafe { core::mem::transmute::<usize, fn(IntVec)>(thread) };
+            thread(IntVec(index as u8));
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! The local APIC timer, which drives the scheduler tick.
+//!
+//! [`init`] allocates a vector for the timer and calibrates it against the selected clock source:
+//! the time stamp counter, which [`clock::init`] has already measured against the PIT, or the PIT
+//! itself if the TSC wasn't selected. The timer then fires either
+//! [periodically](start_periodic) or [once](start_one_shot), calling the function registered with
+//! [`set_callback`] on each interrupt. One-shot timers use TSC-deadline mode when the processor
+//! supports it and the TSC is the selected clock source, since the deadline is then exact, and the
+//! timer's own count otherwise.
+//!
+//! The timer belongs to the local APIC, so it only interrupts the processor which started it.
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    fmt,
+    sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use x86_64::registers::model_specific::Msr;
+
+use super::{
+    apic::{self, Lvt},
+    clock,
+    interrupt::{self, Context, IntVec},
+    storm, vector,
+};
+
+/// The x2APIC timer's initial count register.
+const X2APIC_TIMER_INITIAL_COUNT: u32 = 0x838;
+/// The x2APIC timer's current count register.
+const X2APIC_TIMER_CURRENT_COUNT: u32 = 0x839;
+/// The x2APIC timer's divide configuration register.
+const X2APIC_TIMER_DIVIDE: u32 = 0x83e;
+/// `IA32_TSC_DEADLINE`, which arms the timer in TSC-deadline mode.
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+/// Divides the timer's input clock by 16, in the divide configuration register.
+const DIVIDE_BY_16: u64 = 0b0011;
+/// Masks the interrupt, in the timer's local vector table register.
+const LVT_MASKED: u64 = 1 << 16;
+/// Periodic mode, in the timer's local vector table register.
+const LVT_PERIODIC: u64 = 1 << 17;
+/// TSC-deadline mode, in the timer's local vector table register.
+const LVT_TSC_DEADLINE: u64 = 2 << 17;
+
+/// The length of the calibration, in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+/// The number of nanoseconds in a second.
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// The timer's vector, or zero if it hasn't been allocated yet.
+static VECTOR: AtomicU8 = AtomicU8::new(0);
+/// The timer's frequency, in ticks of its count per second, or zero if it hasn't been calibrated.
+static HZ: AtomicU64 = AtomicU64::new(0);
+/// The function called on each timer interrupt, as a `fn()`, or zero if there isn't one.
+static CALLBACK: AtomicUsize = AtomicUsize::new(0);
+/// The number of timer interrupts since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// An error setting up or starting the timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The local APIC hasn't been enabled.
+    ApicNotEnabled,
+    /// There is no free interrupt vector for the timer.
+    NoFreeVector,
+    /// The timer's handler could not be registered.
+    Register(interrupt::Error),
+    /// The timer didn't count during calibration.
+    NotCounting,
+    /// The PIT, which the timer is calibrated against when it's the clock source, didn't count.
+    NoReference,
+    /// The timer hasn't been set up by [`init`].
+    NotInitialized,
+    /// The requested interval is too long for the timer's count.
+    IntervalTooLong,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ApicNotEnabled => write!(f, "the local APIC is not enabled"),
+            Error::NoFreeVector => write!(f, "no free interrupt vector for the timer"),
+            Error::Register(_) => write!(f, "cannot register the timer's handler"),
+            Error::NotCounting => write!(f, "the local APIC timer is not counting"),
+            Error::NoReference => write!(f, "the PIT is not counting"),
+            Error::NotInitialized => write!(f, "the local APIC timer is not initialized"),
+            Error::IntervalTooLong => write!(f, "the interval is too long for the timer"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {
+    fn source(&self) -> Option<&(dyn crate::error::Error + 'static)> {
+        match self {
+            Error::Register(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Allocates a vector for the timer, registers its handler, and calibrates it, returning its
+/// frequency. The timer is left stopped. Calls after the first succeeds only return the frequency.
+pub fn init() -> Result<u64, Error> {
+    if !apic::is_enabled() {
+        return Err(Error::ApicNotEnabled);
+    }
+    if VECTOR.load(Ordering::Acquire) == 0 {
+        let vec = vector::alloc().ok_or(Error::NoFreeVector)?;
+        if let Err(err) = interrupt::register(vec, handle) {
+            vector::free(vec);
+            return Err(Error::Register(err));
+        }
+        VECTOR.store(vec.0, Ordering::Release);
+    }
+    if HZ.load(Ordering::Relaxed) == 0 {
+        HZ.store(calibrate()?, Ordering::Relaxed);
+    }
+    Ok(HZ.load(Ordering::Relaxed))
+}
+
+/// Sets `callback` as the function called on each timer interrupt, with interrupts disabled.
+pub fn set_callback(callback: fn()) {
+    CALLBACK.store(callback as usize, Ordering::Release);
+}
+
+/// Starts the timer, firing every `period_ns` nanoseconds until it's [stopped](stop).
+pub fn start_periodic(period_ns: u64) -> Result<(), Error> {
+    let (vec, count) = (vector()?, count(period_ns)?);
+    // SAFETY: x2APIC mode is enabled, since `init` succeeded, and `vec` has a handler which
+    //         signals end-of-interrupt
+    unsafe {
+        Msr::new(Lvt::Timer as u32).write(LVT_PERIODIC | u64::from(vec.0));
+        Msr::new(X2APIC_TIMER_INITIAL_COUNT).write(count.into());
+    }
+    Ok(())
+}
+
+/// Starts the timer, firing once after `delay_ns` nanoseconds, replacing any timer already running.
+pub fn start_one_shot(delay_ns: u64) -> Result<(), Error> {
+    let vec = vector()?;
+    if tsc_deadline_supported() {
+        let ticks = u128::from(delay_ns) * u128::from(clock::tsc_hz()) / NANOS_PER_SECOND;
+        let deadline = rdtsc().saturating_add(ticks.try_into().unwrap_or(u64::MAX));
+        // SAFETY: x2APIC mode is enabled, since `init` succeeded, TSC-deadline mode is supported,
+        //         and `vec` has a handler which signals end-of-interrupt
+        unsafe {
+            Msr::new(Lvt::Timer as u32).write(LVT_TSC_DEADLINE | u64::from(vec.0));
+            Msr::new(IA32_TSC_DEADLINE).write(deadline.max(1));
+        }
+    } else {
+        let count = count(delay_ns)?;
+        // SAFETY: x2APIC mode is enabled, since `init` succeeded, and `vec` has a handler which
+        //         signals end-of-interrupt
+        unsafe {
+            Msr::new(Lvt::Timer as u32).write(u64::from(vec.0));
+            Msr::new(X2APIC_TIMER_INITIAL_COUNT).write(count.into());
+        }
+    }
+    Ok(())
+}
+
+/// Stops the timer, if it's running.
+pub fn stop() {
+    if VECTOR.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    // SAFETY: x2APIC mode is enabled, since `init` succeeded, and masking the timer and clearing
+    //         its count and deadline stops it in every mode
+    unsafe {
+        Msr::new(Lvt::Timer as u32).write(LVT_MASKED);
+        Msr::new(X2APIC_TIMER_INITIAL_COUNT).write(0);
+        if tsc_deadline_supported() {
+            Msr::new(IA32_TSC_DEADLINE).write(0);
+        }
+    }
+}
+
+/// Returns the timer's frequency, in ticks of its count per second, or `None` if it hasn't been
+/// calibrated by [`init`].
+pub fn frequency() -> Option<u64> {
+    match HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Handles a timer interrupt.
+fn handle(_context: &mut Context) {
+    // every timer interrupt was asked for, so it's never part of a storm
+    storm::progress(IntVec(VECTOR.load(Ordering::Relaxed)));
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    match CALLBACK.load(Ordering::Acquire) {
+        0 => {}
+        callback => {
+            // SAFETY: only `fn()` pointers are stored in `CALLBACK`
+            let callback = unsafe { core::mem::transmute::<usize, fn()>(callback) };
+            callback();
+        }
+    }
+    apic::eoi();
+}
+
+/// Measures the timer's frequency against the selected [clock source](clock::source), with the
+/// timer masked.
+fn calibrate() -> Result<u64, Error> {
+    // SAFETY: x2APIC mode is enabled, and masking the timer keeps it from interrupting
+    unsafe {
+        Msr::new(Lvt::Timer as u32).write(LVT_MASKED);
+        Msr::new(X2APIC_TIMER_DIVIDE).write(DIVIDE_BY_16);
+    }
+    // SAFETY: x2APIC mode is enabled, and the timer is masked, so it can't interrupt
+    let start = || unsafe { Msr::new(X2APIC_TIMER_INITIAL_COUNT).write(u32::MAX.into()) };
+    let timed = match clock::source() {
+        clock::Source::Tsc => {
+            let wait = clock::tsc_hz() * CALIBRATION_MS / 1000;
+            start();
+            let start = rdtsc();
+            while rdtsc() - start < wait {
+                core::hint::spin_loop();
+            }
+            true
+        }
+        // the TSC's frequency is only a guess, so the PIT is used directly
+        clock::Source::Pit => clock::time_with_pit(CALIBRATION_MS, start).is_some(),
+    };
+    // SAFETY: x2APIC mode is enabled, and clearing the initial count stops the timer
+    let remaining = unsafe {
+        let remaining = Msr::new(X2APIC_TIMER_CURRENT_COUNT).read();
+        Msr::new(X2APIC_TIMER_INITIAL_COUNT).write(0);
+        remaining
+    };
+
+    if !timed {
+        return Err(Error::NoReference);
+    }
+    let elapsed = u64::from(u32::MAX) - remaining;
+    if elapsed == 0 {
+        return Err(Error::NotCounting);
+    }
+    Ok(elapsed * 1000 / CALIBRATION_MS)
+}
+
+/// Returns the timer's vector, if [`init`] has succeeded.
+fn vector() -> Result<IntVec, Error> {
+    match (VECTOR.load(Ordering::Acquire), HZ.load(Ordering::Relaxed)) {
+        (0, _) | (_, 0) => Err(Error::NotInitialized),
+        (vec, _) => Ok(IntVec(vec)),
+    }
+}
+
+/// Returns the timer's count for an interval of `ns` nanoseconds, which is at least one.
+fn count(ns: u64) -> Result<u32, Error> {
+    let count = u128::from(ns) * u128::from(HZ.load(Ordering::Relaxed)) / NANOS_PER_SECOND;
+    u32::try_from(count.max(1)).map_err(|_| Error::IntervalTooLong)
+}
+
+/// Returns `true` if one-shot timers use TSC-deadline mode, which requires the processor to
+/// support it, and the time stamp counter to be the selected clock source.
+fn tsc_deadline_supported() -> bool {
+    // SAFETY: CPUID is available on all x86_64 processors
+    clock::source() == clock::Source::Tsc && unsafe { __cpuid(1) }.ecx & (1 << 24) != 0
+}
+
+/// Returns the time stamp counter.
+fn rdtsc() -> u64 {
+    // SAFETY: the time stamp counter is available on all x86_64 processors
+    unsafe { _rdtsc() }
+}
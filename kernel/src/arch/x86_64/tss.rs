@@ -0,0 +1,108 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! The task state segment, and the interrupt stacks it provides.
+//!
+//! In 64-bit mode, the task state segment (TSS) is only used for the stacks the processor switches
+//! to. An IDT entry with a stack index runs its handler on that entry of the interrupt stack table
+//! (IST), whatever the state of the interrupted stack, so [`load`] gives double faults, NMIs and
+//! machine checks stacks of their own. A double fault caused by a stack overflow can then still be
+//! reported, and an NMI or machine check which arrives while the stack pointer is being changed
+//! doesn't run on a stack which isn't one.
+//!
+//! The loader's global descriptor table (GDT) has no room for a TSS descriptor, so [`load`] also
+//! replaces it with one of the kernel's own. Only the processor which calls [`load`] has a TSS.
+//!
+//! An IST stack is reused from the top each time its exception occurs, so the handlers using it
+//! must not be nested. Other exceptions raised by those handlers, such as page faults, don't switch
+//! stacks, so they run on the IST stack too.
+
+use core::ptr::addr_of;
+
+use x86_64::{
+    instructions::{
+        segmentation::{Segment, CS, DS, ES, SS},
+        tables::load_tss,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable},
+        tss::TaskStateSegment,
+    },
+    VirtAddr,
+};
+
+/// The IST index of the stack for double faults.
+pub const DOUBLE_FAULT_IST: u16 = 0;
+/// The IST index of the stack for non-maskable interrupts.
+pub const NMI_IST: u16 = 1;
+/// The IST index of the stack for machine checks.
+pub const MACHINE_CHECK_IST: u16 = 2;
+
+/// The size of each IST stack.
+pub const IST_STACK_SIZE: usize = 0x4000;
+
+/// The number of IST stacks in use.
+const IST_STACKS: usize = 3;
+
+/// An IST stack.
+#[repr(C, align(16))]
+struct Stack([u8; IST_STACK_SIZE]);
+
+/// The IST stacks, in order of IST index.
+static mut STACKS: [Stack; IST_STACKS] = [const { Stack([0; IST_STACK_SIZE]) }; IST_STACKS];
+/// The task state segment, which is written only by [`load`].
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+/// The global descriptor table, which is written only by [`load`], and by the processor when it
+/// marks the TSS busy.
+static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+
+/// Loads a global descriptor table with the kernel's code and data segments and the task state
+/// segment, reloads the segment registers, and loads the task register.
+///
+/// # Safety
+/// Must only be called once, before any IDT entry uses an IST stack.
+pub(super) unsafe fn load() {
+    // SAFETY: the caller guarantees this is the only call, so nothing else accesses `TSS` or
+    //         `STACKS`, and `TSS` is not yet in use by the processor
+    let tss = unsafe { &mut *core::ptr::addr_of_mut!(TSS) };
+    // the TSS is packed, so its stack pointers are replaced together rather than borrowed
+    let mut stacks = tss.interrupt_stack_table;
+    for (index, stack) in stacks[..IST_STACKS].iter_mut().enumerate() {
+        // SAFETY: only the address of `STACKS` is taken
+        let start = VirtAddr::from_ptr(unsafe { addr_of!(STACKS[index]) });
+        *stack = start + IST_STACK_SIZE;
+    }
+    tss.interrupt_stack_table = stacks;
+
+    // SAFETY: the caller guarantees this is the only call, so nothing else accesses `GDT`
+    let gdt = unsafe { &mut *core::ptr::addr_of_mut!(GDT) };
+    let code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data = gdt.add_entry(Descriptor::kernel_data_segment());
+    // SAFETY: `TSS` is static, and is not changed again
+    let tss_selector = gdt.add_entry(unsafe { Descriptor::tss_segment_unchecked(addr_of!(TSS)) });
+
+    // SAFETY: `GDT` is static, and has valid code and data segments, which are loaded
+    //         immediately, replacing selectors which refer to the loader's GDT
+    //         `tss_selector` is an available TSS descriptor of `GDT`
+    unsafe {
+        gdt.load_unsafe();
+        CS::set_reg(code);
+        SS::set_reg(data);
+        DS::set_reg(data);
+        ES::set_reg(data);
+        load_tss(tss_selector);
+    }
+}
+
+/// Returns `true` if `addr` is within one of the IST stacks, or is the end of one.
+pub fn is_ist_stack(addr: u64) -> bool {
+    // SAFETY: only the address of `STACKS` is taken
+    let start = unsafe { addr_of!(STACKS) } as u64;
+    (0..IST_STACKS as u64).any(|index| {
+        addr.wrapping_sub(start + index * IST_STACK_SIZE as u64) <= IST_STACK_SIZE as u64
+    })
+}
@@ -0,0 +1,74 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Allocation of user interrupt vectors.
+//!
+//! Drivers get their vectors from [`alloc`], rather than choosing them, so that no two claim the
+//! same one, and then [register](super::interrupt::register) a handler for them. [`alloc_block`]
+//! allocates a block of consecutive vectors, such as for multiple-message MSI, which requires the
+//! block to be aligned to its size. The exception vectors, and the vectors the kernel handles
+//! itself, are never allocated.
+
+use lazy_static::lazy_static;
+
+use super::{apic, interrupt::IntVec, mce};
+use crate::util::IdAllocator;
+
+lazy_static! {
+    /// The allocated vectors, which always include the exception vectors, and the vectors the
+    /// kernel handles itself.
+    static ref VECTORS: IdAllocator<4> = {
+        let vectors = IdAllocator::new(256);
+        for vec in (0..32).chain([mce::CMCI_VECTOR.0, apic::SPURIOUS_VECTOR.0]) {
+            vectors.reserve(vec.into());
+        }
+        vectors
+    };
+}
+
+/// Allocates a free user interrupt vector, returning `None` if all of them are in use.
+pub fn alloc() -> Option<IntVec> {
+    VECTORS.alloc().map(|vec| IntVec(vec as u8))
+}
+
+/// Allocates `count` consecutive user interrupt vectors, the first of which is a multiple of
+/// `align`, and returns the first, or `None` if there isn't such a block free.
+///
+/// `count` must be at most 64, and `align` must be a power of two.
+pub fn alloc_block(count: usize, align: usize) -> Option<IntVec> {
+    VECTORS
+        .alloc_block(count, align)
+        .map(|vec| IntVec(vec as u8))
+}
+
+/// Frees `vec`, which must have been allocated by [`alloc`], so it can be allocated again.
+///
+/// # Panics
+/// Panics if `vec` is not allocated.
+pub fn free(vec: IntVec) {
+    free_block(vec, 1);
+}
+
+/// Frees the `count` vectors starting at `first`, which must have been allocated by
+/// [`alloc_block`], so they can be allocated again.
+///
+/// # Panics
+/// Panics if any of the vectors is not allocated, or is reserved by the kernel.
+pub fn free_block(first: IntVec, count: usize) {
+    for vec in usize::from(first.0)..usize::from(first.0) + count {
+        assert!(
+            vec >= 32 && vec != mce::CMCI_VECTOR.0.into() && vec != apic::SPURIOUS_VECTOR.0.into(),
+            "interrupt vector {vec} is reserved"
+        );
+        VECTORS.free(vec);
+    }
+}
+
+/// Returns `true` if `vec` is allocated, or reserved by the kernel.
+pub fn is_allocated(vec: IntVec) -> bool {
+    VECTORS.is_allocated(vec.0.into())
+}
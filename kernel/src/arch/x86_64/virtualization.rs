@@ -0,0 +1,84 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Detection of hardware virtualization support (Intel VMX and AMD SVM).
+//!
+//! This is groundwork for running the kernel as a hypervisor. Actually entering VMX or SVM
+//! operation requires physically addressed, page-aligned control structures (the VMXON region and
+//! VMCS, or the host save area and VMCB), so it must wait until the kernel manages its own memory.
+
+use core::arch::x86_64::__cpuid;
+
+use x86_64::registers::model_specific::Msr;
+
+/// `IA32_FEATURE_CONTROL`, which firmware uses to enable or disable VMX.
+const IA32_FEATURE_CONTROL: u32 = 0x3a;
+/// The lock bit in `IA32_FEATURE_CONTROL`.
+const FEATURE_CONTROL_LOCKED: u64 = 1 << 0;
+/// Enables VMX outside SMX operation in `IA32_FEATURE_CONTROL`.
+const FEATURE_CONTROL_VMX_OUTSIDE_SMX: u64 = 1 << 2;
+
+/// `VM_CR`, which firmware uses to disable SVM.
+const VM_CR: u32 = 0xc001_0114;
+/// The SVM disable bit in `VM_CR`.
+const VM_CR_SVMDIS: u64 = 1 << 4;
+
+/// A hardware virtualization extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    /// Intel Virtual Machine Extensions.
+    Vmx,
+    /// AMD Secure Virtual Machine.
+    Svm,
+}
+
+/// The state of hardware virtualization support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    /// The processor doesn't support hardware virtualization.
+    Unsupported,
+    /// The processor supports the extension, but it has been disabled by firmware.
+    Disabled(Extension),
+    /// The processor supports the extension, and it may be enabled. For VMX, this includes the
+    /// case where firmware left `IA32_FEATURE_CONTROL` unlocked, so the kernel can enable it.
+    Available(Extension),
+}
+
+/// Detects whether hardware virtualization is supported and enabled.
+pub fn detect() -> Support {
+    // SAFETY: CPUID is available on all x86_64 processors
+    let vmx = unsafe { __cpuid(1) }.ecx & (1 << 5) != 0;
+    // SAFETY: CPUID is available on all x86_64 processors
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    // SAFETY: CPUID is available on all x86_64 processors, and the leaf is checked above
+    let svm =
+        max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.ecx & (1 << 2) != 0;
+
+    if vmx {
+        // SAFETY: `IA32_FEATURE_CONTROL` exists on all processors supporting VMX
+        let feature_control = unsafe { Msr::new(IA32_FEATURE_CONTROL).read() };
+
+        if feature_control & FEATURE_CONTROL_LOCKED != 0
+            && feature_control & FEATURE_CONTROL_VMX_OUTSIDE_SMX == 0
+        {
+            Support::Disabled(Extension::Vmx)
+        } else {
+            Support::Available(Extension::Vmx)
+        }
+    } else if svm {
+        // SAFETY: `VM_CR` exists on all processors supporting SVM
+        let vm_cr = unsafe { Msr::new(VM_CR).read() };
+
+        if vm_cr & VM_CR_SVMDIS != 0 {
+            Support::Disabled(Extension::Svm)
+        } else {
+            Support::Available(Extension::Svm)
+        }
+    } else {
+        Support::Unsupported
+    }
+}
@@ -9,10 +9,16 @@
 //!
 //! [BOOTBOOT]: https://gitlab.com/bztsrc/bootboot
 
+mod capture;
 mod framebuffer;
+pub mod memory_map;
 use core::{mem::size_of, ops::Range, slice};
 
-pub use framebuffer::{Console, Framebuffer};
+use crate::{mem, quarantine, util::bytes};
+
+pub use capture::{CaptureBuffer, CaptureGuard, CaptureSink};
+pub use framebuffer::{Console, Framebuffer, PagingGuard, SCREENSHOT};
+pub use memory_map::{normalized_memory_map, MemoryMap};
 
 extern "C" {
     /// The BOOTBOOT information structure.
@@ -41,14 +47,87 @@ extern "C" {
     /// [`BOOTBOOT.fb_size`]: Bootboot::fb_size
     #[link_name = "fb"]
     pub static mut FRAMEBUFFER: [u8; 0];
+
+    /// The environment, a NUL-terminated UTF-8 string of `key=value` pairs, one per line, from the
+    /// BOOTBOOT configuration file.
+    ///
+    /// Imported from the symbol `environment`.
+    ///
+    /// # Safety
+    /// This static is always safe to read assuming the kernel is loaded by a BOOTBOOT-compliant
+    /// loader.
+    /// Use [`environment`] instead to avoid using the `unsafe` keyword.
+    ///
+    /// Note that while `ENVIRONMENT_EXT` is defined here as a zero-length array, it is actually
+    /// valid for [`ENVIRONMENT_SIZE`] bytes, but Rust has no way to indicate this at compile-time.
+    #[link_name = "environment"]
+    pub static ENVIRONMENT_EXT: [u8; 0];
+
+    /// The BCM2837 memory mapped I/O.
+    ///
+    /// Imported from the symbol `mmio`.
+    ///
+    /// # Safety
+    /// The kernel must be loaded by a BOOTBOOT-compliant loader, and access to each device's
+    /// registers must be synchronized.
+    ///
+    /// Note that while `MMIO` is defined here as a zero-length array, it actually maps the
+    /// peripherals at [`ArchAarch64::mmio_ptr`].
+    #[cfg(target_arch = "aarch64")]
+    #[link_name = "mmio"]
+    pub static MMIO: [u8; 0];
 }
 
+/// The size of the memory reserved for the BOOTBOOT information structure, including the memory map.
+pub const BOOTBOOT_SIZE: usize = 4096;
+
+/// The size of the memory reserved for the environment.
+pub const ENVIRONMENT_SIZE: usize = 4096;
+
+/// The size of each processor's stack, which must match `initstack` in `aleph-naught.ld`.
+///
+/// The loader places each processor's stack at the top of the address space, below the stacks of
+/// the processors with lower local APIC IDs, so the bootstrap processor's stack ends at address
+/// `0`.
+pub const INIT_STACK_SIZE: u64 = 0x4000;
+
 /// A safe reference to the BOOTBOOT information structure.
 pub static BOOTBOOT: &Bootboot = {
     // SAFETY: the kernel must be loaded by a BOOTBOOT-compliant loader
     unsafe { &BOOTBOOT_EXT }
 };
 
+/// Returns the environment passed by the loader, or an empty string if it isn't valid UTF-8.
+///
+/// The environment contains `key=value` pairs, one per line, and may contain comments. Use
+/// [`env_var`] to look up a value.
+pub fn environment() -> &'static str {
+    // SAFETY: the kernel must be loaded by a BOOTBOOT-compliant loader, which provides
+    //         `ENVIRONMENT_SIZE` bytes of environment
+    let bytes = unsafe { slice::from_raw_parts(ENVIRONMENT_EXT.as_ptr(), ENVIRONMENT_SIZE) };
+    let len = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(ENVIRONMENT_SIZE);
+
+    core::str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+/// Returns the value of `key` in the [`environment`], if present.
+///
+/// Lines starting with `#` or `//` are ignored, as is any whitespace surrounding keys and values.
+/// If a key appears more than once, the last value is returned.
+pub fn env_var(key: &str) -> Option<&'static str> {
+    environment()
+        .lines()
+        .rev()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#') && !line.starts_with("//"))
+        .filter_map(|line| line.split_once('='))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, value)| value.trim())
+}
+
 /// The color format for a pixel in the [`FRAMEBUFFER`].
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum PixelFormat {
@@ -110,31 +189,52 @@ pub struct Bootboot {
 }
 
 impl Bootboot {
-    /// Returns the [`PixelFormat`] that should be used for the [`FRAMEBUFFER`].
-    pub fn pixel_format(&self) -> PixelFormat {
+    /// Returns the [`PixelFormat`] that should be used for the [`FRAMEBUFFER`], or `None` if
+    /// `fb_type` is invalid.
+    pub fn pixel_format(&self) -> Option<PixelFormat> {
         match self.fb_type {
-            0 => PixelFormat::Argb,
-            1 => PixelFormat::Rgba,
-            2 => PixelFormat::Abgr,
-            3 => PixelFormat::Bgra,
-            t => panic!("BOOTBOOT.fb_type has an invalid value: {t}"),
+            0 => Some(PixelFormat::Argb),
+            1 => Some(PixelFormat::Rgba),
+            2 => Some(PixelFormat::Abgr),
+            3 => Some(PixelFormat::Bgra),
+            _ => None,
         }
     }
 
-    /// Returns a reference to the memory map.
-    pub fn memory_map(&self) -> &[MMapEnt] {
-        let n = (self.size as usize - size_of::<Self>()) / size_of::<MMapEnt>();
+    /// Returns the initial ramdisk.
+    pub fn initrd(&self) -> &'static [u8] {
+        // SAFETY: BOOTBOOT guarantees that the ramdisk is loaded at this physical address, and that
+        //         it's identity mapped
+        unsafe { slice::from_raw_parts(self.initrd_ptr as *const u8, self.initrd_size as usize) }
+    }
 
-        // SAFETY: BOOTBOOT guarantees that this memory is used for the memory map
+    /// Returns a reference to the memory map, as the loader provided it.
+    ///
+    /// An invalid `size` is clamped to the page containing the structure. Use
+    /// [`normalized_memory_map`] for a sorted and validated copy.
+    pub fn memory_map(&self) -> &[MMapEnt] {
+        // SAFETY: BOOTBOOT guarantees that the structure, including the memory map, occupies a
+        //         whole page
         // TODO: determine if pointer provenance still makes this unsound
-        unsafe { slice::from_raw_parts(self.mmap.as_ptr(), n) }
+        let page =
+            unsafe { slice::from_raw_parts((self as *const Self).cast::<u8>(), BOOTBOOT_SIZE) };
+        let entries = page
+            .get(size_of::<Self>()..(self.size as usize).min(BOOTBOOT_SIZE))
+            .unwrap_or_default();
+
+        bytes::overlay_slice(entries, entries.len() / size_of::<MMapEnt>()).unwrap_or_default()
     }
 
-    /// Returns an iterator over free frames of memory.
+    /// Returns an iterator over free frames of memory in the [normalized](normalized_memory_map)
+    /// memory map, excluding any [quarantined](quarantine) or [reserved](mem::reserve_physical)
+    /// memory.
+    ///
+    /// # Panics
+    /// Panics if the memory map is inconsistent.
     pub fn free_frames<const FRAME_SIZE: u64>(&'static self) -> FreeFrames<FRAME_SIZE> {
         const { assert!(FRAME_SIZE.is_power_of_two()) };
 
-        let mem_map = self.memory_map().iter();
+        let mem_map = normalized_memory_map().entries().iter();
         FreeFrames {
             mem_map,
             frames: 0..0,
@@ -180,6 +280,9 @@ pub struct MMapEnt {
     size: u64,
 }
 
+// SAFETY: both fields are integers, so every bit pattern is valid, and there is no padding
+unsafe impl bytes::FromBytes for MMapEnt {}
+
 impl MMapEnt {
     /// Returns the 64-bit physical address of the memory region.
     pub fn address(&self) -> u64 {
@@ -235,10 +338,11 @@ impl<const FRAME_SIZE: u64> Iterator for FreeFrames<FRAME_SIZE> {
         const { assert!(FRAME_SIZE.is_power_of_two()) };
         let frame_mask: u64 = FRAME_SIZE - 1;
 
-        let mut frame = self.frames.next();
+        loop {
+            let mut frame = self.frames.next();
 
-        while frame.is_none() {
-            if let Some(mmap_ent) = self.mem_map.next() {
+            while frame.is_none() {
+                let mmap_ent = self.mem_map.next()?;
                 if mmap_ent.mem_type() != MemType::Free {
                     continue;
                 }
@@ -253,8 +357,12 @@ impl<const FRAME_SIZE: u64> Iterator for FreeFrames<FRAME_SIZE> {
                 self.frames = start..(start + len);
                 frame = self.frames.next();
             }
-        }
 
-        frame.map(|frame| frame * FRAME_SIZE)
+            let address = frame? * FRAME_SIZE;
+            let frame = address..(address + FRAME_SIZE);
+            if !quarantine::overlaps(frame.clone()) && !mem::is_reserved(frame) {
+                return Some(address);
+            }
+        }
     }
 }
@@ -0,0 +1,129 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Capture of console output, so that tests can check what was logged.
+//!
+//! While a [`CaptureGuard`] returned by [`Console::capture`](super::Console::capture) is alive,
+//! every log message written to the console is also written to the capture sink, in the same
+//! `LEVEL: message` form as on the serial console. [`CaptureBuffer`] is a sink which keeps the
+//! output in a fixed-size buffer for assertions to match against.
+
+use core::{fmt, str};
+
+use spin::Mutex;
+
+/// The active capture sink.
+pub(super) static SINK: Mutex<Option<&'static dyn CaptureSink>> = Mutex::new(None);
+
+/// A destination for captured console output.
+pub trait CaptureSink: Sync {
+    /// Records `s`, which is part of the console output.
+    fn write_str(&self, s: &str);
+}
+
+/// Stops capturing when dropped.
+#[must_use = "capturing stops when the guard is dropped"]
+pub struct CaptureGuard {
+    pub(super) previous: Option<&'static dyn CaptureSink>,
+}
+
+impl fmt::Debug for CaptureGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaptureGuard").finish_non_exhaustive()
+    }
+}
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        *SINK.lock() = self.previous;
+    }
+}
+
+/// Writes console output to a capture sink.
+pub(super) struct Writer<'a>(pub(super) &'a dyn CaptureSink);
+
+impl fmt::Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+/// A capture sink which keeps up to `N` bytes of output, dropping any more.
+#[derive(Debug)]
+pub struct CaptureBuffer<const N: usize> {
+    inner: Mutex<Inner<N>>,
+}
+
+/// The contents of a [`CaptureBuffer`].
+#[derive(Debug)]
+struct Inner<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    overflowed: bool,
+}
+
+impl<const N: usize> CaptureBuffer<N> {
+    /// Returns an empty buffer.
+    pub const fn new() -> Self {
+        CaptureBuffer {
+            inner: Mutex::new(Inner {
+                buffer: [0; N],
+                len: 0,
+                overflowed: false,
+            }),
+        }
+    }
+
+    /// Calls `f` with the captured output.
+    pub fn with_output<R>(&self, f: impl FnOnce(&str) -> R) -> R {
+        let inner = self.inner.lock();
+        // only whole strings are written, so the output is only invalid where it was cut off
+        let output = match str::from_utf8(&inner.buffer[..inner.len]) {
+            Ok(output) => output,
+            Err(err) => {
+                // SAFETY: `valid_up_to` is the length of the valid prefix
+                unsafe { str::from_utf8_unchecked(&inner.buffer[..err.valid_up_to()]) }
+            }
+        };
+        f(output)
+    }
+
+    /// Returns `true` if the captured output contains `pattern`.
+    pub fn contains(&self, pattern: &str) -> bool {
+        self.with_output(|output| output.contains(pattern))
+    }
+
+    /// Returns `true` if output was dropped because the buffer was full.
+    pub fn overflowed(&self) -> bool {
+        self.inner.lock().overflowed
+    }
+
+    /// Discards the captured output.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock();
+        inner.len = 0;
+        inner.overflowed = false;
+    }
+}
+
+impl<const N: usize> Default for CaptureBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> CaptureSink for CaptureBuffer<N> {
+    fn write_str(&self, s: &str) {
+        let mut inner = self.inner.lock();
+        let start = inner.len;
+        let len = s.len().min(N - start);
+        inner.buffer[start..start + len].copy_from_slice(&s.as_bytes()[..len]);
+        inner.len += len;
+        inner.overflowed |= len < s.len();
+    }
+}
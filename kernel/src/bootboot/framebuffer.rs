@@ -6,11 +6,26 @@
 //
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 //! Provides a means of writing and drawing to the screen.
-use super::{PixelFormat, BOOTBOOT, FRAMEBUFFER};
+//!
+//! The screen's contents can be captured as a PPM image with [`Framebuffer::write_ppm`]. If the
+//! [`SCREENSHOT`] parameter is set, the kernel sends one over the serial console once it finishes
+//! initializing, so that headless test automation can check what was displayed.
+//!
+//! While a [`PagingGuard`] returned by [`Console::start_paging`] is alive, log messages pause
+//! whenever the screen fills, until a key is pressed, like `more`, so that long output can be read
+//! before it runs off the bottom of the screen. The console's lock is released while waiting, and
+//! messages logged by others in the meantime, such as by interrupt handlers, are only written to
+//! the serial console. Messages logged from interrupt handlers never wait, and neither does text
+//! written directly to a [`Framebuffer`], since its lock is held.
+use super::{
+    capture::{self, CaptureGuard, CaptureSink},
+    PixelFormat, BOOTBOOT, FRAMEBUFFER,
+};
+use crate::param;
 use core::{
     fmt::{self, Write},
     mem::size_of,
-    ops::{Deref as _, DerefMut as _},
+    ops::Deref as _,
     slice,
 };
 use embedded_graphics::{
@@ -23,30 +38,15 @@ use lazy_static::lazy_static;
 use log::{Level, LevelFilter, Log};
 use spin::{Mutex, MutexGuard};
 
+param! {
+    /// Whether to send a screenshot over the serial console once initialization is complete.
+    pub static SCREENSHOT: bool = false, name = "screenshot";
+}
+
 lazy_static! {
     /// The main framebuffer, which was setup by the BOOTBOOT loader.
     pub static ref CONSOLE: Console = Console {
-        fb: Mutex::new(Framebuffer {
-            // SAFETY:
-            // - kernel must be loaded by a BOOTBOOT-compliant loader
-            // - all accesses to `FRAMEBUFFER` are synchronized through `CONSOLE`
-            // - `FRAMEBUFFER` must be valid for `BOOTBOOT.fb_size` bytes
-            // - all values are valid for `RawPixel`
-            buffer: unsafe { slice::from_raw_parts_mut(
-                FRAMEBUFFER.as_mut_ptr().cast::<RawPixel>(),
-                BOOTBOOT.fb_size as usize / size_of::<RawPixel>())},
-
-            size: Size{ width: BOOTBOOT.fb_width, height: BOOTBOOT.fb_height },
-            pitch: BOOTBOOT.fb_scanline / size_of::<RawPixel>() as u32,
-            pixel_format: BOOTBOOT.pixel_format(),
-
-            max_chars: Size {
-                width: BOOTBOOT.fb_width / Framebuffer::FONT_SIZE.width,
-                height: BOOTBOOT.fb_height / Framebuffer::FONT_SIZE.height,
-            },
-            cursor: Point::zero(),
-            text_color: Rgb888::CSS_GRAY,
-        }),
+        fb: Mutex::new(Framebuffer::from_bootboot().unwrap_or_else(Framebuffer::absent)),
         level: LevelFilter::Debug,
     };
 }
@@ -60,14 +60,68 @@ pub struct Console {
 
 impl Console {
     /// Perform console initialization.
+    ///
+    /// On `x86_64`, log messages are also written to the serial console, if there is one. If the
+    /// loader didn't provide a usable framebuffer, the serial console is the only output.
     pub fn init() -> Result<(), log::SetLoggerError> {
-        log::set_logger(CONSOLE.deref()).map(|_| log::set_max_level(LevelFilter::Debug))
+        #[cfg(target_arch = "x86_64")]
+        crate::arch::serial::init();
+
+        log::set_logger(CONSOLE.deref()).map(|_| log::set_max_level(LevelFilter::Debug))?;
+        if !Self::get().is_present() {
+            log::warn!("degraded: no usable framebuffer; logging to the serial console only");
+        }
+        Ok(())
     }
 
     /// Returns exclusive access to the main [`Framebuffer`].
     pub fn get() -> MutexGuard<'static, Framebuffer> {
         CONSOLE.fb.lock()
     }
+
+    /// Starts capturing log messages to `sink`, until the returned guard is dropped.
+    ///
+    /// Captures may be nested, in which case only the innermost sink receives output.
+    pub fn capture(sink: &'static dyn CaptureSink) -> CaptureGuard {
+        CaptureGuard {
+            previous: capture::SINK.lock().replace(sink),
+        }
+    }
+
+    /// Starts pausing at each full screen of text, until the returned guard is dropped.
+    ///
+    /// At each pause, any key continues, and `q` stops pausing for the rest of the output. Keys
+    /// are read from a PS/2 keyboard or the serial console. On architectures without either,
+    /// nothing pauses.
+    pub fn start_paging() -> PagingGuard {
+        let mut fb = Self::get();
+        PagingGuard {
+            previous: core::mem::replace(&mut fb.paging, true),
+        }
+    }
+
+    /// Sends a screenshot of the main framebuffer to the serial console, as a binary PPM image.
+    ///
+    /// The image follows a line containing only `SCREENSHOT`, so that it can be found among the
+    /// log messages.
+    #[cfg(target_arch = "x86_64")]
+    pub fn send_screenshot() {
+        crate::arch::serial::write_bytes(b"SCREENSHOT\n");
+        Self::get().write_ppm(crate::arch::serial::write_bytes);
+    }
+}
+
+/// Stops paging when dropped.
+#[derive(Debug)]
+#[must_use = "paging stops when the guard is dropped"]
+pub struct PagingGuard {
+    previous: bool,
+}
+
+impl Drop for PagingGuard {
+    fn drop(&mut self) {
+        Console::get().paging = self.previous;
+    }
 }
 
 impl Log for Console {
@@ -77,12 +131,30 @@ impl Log for Console {
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
+            let sink = *capture::SINK.lock();
+            if let Some(sink) = sink {
+                // capture sinks never fail
+                let _ = writeln!(
+                    capture::Writer(sink),
+                    "{level}: {args}",
+                    level = record.level(),
+                    args = record.args()
+                );
+            }
+
+            #[cfg(target_arch = "x86_64")]
+            crate::arch::serial::write_fmt(format_args!(
+                "{level}: {args}\n",
+                level = record.level(),
+                args = record.args()
+            ));
+
             if record.level() >= Level::Info {
-                writeln!(self.fb.lock().deref_mut(), "{args}", args = record.args())
+                writeln!(PagedWriter(self), "{args}", args = record.args())
                     .expect("write log message");
             } else {
                 writeln!(
-                    self.fb.lock().deref_mut(),
+                    PagedWriter(self),
                     "{level}: {args}",
                     level = record.level(),
                     args = record.args()
@@ -95,6 +167,28 @@ impl Log for Console {
     fn flush(&self) {}
 }
 
+/// Writes to a console's framebuffer, releasing its lock while waiting for a key at a page break.
+struct PagedWriter<'a>(&'a Console);
+
+impl Write for PagedWriter<'_> {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while !s.is_empty() {
+            let mut fb = self.0.fb.lock();
+            // another writer is waiting at a page break, so the rest of the text is dropped
+            if fb.break_pending {
+                break;
+            }
+            s = &s[fb.write_until_break(s)..];
+            if fb.break_pending {
+                drop(fb);
+                let key = wait_for_key();
+                self.0.fb.lock().finish_page_break(key);
+            }
+        }
+        Ok(())
+    }
+}
+
 /// The raw pixel data as it appears in the framebuffer.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct RawPixel(u32);
@@ -112,6 +206,18 @@ impl RawPixel {
 
         RawPixel(raw_pixel)
     }
+
+    /// Returns the [`Rgb888`] color of a `RawPixel` based on the given [`PixelFormat`].
+    fn to_color(self, format: PixelFormat) -> Rgb888 {
+        let raw_color = match format {
+            PixelFormat::Argb => self.0,
+            PixelFormat::Rgba => self.0 >> 8,
+            PixelFormat::Abgr => self.0.swap_bytes() >> 8,
+            PixelFormat::Bgra => self.0.swap_bytes(),
+        };
+
+        Rgb888::from(embedded_graphics::pixelcolor::raw::RawU24::new(raw_color))
+    }
 }
 
 /// The video memory and metadata used for writing and drawing to a screen.
@@ -132,6 +238,11 @@ pub struct Framebuffer {
     cursor: Point,
     /// The foreground color to use when printing text.
     text_color: Rgb888,
+    /// Whether to pause for a key press when the screen is full.
+    paging: bool,
+    /// Whether the screen is full and the prompt is displayed, waiting for
+    /// [`finish_page_break`](Self::finish_page_break).
+    break_pending: bool,
 }
 
 impl Framebuffer {
@@ -142,10 +253,146 @@ impl Framebuffer {
     };
     const TAB: &'static str = "        ";
 
+    /// Returns the framebuffer set up by the loader, or `None` if BOOTBOOT's description of it is
+    /// invalid.
+    fn from_bootboot() -> Option<Self> {
+        let pixel_format = BOOTBOOT.pixel_format()?;
+        let pitch = BOOTBOOT.fb_scanline / size_of::<RawPixel>() as u32;
+        let size = Size {
+            width: BOOTBOOT.fb_width,
+            height: BOOTBOOT.fb_height,
+        };
+        let len = BOOTBOOT.fb_size as usize / size_of::<RawPixel>();
+        if size.width == 0 || pitch < size.width || pitch as usize * size.height as usize > len {
+            return None;
+        }
+
+        Some(Framebuffer {
+            // SAFETY:
+            // - kernel must be loaded by a BOOTBOOT-compliant loader
+            // - all accesses to `FRAMEBUFFER` are synchronized through `CONSOLE`
+            // - `FRAMEBUFFER` must be valid for `BOOTBOOT.fb_size` bytes
+            // - all values are valid for `RawPixel`
+            buffer: unsafe {
+                slice::from_raw_parts_mut(FRAMEBUFFER.as_mut_ptr().cast::<RawPixel>(), len)
+            },
+            size,
+            pitch,
+            pixel_format,
+            max_chars: Size {
+                width: size.width / Framebuffer::FONT_SIZE.width,
+                height: size.height / Framebuffer::FONT_SIZE.height,
+            },
+            cursor: Point::zero(),
+            text_color: Rgb888::CSS_GRAY,
+            paging: false,
+            break_pending: false,
+        })
+    }
+
+    /// Returns an empty framebuffer, on which drawing does nothing, for when there is no usable
+    /// framebuffer.
+    fn absent() -> Self {
+        Framebuffer {
+            buffer: &mut [],
+            size: Size::zero(),
+            pitch: 0,
+            pixel_format: PixelFormat::Argb,
+            max_chars: Size::zero(),
+            cursor: Point::zero(),
+            text_color: Rgb888::CSS_GRAY,
+            paging: false,
+            break_pending: false,
+        }
+    }
+
+    /// Returns `false` if there is no usable framebuffer, so nothing drawn is displayed.
+    pub fn is_present(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
     pub(crate) fn cursor_pixel(&self) -> Point {
         self.cursor.component_mul(Point::zero() + Self::FONT_SIZE)
     }
 
+    /// Returns the color of the pixel at `point`, or `None` if it's off the screen.
+    pub fn pixel(&self, point: Point) -> Option<Rgb888> {
+        self.bounding_box().contains(point).then(|| {
+            let index = point.y as usize * self.pitch as usize + point.x as usize;
+            // SAFETY: casting a reference to a pointer and reading from it is just as safe as
+            // reading directly from the reference.
+            unsafe { (&self.buffer[index] as *const RawPixel).read_volatile() }
+                .to_color(self.pixel_format)
+        })
+    }
+
+    /// Writes the screen's contents as a binary PPM image to `out`, in chunks.
+    pub fn write_ppm(&self, mut out: impl FnMut(&[u8])) {
+        /// Adapts `out` for writing the text header.
+        struct Header<F>(F);
+
+        impl<F: FnMut(&[u8])> Write for Header<F> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                (self.0)(s.as_bytes());
+                Ok(())
+            }
+        }
+
+        let Size { width, height } = self.size;
+        // writing the header never fails
+        let _ = write!(Header(&mut out), "P6\n{width} {height}\n255\n");
+
+        let mut chunk = [0; 3 * 64];
+        let mut len = 0;
+        for point in self.bounding_box().points() {
+            let color = self.pixel(point).unwrap_or(Rgb888::BLACK);
+            chunk[len..len + 3].copy_from_slice(&[color.r(), color.g(), color.b()]);
+            len += 3;
+            if len == chunk.len() {
+                out(&chunk);
+                len = 0;
+            }
+        }
+        out(&chunk[..len]);
+    }
+
+    /// Moves the cursor to the start of the next line, displaying the page break prompt if paging
+    /// and the screen is full.
+    fn new_line(&mut self) {
+        self.cursor.x = 0;
+        self.cursor.y += 1;
+        // TODO: scrolling
+        // the last line is kept for the prompt, so a screen without room for it can't be paged
+        let last_line = self.max_chars.height as i32 - 1;
+        if self.paging && last_line > 0 && self.cursor.y >= last_line {
+            self.start_page_break();
+        }
+    }
+
+    /// Displays the page break prompt on the last line. Nothing more is written until
+    /// [`finish_page_break`](Self::finish_page_break) is called.
+    fn start_page_break(&mut self) {
+        const PROMPT: &str = "-- More -- (q to stop paging)";
+
+        let char_style = MonoTextStyle::new(&Framebuffer::FONT, Rgb888::BLACK);
+        let prompt = Text::new(PROMPT, self.cursor_pixel(), char_style);
+        // drawing never fails
+        let _ = self.fill_solid(&prompt.bounding_box(), Rgb888::CSS_GRAY);
+        let _ = prompt.draw(self);
+        self.break_pending = true;
+    }
+
+    /// Clears the screen after a page break, once `key` has been pressed, or without waiting if
+    /// `key` is `None`.
+    fn finish_page_break(&mut self, key: Option<Key>) {
+        if key == Some(Key::Quit) {
+            self.paging = false;
+        }
+        let _ = self.clear(Rgb888::BLACK);
+        self.cursor = Point::zero();
+        self.break_pending = false;
+    }
+
     /// Sets the position of the cursor, where `cursor.x` and `cursor.y` indicate the number of
     /// characters horizontally and vertically, respectively, from the top-left corner of the
     /// screen.
@@ -185,7 +432,21 @@ impl DrawTarget for Framebuffer {
 }
 
 impl Write for Framebuffer {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
+    /// Writes `s`, continuing at each page break without waiting, since the lock is held.
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        loop {
+            s = &s[self.write_until_break(s)..];
+            if !self.break_pending {
+                return Ok(());
+            }
+            self.finish_page_break(None);
+        }
+    }
+}
+
+impl Framebuffer {
+    /// Writes `s` up to the first page break, returning the number of bytes written.
+    fn write_until_break(&mut self, s: &str) -> usize {
         let char_style = MonoTextStyle::new(&Framebuffer::FONT, self.text_color);
 
         let mut start_index = None;
@@ -211,9 +472,10 @@ impl Write for Framebuffer {
                         self.cursor.x += spaces.len() as i32;
                     }
                     '\n' => {
-                        self.cursor.x = 0;
-                        self.cursor.y += 1;
-                        // TODO: scrolling
+                        self.new_line();
+                        if self.break_pending {
+                            return i + c.len_utf8();
+                        }
                     }
                     _ => { /*ignored */ }
                 }
@@ -224,13 +486,14 @@ impl Write for Framebuffer {
                         Text::new(&s[si..i], self.cursor_pixel(), char_style)
                             .draw(self)
                             .expect("draw text");
-                        start_index = Some(i);
-                        char_count = 1;
                     }
+                    start_index = Some(i);
+                    char_count = 1;
 
-                    self.cursor.x = 0;
-                    self.cursor.y += 1;
-                    // TODO: scrolling
+                    self.new_line();
+                    if self.break_pending {
+                        return i;
+                    }
                 } else {
                     start_index.get_or_insert(i);
                 }
@@ -244,6 +507,49 @@ impl Write for Framebuffer {
             self.cursor.x += char_count as i32;
         }
 
-        Ok(())
+        s.len()
+    }
+}
+
+/// A key pressed at a page break.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    /// A key to continue to the next page.
+    Continue,
+    /// The key to stop paging.
+    Quit,
+}
+
+/// Waits for a key press on the keyboard or the serial console. Returns `None` without waiting if
+/// called from an interrupt handler, which must not wait for input.
+#[cfg(target_arch = "x86_64")]
+fn wait_for_key() -> Option<Key> {
+    use crate::{
+        arch::{interrupt, ps2, serial},
+        input::{InputEvent, KeyCode},
+    };
+
+    if interrupt::depth() != 0 {
+        return None;
     }
+    loop {
+        if let Some(event) = ps2::read_event() {
+            match (event.key_code(), event.value) {
+                (Some(KeyCode::Q), InputEvent::PRESSED) => return Some(Key::Quit),
+                (Some(_), InputEvent::PRESSED) => return Some(Key::Continue),
+                _ => {}
+            }
+        }
+        match serial::read_byte() {
+            Some(b'q' | b'Q') => return Some(Key::Quit),
+            Some(_) => return Some(Key::Continue),
+            None => core::hint::spin_loop(),
+        }
+    }
+}
+
+/// Continues immediately, since there is no input driver.
+#[cfg(not(target_arch = "x86_64"))]
+fn wait_for_key() -> Option<Key> {
+    Some(Key::Continue)
 }
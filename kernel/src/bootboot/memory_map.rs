@@ -0,0 +1,181 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Normalization and validation of the memory map.
+//!
+//! The loader's memory map isn't guaranteed to be sorted, may split a region of one type into
+//! several adjacent entries, and could, if the firmware is buggy, contain overlapping entries. A
+//! [`MemoryMap`] is a copy of it which is sorted, with empty entries removed and adjacent entries of
+//! the same type merged, and which has been checked for overlaps. [`normalized_memory_map`] returns
+//! the normalized and [validated](MemoryMap::validate) map of the loader's memory map, which is what
+//! [`Bootboot::free_frames`] uses.
+
+use core::{fmt, mem::size_of, ops::Range};
+
+use lazy_static::lazy_static;
+
+use super::{Bootboot, MMapEnt, MemType, BOOTBOOT, BOOTBOOT_SIZE};
+
+/// The maximum number of entries which fit in the BOOTBOOT information structure.
+pub const MAX_ENTRIES: usize = (BOOTBOOT_SIZE - size_of::<Bootboot>()) / size_of::<MMapEnt>();
+
+lazy_static! {
+    /// The normalized memory map.
+    static ref MEMORY_MAP: MemoryMap = {
+        let map = MemoryMap::normalize(BOOTBOOT.memory_map())
+            .and_then(|map| map.validate().map(|()| map))
+            .unwrap_or_else(|err| panic!("the loader's memory map is inconsistent: {err}"));
+        log::debug!("memory map:\n{map}");
+        map
+    };
+}
+
+/// Returns the normalized memory map.
+///
+/// [`mem::init`](crate::mem::init) calls this first, since validating the map on `x86_64` locks the
+/// page tables.
+///
+/// # Panics
+/// Panics the first time it's called if the loader's memory map has overlapping entries, or
+/// reports memory which the loader is using as free.
+pub fn normalized_memory_map() -> &'static MemoryMap {
+    &MEMORY_MAP
+}
+
+/// An inconsistency in a memory map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Two entries overlap.
+    Overlap(Range<u64>, Range<u64>),
+    /// Memory which is in use, named by the `&str`, is in an entry of free memory.
+    InUse(&'static str, Range<u64>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Overlap(a, b) => write!(f, "entries {a:#x?} and {b:#x?} overlap"),
+            Error::InUse(name, range) => {
+                write!(f, "the {name} at {range:#x?} is in free memory")
+            }
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// A sorted memory map, without empty entries, overlaps, or adjacent entries of the same type.
+#[derive(Clone)]
+pub struct MemoryMap {
+    entries: [MMapEnt; MAX_ENTRIES],
+    len: usize,
+}
+
+impl MemoryMap {
+    /// Returns the normalized copy of `raw`, or an error if any of its entries overlap.
+    ///
+    /// Entries past [`MAX_ENTRIES`] are ignored.
+    pub fn normalize(raw: &[MMapEnt]) -> Result<Self, Error> {
+        let mut map = MemoryMap {
+            entries: [MMapEnt { ptr: 0, size: 0 }; MAX_ENTRIES],
+            len: 0,
+        };
+        for entry in raw.iter().filter(|entry| entry.size() != 0) {
+            if map.len == MAX_ENTRIES {
+                break;
+            }
+            map.entries[map.len] = *entry;
+            map.len += 1;
+        }
+        map.entries[..map.len].sort_unstable_by_key(MMapEnt::address);
+
+        let mut len: usize = 0;
+        for i in 0..map.len {
+            let entry = map.entries[i];
+            if let Some(last) = len.checked_sub(1).map(|last| &mut map.entries[last]) {
+                let last_end = last.address().saturating_add(last.size());
+                if entry.address() < last_end {
+                    return Err(Error::Overlap(range(last), range(&entry)));
+                }
+                if entry.address() == last_end && entry.mem_type() == last.mem_type() {
+                    last.size += entry.size();
+                    continue;
+                }
+            }
+            map.entries[len] = entry;
+            len += 1;
+        }
+        map.len = len;
+        Ok(map)
+    }
+
+    /// Returns the entries, in order of address.
+    pub fn entries(&self) -> &[MMapEnt] {
+        &self.entries[..self.len]
+    }
+
+    /// Checks that the memory the loader placed the initrd and framebuffer in, and on `x86_64`, the
+    /// kernel image, isn't reported as free.
+    pub fn validate(&self) -> Result<(), Error> {
+        let initrd = BOOTBOOT.initrd_ptr..BOOTBOOT.initrd_ptr + BOOTBOOT.initrd_size;
+        self.check_in_use("initrd", initrd)?;
+        let framebuffer = BOOTBOOT.fb_ptr..BOOTBOOT.fb_ptr + u64::from(BOOTBOOT.fb_size);
+        self.check_in_use("framebuffer", framebuffer)?;
+
+        #[cfg(target_arch = "x86_64")]
+        for range in crate::mem::physical_ranges(crate::mem::kernel_image()) {
+            self.check_in_use("kernel image", range)?;
+        }
+        Ok(())
+    }
+
+    /// Returns an error if any of `range`, which is in use, is in an entry of free memory.
+    fn check_in_use(&self, name: &'static str, range: Range<u64>) -> Result<(), Error> {
+        let free = self
+            .entries()
+            .iter()
+            .filter(|entry| entry.mem_type() == MemType::Free)
+            .any(|entry| {
+                let entry = self::range(entry);
+                entry.start < range.end && range.start < entry.end
+            });
+        if free {
+            return Err(Error::InUse(name, range));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.entries()).finish()
+    }
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, entry) in self.entries().iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let range = range(entry);
+            write!(
+                f,
+                "{:#018x}-{:#018x} {:?}",
+                range.start,
+                range.end,
+                entry.mem_type()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the addresses in `entry`.
+fn range(entry: &MMapEnt) -> Range<u64> {
+    entry.address()..entry.address().saturating_add(entry.size())
+}
@@ -0,0 +1,92 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Identifies the build of the kernel which is running.
+//!
+//! The information is collected by the build script. A copy of the [`Display`](fmt::Display)
+//! form of [`BUILD_ID`] is also embedded in the `.build_id` section of the kernel image, so that it
+//! can be extracted from an image without running it, for instance with
+//! `objcopy -O binary -j .build_id aleph-naught /dev/stdout`.
+
+use core::fmt;
+
+/// Information identifying a build of the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildId {
+    /// The name of the kernel.
+    pub name: &'static str,
+    /// The version of the kernel.
+    pub version: &'static str,
+    /// The abbreviated git commit hash, with `-dirty` appended if there were uncommitted changes.
+    pub git_hash: &'static str,
+    /// The UTC date and time of the build in ISO 8601 format.
+    pub timestamp: &'static str,
+    /// The output of `rustc --version` for the compiler used.
+    pub rustc_version: &'static str,
+    /// The target triple the kernel was built for.
+    pub target: &'static str,
+    /// The cargo profile used for the build.
+    pub profile: &'static str,
+}
+
+/// The build of the running kernel.
+pub static BUILD_ID: BuildId = BuildId {
+    name: env!("CARGO_PKG_NAME"),
+    version: env!("CARGO_PKG_VERSION"),
+    git_hash: env!("ALEPH_BUILD_GIT_HASH"),
+    timestamp: env!("ALEPH_BUILD_TIMESTAMP"),
+    rustc_version: env!("ALEPH_BUILD_RUSTC_VERSION"),
+    target: env!("ALEPH_BUILD_TARGET"),
+    profile: env!("ALEPH_BUILD_PROFILE"),
+};
+
+/// The build ID string, which must match the output of `BUILD_ID`'s [`Display`](fmt::Display)
+/// implementation.
+const BUILD_ID_STR: &str = concat!(
+    env!("CARGO_PKG_NAME"),
+    " ",
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("ALEPH_BUILD_GIT_HASH"),
+    " ",
+    env!("ALEPH_BUILD_TIMESTAMP"),
+    ") ",
+    env!("ALEPH_BUILD_TARGET"),
+    " ",
+    env!("ALEPH_BUILD_PROFILE"),
+    ", ",
+    env!("ALEPH_BUILD_RUSTC_VERSION"),
+);
+
+/// The build ID string as it is embedded in the kernel image.
+#[used]
+#[link_section = ".build_id"]
+static BUILD_ID_SECTION: [u8; BUILD_ID_STR.len()] = {
+    let mut bytes = [0; BUILD_ID_STR.len()];
+    let mut i = 0;
+    while i < bytes.len() {
+        bytes[i] = BUILD_ID_STR.as_bytes()[i];
+        i += 1;
+    }
+    bytes
+};
+
+impl fmt::Display for BuildId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({} {}) {} {}, {}",
+            self.name,
+            self.version,
+            self.git_hash,
+            self.timestamp,
+            self.target,
+            self.profile,
+            self.rustc_version,
+        )
+    }
+}
@@ -0,0 +1,95 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Identification of crashes, so that reports of the same crash can be matched.
+//!
+//! A [`CrashId`] is a hash of where the kernel panicked, which the panic handler prints. It
+//! excludes the panic message, which often contains addresses or other values which differ between
+//! occurrences of the same crash.
+//!
+//! There is no unwinder or symbol table yet, so the ID only covers the location of the panic
+//! itself, not the frames which called it. Panics from a shared helper, such as an `expect` in a
+//! common function, therefore get the same ID whatever called them.
+//!
+//! The panic handler reports the crash with [`on_emergency_stack`], on a stack reserved for it, so
+//! that a panic caused by running out of stack can still be reported.
+
+use core::{
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::arch;
+
+/// The size of the emergency stack.
+pub const EMERGENCY_STACK_SIZE: usize = 0x4000;
+
+/// A stack for [`on_emergency_stack`].
+#[repr(C, align(16))]
+struct Stack([u8; EMERGENCY_STACK_SIZE]);
+
+/// The emergency stack, which is only used by the first call to [`on_emergency_stack`].
+static mut EMERGENCY_STACK: Stack = Stack([0; EMERGENCY_STACK_SIZE]);
+/// Set once the emergency stack is in use.
+static EMERGENCY_STACK_USED: AtomicBool = AtomicBool::new(false);
+
+/// Calls `f(arg)` on the emergency stack, which is reserved for reporting crashes.
+///
+/// Since `f` never returns, the emergency stack is only used once. Later calls, such as for a
+/// panic while reporting a panic, or panics on other processors, call `f` on the current stack
+/// instead.
+pub fn on_emergency_stack(f: extern "C" fn(usize) -> !, arg: usize) -> ! {
+    if EMERGENCY_STACK_USED.swap(true, Ordering::Acquire) {
+        f(arg)
+    }
+
+    // SAFETY: only the address of `EMERGENCY_STACK` is taken
+    let stack_top = unsafe { core::ptr::addr_of_mut!(EMERGENCY_STACK) }
+        .cast::<u8>()
+        .wrapping_add(EMERGENCY_STACK_SIZE);
+    // SAFETY: the emergency stack is 16-byte aligned, and only used here, once
+    unsafe { arch::call_on_stack(stack_top, f, arg) }
+}
+
+/// Returns `true` if `addr` is within the emergency stack, or is the end of it.
+pub fn is_emergency_stack(addr: usize) -> bool {
+    // SAFETY: only the address of `EMERGENCY_STACK` is taken
+    let start = unsafe { core::ptr::addr_of!(EMERGENCY_STACK) } as usize;
+    addr.wrapping_sub(start) <= EMERGENCY_STACK_SIZE
+}
+
+/// A signature of a crash, which is the same for every occurrence of it in the same build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CrashId(pub u32);
+
+impl CrashId {
+    /// Returns the ID of a crash at `location`.
+    pub fn from_location(location: &Location<'_>) -> Self {
+        let hash = fnv1a(FNV_OFFSET_BASIS, location.file().as_bytes());
+        let hash = fnv1a(hash, &location.line().to_le_bytes());
+        CrashId(fnv1a(hash, &location.column().to_le_bytes()))
+    }
+}
+
+impl fmt::Display for CrashId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+/// The initial value of a 32-bit FNV-1a hash.
+const FNV_OFFSET_BASIS: u32 = 0x811c_9dc5;
+/// The multiplier of a 32-bit FNV-1a hash.
+const FNV_PRIME: u32 = 0x0100_0193;
+
+/// Continues the 32-bit FNV-1a hash `hash` with `bytes`.
+fn fnv1a(hash: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(FNV_PRIME)
+    })
+}
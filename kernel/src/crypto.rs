@@ -0,0 +1,54 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Cryptographic primitives.
+//!
+//! These are implemented without secret-dependent branches or memory accesses, so their timing
+//! doesn't depend on keys or (except for lengths) data.
+
+mod chacha20;
+mod hmac;
+mod sha256;
+
+pub use chacha20::ChaCha20;
+pub use hmac::HmacSha256;
+pub use sha256::Sha256;
+
+use core::fmt;
+
+/// An error using a cryptographic primitive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A stream cipher has used every block its counter can address, so the key and nonce can't be
+    /// used for any more data.
+    KeystreamExhausted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::KeystreamExhausted => write!(f, "the keystream is exhausted"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// Compares two byte slices in constant time, returning `true` if they are equal.
+///
+/// Only the contents are compared in constant time; slices of different lengths are unequal.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let difference = a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b));
+
+    // SAFETY: `difference` is a valid local variable. The volatile read prevents the compiler from
+    //         turning the fold into an early-exit comparison
+    unsafe { core::ptr::read_volatile(&difference) == 0 }
+}
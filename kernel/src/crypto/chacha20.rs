@@ -0,0 +1,218 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! The ChaCha20 stream cipher, as specified in [RFC 8439].
+//!
+//! [RFC 8439]: https://www.rfc-editor.org/rfc/rfc8439
+
+use super::Error;
+
+/// The ChaCha20 stream cipher, with a 96-bit nonce and 32-bit block counter.
+#[derive(Debug, Clone)]
+pub struct ChaCha20 {
+    state: [u32; 16],
+    /// The current block of keystream.
+    keystream: [u8; Self::BLOCK_LEN],
+    /// The number of bytes of `keystream` which have been used.
+    used: usize,
+    /// Whether the block with counter `u32::MAX` has been generated, so the counter can't be
+    /// incremented again.
+    exhausted: bool,
+}
+
+impl ChaCha20 {
+    /// The length of a key in bytes.
+    pub const KEY_LEN: usize = 32;
+    /// The length of a nonce in bytes.
+    pub const NONCE_LEN: usize = 12;
+    /// The length of a block of keystream in bytes.
+    pub const BLOCK_LEN: usize = 64;
+
+    /// Returns a new cipher using the given key and nonce, starting at block `counter`.
+    pub fn new(key: &[u8; Self::KEY_LEN], nonce: &[u8; Self::NONCE_LEN], counter: u32) -> Self {
+        let mut state = [0; 16];
+        state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+        for (word, bytes) in state[4..12].iter_mut().zip(key.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        state[12] = counter;
+        for (word, bytes) in state[13..].iter_mut().zip(nonce.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+
+        ChaCha20 {
+            state,
+            keystream: [0; Self::BLOCK_LEN],
+            used: Self::BLOCK_LEN,
+            exhausted: false,
+        }
+    }
+
+    /// Encrypts or decrypts `data` in place by XORing it with the keystream.
+    ///
+    /// Returns an error, leaving `data` unchanged, if there isn't enough keystream left before the
+    /// 32-bit block counter would wrap around.
+    pub fn apply_keystream(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        if data.len() as u64 > self.remaining() {
+            return Err(Error::KeystreamExhausted);
+        }
+
+        for byte in data {
+            if self.used == Self::BLOCK_LEN {
+                self.keystream = self.next_block()?;
+                self.used = 0;
+            }
+
+            *byte ^= self.keystream[self.used];
+            self.used += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes of keystream left before the block counter would wrap around.
+    pub fn remaining(&self) -> u64 {
+        let blocks = if self.exhausted {
+            0
+        } else {
+            u64::from(u32::MAX - self.state[12]) + 1
+        };
+        (Self::BLOCK_LEN - self.used) as u64 + blocks * Self::BLOCK_LEN as u64
+    }
+
+    /// Returns the next block of keystream, and increments the block counter.
+    ///
+    /// Returns an error once the block with counter `u32::MAX` has been returned, since reusing a
+    /// counter would reuse keystream.
+    pub fn next_block(&mut self) -> Result<[u8; Self::BLOCK_LEN], Error> {
+        if self.exhausted {
+            return Err(Error::KeystreamExhausted);
+        }
+
+        let mut x = self.state;
+        for _ in 0..10 {
+            // column rounds
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            // diagonal rounds
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+
+        let mut block = [0; Self::BLOCK_LEN];
+        for ((bytes, x), state) in block.chunks_exact_mut(4).zip(x).zip(self.state) {
+            bytes.copy_from_slice(&x.wrapping_add(state).to_le_bytes());
+        }
+
+        match self.state[12].checked_add(1) {
+            Some(counter) => self.state[12] = counter,
+            None => self.exhausted = true,
+        }
+
+        Ok(block)
+    }
+}
+
+/// The ChaCha quarter round.
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The key used by the test vectors in RFC 8439, bytes 0 to 31.
+    fn key() -> [u8; ChaCha20::KEY_LEN] {
+        let mut key = [0; ChaCha20::KEY_LEN];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        key
+    }
+
+    /// RFC 8439, section 2.3.2.
+    #[test]
+    fn block_function() {
+        let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let expected = [
+            0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20,
+            0x71, 0xc4, 0xc7, 0xd1, 0xf4, 0xc7, 0x33, 0xc0, 0x68, 0x03, 0x04, 0x22, 0xaa, 0x9a,
+            0xc3, 0xd4, 0x6c, 0x4e, 0xd2, 0x82, 0x64, 0x46, 0x07, 0x9f, 0xaa, 0x09, 0x14, 0xc2,
+            0xd7, 0x05, 0xd9, 0x8b, 0x02, 0xa2, 0xb5, 0x12, 0x9c, 0xd1, 0xde, 0x16, 0x4e, 0xb9,
+            0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50, 0x3c, 0x4e,
+        ];
+        assert_eq!(ChaCha20::new(&key(), &nonce, 1).next_block(), Ok(expected));
+    }
+
+    /// RFC 8439, section 2.4.2.
+    #[test]
+    fn encryption() {
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let plaintext =
+            *b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+            tip for the future, sunscreen would be it.";
+        let expected = [
+            0x6e, 0x2e, 0x35, 0x9a, 0x25, 0x68, 0xf9, 0x80, 0x41, 0xba, 0x07, 0x28, 0xdd, 0x0d,
+            0x69, 0x81, 0xe9, 0x7e, 0x7a, 0xec, 0x1d, 0x43, 0x60, 0xc2, 0x0a, 0x27, 0xaf, 0xcc,
+            0xfd, 0x9f, 0xae, 0x0b, 0xf9, 0x1b, 0x65, 0xc5, 0x52, 0x47, 0x33, 0xab, 0x8f, 0x59,
+            0x3d, 0xab, 0xcd, 0x62, 0xb3, 0x57, 0x16, 0x39, 0xd6, 0x24, 0xe6, 0x51, 0x52, 0xab,
+            0x8f, 0x53, 0x0c, 0x35, 0x9f, 0x08, 0x61, 0xd8, 0x07, 0xca, 0x0d, 0xbf, 0x50, 0x0d,
+            0x6a, 0x61, 0x56, 0xa3, 0x8e, 0x08, 0x8a, 0x22, 0xb6, 0x5e, 0x52, 0xbc, 0x51, 0x4d,
+            0x16, 0xcc, 0xf8, 0x06, 0x81, 0x8c, 0xe9, 0x1a, 0xb7, 0x79, 0x37, 0x36, 0x5a, 0xf9,
+            0x0b, 0xbf, 0x74, 0xa3, 0x5b, 0xe6, 0xb4, 0x0b, 0x8e, 0xed, 0xf2, 0x78, 0x5e, 0x42,
+            0x87, 0x4d,
+        ];
+
+        let mut data = plaintext;
+        let mut cipher = ChaCha20::new(&key(), &nonce, 1);
+        // in uneven pieces, so that the keystream is carried over between calls
+        let (first, rest) = data.split_at_mut(7);
+        assert_eq!(cipher.apply_keystream(first), Ok(()));
+        assert_eq!(cipher.apply_keystream(rest), Ok(()));
+        assert_eq!(data, expected);
+
+        let mut cipher = ChaCha20::new(&key(), &nonce, 1);
+        assert_eq!(cipher.apply_keystream(&mut data), Ok(()));
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn counter_exhausted() {
+        let mut cipher = ChaCha20::new(&key(), &[0; ChaCha20::NONCE_LEN], u32::MAX - 1);
+        assert_eq!(cipher.remaining(), 2 * ChaCha20::BLOCK_LEN as u64);
+
+        let mut data = [0; ChaCha20::BLOCK_LEN + 10];
+        assert_eq!(cipher.apply_keystream(&mut data), Ok(()));
+        assert_eq!(cipher.remaining(), ChaCha20::BLOCK_LEN as u64 - 10);
+
+        let mut rest = [0; ChaCha20::BLOCK_LEN - 9];
+        assert_eq!(
+            cipher.apply_keystream(&mut rest),
+            Err(Error::KeystreamExhausted)
+        );
+        assert_eq!(rest, [0; ChaCha20::BLOCK_LEN - 9]);
+        assert_eq!(cipher.apply_keystream(&mut rest[1..]), Ok(()));
+        assert_eq!(cipher.remaining(), 0);
+        assert_eq!(cipher.apply_keystream(&mut []), Ok(()));
+        assert_eq!(
+            cipher.apply_keystream(&mut [0]),
+            Err(Error::KeystreamExhausted)
+        );
+        assert_eq!(cipher.next_block(), Err(Error::KeystreamExhausted));
+    }
+}
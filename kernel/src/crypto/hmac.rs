@@ -0,0 +1,114 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! HMAC using SHA-256, as specified in [RFC 2104].
+//!
+//! [RFC 2104]: https://www.rfc-editor.org/rfc/rfc2104
+
+use super::Sha256;
+
+/// An incremental HMAC-SHA-256 message authentication code.
+#[derive(Debug, Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    /// The length of an HMAC-SHA-256 tag in bytes.
+    pub const TAG_LEN: usize = Sha256::DIGEST_LEN;
+
+    /// Returns a new message authentication code using the given key.
+    pub fn new(key: &[u8]) -> Self {
+        let mut block = [0; Sha256::BLOCK_LEN];
+        if key.len() > Sha256::BLOCK_LEN {
+            block[..Sha256::DIGEST_LEN].copy_from_slice(&Sha256::digest(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(&block.map(|b| b ^ 0x36));
+        let mut outer = Sha256::new();
+        outer.update(&block.map(|b| b ^ 0x5c));
+
+        HmacSha256 { inner, outer }
+    }
+
+    /// Returns the tag for `message` using `key`.
+    pub fn mac(key: &[u8], message: &[u8]) -> [u8; Self::TAG_LEN] {
+        let mut mac = Self::new(key);
+        mac.update(message);
+        mac.finalize()
+    }
+
+    /// Adds `data` to the message.
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// Returns the tag for the message.
+    pub fn finalize(self) -> [u8; Self::TAG_LEN] {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finalize());
+        outer.finalize()
+    }
+
+    /// Returns `true` if `tag` is the correct tag for the message, comparing in constant time.
+    pub fn verify(self, tag: &[u8]) -> bool {
+        super::constant_time_eq(&self.finalize(), tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 4231, test case 1.
+    #[test]
+    fn short_key() {
+        let expected = [
+            0xb0, 0x34, 0x4c, 0x61, 0xd8, 0xdb, 0x38, 0x53, 0x5c, 0xa8, 0xaf, 0xce, 0xaf, 0x0b,
+            0xf1, 0x2b, 0x88, 0x1d, 0xc2, 0x00, 0xc9, 0x83, 0x3d, 0xa7, 0x26, 0xe9, 0x37, 0x6c,
+            0x2e, 0x32, 0xcf, 0xf7,
+        ];
+        assert_eq!(HmacSha256::mac(&[0x0b; 20], b"Hi There"), expected);
+    }
+
+    /// RFC 4231, test case 6, where the key is longer than a block, so it's hashed first.
+    #[test]
+    fn long_key() {
+        let key = [0xaa; 131];
+        let message = b"Test Using Larger Than Block-Size Key - Hash Key First";
+        let expected = [
+            0x60, 0xe4, 0x31, 0x59, 0x1e, 0xe0, 0xb6, 0x7f, 0x0d, 0x8a, 0x26, 0xaa, 0xcb, 0xf5,
+            0xb7, 0x7f, 0x8e, 0x0b, 0xc6, 0x21, 0x37, 0x28, 0xc5, 0x14, 0x05, 0x46, 0x04, 0x0f,
+            0x0e, 0xe3, 0x7f, 0x54,
+        ];
+        assert_eq!(HmacSha256::mac(&key, message), expected);
+
+        let (first, rest) = message.split_at(9);
+        let mut mac = HmacSha256::new(&key);
+        mac.update(first);
+        mac.update(rest);
+        assert!(mac.verify(&expected));
+    }
+
+    #[test]
+    fn verify_rejects_wrong_tags() {
+        let mut tag = HmacSha256::mac(&[0x0b; 20], b"Hi There");
+        let mac = || {
+            let mut mac = HmacSha256::new(&[0x0b; 20]);
+            mac.update(b"Hi There");
+            mac
+        };
+        assert!(mac().verify(&tag));
+        assert!(!mac().verify(&tag[..16]));
+        tag[31] ^= 1;
+        assert!(!mac().verify(&tag));
+    }
+}
@@ -0,0 +1,47 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! The kernel's conventions for errors.
+//!
+//! Each subsystem reports failures with its own `Error` enum, which derives `Debug`, `Clone`,
+//! `Copy`, `PartialEq` and `Eq`, and implements [`Display`](fmt::Display) with a short lowercase
+//! message, without trailing punctuation, that makes sense on its own. Functions which can fail
+//! return `Result`, rather than panicking or returning `bool` or `Option`, so that the caller
+//! decides whether a failure is fatal.
+//!
+//! When a failure in one subsystem causes a failure in another, the outer error keeps the inner
+//! one as its [`source`](Error::source) instead of repeating its message, and says what was being
+//! attempted. [`Report`] displays an error followed by its chain of sources, such as
+//! `"cannot make the IDT read-only: page at 0x1000 is part of a huge page"`.
+
+use core::fmt;
+
+/// An error which may have been caused by another error.
+///
+/// This is the kernel's equivalent of `std::error::Error`.
+pub trait Error: fmt::Debug + fmt::Display {
+    /// Returns the error which caused this one, if any.
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+/// Displays an error followed by each of its sources, separated by colons.
+#[derive(Debug, Clone, Copy)]
+pub struct Report<'a>(pub &'a dyn Error);
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(err) = source {
+            write!(f, ": {err}")?;
+            source = err.source();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Verification of the initial ramdisk.
+//!
+//! If the `ALEPH_INITRD_KEY` environment variable is set to a hex-encoded key (of at most 64
+//! bytes) when the kernel is built, the key is embedded in the kernel, and [`verify`] checks the
+//! ramdisk's HMAC-SHA-256 tag against the hex-encoded tag given by the [`INITRD_HMAC`] parameter,
+//! normally set in the BOOTBOOT environment. The tag can be computed with
+//! `openssl dgst -sha256 -mac HMAC -macopt hexkey:$ALEPH_INITRD_KEY <initrd>`.
+//!
+//! During development, verification can be skipped by setting the [`INITRD_VERIFY`] parameter to
+//! `off`.
+
+use core::fmt;
+
+use crate::{bootboot::BOOTBOOT, crypto::HmacSha256, param};
+
+param! {
+    /// Whether to verify the initrd, if a key was embedded at build time.
+    pub static INITRD_VERIFY: bool = true, name = "initrd_verify";
+
+    /// The hex-encoded HMAC-SHA-256 tag of the initrd.
+    pub static INITRD_HMAC: &'static str = "", name = "initrd_hmac";
+}
+
+/// The hex-encoded key embedded at build time, if any.
+const KEY: Option<&str> = option_env!("ALEPH_INITRD_KEY");
+
+/// The maximum length of the key in bytes.
+const MAX_KEY_LEN: usize = 64;
+
+/// The outcome of a successful call to [`verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// The ramdisk's tag was verified.
+    Verified,
+    /// No key was embedded when the kernel was built, so nothing was verified.
+    NoKey,
+    /// Verification was skipped because [`INITRD_VERIFY`] is `false`.
+    Skipped,
+}
+
+/// An error which caused verification of the ramdisk to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The embedded key is not valid hex, or is too long.
+    InvalidKey,
+    /// [`INITRD_HMAC`] is not set.
+    MissingTag,
+    /// [`INITRD_HMAC`] is not valid hex, or has the wrong length.
+    InvalidTag,
+    /// The tag doesn't match the ramdisk.
+    Mismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidKey => write!(f, "the embedded initrd key is invalid"),
+            Error::MissingTag => write!(f, "initrd_hmac is not set"),
+            Error::InvalidTag => write!(f, "initrd_hmac is not a valid HMAC-SHA-256 tag"),
+            Error::Mismatch => write!(f, "the initrd does not match initrd_hmac"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// Verifies the initial ramdisk as described in the [module documentation](self).
+///
+/// This must be called before anything from the ramdisk is used.
+pub fn verify() -> Result<Verification, Error> {
+    let key = match KEY {
+        Some(key) => key,
+        None => return Ok(Verification::NoKey),
+    };
+    if !INITRD_VERIFY.get() {
+        return Ok(Verification::Skipped);
+    }
+
+    let mut key_bytes = [0; MAX_KEY_LEN];
+    let key = decode_hex(key, &mut key_bytes).ok_or(Error::InvalidKey)?;
+
+    let mut tag = [0; HmacSha256::TAG_LEN];
+    let tag_hex = INITRD_HMAC.get();
+    if tag_hex.is_empty() {
+        return Err(Error::MissingTag);
+    }
+    match decode_hex(tag_hex, &mut tag) {
+        Some(tag) if tag.len() == HmacSha256::TAG_LEN => {}
+        _ => return Err(Error::InvalidTag),
+    }
+
+    let mut mac = HmacSha256::new(key);
+    mac.update(BOOTBOOT.initrd());
+    if mac.verify(&tag) {
+        Ok(Verification::Verified)
+    } else {
+        Err(Error::Mismatch)
+    }
+}
+
+/// Decodes a hex string into `buffer`, returning the decoded bytes, or `None` if `hex` is not
+/// valid or doesn't fit in `buffer`.
+fn decode_hex<'a>(hex: &str, buffer: &'a mut [u8]) -> Option<&'a [u8]> {
+    let hex = hex.as_bytes();
+    if hex.len() % 2 != 0 || hex.len() / 2 > buffer.len() {
+        return None;
+    }
+
+    for (byte, digits) in buffer.iter_mut().zip(hex.chunks_exact(2)) {
+        let high = (digits[0] as char).to_digit(16)?;
+        let low = (digits[1] as char).to_digit(16)?;
+        *byte = (high << 4 | low) as u8;
+    }
+
+    Some(&buffer[..hex.len() / 2])
+}
@@ -0,0 +1,139 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Input events, shared by all input device drivers.
+//!
+//! Drivers translate their devices' reports into [`InputEvent`]s, so that consumers don't need to
+//! know what kind of device an event came from. The layout of `InputEvent`, and the numbering of
+//! [`KeyCode`]s, which follows Linux's evdev, are a stable ABI, so that events can later be handed
+//! to userspace unchanged.
+//!
+//! [`scancode`] decodes PS/2 keyboard scancodes into key events.
+
+use core::fmt;
+
+/// An event from an input device.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    /// When the event occurred, in microseconds since boot, as reported by the driver.
+    pub timestamp: u64,
+    /// The kind of event.
+    pub kind: EventKind,
+    /// What the event is about, such as a [`KeyCode`] for key events, depending on `kind`.
+    pub code: u16,
+    /// The event's value, such as [`InputEvent::PRESSED`] for key events, depending on `kind`.
+    pub value: i32,
+}
+
+impl InputEvent {
+    /// The value of a key event for a key which was released.
+    pub const RELEASED: i32 = 0;
+    /// The value of a key event for a key which was pressed.
+    pub const PRESSED: i32 = 1;
+    /// The value of a key event for a key which is repeating because it is held down.
+    pub const REPEATED: i32 = 2;
+
+    /// Returns a key event for `key`, with value `value`.
+    pub const fn key(timestamp: u64, key: KeyCode, value: i32) -> Self {
+        InputEvent {
+            timestamp,
+            kind: EventKind::Key,
+            code: key.0,
+            value,
+        }
+    }
+
+    /// Returns the key code of a key event.
+    pub fn key_code(&self) -> Option<KeyCode> {
+        (self.kind == EventKind::Key).then_some(KeyCode(self.code))
+    }
+}
+
+/// The kind of an [`InputEvent`].
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// Marks the end of a group of events which happened together.
+    Sync = 0,
+    /// A key or button was pressed, released or repeated.
+    Key = 1,
+    /// A relative axis, such as a mouse's motion, changed.
+    Relative = 2,
+    /// An absolute axis, such as a touchscreen's position, changed.
+    Absolute = 3,
+}
+
+/// A key, numbered as in Linux's evdev.
+///
+/// The codes from `1` to `88` are the same as the PS/2 scancode set 1 make codes.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyCode(pub u16);
+
+impl KeyCode {
+    /// The escape key.
+    pub const ESC: KeyCode = KeyCode(1);
+    /// The Q key.
+    pub const Q: KeyCode = KeyCode(16);
+    /// The enter key.
+    pub const ENTER: KeyCode = KeyCode(28);
+    /// The left control key.
+    pub const LEFT_CTRL: KeyCode = KeyCode(29);
+    /// The left shift key.
+    pub const LEFT_SHIFT: KeyCode = KeyCode(42);
+    /// The right shift key.
+    pub const RIGHT_SHIFT: KeyCode = KeyCode(54);
+    /// The left alt key.
+    pub const LEFT_ALT: KeyCode = KeyCode(56);
+    /// The enter key on the keypad.
+    pub const KP_ENTER: KeyCode = KeyCode(96);
+    /// The right control key.
+    pub const RIGHT_CTRL: KeyCode = KeyCode(97);
+    /// The slash key on the keypad.
+    pub const KP_SLASH: KeyCode = KeyCode(98);
+    /// The print screen key.
+    pub const SYSRQ: KeyCode = KeyCode(99);
+    /// The right alt key.
+    pub const RIGHT_ALT: KeyCode = KeyCode(100);
+    /// The home key.
+    pub const HOME: KeyCode = KeyCode(102);
+    /// The up arrow key.
+    pub const UP: KeyCode = KeyCode(103);
+    /// The page up key.
+    pub const PAGE_UP: KeyCode = KeyCode(104);
+    /// The left arrow key.
+    pub const LEFT: KeyCode = KeyCode(105);
+    /// The right arrow key.
+    pub const RIGHT: KeyCode = KeyCode(106);
+    /// The end key.
+    pub const END: KeyCode = KeyCode(107);
+    /// The down arrow key.
+    pub const DOWN: KeyCode = KeyCode(108);
+    /// The page down key.
+    pub const PAGE_DOWN: KeyCode = KeyCode(109);
+    /// The insert key.
+    pub const INSERT: KeyCode = KeyCode(110);
+    /// The delete key.
+    pub const DELETE: KeyCode = KeyCode(111);
+    /// The pause key.
+    pub const PAUSE: KeyCode = KeyCode(119);
+    /// The left logo key.
+    pub const LEFT_META: KeyCode = KeyCode(125);
+    /// The right logo key.
+    pub const RIGHT_META: KeyCode = KeyCode(126);
+    /// The menu key.
+    pub const COMPOSE: KeyCode = KeyCode(127);
+}
+
+impl fmt::Display for KeyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "key {}", self.0)
+    }
+}
+
+pub mod scancode;
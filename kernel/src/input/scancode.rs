@@ -0,0 +1,292 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Decoding of PS/2 keyboard scancodes.
+//!
+//! [`Set1Decoder`] turns scancode set 1 bytes, which is what the PS/2 controller produces with
+//! translation enabled, into [`InputEvent`]s, one byte at a time, so that it can be fed directly
+//! from the keyboard's interrupt handler.
+
+use crate::util::Bitmap;
+
+use super::{InputEvent, KeyCode};
+
+/// The prefix of extended scancodes.
+const EXTENDED: u8 = 0xe0;
+/// The prefix of the pause key's scancode, which is the only one using it.
+const PAUSE: u8 = 0xe1;
+/// The number of bytes following [`PAUSE`] in the pause key's scancode.
+const PAUSE_LEN: u8 = 5;
+/// Set in the scancode of a key release.
+const BREAK: u8 = 0x80;
+/// The extended left shift scancode, which some keyboards send around other extended keys.
+const FAKE_SHIFT: u8 = 0x2a;
+/// The extended right shift scancode, which some keyboards send around other extended keys.
+const FAKE_RIGHT_SHIFT: u8 = 0x36;
+/// The largest non-extended scancode with a key code.
+const LAST_BASIC: u8 = 0x58;
+
+/// Where the decoder is within a multi-byte scancode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// At the start of a scancode.
+    Start,
+    /// After the extended prefix.
+    Extended,
+    /// In the pause key's scancode, with the given number of bytes left.
+    Pause(u8),
+}
+
+/// A decoder for scancode set 1, which tracks which keys are held down to report repeats.
+#[derive(Debug, Clone)]
+pub struct Set1Decoder {
+    state: State,
+    pressed: Bitmap<4>,
+}
+
+impl Set1Decoder {
+    /// Returns a decoder at the start of a scancode, with no keys held down.
+    pub const fn new() -> Self {
+        Set1Decoder {
+            state: State::Start,
+            pressed: Bitmap::new(),
+        }
+    }
+
+    /// Decodes the next byte from the keyboard, which happened at `timestamp`, returning a key
+    /// event if the byte completes a scancode for a known key.
+    ///
+    /// The pause key's scancode is sent only when it's pressed, so it is reported as pressed, and
+    /// never as held down or released.
+    pub fn decode(&mut self, byte: u8, timestamp: u64) -> Option<InputEvent> {
+        let (key, released) = match (self.state, byte) {
+            (State::Start, EXTENDED) => {
+                self.state = State::Extended;
+                return None;
+            }
+            (State::Start, PAUSE) => {
+                self.state = State::Pause(PAUSE_LEN);
+                return None;
+            }
+            (State::Start, _) => (basic_key(byte & !BREAK)?, byte & BREAK != 0),
+            (State::Extended, _) => {
+                self.state = State::Start;
+                (extended_key(byte & !BREAK)?, byte & BREAK != 0)
+            }
+            (State::Pause(1), _) => {
+                // the pause key has no release scancode, so only the press is reported, and it
+                // isn't recorded as held down
+                self.state = State::Start;
+                return Some(InputEvent::key(
+                    timestamp,
+                    KeyCode::PAUSE,
+                    InputEvent::PRESSED,
+                ));
+            }
+            (State::Pause(left), _) => {
+                self.state = State::Pause(left - 1);
+                return None;
+            }
+        };
+
+        let was_pressed = self.pressed.set(key.0.into(), !released);
+        let value = match (released, was_pressed) {
+            (true, _) => InputEvent::RELEASED,
+            (false, false) => InputEvent::PRESSED,
+            (false, true) => InputEvent::REPEATED,
+        };
+        Some(InputEvent::key(timestamp, key, value))
+    }
+
+    /// Returns `true` if `key` is held down.
+    pub fn is_pressed(&self, key: KeyCode) -> bool {
+        usize::from(key.0) < Bitmap::<4>::BITS && self.pressed.get(key.0.into())
+    }
+
+    /// Forgets any partial scancode and any keys held down, for instance after the keyboard is
+    /// reset.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for Set1Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the key for non-extended make code `code`.
+fn basic_key(code: u8) -> Option<KeyCode> {
+    // non-extended make codes match their key codes
+    (0x01..=LAST_BASIC)
+        .contains(&code)
+        .then(|| KeyCode(code.into()))
+}
+
+/// Returns the key for extended make code `code`.
+fn extended_key(code: u8) -> Option<KeyCode> {
+    Some(match code {
+        0x1c => KeyCode::KP_ENTER,
+        0x1d => KeyCode::RIGHT_CTRL,
+        FAKE_SHIFT | FAKE_RIGHT_SHIFT => return None,
+        0x35 => KeyCode::KP_SLASH,
+        0x37 => KeyCode::SYSRQ,
+        0x38 => KeyCode::RIGHT_ALT,
+        0x47 => KeyCode::HOME,
+        0x48 => KeyCode::UP,
+        0x49 => KeyCode::PAGE_UP,
+        0x4b => KeyCode::LEFT,
+        0x4d => KeyCode::RIGHT,
+        0x4f => KeyCode::END,
+        0x50 => KeyCode::DOWN,
+        0x51 => KeyCode::PAGE_DOWN,
+        0x52 => KeyCode::INSERT,
+        0x53 => KeyCode::DELETE,
+        0x5b => KeyCode::LEFT_META,
+        0x5c => KeyCode::RIGHT_META,
+        0x5d => KeyCode::COMPOSE,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A key and what happened to it.
+    type Event = (KeyCode, i32);
+
+    /// Feeds `bytes` to `decoder`, returning the events they produce.
+    fn feed(decoder: &mut Set1Decoder, bytes: &[u8]) -> [Option<Event>; 8] {
+        let mut events = [None; 8];
+        let mut n = 0;
+        for &byte in bytes {
+            if let Some(event) = decoder.decode(byte, 0) {
+                events[n] = Some((event.key_code().unwrap(), event.value));
+                n += 1;
+            }
+        }
+        events
+    }
+
+    /// Returns the first events of `events`, followed by `None`s.
+    fn events(events: &[Event]) -> [Option<Event>; 8] {
+        let mut all = [None; 8];
+        for (slot, &event) in all.iter_mut().zip(events) {
+            *slot = Some(event);
+        }
+        all
+    }
+
+    const PRESSED: i32 = InputEvent::PRESSED;
+    const RELEASED: i32 = InputEvent::RELEASED;
+    const REPEATED: i32 = InputEvent::REPEATED;
+
+    #[test]
+    fn sequences() {
+        let table: &[(&[u8], &[Event])] = &[
+            (
+                &[0x01, 0x81],
+                &[(KeyCode::ESC, PRESSED), (KeyCode::ESC, RELEASED)],
+            ),
+            (
+                &[0x10, 0x10, 0x90],
+                &[
+                    (KeyCode::Q, PRESSED),
+                    (KeyCode::Q, REPEATED),
+                    (KeyCode::Q, RELEASED),
+                ],
+            ),
+            (
+                &[0x1d, 0xe0, 0x1d, 0xe0, 0x9d, 0x9d],
+                &[
+                    (KeyCode::LEFT_CTRL, PRESSED),
+                    (KeyCode::RIGHT_CTRL, PRESSED),
+                    (KeyCode::RIGHT_CTRL, RELEASED),
+                    (KeyCode::LEFT_CTRL, RELEASED),
+                ],
+            ),
+            (
+                &[0xe0, 0x48, 0xe0, 0x48, 0xe0, 0xc8],
+                &[
+                    (KeyCode::UP, PRESSED),
+                    (KeyCode::UP, REPEATED),
+                    (KeyCode::UP, RELEASED),
+                ],
+            ),
+            (
+                &[0xe0, 0x1c, 0xe0, 0x9c, 0x1c, 0x9c],
+                &[
+                    (KeyCode::KP_ENTER, PRESSED),
+                    (KeyCode::KP_ENTER, RELEASED),
+                    (KeyCode::ENTER, PRESSED),
+                    (KeyCode::ENTER, RELEASED),
+                ],
+            ),
+            // print screen, with the fake shifts some keyboards send around it
+            (
+                &[0xe0, 0x2a, 0xe0, 0x37, 0xe0, 0xb7, 0xe0, 0xaa],
+                &[(KeyCode::SYSRQ, PRESSED), (KeyCode::SYSRQ, RELEASED)],
+            ),
+            (&[0xe0, 0x36, 0xe0, 0xb6], &[]),
+            // the bytes after E1 include what would otherwise be the left control key
+            (
+                &[0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5],
+                &[(KeyCode::PAUSE, PRESSED)],
+            ),
+            (
+                &[0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5, 0x01],
+                &[(KeyCode::PAUSE, PRESSED), (KeyCode::ESC, PRESSED)],
+            ),
+            // unknown codes are ignored, without losing track of the next scancode
+            (&[0x00, 0x59, 0xd9, 0x7f, 0xff], &[]),
+            (&[0xe0, 0x01, 0x01], &[(KeyCode::ESC, PRESSED)]),
+            (&[0xe0, 0x81, 0x10], &[(KeyCode::Q, PRESSED)]),
+        ];
+
+        for &(bytes, expected) in table {
+            let mut decoder = Set1Decoder::new();
+            assert_eq!(
+                feed(&mut decoder, bytes),
+                events(expected),
+                "bytes {bytes:x?}"
+            );
+        }
+    }
+
+    #[test]
+    fn held_keys() {
+        let mut decoder = Set1Decoder::new();
+        feed(
+            &mut decoder,
+            &[0x2a, 0xe0, 0x38, 0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5],
+        );
+        assert!(decoder.is_pressed(KeyCode::LEFT_SHIFT));
+        assert!(decoder.is_pressed(KeyCode::RIGHT_ALT));
+        assert!(!decoder.is_pressed(KeyCode::LEFT_ALT));
+        assert!(!decoder.is_pressed(KeyCode::PAUSE));
+        assert!(!decoder.is_pressed(KeyCode::LEFT_CTRL));
+        assert!(!decoder.is_pressed(KeyCode(u16::MAX)));
+
+        feed(&mut decoder, &[0xaa]);
+        assert!(!decoder.is_pressed(KeyCode::LEFT_SHIFT));
+    }
+
+    #[test]
+    fn reset() {
+        let mut decoder = Set1Decoder::new();
+        feed(&mut decoder, &[0x01, 0xe0]);
+        decoder.reset();
+        assert!(!decoder.is_pressed(KeyCode::ESC));
+        // after a reset, 0x1d is no longer the second byte of an extended scancode
+        assert_eq!(
+            feed(&mut decoder, &[0x1d]),
+            events(&[(KeyCode::LEFT_CTRL, PRESSED)])
+        );
+    }
+}
@@ -16,8 +16,21 @@
 #![warn(clippy::todo)]
 #![warn(clippy::undocumented_unsafe_blocks)]
 #![feature(inline_const)]
+#![feature(variant_count)]
 #![cfg_attr(target_arch = "x86_64", feature(asm_const))]
 #![cfg_attr(target_arch = "x86_64", feature(naked_functions))]
 
+pub mod acpi;
 pub mod arch;
 pub mod bootboot;
+pub mod build_id;
+pub mod crash;
+pub mod crypto;
+pub mod error;
+pub mod initrd;
+pub mod input;
+pub mod mem;
+pub mod memtest;
+pub mod param;
+pub mod quarantine;
+pub mod util;
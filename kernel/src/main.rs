@@ -29,7 +29,12 @@ use tinytga::DynamicTga;
 
 #[cfg(not(test))]
 mod panic_handler;
-use aleph_naught::bootboot::Console;
+use aleph_naught::{
+    bootboot::Console,
+    error::Report,
+    initrd::{self, Verification},
+    mem, memtest, param, quarantine,
+};
 
 /// The kernel's entry point.
 ///
@@ -41,6 +46,39 @@ use aleph_naught::bootboot::Console;
 fn main() -> ! {
     // initialize the logger
     Console::init().expect("init logger");
+    log::info!("{}", aleph_naught::build_id::BUILD_ID);
+
+    // read the kernel parameters before anything depends on them
+    param::init();
+
+    // verify the initrd before anything else uses it
+    match initrd::verify() {
+        Ok(Verification::Verified) => log::info!("initrd verified"),
+        Ok(Verification::NoKey) => log::info!("initrd not verified: no key embedded at build time"),
+        Ok(Verification::Skipped) => log::warn!("initrd verification skipped"),
+        Err(err) => panic!("initrd verification failed: {err}"),
+    }
+
+    mem::init();
+    quarantine::init();
+    if memtest::MEMTEST.get() {
+        let report = memtest::run();
+        log::info!(
+            "memory test: {} frames tested, {} failed",
+            report.tested,
+            report.failed
+        );
+        if report.not_quarantined != 0 {
+            log::error!(
+                "{} bad frames could not be quarantined",
+                report.not_quarantined
+            );
+        }
+    }
+    let bad_memory = quarantine::ranges();
+    if !bad_memory.is_empty() {
+        log::warn!("quarantined memory:\n{bad_memory}");
+    }
 
     // set the cursor position after the image and custom text which are displayed below
     Console::get().set_cursor(Point::new(0, 11));
@@ -62,7 +100,16 @@ fn main() -> ! {
     line.draw(Console::get().deref_mut())
         .expect("printing text");
 
-    aleph_naught::arch::init();
+    let degraded = aleph_naught::arch::init();
+    for err in degraded.errors() {
+        log::warn!("degraded: {}", Report(err));
+    }
+
+    // initialization is finished; the `ud2` below ends in a panic, so send the screenshot first
+    #[cfg(target_arch = "x86_64")]
+    if aleph_naught::bootboot::SCREENSHOT.get() {
+        Console::send_screenshot();
+    }
 
     #[cfg(target_arch = "x86_64")]
     // SAFETY: the `ud2` instruction cannot trigger undefined behavior
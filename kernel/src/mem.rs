@@ -0,0 +1,563 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Management of the kernel's virtual address space.
+//!
+//! A [`VirtualRegionMap`] records which ranges of an address space are in use, and what for, and
+//! hands out ranges which don't overlap any of them. [`KERNEL_SPACE`] is the map of the upper half
+//! of the address space, which [`init`] populates with the fixed regions set up by the loader and
+//! the linker script, so that nothing else is placed over them.
+//!
+//! The map only tracks addresses. Mapping pages in a region is up to its owner.
+//!
+//! Physical memory which must never be allocated, such as the kernel image, the initrd and the
+//! ACPI tables, is recorded with [`reserve_physical`], and is never returned by
+//! [`Bootboot::free_frames`](crate::bootboot::Bootboot::free_frames), so the frame allocator never
+//! hands it out. [`init`] reserves everything the loader and the firmware set up.
+//!
+//! [`dma_alloc`] allocates physically contiguous buffers for devices to access directly.
+
+use core::{fmt, ops::Range};
+
+use spin::Mutex;
+
+use crate::{
+    acpi,
+    bootboot::{BOOTBOOT, BOOTBOOT_SIZE, ENVIRONMENT_SIZE, INIT_STACK_SIZE},
+};
+
+/// The maximum number of regions in [`KERNEL_SPACE`].
+pub const KERNEL_SPACE_CAPACITY: usize = 32;
+/// The maximum number of reserved ranges of physical memory.
+pub const MAX_RESERVED: usize = 64;
+
+/// The start of the upper half of the address space.
+const UPPER_HALF: u64 = 0xffff_8000_0000_0000;
+/// The number of boot stacks reserved at the top of the address space, one for each possible local
+/// APIC ID.
+const BOOT_STACKS: u64 = 256;
+
+/// The regions of the kernel's half of the address space.
+///
+/// The boot stacks at the very top aren't included in the map's range, since its end would be
+/// `2^64`, but they're never handed out either.
+pub static KERNEL_SPACE: Mutex<VirtualRegionMap<KERNEL_SPACE_CAPACITY>> = Mutex::new(
+    VirtualRegionMap::new(UPPER_HALF..0u64.wrapping_sub(BOOT_STACKS * INIT_STACK_SIZE)),
+);
+
+/// The reserved ranges of physical memory, with empty ranges unused.
+static RESERVED: Mutex<[Region; MAX_RESERVED]> =
+    Mutex::new([const { Region::EMPTY }; MAX_RESERVED]);
+
+/// An error changing a [`VirtualRegionMap`], or allocating memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The range is empty, or extends outside the address space.
+    OutOfRange,
+    /// The range overlaps an existing region.
+    Overlaps,
+    /// There are no free ranges large enough.
+    NoSpace,
+    /// The map has no room for any more regions.
+    Full,
+    /// There is no region at the given address.
+    NotFound(u64),
+    /// There isn't enough free physical memory.
+    OutOfMemory,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::OutOfRange => write!(f, "the range is outside the address space"),
+            Error::Overlaps => write!(f, "the range overlaps an existing region"),
+            Error::NoSpace => write!(f, "no free range is large enough"),
+            Error::Full => write!(f, "too many regions"),
+            Error::NotFound(addr) => write!(f, "no region starts at {addr:#x}"),
+            Error::OutOfMemory => write!(f, "out of physical memory"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// A range of addresses in use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    /// The addresses in the region.
+    pub range: Range<u64>,
+    /// What the region is used for, such as `"kernel heap"`.
+    pub name: &'static str,
+}
+
+impl Region {
+    /// An unused entry.
+    const EMPTY: Self = Region {
+        range: 0..0,
+        name: "",
+    };
+}
+
+/// The regions in use in an address space, up to `N` of them.
+#[derive(Clone)]
+pub struct VirtualRegionMap<const N: usize> {
+    space: Range<u64>,
+    /// The regions, sorted by address.
+    regions: [Region; N],
+    len: usize,
+}
+
+impl<const N: usize> VirtualRegionMap<N> {
+    /// Returns a map of `space`, with no regions in use.
+    pub const fn new(space: Range<u64>) -> Self {
+        VirtualRegionMap {
+            space,
+            regions: [const { Region::EMPTY }; N],
+            len: 0,
+        }
+    }
+
+    /// Returns the addresses the map covers.
+    pub fn space(&self) -> Range<u64> {
+        self.space.clone()
+    }
+
+    /// Returns the regions in use, in order of address.
+    pub fn iter(&self) -> core::slice::Iter<'_, Region> {
+        self.regions[..self.len].iter()
+    }
+
+    /// Returns the region containing `addr`, if any.
+    pub fn find(&self, addr: u64) -> Option<&Region> {
+        self.iter().find(|region| region.range.contains(&addr))
+    }
+
+    /// Marks `range` as in use for `name`.
+    pub fn reserve(&mut self, range: Range<u64>, name: &'static str) -> Result<(), Error> {
+        if range.is_empty() || range.start < self.space.start || range.end > self.space.end {
+            return Err(Error::OutOfRange);
+        }
+        let index =
+            self.regions[..self.len].partition_point(|region| region.range.end <= range.start);
+        if self.regions[..self.len]
+            .get(index)
+            .is_some_and(|next| next.range.start < range.end)
+        {
+            return Err(Error::Overlaps);
+        }
+        self.insert(index, Region { range, name })
+    }
+
+    /// Finds a free range of `size` bytes, starting at a multiple of `align`, and marks it as in
+    /// use for `name`. Returns the range.
+    ///
+    /// # Panics
+    /// Panics if `align` isn't a power of two.
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        align: u64,
+        name: &'static str,
+    ) -> Result<Range<u64>, Error> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        if size == 0 {
+            return Err(Error::OutOfRange);
+        }
+
+        let mut start = self.space.start;
+        for index in 0..=self.len {
+            let end = self.regions[..self.len]
+                .get(index)
+                .map_or(self.space.end, |next| next.range.start);
+            let candidate = start
+                .checked_add(align - 1)
+                .map(|start| start & !(align - 1))
+                .and_then(|start| Some(start..start.checked_add(size)?));
+            if let Some(range) = candidate.filter(|range| range.end <= end) {
+                self.insert(
+                    index,
+                    Region {
+                        range: range.clone(),
+                        name,
+                    },
+                )?;
+                return Ok(range);
+            }
+            if let Some(next) = self.regions[..self.len].get(index) {
+                start = next.range.end;
+            }
+        }
+        Err(Error::NoSpace)
+    }
+
+    /// Removes the region starting at `start`, and returns it.
+    pub fn release(&mut self, start: u64) -> Result<Region, Error> {
+        let index = self.regions[..self.len]
+            .iter()
+            .position(|region| region.range.start == start)
+            .ok_or(Error::NotFound(start))?;
+        let region = core::mem::replace(&mut self.regions[index], Region::EMPTY);
+        self.regions[index..self.len].rotate_left(1);
+        self.len -= 1;
+        Ok(region)
+    }
+
+    /// Inserts `region` at `index`, which keeps the regions sorted.
+    fn insert(&mut self, index: usize, region: Region) -> Result<(), Error> {
+        if self.len == N {
+            return Err(Error::Full);
+        }
+        self.regions[index..=self.len].rotate_right(1);
+        self.regions[index] = region;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+impl<const N: usize> fmt::Debug for VirtualRegionMap<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VirtualRegionMap")
+            .field("space", &self.space)
+            .field("regions", &&self.regions[..self.len])
+            .finish()
+    }
+}
+
+impl<const N: usize> fmt::Display for VirtualRegionMap<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, region) in self.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{:#018x}-{:#018x} {}",
+                region.range.start, region.range.end, region.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Records that the physical addresses in `range` are used for `name`, so they are never allocated.
+///
+/// Frames in ranges reserved after the frame allocator is first used may already have been
+/// allocated, so memory set up before the kernel starts is reserved by [`init`].
+pub fn reserve_physical(range: Range<u64>, name: &'static str) -> Result<(), Error> {
+    if range.is_empty() {
+        return Ok(());
+    }
+    let mut reserved = RESERVED.lock();
+    let slot = reserved
+        .iter_mut()
+        .find(|region| region.range.is_empty())
+        .ok_or(Error::Full)?;
+    *slot = Region { range, name };
+    Ok(())
+}
+
+/// Returns `true` if any physical address in `range` is [reserved](reserve_physical).
+pub fn is_reserved(range: Range<u64>) -> bool {
+    RESERVED
+        .lock()
+        .iter()
+        .any(|region| region.range.start < range.end && range.start < region.range.end)
+}
+
+/// Returns the virtual addresses of the kernel image, from the start of the page the loader placed
+/// it at to the end of `.bss`.
+pub fn kernel_image() -> Range<u64> {
+    extern "C" {
+        static __text_start: [u8; 0];
+        static __bss_end: [u8; 0];
+    }
+
+    // SAFETY: only the addresses of the linker symbols are taken
+    let (text_start, bss_end) =
+        unsafe { (__text_start.as_ptr() as u64, __bss_end.as_ptr() as u64) };
+    // the kernel is loaded at the start of its page, before the headers which precede `.text`
+    (text_start & !0xfff)..bss_end
+}
+
+/// Returns the ranges of physical memory which the pages overlapping the virtual addresses in
+/// `range` are mapped to, merging pages which are physically contiguous. Unmapped pages are
+/// skipped.
+#[cfg(target_arch = "x86_64")]
+pub fn physical_ranges(range: Range<u64>) -> impl Iterator<Item = Range<u64>> {
+    use crate::arch::paging::{self, PAGE_SIZE};
+    use x86_64::VirtAddr;
+
+    let mut frames = (range.start & !(PAGE_SIZE - 1)..range.end)
+        .step_by(PAGE_SIZE as usize)
+        .filter_map(|page| paging::translate(VirtAddr::new(page)))
+        .map(|addr| addr.as_u64() & !(PAGE_SIZE - 1))
+        .peekable();
+    core::iter::from_fn(move || {
+        let start = frames.next()?;
+        let mut end = start + PAGE_SIZE;
+        while frames.next_if_eq(&end).is_some() {
+            end += PAGE_SIZE;
+        }
+        Some(start..end)
+    })
+}
+
+/// Reserves the regions of [`KERNEL_SPACE`] which the loader and the linker script set up: the
+/// MMIO and framebuffer windows, the BOOTBOOT information structure and environment, and the
+/// kernel image. Also [reserves](reserve_physical) the physical memory of the initrd, the
+/// framebuffer and the ACPI tables, and on `x86_64`, of the BOOTBOOT information structure,
+/// environment and kernel image, and normalizes and validates the loader's memory map.
+///
+/// # Panics
+/// Panics if the loader's memory map is inconsistent.
+pub fn init() {
+    // the memory map is validated with the page tables on `x86_64`, so this must happen before the
+    // frame allocator is first used, which can be while the page tables are locked
+    crate::bootboot::normalized_memory_map();
+
+    extern "C" {
+        #[link_name = "mmio"]
+        static MMIO_START: [u8; 0];
+        #[link_name = "fb"]
+        static FRAMEBUFFER_START: [u8; 0];
+        #[link_name = "bootboot"]
+        static BOOTBOOT_START: [u8; 0];
+        #[link_name = "environment"]
+        static ENVIRONMENT_START: [u8; 0];
+    }
+
+    // SAFETY: only the addresses of the linker symbols are taken
+    let (mmio, framebuffer, bootboot, environment) = unsafe {
+        (
+            MMIO_START.as_ptr() as u64,
+            FRAMEBUFFER_START.as_ptr() as u64,
+            BOOTBOOT_START.as_ptr() as u64,
+            ENVIRONMENT_START.as_ptr() as u64,
+        )
+    };
+    // the framebuffer can't extend past the BOOTBOOT information structure, which follows it
+    let framebuffer_end = (framebuffer + u64::from(BOOTBOOT.fb_size))
+        .next_multiple_of(4096)
+        .min(bootboot);
+
+    let fixed = [
+        (mmio..framebuffer, "MMIO"),
+        (framebuffer..framebuffer_end, "framebuffer"),
+        (
+            bootboot..bootboot + BOOTBOOT_SIZE as u64,
+            "BOOTBOOT information",
+        ),
+        (
+            environment..environment + ENVIRONMENT_SIZE as u64,
+            "environment",
+        ),
+        (kernel_image(), "kernel image"),
+    ];
+    let mut space = KERNEL_SPACE.lock();
+    for (range, name) in fixed.iter().cloned() {
+        if let Err(err) = space.reserve(range.clone(), name) {
+            log::error!("cannot reserve {range:#x?} for the {name}: {err}");
+        }
+    }
+    drop(space);
+
+    let reserve = |range: Range<u64>, name| {
+        if let Err(err) = reserve_physical(range.clone(), name) {
+            log::error!("cannot reserve physical memory {range:#x?} for the {name}: {err}");
+        }
+    };
+    reserve(
+        BOOTBOOT.initrd_ptr..BOOTBOOT.initrd_ptr + BOOTBOOT.initrd_size,
+        "initrd",
+    );
+    reserve(
+        BOOTBOOT.fb_ptr..BOOTBOOT.fb_ptr + u64::from(BOOTBOOT.fb_size),
+        "framebuffer",
+    );
+    for table in acpi::tables() {
+        let start = table.as_ptr() as u64;
+        reserve(start..start + table.len() as u64, "ACPI tables");
+    }
+    // the BOOTBOOT information structure, environment and kernel image
+    #[cfg(target_arch = "x86_64")]
+    for (range, name) in fixed.into_iter().skip(2) {
+        for range in physical_ranges(range) {
+            reserve(range, name);
+        }
+    }
+}
+
+/// A physically contiguous buffer which a device can access directly, allocated by [`dma_alloc`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaBuffer {
+    /// The address the kernel accesses the buffer at.
+    pub virt: u64,
+    /// The address the device accesses the buffer at.
+    pub phys: u64,
+    /// The size of the buffer in bytes, which is a multiple of the page size.
+    pub len: u64,
+}
+
+/// Allocates a zeroed buffer of at least `len` bytes of physically contiguous memory, starting at
+/// a multiple of `align` and ending at or below the physical address `max_phys`.
+///
+/// The buffer is accessed through the identity map, so its virtual address is the same as its
+/// physical address. Its frames are flagged as [pinned](crate::arch::frame::FrameFlags::PINNED),
+/// and are never moved, swapped or freed, so it stays pinned for as long as a device might use it.
+///
+/// # Panics
+/// Panics if `align` isn't a power of two.
+#[cfg(target_arch = "x86_64")]
+pub fn dma_alloc(len: u64, align: u64, max_phys: u64) -> Result<DmaBuffer, Error> {
+    use crate::arch::{frame, paging::PAGE_SIZE};
+    use x86_64::structures::paging::PhysFrame;
+
+    if len == 0 {
+        return Err(Error::OutOfRange);
+    }
+    let count = len.div_ceil(PAGE_SIZE);
+    let start =
+        frame::alloc_contiguous(count as usize, align, max_phys).ok_or(Error::OutOfMemory)?;
+    // SAFETY: the frames were just allocated, so nothing else is using them
+    unsafe { frame::zero(start, count as usize) };
+    for frame in PhysFrame::range(start, start + count) {
+        frame::set_flags(frame, frame::FrameFlags::PINNED);
+    }
+
+    let phys = start.start_address().as_u64();
+    Ok(DmaBuffer {
+        virt: phys,
+        phys,
+        len: count * PAGE_SIZE,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the ranges of the regions in `map`.
+    fn ranges<const N: usize>(map: &VirtualRegionMap<N>) -> impl Iterator<Item = Range<u64>> + '_ {
+        map.iter().map(|region| region.range.clone())
+    }
+
+    #[test]
+    fn overlapping_reservations() {
+        let mut map = VirtualRegionMap::<8>::new(0x1000..0x10000);
+        assert_eq!(map.reserve(0x4000..0x6000, "a"), Ok(()));
+        assert_eq!(map.reserve(0x2000..0x3000, "b"), Ok(()));
+        for range in [
+            0x4000..0x6000,
+            0x3000..0x4001,
+            0x5fff..0x7000,
+            0x4800..0x5000,
+            0x1000..0x8000,
+            0x2fff..0x3000,
+        ] {
+            assert_eq!(map.reserve(range, "c"), Err(Error::Overlaps));
+        }
+        // adjacent ranges don't overlap
+        assert_eq!(map.reserve(0x3000..0x4000, "c"), Ok(()));
+        assert_eq!(map.reserve(0x6000..0x7000, "d"), Ok(()));
+        assert!(ranges(&map).eq([
+            0x2000..0x3000,
+            0x3000..0x4000,
+            0x4000..0x6000,
+            0x6000..0x7000
+        ]));
+        assert_eq!(map.find(0x5fff).map(|region| region.name), Some("a"));
+        assert_eq!(map.find(0x7000), None);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut map = VirtualRegionMap::<8>::new(0x1000..0x10000);
+        let backwards = Range {
+            start: 0x3000,
+            end: 0x2000,
+        };
+        for range in [0x2000..0x2000, backwards, 0..0x2000, 0xf000..0x10001] {
+            assert_eq!(map.reserve(range, "a"), Err(Error::OutOfRange));
+        }
+        assert_eq!(map.allocate(0, 1, "a"), Err(Error::OutOfRange));
+        assert_eq!(map.reserve(0x1000..0x10000, "all"), Ok(()));
+    }
+
+    #[test]
+    fn alignment() {
+        let mut map = VirtualRegionMap::<8>::new(0x1000..0x100000);
+        map.reserve(0x1000..0x1800, "a").unwrap();
+        assert_eq!(map.allocate(0x100, 0x100, "b"), Ok(0x1800..0x1900));
+        assert_eq!(map.allocate(0x1000, 0x10000, "c"), Ok(0x10000..0x11000));
+        // the gap before the aligned region is still used
+        assert_eq!(map.allocate(0x800, 1, "d"), Ok(0x1900..0x2100));
+        assert!(ranges(&map).eq([
+            0x1000..0x1800,
+            0x1800..0x1900,
+            0x1900..0x2100,
+            0x10000..0x11000
+        ]));
+    }
+
+    #[test]
+    #[should_panic = "alignment must be a power of two"]
+    fn alignment_not_power_of_two() {
+        let _ = VirtualRegionMap::<8>::new(0x1000..0x10000).allocate(0x1000, 3, "a");
+    }
+
+    #[test]
+    fn no_space() {
+        let mut map = VirtualRegionMap::<8>::new(0x1000..0x10000);
+        map.reserve(0x8000..0x9000, "a").unwrap();
+        assert_eq!(map.allocate(0x8000, 1, "b"), Err(Error::NoSpace));
+        assert_eq!(map.allocate(0x7000, 1, "b"), Ok(0x1000..0x8000));
+        assert_eq!(map.allocate(0x7001, 1, "c"), Err(Error::NoSpace));
+        assert_eq!(map.allocate(0x1000, 0x10000, "c"), Err(Error::NoSpace));
+        // aligning near the top of the address space doesn't overflow
+        let mut map = VirtualRegionMap::<8>::new(u64::MAX - 0x2000..u64::MAX);
+        assert_eq!(map.allocate(0x1000, 1 << 63, "a"), Err(Error::NoSpace));
+        assert_eq!(map.allocate(u64::MAX, 1, "a"), Err(Error::NoSpace));
+    }
+
+    #[test]
+    fn full() {
+        let mut map = VirtualRegionMap::<2>::new(0x1000..0x10000);
+        map.reserve(0x1000..0x2000, "a").unwrap();
+        map.allocate(0x1000, 1, "b").unwrap();
+        assert_eq!(map.reserve(0x8000..0x9000, "c"), Err(Error::Full));
+        assert_eq!(map.allocate(0x1000, 1, "c"), Err(Error::Full));
+        assert_eq!(map.iter().len(), 2);
+    }
+
+    #[test]
+    fn release_and_reuse() {
+        let mut map = VirtualRegionMap::<5>::new(0x1000..0x10000);
+        let a = map.allocate(0x1000, 1, "a").unwrap();
+        let b = map.allocate(0x1000, 1, "b").unwrap();
+        let c = map.allocate(0x1000, 1, "c").unwrap();
+        assert_eq!(map.release(b.start + 1), Err(Error::NotFound(b.start + 1)));
+        assert_eq!(
+            map.release(b.start),
+            Ok(Region {
+                range: b.clone(),
+                name: "b"
+            })
+        );
+        assert_eq!(map.release(b.start), Err(Error::NotFound(b.start)));
+        assert!(ranges(&map).eq([a.clone(), c.clone()]));
+
+        // the hole is reused, and the regions stay sorted
+        assert_eq!(map.allocate(0x800, 1, "d"), Ok(b.start..b.start + 0x800));
+        assert_eq!(map.reserve(b.start + 0x800..b.end, "e"), Ok(()));
+        assert_eq!(map.allocate(0x1000, 1, "f"), Ok(c.end..c.end + 0x1000));
+        assert!(map
+            .iter()
+            .map(|region| region.name)
+            .eq(["a", "d", "e", "c", "f"]));
+        assert_eq!(map.allocate(0x1000, 1, "g"), Err(Error::Full));
+    }
+}
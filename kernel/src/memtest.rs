@@ -0,0 +1,125 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! A boot-time memory test.
+//!
+//! When the [`MEMTEST`] parameter is set, [`run`] tests every free frame with the March C-
+//! algorithm, once with a background of all zeroes and once with alternating bits, and
+//! [quarantines](crate::quarantine) any frame which fails.
+//!
+//! Only memory below [`IDENTITY_MAPPED_LIMIT`] is tested, since that is all BOOTBOOT maps for us.
+
+use core::ptr;
+
+use crate::{
+    bootboot::BOOTBOOT,
+    param,
+    quarantine::{self, Reason},
+};
+
+param! {
+    /// Whether to test free memory at boot.
+    pub static MEMTEST: bool = false, name = "memtest";
+}
+
+/// The size of the frames which are tested.
+pub const FRAME_SIZE: u64 = 4096;
+
+/// The end of the physical memory which BOOTBOOT identity maps.
+pub const IDENTITY_MAPPED_LIMIT: u64 = 16 << 30;
+
+/// The background patterns each frame is tested with.
+const BACKGROUNDS: [u64; 2] = [0, 0x5555_5555_5555_5555];
+
+/// The number of words in a frame.
+const WORDS: usize = (FRAME_SIZE / 8) as usize;
+
+/// The results of a memory test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Report {
+    /// The number of frames tested.
+    pub tested: u64,
+    /// The number of frames which failed.
+    pub failed: u64,
+    /// The number of failed frames which couldn't be quarantined because the quarantine was full.
+    pub not_quarantined: u64,
+}
+
+/// Tests all free memory, quarantining any bad frames.
+///
+/// This must be called before any free frames are used, since their contents are overwritten.
+pub fn run() -> Report {
+    let mut report = Report::default();
+
+    for frame in BOOTBOOT
+        .free_frames::<FRAME_SIZE>()
+        .filter(|&frame| frame < IDENTITY_MAPPED_LIMIT)
+    {
+        report.tested += 1;
+
+        // SAFETY: the frame is free and identity mapped, and the caller guarantees that nothing is
+        //         using free frames yet
+        let passed = BACKGROUNDS
+            .iter()
+            .all(|&background| unsafe { march_c(frame as *mut u64, background) });
+        if passed {
+            continue;
+        }
+
+        report.failed += 1;
+        log::error!("memory test failed for frame {frame:#x}");
+        if quarantine::add(frame..(frame + FRAME_SIZE), Reason::MemTest).is_err() {
+            report.not_quarantined += 1;
+        }
+    }
+
+    report
+}
+
+/// Runs the March C- test over a frame, returning `true` if it passes.
+///
+/// Each element reads the expected value from every word and writes the new one, either in
+/// ascending or descending order: ⇕(w0); ⇑(r0,w1); ⇑(r1,w0); ⇓(r0,w1); ⇓(r1,w0); ⇕(r0), where
+/// `0` is `background` and `1` its complement.
+///
+/// # Safety
+/// `frame` must point to a frame of `FRAME_SIZE` bytes which nothing else is using.
+unsafe fn march_c(frame: *mut u64, background: u64) -> bool {
+    let zero = background;
+    let one = !background;
+    let word = |i: usize| {
+        // SAFETY: the caller guarantees that `frame` points to `WORDS` words
+        unsafe { frame.add(i) }
+    };
+
+    for i in 0..WORDS {
+        // SAFETY: the caller guarantees that nothing else is using the frame
+        unsafe { ptr::write_volatile(word(i), zero) };
+    }
+
+    let elements = [
+        (false, zero, one),
+        (false, one, zero),
+        (true, zero, one),
+        (true, one, zero),
+    ];
+    for (descending, expected, new) in elements {
+        for n in 0..WORDS {
+            let i = if descending { WORDS - 1 - n } else { n };
+            // SAFETY: the caller guarantees that nothing else is using the frame
+            unsafe {
+                if ptr::read_volatile(word(i)) != expected {
+                    return false;
+                }
+                ptr::write_volatile(word(i), new);
+            }
+        }
+    }
+
+    // SAFETY: the caller guarantees that nothing else is using the frame
+    (0..WORDS).all(|i| unsafe { ptr::read_volatile(word(i)) } == zero)
+}
@@ -12,14 +12,41 @@
 //!
 //! [panic handler]: https://doc.rust-lang.org/stable/reference/runtime.html#the-panic_handler-attribute
 //! [`no_std`]: https://doc.rust-lang.org/stable/reference/names/preludes.html#the-no_std-attribute
+use aleph_naught::{
+    build_id::BUILD_ID,
+    crash::{self, CrashId},
+};
 use core::panic::PanicInfo;
 
 /// The kernel's panic handler.
 ///
-/// It logs an [error][log::error] and halts execution.
+/// It logs an [error][log::error], along with a [crash ID](CrashId) identifying where it occurred
+/// and the [build ID](BUILD_ID), and halts execution. On `aarch64`, it resets the system after a
+/// delay instead, unless disabled by [`PANIC_RESET`](aleph_naught::arch::PANIC_RESET).
+///
+/// The panic is reported on the [emergency stack](crash::on_emergency_stack), in case it was
+/// caused by running out of stack.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    crash::on_emergency_stack(report, info as *const PanicInfo as usize)
+}
+
+/// Reports the panic described by `info`, which is the address of a [`PanicInfo`].
+extern "C" fn report(info: usize) -> ! {
+    // SAFETY: `panic` passes a pointer to its `PanicInfo`, which remains valid, since `panic`
+    //         never returns
+    let info = unsafe { &*(info as *const PanicInfo) };
+
     log::error!("{info}");
+    if let Some(location) = info.location() {
+        log::error!("crash ID: {}", CrashId::from_location(location));
+    }
+    log::error!("kernel build: {BUILD_ID}");
+
+    #[cfg(target_arch = "aarch64")]
+    aleph_naught::arch::panic_reset();
 
-    loop {}
+    loop {
+        core::hint::spin_loop();
+    }
 }
@@ -0,0 +1,346 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Kernel parameters.
+//!
+//! A kernel parameter is a named, typed setting, declared with the [`param!`](crate::param!)
+//! macro. Its initial value is taken from the BOOTBOOT [environment](crate::bootboot::environment)
+//! if the parameter's name appears there, or from its default otherwise. It can be changed at
+//! runtime with [`Param::set`], or by name with [`set`].
+//!
+//! ```ignore
+//! aleph_naught::param! {
+//!     /// Enables the frobnicator.
+//!     pub static FROBNICATE: bool = false, name = "frobnicate";
+//! }
+//!
+//! if FROBNICATE.get() {
+//!     // ...
+//! }
+//! ```
+//!
+//! Every parameter is registered in the `.params` section of the kernel image, so they can all be
+//! listed with [`all`], and read from the environment up front with [`init`].
+//!
+//! Reading a parameter never waits for a lock, so parameters can be read by interrupt handlers.
+//! The value is kept in two slots, and [`Param::set`] writes the one which isn't current before
+//! publishing it, so a reader only retries if the value changed while it was being read.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    mem::MaybeUninit,
+    sync::atomic::{self, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::bootboot::env_var;
+
+/// A type which can be used as the value of a kernel parameter.
+pub trait ParamValue: Copy + fmt::Display + Send + Sync + 'static {
+    /// Parses a value, returning `None` if `s` is not a valid value.
+    fn parse(s: &'static str) -> Option<Self>;
+}
+
+impl ParamValue for bool {
+    /// Accepts `1`, `true`, `on` or `yes` for `true`, and `0`, `false`, `off` or `no` for `false`.
+    fn parse(s: &'static str) -> Option<Self> {
+        match s {
+            "1" | "true" | "on" | "yes" => Some(true),
+            "0" | "false" | "off" | "no" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl ParamValue for &'static str {
+    fn parse(s: &'static str) -> Option<Self> {
+        Some(s)
+    }
+}
+
+macro_rules! impl_param_value_for_int {
+    ($($t:ty),*) => {$(
+        impl ParamValue for $t {
+            /// Accepts decimal, or hexadecimal with a `0x` prefix.
+            fn parse(s: &'static str) -> Option<Self> {
+                match s.strip_prefix("0x") {
+                    Some(hex) => <$t>::from_str_radix(hex, 16).ok(),
+                    None => s.parse().ok(),
+                }
+            }
+        }
+    )*};
+}
+impl_param_value_for_int!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// A kernel parameter with a value of type `T`.
+///
+/// Use the [`param!`](crate::param!) macro to declare kernel parameters.
+#[derive(Debug)]
+pub struct Param<T: ParamValue> {
+    name: &'static str,
+    description: &'static str,
+    default: T,
+    /// The number of values which have been published, or zero if the value hasn't been read from
+    /// the environment yet. The current value is in `slots[version % 2]`.
+    version: AtomicUsize,
+    /// The current and previous values.
+    slots: [UnsafeCell<MaybeUninit<T>>; 2],
+    /// Serializes changes to the value.
+    lock: Mutex<()>,
+}
+
+// SAFETY: a slot is only written while `lock` is held and it isn't current, and values read from
+//         it are only used if it wasn't written in the meantime
+unsafe impl<T: ParamValue> Sync for Param<T> {}
+
+impl<T: ParamValue> Param<T> {
+    /// Returns a new parameter. Use [`param!`](crate::param!) instead, which also registers the
+    /// parameter.
+    #[doc(hidden)]
+    pub const fn new(name: &'static str, description: &'static str, default: T) -> Self {
+        Param {
+            name,
+            description,
+            default,
+            version: AtomicUsize::new(0),
+            slots: [
+                UnsafeCell::new(MaybeUninit::uninit()),
+                UnsafeCell::new(MaybeUninit::uninit()),
+            ],
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the parameter's current value.
+    ///
+    /// If the parameter hasn't been read from the environment yet, it is read first. After
+    /// [`init`], this never waits.
+    pub fn get(&self) -> T {
+        loop {
+            let version = self.version.load(Ordering::Acquire);
+            if version == 0 {
+                self.resolve();
+                continue;
+            }
+            // SAFETY: the slot was initialized before `version` was published. It may be written
+            //         while it's being read, but then `version` changes and the copy is discarded
+            //         without being assumed to be initialized
+            let value = unsafe { self.slots[version % 2].get().read_volatile() };
+            atomic::fence(Ordering::Acquire);
+            if self.version.load(Ordering::Relaxed) == version {
+                // SAFETY: the slot wasn't written while it was being read
+                return unsafe { value.assume_init() };
+            }
+        }
+    }
+
+    /// Sets the parameter's value.
+    ///
+    /// This must not be called by interrupt handlers.
+    pub fn set(&self, value: T) {
+        let _guard = self.lock.lock();
+        self.publish(value);
+    }
+
+    /// Reads the parameter's initial value from the environment, unless it already has a value.
+    fn resolve(&self) {
+        let _guard = self.lock.lock();
+        if self.version.load(Ordering::Relaxed) != 0 {
+            return;
+        }
+        let value = match env_var(self.name) {
+            Some(s) => T::parse(s).unwrap_or_else(|| {
+                log::warn!(
+                    "invalid value for parameter {}: {s:?}; using default: {}",
+                    self.name,
+                    self.default
+                );
+                self.default
+            }),
+            None => self.default,
+        };
+        self.publish(value);
+    }
+
+    /// Writes `value` to the slot which isn't current, and makes it current. `lock` must be held.
+    fn publish(&self, value: T) {
+        let version = self.version.load(Ordering::Relaxed) + 1;
+        // readers which see the new value must also see that the previous version was replaced
+        atomic::fence(Ordering::Release);
+        // SAFETY: `lock` is held, and readers discard the slot if they read it while it's written
+        unsafe {
+            self.slots[version % 2]
+                .get()
+                .write_volatile(MaybeUninit::new(value))
+        };
+        self.version.store(version, Ordering::Release);
+    }
+
+    /// Returns the parameter's default value.
+    pub fn default(&self) -> T {
+        self.default
+    }
+}
+
+/// A kernel parameter of any type.
+pub trait AnyParam: Send + Sync {
+    /// Returns the parameter's name.
+    fn name(&self) -> &'static str;
+
+    /// Returns the parameter's description, taken from its doc comment.
+    fn description(&self) -> &'static str;
+
+    /// Reads the parameter's initial value from the environment, unless it already has a value.
+    fn init(&self);
+
+    /// Parses `value` and sets the parameter to the result.
+    fn set_str(&self, value: &'static str) -> Result<(), Error>;
+
+    /// Writes the parameter's current value.
+    fn fmt_value(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+impl<T: ParamValue> AnyParam for Param<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.description.trim()
+    }
+
+    fn init(&self) {
+        self.resolve();
+    }
+
+    fn set_str(&self, value: &'static str) -> Result<(), Error> {
+        self.set(T::parse(value).ok_or(Error::InvalidValue)?);
+        Ok(())
+    }
+
+    fn fmt_value(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.get())
+    }
+}
+
+impl fmt::Debug for dyn AnyParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = ", self.name())?;
+        self.fmt_value(f)
+    }
+}
+
+impl fmt::Display for dyn AnyParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=", self.name())?;
+        self.fmt_value(f)
+    }
+}
+
+/// An error which occurred while setting a parameter by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There is no parameter with the given name.
+    UnknownParam,
+    /// The value is not valid for the parameter's type.
+    InvalidValue,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownParam => write!(f, "unknown parameter"),
+            Error::InvalidValue => write!(f, "invalid parameter value"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// Returns all registered kernel parameters.
+pub fn all() -> &'static [&'static dyn AnyParam] {
+    extern "C" {
+        static __params_start: [u8; 0];
+        static __params_end: [u8; 0];
+    }
+
+    // SAFETY: the linker script places `__params_start` and `__params_end` at the start and end of
+    //         the `.params` section, which only contains registrations made by `param!`
+    unsafe {
+        let start = __params_start.as_ptr() as *const &'static dyn AnyParam;
+        let len = __params_end.as_ptr().offset_from(__params_start.as_ptr()) as usize
+            / core::mem::size_of::<&dyn AnyParam>();
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// Reads every parameter's initial value from the environment, so that reading them afterwards
+/// never waits.
+///
+/// This should be called before interrupts are enabled.
+pub fn init() {
+    for param in all() {
+        param.init();
+    }
+}
+
+/// Sets the parameter called `name` to `value`.
+pub fn set(name: &str, value: &'static str) -> Result<(), Error> {
+    all()
+        .iter()
+        .find(|param| param.name() == name)
+        .ok_or(Error::UnknownParam)?
+        .set_str(value)
+}
+
+/// Declares and registers a kernel [parameter](crate::param).
+///
+/// Doc comments on the parameter become its [description](AnyParam::description).
+#[macro_export]
+macro_rules! param {
+    ($(
+        $(#[doc = $doc:literal])*
+        $vis:vis static $ident:ident: $t:ty = $default:expr, name = $name:literal;
+    )*) => {$(
+        $(#[doc = $doc])*
+        $vis static $ident: $crate::param::Param<$t> =
+            $crate::param::Param::new($name, concat!($($doc, "\n"),*), $default);
+
+        const _: () = {
+            #[used]
+            #[link_section = ".params"]
+            static REGISTRATION: &'static dyn $crate::param::AnyParam = &$ident;
+        };
+    )*};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_get() {
+        let param = Param::new("test", "", 1u32);
+        assert_eq!(param.default(), 1);
+        // each value is written to the other slot
+        for value in [2, 3, 4] {
+            param.set(value);
+            assert_eq!(param.get(), value);
+        }
+    }
+
+    #[test]
+    fn str_value() {
+        let param = Param::new("test", "", "default");
+        param.set("changed");
+        assert_eq!(param.get(), "changed");
+        assert_eq!(param.default(), "default");
+    }
+}
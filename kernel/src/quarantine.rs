@@ -0,0 +1,302 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Quarantine of bad physical memory.
+//!
+//! Ranges of physical memory found to be bad are added to the quarantine with [`add`], and are
+//! never returned by [`Bootboot::free_frames`](crate::bootboot::Bootboot::free_frames), nor handed
+//! out again by the frame allocator. Memory can
+//! be quarantined by the [memory test](crate::memtest), by machine check handling, or by listing
+//! ranges in the [`BAD_MEMORY`] parameter, which [`init`] reads.
+//!
+//! The quarantined ranges can be listed with [`ranges`].
+
+use core::{fmt, ops::Range};
+
+use spin::Mutex;
+
+use crate::param;
+
+param! {
+    /// Comma-separated ranges of bad physical memory, such as `0x1000-0x3000,0x7000`. The end of
+    /// each range is exclusive, and a single address quarantines the 4 KiB frame containing it.
+    pub static BAD_MEMORY: &'static str = "", name = "bad_memory";
+}
+
+/// The maximum number of quarantined ranges.
+pub const CAPACITY: usize = 64;
+
+/// The quarantined ranges.
+static RANGES: Mutex<Ranges> = Mutex::new(Ranges::EMPTY);
+
+/// The reason memory was quarantined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The memory was listed in the [`BAD_MEMORY`] parameter.
+    Param,
+    /// The memory failed the [memory test](crate::memtest).
+    MemTest,
+    /// A machine check reported an error in the memory.
+    MachineCheck,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Param => write!(f, "bad_memory parameter"),
+            Reason::MemTest => write!(f, "memory test"),
+            Reason::MachineCheck => write!(f, "machine check"),
+        }
+    }
+}
+
+/// A quarantined range of physical memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The physical addresses which are quarantined.
+    pub range: Range<u64>,
+    /// The reason the range was quarantined.
+    pub reason: Reason,
+}
+
+impl Entry {
+    /// An unused entry.
+    const EMPTY: Entry = Entry {
+        range: 0..0,
+        reason: Reason::Param,
+    };
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}-{:#x} ({})",
+            self.range.start, self.range.end, self.reason
+        )
+    }
+}
+
+/// A snapshot of the quarantined ranges, returned by [`ranges`].
+#[derive(Debug, Clone)]
+pub struct Ranges {
+    entries: [Entry; CAPACITY],
+    len: usize,
+}
+
+impl Ranges {
+    /// No quarantined ranges.
+    const EMPTY: Ranges = Ranges {
+        entries: [const { Entry::EMPTY }; CAPACITY],
+        len: 0,
+    };
+
+    /// Adds `range` to the quarantined ranges, merging it with an existing range if it can.
+    fn insert(&mut self, range: Range<u64>, reason: Reason) -> Result<(), Full> {
+        let len = self.len;
+        if let Some(existing) = self.entries[..len].iter_mut().find(|existing| {
+            existing.reason == reason
+                && existing.range.start <= range.end
+                && range.start <= existing.range.end
+        }) {
+            existing.range.start = existing.range.start.min(range.start);
+            existing.range.end = existing.range.end.max(range.end);
+            return Ok(());
+        }
+
+        if len == CAPACITY {
+            return Err(Full);
+        }
+        self.entries[len] = Entry { range, reason };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Returns an iterator over the quarantined ranges.
+    pub fn iter(&self) -> core::slice::Iter<'_, Entry> {
+        self.entries[..self.len].iter()
+    }
+
+    /// Returns the number of quarantined ranges.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if no memory is quarantined.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl fmt::Display for Ranges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in self.iter() {
+            writeln!(f, "{entry}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The error returned when the quarantine is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full;
+
+impl fmt::Display for Full {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the bad memory quarantine is full")
+    }
+}
+
+/// Quarantines the ranges listed in the [`BAD_MEMORY`] parameter.
+///
+/// Invalid ranges are logged and ignored.
+pub fn init() {
+    for s in BAD_MEMORY.get().split(',').map(str::trim) {
+        if s.is_empty() {
+            continue;
+        }
+
+        match parse_range(s) {
+            Some(range) => {
+                if let Err(err) = add(range, Reason::Param) {
+                    log::error!("cannot quarantine {s}: {err}");
+                }
+            }
+            None => log::warn!("invalid range in bad_memory parameter: {s:?}"),
+        }
+    }
+}
+
+/// Quarantines the physical addresses in `range`, so they are never handed out as free memory.
+///
+/// A range which overlaps or is adjacent to an existing range with the same reason is merged with
+/// it.
+pub fn add(range: Range<u64>, reason: Reason) -> Result<(), Full> {
+    if range.is_empty() {
+        return Ok(());
+    }
+
+    RANGES.lock().insert(range.clone(), reason)?;
+    // the frame allocator checks the quarantine while it's locked, so the quarantine must be
+    // unlocked first
+    #[cfg(target_arch = "x86_64")]
+    crate::arch::frame::withdraw(range);
+    Ok(())
+}
+
+/// Returns `true` if any address in `range` is quarantined.
+pub fn overlaps(range: Range<u64>) -> bool {
+    RANGES
+        .lock()
+        .iter()
+        .any(|bad| bad.range.start < range.end && range.start < bad.range.end)
+}
+
+/// Returns a snapshot of the quarantined ranges.
+pub fn ranges() -> Ranges {
+    RANGES.lock().clone()
+}
+
+/// Parses a range in the format used by [`BAD_MEMORY`].
+///
+/// Returns `None` if the range is invalid, including if it's empty or its end is before its start.
+fn parse_range(s: &str) -> Option<Range<u64>> {
+    match s.split_once('-') {
+        Some((start, end)) => {
+            let range = parse_address(start.trim())?..parse_address(end.trim())?;
+            (!range.is_empty()).then_some(range)
+        }
+        None => {
+            let frame = parse_address(s)? & !0xfff;
+            Some(frame..frame.checked_add(0x1000)?)
+        }
+    }
+}
+
+/// Parses an address, in decimal or in hexadecimal with a `0x` prefix.
+fn parse_address(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_single_address() {
+        assert_eq!(parse_range("0x1234"), Some(0x1000..0x2000));
+        assert_eq!(parse_range("8192"), Some(0x2000..0x3000));
+        assert_eq!(parse_range("0xfffffffffffff000"), None);
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(parse_range("0x1000-0x3000"), Some(0x1000..0x3000));
+        assert_eq!(parse_range("4096 - 0x1800"), Some(0x1000..0x1800));
+        assert_eq!(parse_range("0x3000-0x1000"), None);
+        assert_eq!(parse_range("0x1000-0x1000"), None);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(parse_range(""), None);
+        assert_eq!(parse_range("0x"), None);
+        assert_eq!(parse_range("0x1000-"), None);
+        assert_eq!(parse_range("-0x1000"), None);
+        assert_eq!(parse_range("0x1000-0x2000-0x3000"), None);
+        assert_eq!(parse_range("0xg000"), None);
+        assert_eq!(parse_range("0x10000000000000000"), None);
+    }
+
+    fn entries(ranges: &Ranges) -> impl Iterator<Item = (Range<u64>, Reason)> + '_ {
+        ranges
+            .iter()
+            .map(|entry| (entry.range.clone(), entry.reason))
+    }
+
+    #[test]
+    fn merge() {
+        let mut ranges = Ranges::EMPTY;
+        ranges.insert(0x2000..0x3000, Reason::Param).unwrap();
+        ranges.insert(0x3000..0x4000, Reason::Param).unwrap();
+        ranges.insert(0x1000..0x2800, Reason::Param).unwrap();
+        assert!(entries(&ranges).eq([(0x1000..0x4000, Reason::Param)]));
+
+        // ranges with different reasons are kept apart
+        ranges.insert(0x4000..0x5000, Reason::MemTest).unwrap();
+        ranges.insert(0x3800..0x4800, Reason::MachineCheck).unwrap();
+        assert!(entries(&ranges).eq([
+            (0x1000..0x4000, Reason::Param),
+            (0x4000..0x5000, Reason::MemTest),
+            (0x3800..0x4800, Reason::MachineCheck),
+        ]));
+
+        // a gap between ranges isn't merged
+        ranges.insert(0x6000..0x7000, Reason::Param).unwrap();
+        assert_eq!(ranges.len(), 4);
+    }
+
+    #[test]
+    fn full() {
+        let mut ranges = Ranges::EMPTY;
+        for i in 0..CAPACITY as u64 {
+            ranges
+                .insert(i * 0x2000..i * 0x2000 + 0x1000, Reason::MemTest)
+                .unwrap();
+        }
+        assert_eq!(
+            ranges.insert(u64::MAX - 1..u64::MAX, Reason::MemTest),
+            Err(Full)
+        );
+        // merging doesn't need another entry
+        assert_eq!(ranges.insert(0x1000..0x2000, Reason::MemTest), Ok(()));
+        assert_eq!(ranges.len(), CAPACITY);
+    }
+}
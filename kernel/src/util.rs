@@ -0,0 +1,29 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Generic data structures which don't allocate.
+//!
+//! [`Bitmap`] and [`IdAllocator`] track which of a fixed number of bits or IDs are in use.
+//! The [ring buffers](ring) are lock-free, so they can be shared with interrupt handlers. The
+//! [intrusive list](list) links elements through fields embedded in the elements themselves, so it
+//! can hold statically allocated elements, as can the [red-black tree](rbtree), which is also an
+//! interval tree.
+//!
+//! [`bytes`] parses binary data, such as firmware tables, without panicking on malformed input.
+//!
+//! A [`HandleTable`] holds reference-counted kernel objects, named by typed [`Handle`]s which can
+//! be passed across the system call interface.
+
+pub mod bitmap;
+pub mod bytes;
+pub mod handle;
+pub mod list;
+pub mod rbtree;
+pub mod ring;
+
+pub use bitmap::{Bitmap, IdAllocator};
+pub use handle::{Handle, HandleTable};
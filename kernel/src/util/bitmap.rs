@@ -0,0 +1,391 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Fixed-size bitmaps, and an allocator for small integer IDs built on them.
+//!
+//! [`Bitmap`] is a plain bitmap for use under a lock or by a single owner. [`IdAllocator`] is
+//! lock-free, so IDs such as interrupt vectors can be allocated and freed from interrupt context.
+//! Both hold `WORDS * 64` bits.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// The number of bits in a word.
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// A bitmap of `WORDS * 64` bits.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Bitmap<const WORDS: usize> {
+    words: [u64; WORDS],
+}
+
+impl<const WORDS: usize> Bitmap<WORDS> {
+    /// The number of bits in the bitmap.
+    pub const BITS: usize = WORDS * WORD_BITS;
+
+    /// Returns a bitmap with every bit clear.
+    pub const fn new() -> Self {
+        Bitmap { words: [0; WORDS] }
+    }
+
+    /// Returns the bit at `index`.
+    ///
+    /// # Panics
+    /// Panics if `index` is out of range.
+    pub fn get(&self, index: usize) -> bool {
+        self.words[index / WORD_BITS] & (1 << (index % WORD_BITS)) != 0
+    }
+
+    /// Sets the bit at `index` to `value`, returning its previous value.
+    ///
+    /// # Panics
+    /// Panics if `index` is out of range.
+    pub fn set(&mut self, index: usize, value: bool) -> bool {
+        let word = &mut self.words[index / WORD_BITS];
+        let mask = 1 << (index % WORD_BITS);
+        let old = *word & mask != 0;
+        if value {
+            *word |= mask;
+        } else {
+            *word &= !mask;
+        }
+        old
+    }
+
+    /// Sets every bit in `range` to `value`.
+    ///
+    /// # Panics
+    /// Panics if `range` is out of range.
+    pub fn set_range(&mut self, range: core::ops::Range<usize>, value: bool) {
+        for index in range {
+            self.set(index, value);
+        }
+    }
+
+    /// Returns the index of the first set bit at or after `start`.
+    pub fn next_set(&self, start: usize) -> Option<usize> {
+        self.next_matching(start, |word| word)
+    }
+
+    /// Returns the index of the first clear bit at or after `start`.
+    pub fn next_clear(&self, start: usize) -> Option<usize> {
+        self.next_matching(start, |word| !word)
+    }
+
+    /// Returns the number of set bits.
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Returns the index of the first bit at or after `start` which is set in `f(word)`.
+    fn next_matching(&self, start: usize, f: impl Fn(u64) -> u64) -> Option<usize> {
+        let first = start / WORD_BITS;
+        let mut mask = u64::MAX << (start % WORD_BITS);
+        for (i, &word) in self.words.iter().enumerate().skip(first) {
+            let bits = f(word) & mask;
+            if bits != 0 {
+                return Some(i * WORD_BITS + bits.trailing_zeros() as usize);
+            }
+            mask = u64::MAX;
+        }
+        None
+    }
+}
+
+impl<const WORDS: usize> Default for Bitmap<WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const WORDS: usize> fmt::Debug for Bitmap<WORDS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut set = f.debug_set();
+        let mut index = 0;
+        while let Some(i) = self.next_set(index) {
+            set.entry(&i);
+            index = i + 1;
+        }
+        set.finish()
+    }
+}
+
+/// A lock-free allocator for IDs from `0` to `len - 1`, where `len` is at most `WORDS * 64`.
+///
+/// Allocation starts searching at the word where the last ID was allocated or freed, so it is
+/// usually constant time, even when most IDs are in use.
+pub struct IdAllocator<const WORDS: usize> {
+    /// A set bit for each allocated ID, and for each bit past `len`.
+    words: [AtomicU64; WORDS],
+    len: usize,
+    /// The word to start searching from.
+    hint: AtomicUsize,
+}
+
+impl<const WORDS: usize> IdAllocator<WORDS> {
+    /// Returns an allocator for IDs from `0` to `len - 1`, with none allocated.
+    ///
+    /// # Panics
+    /// Panics if `len` is greater than `WORDS * 64`.
+    pub const fn new(len: usize) -> Self {
+        assert!(len <= WORDS * WORD_BITS, "too many IDs for the bitmap");
+
+        let mut words = [const { AtomicU64::new(0) }; WORDS];
+        let mut i = len / WORD_BITS;
+        if i < WORDS {
+            words[i] = AtomicU64::new(!((1 << (len % WORD_BITS)) - 1));
+            i += 1;
+        }
+        while i < WORDS {
+            words[i] = AtomicU64::new(u64::MAX);
+            i += 1;
+        }
+
+        IdAllocator {
+            words,
+            len,
+            hint: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of IDs.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if there are no IDs.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Allocates an ID, returning `None` if all of them are in use.
+    pub fn alloc(&self) -> Option<usize> {
+        let hint = self.hint.load(Ordering::Relaxed);
+        for i in (hint..WORDS).chain(0..hint) {
+            let word = &self.words[i];
+            let mut current = word.load(Ordering::Relaxed);
+            while current != u64::MAX {
+                let bit = 1 << (!current).trailing_zeros();
+                match word.compare_exchange_weak(
+                    current,
+                    current | bit,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        self.hint.store(i, Ordering::Relaxed);
+                        return Some(i * WORD_BITS + bit.trailing_zeros() as usize);
+                    }
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+        None
+    }
+
+    /// Allocates `count` consecutive IDs, the first of which is a multiple of `align`, returning the
+    /// first, or `None` if there isn't such a block free.
+    ///
+    /// Blocks never span words, so `count` must be at most 64, and `align` must be a power of two.
+    pub fn alloc_block(&self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || count > WORD_BITS || !align.is_power_of_two() {
+            return None;
+        }
+        let mask = u64::MAX >> (WORD_BITS - count);
+        for (i, word) in self.words.iter().enumerate() {
+            let mut shift = 0;
+            while shift + count <= WORD_BITS {
+                let bits = mask << shift;
+                let mut current = word.load(Ordering::Relaxed);
+                while (i * WORD_BITS + shift) % align == 0 && current & bits == 0 {
+                    match word.compare_exchange_weak(
+                        current,
+                        current | bits,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => return Some(i * WORD_BITS + shift),
+                        Err(actual) => current = actual,
+                    }
+                }
+                shift += align.min(WORD_BITS);
+            }
+        }
+        None
+    }
+
+    /// Allocates `id` specifically, returning `false` if it is already in use or out of range.
+    pub fn reserve(&self, id: usize) -> bool {
+        if id >= self.len {
+            return false;
+        }
+        let bit = 1 << (id % WORD_BITS);
+        self.words[id / WORD_BITS].fetch_or(bit, Ordering::Acquire) & bit == 0
+    }
+
+    /// Frees `id`, so it can be allocated again.
+    ///
+    /// # Panics
+    /// Panics if `id` is not allocated.
+    pub fn free(&self, id: usize) {
+        assert!(id < self.len, "ID {id} is out of range");
+        let bit = 1 << (id % WORD_BITS);
+        let old = self.words[id / WORD_BITS].fetch_and(!bit, Ordering::Release);
+        assert!(old & bit != 0, "ID {id} is not allocated");
+        self.hint.store(id / WORD_BITS, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if `id` is allocated.
+    pub fn is_allocated(&self, id: usize) -> bool {
+        id < self.len
+            && self.words[id / WORD_BITS].load(Ordering::Relaxed) & (1 << (id % WORD_BITS)) != 0
+    }
+
+    /// Returns the number of allocated IDs.
+    pub fn allocated(&self) -> usize {
+        let total: usize = self
+            .words
+            .iter()
+            .map(|word| word.load(Ordering::Relaxed).count_ones() as usize)
+            .sum();
+        total - (WORDS * WORD_BITS - self.len)
+    }
+}
+
+impl<const WORDS: usize> fmt::Debug for IdAllocator<WORDS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdAllocator")
+            .field("len", &self.len)
+            .field("allocated", &self.allocated())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_and_last_bits() {
+        let mut bitmap = Bitmap::<2>::new();
+        assert!(!bitmap.set(0, true));
+        assert!(!bitmap.set(Bitmap::<2>::BITS - 1, true));
+        assert!(bitmap.get(0) && bitmap.get(127));
+        assert!(!bitmap.get(1) && !bitmap.get(126));
+        assert_eq!(bitmap.count_ones(), 2);
+        assert!(bitmap.set(127, false));
+        assert!(!bitmap.get(127));
+    }
+
+    #[test]
+    fn word_boundaries() {
+        let mut bitmap = Bitmap::<2>::new();
+        bitmap.set_range(60..68, true);
+        assert_eq!(bitmap.count_ones(), 8);
+        assert_eq!(bitmap.next_set(0), Some(60));
+        assert_eq!(bitmap.next_set(64), Some(64));
+        assert_eq!(bitmap.next_clear(60), Some(68));
+        assert_eq!(bitmap.next_set(68), None);
+    }
+
+    #[test]
+    fn search_past_the_end() {
+        let mut bitmap = Bitmap::<1>::new();
+        bitmap.set_range(0..64, true);
+        assert_eq!(bitmap.next_clear(0), None);
+        assert_eq!(bitmap.next_set(63), Some(63));
+        assert_eq!(bitmap.next_set(64), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn get_out_of_range() {
+        Bitmap::<1>::new().get(64);
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_out_of_range() {
+        Bitmap::<1>::new().set(64, true);
+    }
+
+    #[test]
+    fn alloc_until_exhausted() {
+        let ids = IdAllocator::<2>::new(70);
+        for expected in 0..70 {
+            assert_eq!(ids.alloc(), Some(expected));
+        }
+        assert_eq!(ids.alloc(), None);
+        assert_eq!(ids.allocated(), 70);
+
+        ids.free(0);
+        ids.free(69);
+        assert_eq!(ids.allocated(), 68);
+        assert!(!ids.is_allocated(0) && !ids.is_allocated(69));
+        let (a, b) = (ids.alloc().unwrap(), ids.alloc().unwrap());
+        assert!(a.min(b) == 0 && a.max(b) == 69);
+        assert_eq!(ids.alloc(), None);
+    }
+
+    #[test]
+    fn full_words() {
+        let ids = IdAllocator::<1>::new(64);
+        assert!(ids.reserve(63));
+        assert!(ids.is_allocated(63));
+        assert_eq!(ids.allocated(), 1);
+        assert_eq!(IdAllocator::<1>::new(0).alloc(), None);
+    }
+
+    #[test]
+    fn reserve_out_of_range() {
+        let ids = IdAllocator::<1>::new(10);
+        assert!(ids.reserve(0));
+        assert!(!ids.reserve(0));
+        assert!(ids.reserve(9));
+        assert!(!ids.reserve(10));
+        assert!(!ids.reserve(64));
+        assert!(!ids.is_allocated(10));
+        assert!(!ids.is_allocated(1000));
+        assert_eq!(ids.allocated(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn free_out_of_range() {
+        IdAllocator::<1>::new(10).free(10);
+    }
+
+    #[test]
+    #[should_panic]
+    fn free_unallocated() {
+        IdAllocator::<1>::new(10).free(3);
+    }
+
+    #[test]
+    fn aligned_blocks() {
+        let ids = IdAllocator::<2>::new(128);
+        assert!(ids.reserve(0));
+        assert_eq!(ids.alloc_block(4, 4), Some(4));
+        assert_eq!(ids.alloc_block(64, 64), Some(64));
+        assert_eq!(ids.alloc_block(64, 1), None);
+        assert_eq!(ids.alloc_block(0, 1), None);
+        assert_eq!(ids.alloc_block(65, 1), None);
+        assert_eq!(ids.alloc_block(2, 3), None);
+        assert_eq!(ids.allocated(), 69);
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_many_ids() {
+        IdAllocator::<1>::new(65);
+    }
+}
@@ -0,0 +1,425 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Parsing of binary data, such as tables and headers provided by firmware or the loader.
+//!
+//! Nothing here panics on malformed input: reading past the end of the data, or overlaying a
+//! structure on data which is too short or misaligned, returns an [`Error`] instead. Integers are
+//! read with an explicit byte order with [`le`], [`be`], or a [`Reader`], and structures are
+//! overlaid on bytes with [`overlay`] and [`overlay_slice`], or copied out with [`read`], if they
+//! implement [`FromBytes`], which can also validate them.
+//!
+//! [`sum8`] and [`internet_checksum`] compute the checksums used by firmware tables and network
+//! protocols respectively.
+
+use core::{fmt, mem, slice};
+
+/// An error parsing binary data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The data ended before `len` bytes at `offset` could be read.
+    Truncated {
+        /// The offset of the value which was read.
+        offset: usize,
+        /// The length of the value which was read.
+        len: usize,
+    },
+    /// The data isn't suitably aligned to overlay a structure on it.
+    Misaligned,
+    /// The data failed validation.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated { offset, len } => {
+                write!(f, "data truncated reading {len} bytes at offset {offset}")
+            }
+            Error::Misaligned => write!(f, "data is misaligned"),
+            Error::Invalid(reason) => write!(f, "invalid data: {reason}"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// An integer which can be read from bytes in either byte order.
+pub trait Integer: Copy + sealed::Sealed {
+    /// The size of the integer in bytes.
+    const SIZE: usize;
+
+    /// Converts little-endian `bytes`, which are exactly [`SIZE`](Self::SIZE) long.
+    #[doc(hidden)]
+    fn from_le(bytes: &[u8]) -> Self;
+
+    /// Converts big-endian `bytes`, which are exactly [`SIZE`](Self::SIZE) long.
+    #[doc(hidden)]
+    fn from_be(bytes: &[u8]) -> Self;
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+macro_rules! impl_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+
+            impl Integer for $ty {
+                const SIZE: usize = mem::size_of::<$ty>();
+
+                fn from_le(bytes: &[u8]) -> Self {
+                    let mut array = [0; mem::size_of::<$ty>()];
+                    array.copy_from_slice(bytes);
+                    <$ty>::from_le_bytes(array)
+                }
+
+                fn from_be(bytes: &[u8]) -> Self {
+                    let mut array = [0; mem::size_of::<$ty>()];
+                    array.copy_from_slice(bytes);
+                    <$ty>::from_be_bytes(array)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+/// Returns the `len` bytes of `bytes` at `offset`.
+pub fn get(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], Error> {
+    offset
+        .checked_add(len)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or(Error::Truncated { offset, len })
+}
+
+/// Returns the little-endian integer at `offset` in `bytes`.
+pub fn le<T: Integer>(bytes: &[u8], offset: usize) -> Result<T, Error> {
+    get(bytes, offset, T::SIZE).map(T::from_le)
+}
+
+/// Returns the big-endian integer at `offset` in `bytes`.
+pub fn be<T: Integer>(bytes: &[u8], offset: usize) -> Result<T, Error> {
+    get(bytes, offset, T::SIZE).map(T::from_be)
+}
+
+/// A cursor which reads values from the start of a byte slice, in order.
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Returns a reader at the start of `bytes`.
+    pub const fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    /// Returns the offset of the next byte to be read.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns the bytes which haven't been read.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.bytes[self.pos..]
+    }
+
+    /// Returns `true` if every byte has been read.
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    /// Reads the next `len` bytes.
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let bytes = get(self.bytes, self.pos, len)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Skips the next `len` bytes.
+    pub fn skip(&mut self, len: usize) -> Result<(), Error> {
+        self.bytes(len).map(drop)
+    }
+
+    /// Reads the next byte.
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        self.le()
+    }
+
+    /// Reads the next little-endian integer.
+    pub fn le<T: Integer>(&mut self) -> Result<T, Error> {
+        self.bytes(T::SIZE).map(T::from_le)
+    }
+
+    /// Reads the next big-endian integer.
+    pub fn be<T: Integer>(&mut self) -> Result<T, Error> {
+        self.bytes(T::SIZE).map(T::from_be)
+    }
+
+    /// Copies out the next `T`.
+    pub fn read<T: FromBytes>(&mut self) -> Result<T, Error> {
+        let value = read(self.bytes, self.pos)?;
+        self.pos += mem::size_of::<T>();
+        Ok(value)
+    }
+}
+
+/// A type which can be overlaid on arbitrary bytes, such as the `#[repr(C)]` layout of a table
+/// defined by firmware.
+///
+/// # Safety
+/// Every bit pattern of the right size must be a valid `T`, so `T` may only contain integers and
+/// arrays or other `FromBytes` types, and must have no padding.
+pub unsafe trait FromBytes: Copy {
+    /// Checks that the value's fields are consistent, which [`overlay`], [`overlay_slice`] and
+    /// [`read`] do before returning it. By default, every value is valid.
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// SAFETY: every bit pattern is a valid integer, and integers have no padding
+unsafe impl FromBytes for u8 {}
+// SAFETY: every bit pattern is a valid integer, and integers have no padding
+unsafe impl FromBytes for u16 {}
+// SAFETY: every bit pattern is a valid integer, and integers have no padding
+unsafe impl FromBytes for u32 {}
+// SAFETY: every bit pattern is a valid integer, and integers have no padding
+unsafe impl FromBytes for u64 {}
+// SAFETY: every element is valid for any bit pattern, and arrays have no padding between elements
+unsafe impl<T: FromBytes, const N: usize> FromBytes for [T; N] {}
+
+/// Returns a reference to the `T` at the start of `bytes`, which must be suitably aligned.
+pub fn overlay<T: FromBytes>(bytes: &[u8]) -> Result<&T, Error> {
+    overlay_slice(bytes, 1).map(|values| &values[0])
+}
+
+/// Returns a reference to the `count` values of type `T` at the start of `bytes`, which must be
+/// suitably aligned.
+pub fn overlay_slice<T: FromBytes>(bytes: &[u8], count: usize) -> Result<&[T], Error> {
+    let len = mem::size_of::<T>()
+        .checked_mul(count)
+        .ok_or(Error::Truncated {
+            offset: 0,
+            len: usize::MAX,
+        })?;
+    let bytes = get(bytes, 0, len)?;
+    if bytes.as_ptr().align_offset(mem::align_of::<T>()) != 0 {
+        return Err(Error::Misaligned);
+    }
+
+    // SAFETY: `bytes` is long enough and suitably aligned for `count` values, which are valid for
+    //         any bit pattern, and are borrowed for as long as `bytes` is
+    let values = unsafe { slice::from_raw_parts(bytes.as_ptr().cast::<T>(), count) };
+    for value in values {
+        value.validate()?;
+    }
+    Ok(values)
+}
+
+/// Returns a copy of the `T` at `offset` in `bytes`, which needn't be aligned.
+pub fn read<T: FromBytes>(bytes: &[u8], offset: usize) -> Result<T, Error> {
+    let bytes = get(bytes, offset, mem::size_of::<T>())?;
+
+    // SAFETY: `bytes` is long enough for a `T`, which is valid for any bit pattern
+    let value = unsafe { bytes.as_ptr().cast::<T>().read_unaligned() };
+    value.validate()?;
+    Ok(value)
+}
+
+/// Returns the sum of `bytes`, modulo 256.
+///
+/// ACPI and SMBIOS tables are valid if the sum of all their bytes, including the checksum field, is
+/// zero.
+pub fn sum8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Returns the internet checksum of `bytes`, as used by IPv4, ICMP, UDP and TCP.
+///
+/// The checksum is the ones' complement of the ones' complement sum of the big-endian 16-bit words
+/// of `bytes`, padded with a zero byte if needed. Data including a correct checksum sums to zero.
+pub fn internet_checksum(bytes: &[u8]) -> u16 {
+    let mut words = bytes.chunks_exact(2);
+    let mut sum: u64 = (&mut words)
+        .map(|word| u64::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    if let [last] = words.remainder() {
+        sum += u64::from(*last) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes aligned for any of the integers.
+    #[repr(C, align(8))]
+    struct Aligned([u8; 16]);
+
+    #[test]
+    fn get_near_usize_max() {
+        let bytes = [0; 4];
+        assert_eq!(get(&bytes, 1, 3), Ok(&bytes[1..]));
+        assert_eq!(get(&bytes, 4, 0), Ok(&[][..]));
+        for (offset, len) in [
+            (usize::MAX, 1),
+            (1, usize::MAX),
+            (usize::MAX, usize::MAX),
+            (usize::MAX, 0),
+            (2, 3),
+        ] {
+            assert_eq!(
+                get(&bytes, offset, len),
+                Err(Error::Truncated { offset, len })
+            );
+        }
+        assert_eq!(
+            le::<u32>(&bytes, usize::MAX - 1),
+            Err(Error::Truncated {
+                offset: usize::MAX - 1,
+                len: 4
+            })
+        );
+    }
+
+    #[test]
+    fn byte_order() {
+        let bytes = [0x12, 0x34, 0x56, 0x78];
+        assert_eq!(le::<u32>(&bytes, 0), Ok(0x7856_3412));
+        assert_eq!(be::<u32>(&bytes, 0), Ok(0x1234_5678));
+        assert_eq!(be::<i16>(&[0xff, 0xfe], 0), Ok(-2));
+    }
+
+    #[test]
+    fn overlay_slice_overflowing_count() {
+        let aligned = Aligned([0; 16]);
+        assert_eq!(
+            overlay_slice::<u64>(&aligned.0, usize::MAX),
+            Err(Error::Truncated {
+                offset: 0,
+                len: usize::MAX
+            })
+        );
+        assert_eq!(
+            overlay_slice::<u64>(&aligned.0, usize::MAX / 8),
+            Err(Error::Truncated {
+                offset: 0,
+                len: usize::MAX / 8 * 8
+            })
+        );
+        assert_eq!(overlay_slice::<u64>(&aligned.0, 2), Ok(&[0, 0][..]));
+        assert_eq!(overlay_slice::<u64>(&aligned.0, 0), Ok(&[][..]));
+    }
+
+    #[test]
+    fn overlay_misaligned() {
+        let aligned = Aligned([0; 16]);
+        assert_eq!(overlay::<u32>(&aligned.0[1..]), Err(Error::Misaligned));
+        assert_eq!(
+            overlay_slice::<u16>(&aligned.0[3..], 2),
+            Err(Error::Misaligned)
+        );
+        assert_eq!(overlay::<u32>(&aligned.0[4..]), Ok(&0));
+        // bytes have no alignment requirement
+        assert_eq!(overlay::<u8>(&aligned.0[3..]), Ok(&0));
+        // copying doesn't need alignment either
+        assert_eq!(read::<u32>(&aligned.0, 1), Ok(0));
+    }
+
+    #[test]
+    fn validation() {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(C)]
+        struct NonZero(u32);
+
+        // SAFETY: a `u32` is valid for any bit pattern
+        unsafe impl FromBytes for NonZero {
+            fn validate(&self) -> Result<(), Error> {
+                match self.0 {
+                    0 => Err(Error::Invalid("zero")),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let mut aligned = Aligned([0; 16]);
+        aligned.0[0] = 1;
+        assert_eq!(overlay::<NonZero>(&aligned.0), Ok(&NonZero(1)));
+        assert_eq!(
+            overlay_slice::<NonZero>(&aligned.0, 2),
+            Err(Error::Invalid("zero"))
+        );
+        assert_eq!(read::<NonZero>(&aligned.0, 4), Err(Error::Invalid("zero")));
+    }
+
+    #[test]
+    fn reader_past_end() {
+        let mut reader = Reader::new(&[1, 2, 3, 4, 5]);
+        assert_eq!(reader.le::<u16>(), Ok(0x0201));
+        assert_eq!(
+            reader.le::<u32>(),
+            Err(Error::Truncated { offset: 2, len: 4 })
+        );
+        // a failed read doesn't move the reader
+        assert_eq!(reader.position(), 2);
+        assert_eq!(
+            reader.skip(usize::MAX),
+            Err(Error::Truncated {
+                offset: 2,
+                len: usize::MAX
+            })
+        );
+        assert_eq!(
+            reader.read::<[u8; 4]>(),
+            Err(Error::Truncated { offset: 2, len: 4 })
+        );
+        assert_eq!(reader.be::<u16>(), Ok(0x0304));
+        assert_eq!(reader.remaining(), &[5]);
+        assert_eq!(reader.u8(), Ok(5));
+        assert!(reader.is_empty());
+        assert_eq!(reader.u8(), Err(Error::Truncated { offset: 5, len: 1 }));
+        assert_eq!(reader.bytes(0), Ok(&[][..]));
+    }
+
+    #[test]
+    fn sums() {
+        assert_eq!(sum8(&[0xff, 0x02, 0xff]), 0x00);
+        assert_eq!(sum8(&[]), 0);
+    }
+
+    #[test]
+    fn checksum() {
+        // the example from RFC 1071
+        let bytes = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(internet_checksum(&bytes), 0x220d);
+        let mut with_checksum = [0; 10];
+        with_checksum[..8].copy_from_slice(&bytes);
+        with_checksum[8..].copy_from_slice(&0x220du16.to_be_bytes());
+        assert_eq!(internet_checksum(&with_checksum), 0);
+
+        // an odd byte is padded with zero
+        assert_eq!(internet_checksum(&[0x01, 0x02, 0x03]), !0x0402);
+        assert_eq!(
+            internet_checksum(&[0x01, 0x02, 0x03]),
+            internet_checksum(&[0x01, 0x02, 0x03, 0x00])
+        );
+        assert_eq!(internet_checksum(&[0xff]), !0xff00);
+        assert_eq!(internet_checksum(&[]), 0xffff);
+        // carries wrap around
+        assert_eq!(internet_checksum(&[0xff; 7]), 0x00ff);
+    }
+}
@@ -0,0 +1,314 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Tables of reference-counted kernel objects, named by handles.
+//!
+//! A [`HandleTable`] holds up to `N` objects of one type, each named by a [`Handle`], which is what
+//! is passed across the system call interface instead of a pointer. Each object counts its
+//! references: [`insert`](HandleTable::insert) returns the first, [`duplicate`] adds another, and
+//! [`close`] removes one, returning the object to be destroyed once the last is closed.
+//!
+//! A handle includes the generation of its slot, which changes whenever an object is removed, so
+//! a handle which outlives its object fails with [`Error::Stale`] rather than naming whichever
+//! object reuses the slot. Handles are typed, so a handle from one table can't be passed to a
+//! table of a different type by mistake, but [`Handle::from_raw`] trusts the caller's type.
+//!
+//! [`duplicate`]: HandleTable::duplicate
+//! [`close`]: HandleTable::close
+
+use core::{fmt, marker::PhantomData};
+
+use spin::Mutex;
+
+/// An error using a handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The table is full.
+    Full,
+    /// The handle was never valid for the table.
+    Invalid,
+    /// The handle's object has been removed.
+    Stale,
+    /// The object has too many references.
+    TooManyReferences,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Full => write!(f, "handle table is full"),
+            Error::Invalid => write!(f, "invalid handle"),
+            Error::Stale => write!(f, "handle refers to an object which no longer exists"),
+            Error::TooManyReferences => write!(f, "object has too many references"),
+        }
+    }
+}
+
+impl crate::error::Error for Error {}
+
+/// The name of an object of type `T` in a [`HandleTable`].
+pub struct Handle<T> {
+    index: u32,
+    generation: u32,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    /// Returns a handle from the value returned by [`into_raw`](Self::into_raw).
+    ///
+    /// Any value is accepted, since the table checks the handle when it is used.
+    pub const fn from_raw(raw: u64) -> Self {
+        Handle {
+            index: raw as u32,
+            generation: (raw >> 32) as u32,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the handle as an integer, such as to pass to or from a system call.
+    pub const fn into_raw(self) -> u64 {
+        ((self.generation as u64) << 32) | self.index as u64
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handle({}, generation {})", self.index, self.generation)
+    }
+}
+
+/// A slot in a [`HandleTable`].
+struct Slot<T> {
+    /// Incremented whenever the slot's object is removed.
+    generation: u32,
+    /// The number of references to the object, which is zero if there is no object.
+    references: u32,
+    object: Option<T>,
+}
+
+impl<T> Slot<T> {
+    /// An empty slot.
+    const EMPTY: Self = Slot {
+        generation: 0,
+        references: 0,
+        object: None,
+    };
+}
+
+/// A table of up to `N` reference-counted objects of type `T`.
+pub struct HandleTable<T, const N: usize> {
+    slots: Mutex<[Slot<T>; N]>,
+}
+
+impl<T, const N: usize> HandleTable<T, N> {
+    /// Returns an empty table.
+    ///
+    /// # Panics
+    /// Panics if `N` doesn't fit in a `u32`.
+    pub const fn new() -> Self {
+        assert!(N <= u32::MAX as usize, "too many slots for a handle");
+        HandleTable {
+            slots: Mutex::new([Slot::EMPTY; N]),
+        }
+    }
+
+    /// Adds `object` to the table with one reference, and returns its handle, or returns the
+    /// object back with [`Error::Full`] if there is no room.
+    pub fn insert(&self, object: T) -> Result<Handle<T>, (T, Error)> {
+        let mut slots = self.slots.lock();
+        match slots.iter().position(|slot| slot.object.is_none()) {
+            Some(index) => {
+                let slot = &mut slots[index];
+                slot.object = Some(object);
+                slot.references = 1;
+                Ok(Handle {
+                    index: index as u32,
+                    generation: slot.generation,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err((object, Error::Full)),
+        }
+    }
+
+    /// Calls `f` with the object named by `handle`, and returns its result.
+    ///
+    /// The table is locked while `f` runs, so `f` must not use the same table.
+    pub fn with<R>(&self, handle: Handle<T>, f: impl FnOnce(&mut T) -> R) -> Result<R, Error> {
+        let mut slots = self.slots.lock();
+        let slot = Self::slot(&mut slots, handle)?;
+        slot.object.as_mut().map(f).ok_or(Error::Stale)
+    }
+
+    /// Adds a reference to the object named by `handle`, and returns the handle to close it with.
+    pub fn duplicate(&self, handle: Handle<T>) -> Result<Handle<T>, Error> {
+        let mut slots = self.slots.lock();
+        let slot = Self::slot(&mut slots, handle)?;
+        slot.references = slot
+            .references
+            .checked_add(1)
+            .ok_or(Error::TooManyReferences)?;
+        Ok(handle)
+    }
+
+    /// Removes a reference to the object named by `handle`. If it was the last, the object is
+    /// removed from the table and returned, so the caller can destroy it, and every handle to it
+    /// becomes stale.
+    pub fn close(&self, handle: Handle<T>) -> Result<Option<T>, Error> {
+        let mut slots = self.slots.lock();
+        let slot = Self::slot(&mut slots, handle)?;
+        slot.references -= 1;
+        if slot.references > 0 {
+            return Ok(None);
+        }
+        slot.generation = slot.generation.wrapping_add(1);
+        Ok(slot.object.take())
+    }
+
+    /// Returns the number of references to the object named by `handle`.
+    pub fn references(&self, handle: Handle<T>) -> Result<u32, Error> {
+        let mut slots = self.slots.lock();
+        Self::slot(&mut slots, handle).map(|slot| slot.references)
+    }
+
+    /// Returns the number of objects in the table.
+    pub fn len(&self) -> usize {
+        let slots = self.slots.lock();
+        slots.iter().filter(|slot| slot.object.is_some()).count()
+    }
+
+    /// Returns `true` if there are no objects in the table.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the slot of the object named by `handle`, which is present.
+    fn slot(slots: &mut [Slot<T>; N], handle: Handle<T>) -> Result<&mut Slot<T>, Error> {
+        let slot = slots.get_mut(handle.index as usize).ok_or(Error::Invalid)?;
+        if slot.generation != handle.generation || slot.references == 0 {
+            // a generation ahead of the slot's was never issued
+            return Err(
+                if slot.generation.wrapping_sub(handle.generation) as i32 > 0 {
+                    Error::Stale
+                } else {
+                    Error::Invalid
+                },
+            );
+        }
+        Ok(slot)
+    }
+}
+
+impl<T, const N: usize> Default for HandleTable<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for HandleTable<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandleTable")
+            .field("capacity", &N)
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_after_close_and_reuse() {
+        let table = HandleTable::<u32, 2>::new();
+        let first = table.insert(1).unwrap();
+        assert_eq!(table.with(first, |value| *value), Ok(1));
+        assert_eq!(table.close(first), Ok(Some(1)));
+        assert!(table.is_empty());
+        assert_eq!(table.with(first, |value| *value), Err(Error::Stale));
+        assert_eq!(table.close(first), Err(Error::Stale));
+
+        // the slot is reused with a new generation, which the old handle doesn't name
+        let second = table.insert(2).unwrap();
+        assert_ne!(second, first);
+        assert_eq!(table.with(first, |value| *value), Err(Error::Stale));
+        assert_eq!(table.duplicate(first), Err(Error::Stale));
+        assert_eq!(table.references(first), Err(Error::Stale));
+        assert_eq!(table.with(second, |value| *value), Ok(2));
+    }
+
+    #[test]
+    fn invalid_handles() {
+        let table = HandleTable::<u32, 2>::new();
+        let handle = table.insert(1).unwrap();
+        let raw = handle.into_raw();
+        assert_eq!(Handle::<u32>::from_raw(raw), handle);
+
+        // a slot beyond the table, an empty slot, and a generation which was never issued
+        for raw in [2, 1, raw + (1 << 32)] {
+            let handle = Handle::<u32>::from_raw(raw);
+            assert_eq!(table.with(handle, |value| *value), Err(Error::Invalid));
+            assert_eq!(table.close(handle), Err(Error::Invalid));
+        }
+    }
+
+    #[test]
+    fn reference_counting() {
+        let table = HandleTable::<u32, 1>::new();
+        let handle = table.insert(7).unwrap();
+        assert_eq!(table.references(handle), Ok(1));
+        assert_eq!(table.duplicate(handle), Ok(handle));
+        assert_eq!(table.duplicate(handle), Ok(handle));
+        assert_eq!(table.references(handle), Ok(3));
+
+        assert_eq!(table.close(handle), Ok(None));
+        assert_eq!(table.close(handle), Ok(None));
+        assert_eq!(table.references(handle), Ok(1));
+        assert_eq!(table.with(handle, |value| *value), Ok(7));
+        assert_eq!(table.close(handle), Ok(Some(7)));
+        assert_eq!(table.references(handle), Err(Error::Stale));
+    }
+
+    #[test]
+    fn too_many_references() {
+        let table = HandleTable::<u32, 1>::new();
+        let handle = table.insert(7).unwrap();
+        table.slots.lock()[0].references = u32::MAX;
+        assert_eq!(table.duplicate(handle), Err(Error::TooManyReferences));
+        assert_eq!(table.references(handle), Ok(u32::MAX));
+    }
+
+    #[test]
+    fn full() {
+        let table = HandleTable::<u32, 2>::new();
+        let first = table.insert(1).unwrap();
+        table.insert(2).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.insert(3), Err((3, Error::Full)));
+
+        table.close(first).unwrap();
+        let third = table.insert(3).unwrap();
+        assert_eq!(table.with(third, |value| *value), Ok(3));
+        assert_eq!(table.insert(4), Err((4, Error::Full)));
+    }
+}
@@ -0,0 +1,389 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! An intrusive doubly linked list.
+//!
+//! Elements embed a [`Link`], and implement [`Linked`] to return it, so adding an element to a
+//! [`List`] never allocates. Elements are added by reference, and the caller guarantees that they
+//! stay put until they're removed, which is trivially true for `static` elements.
+//!
+//! A list isn't synchronized itself, so a list shared with interrupt handlers must be protected by
+//! a lock which is held with interrupts disabled.
+//!
+//! ```ignore
+//! struct Waiter {
+//!     link: Link<Waiter>,
+//!     id: u32,
+//! }
+//!
+//! // SAFETY: `link` is only used for this list type
+//! unsafe impl Linked for Waiter {
+//!     fn link(&self) -> &Link<Self> {
+//!         &self.link
+//!     }
+//! }
+//! ```
+
+use core::{cell::Cell, fmt, marker::PhantomData, ptr::NonNull};
+
+/// The links embedded in an element of a [`List`].
+pub struct Link<T> {
+    next: Cell<Option<NonNull<T>>>,
+    prev: Cell<Option<NonNull<T>>>,
+    linked: Cell<bool>,
+}
+
+// SAFETY: a link is only accessed by the list which holds its element, through a mutable reference
+//         to the list
+unsafe impl<T> Send for Link<T> {}
+// SAFETY: a link is only accessed by the list which holds its element, through a mutable reference
+//         to the list
+unsafe impl<T> Sync for Link<T> {}
+
+impl<T> Link<T> {
+    /// Returns a link which is not in any list.
+    pub const fn new() -> Self {
+        Link {
+            next: Cell::new(None),
+            prev: Cell::new(None),
+            linked: Cell::new(false),
+        }
+    }
+
+    /// Returns `true` if the element is in a list.
+    pub fn is_linked(&self) -> bool {
+        self.linked.get()
+    }
+}
+
+impl<T> Default for Link<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for Link<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Link")
+            .field("linked", &self.is_linked())
+            .finish_non_exhaustive()
+    }
+}
+
+/// An element of a [`List`].
+///
+/// # Safety
+/// `link` must always return the same `Link`, which must be part of `self`, and must not be used by
+/// more than one list at a time.
+pub unsafe trait Linked: Sized {
+    /// Returns the element's link.
+    fn link(&self) -> &Link<Self>;
+}
+
+/// An intrusive doubly linked list of elements of type `T`.
+pub struct List<T: Linked> {
+    head: Option<NonNull<T>>,
+    tail: Option<NonNull<T>>,
+    len: usize,
+    _marker: PhantomData<*const T>,
+}
+
+// SAFETY: the list only hands out shared references to its elements, which may be sent between
+//         threads if `T` is `Sync`
+unsafe impl<T: Linked + Sync> Send for List<T> {}
+// SAFETY: the list only hands out shared references to its elements, which may be shared between
+//         threads if `T` is `Sync`
+unsafe impl<T: Linked + Sync> Sync for List<T> {}
+
+impl<T: Linked> List<T> {
+    /// Returns an empty list.
+    pub const fn new() -> Self {
+        List {
+            head: None,
+            tail: None,
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the number of elements in the list.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the first element.
+    pub fn front(&self) -> Option<&T> {
+        // SAFETY: elements stay valid while they're in the list
+        self.head.map(|head| unsafe { head.as_ref() })
+    }
+
+    /// Returns the last element.
+    pub fn back(&self) -> Option<&T> {
+        // SAFETY: elements stay valid while they're in the list
+        self.tail.map(|tail| unsafe { tail.as_ref() })
+    }
+
+    /// Adds `element` to the front of the list.
+    ///
+    /// # Panics
+    /// Panics if `element` is already in a list.
+    ///
+    /// # Safety
+    /// `element` must not be moved or dropped until it is removed from the list.
+    pub unsafe fn push_front(&mut self, element: &T) {
+        let link = element.link();
+        assert!(!link.is_linked(), "element is already in a list");
+
+        let ptr = NonNull::from(element);
+        link.prev.set(None);
+        link.next.set(self.head);
+        link.linked.set(true);
+        match self.head {
+            // SAFETY: elements stay valid while they're in the list
+            Some(head) => unsafe { head.as_ref() }.link().prev.set(Some(ptr)),
+            None => self.tail = Some(ptr),
+        }
+        self.head = Some(ptr);
+        self.len += 1;
+    }
+
+    /// Adds `element` to the back of the list.
+    ///
+    /// # Panics
+    /// Panics if `element` is already in a list.
+    ///
+    /// # Safety
+    /// `element` must not be moved or dropped until it is removed from the list.
+    pub unsafe fn push_back(&mut self, element: &T) {
+        let link = element.link();
+        assert!(!link.is_linked(), "element is already in a list");
+
+        let ptr = NonNull::from(element);
+        link.next.set(None);
+        link.prev.set(self.tail);
+        link.linked.set(true);
+        match self.tail {
+            // SAFETY: elements stay valid while they're in the list
+            Some(tail) => unsafe { tail.as_ref() }.link().next.set(Some(ptr)),
+            None => self.head = Some(ptr),
+        }
+        self.tail = Some(ptr);
+        self.len += 1;
+    }
+
+    /// Removes and returns the first element.
+    pub fn pop_front(&mut self) -> Option<NonNull<T>> {
+        let head = self.head?;
+        // SAFETY: `head` is in this list
+        unsafe { self.unlink(head) };
+        Some(head)
+    }
+
+    /// Removes and returns the last element.
+    pub fn pop_back(&mut self) -> Option<NonNull<T>> {
+        let tail = self.tail?;
+        // SAFETY: `tail` is in this list
+        unsafe { self.unlink(tail) };
+        Some(tail)
+    }
+
+    /// Removes `element` from the list.
+    ///
+    /// # Safety
+    /// `element` must be in this list.
+    pub unsafe fn remove(&mut self, element: &T) {
+        // SAFETY: the caller guarantees that `element` is in this list
+        unsafe { self.unlink(NonNull::from(element)) };
+    }
+
+    /// Returns an iterator over the elements, from front to back.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            next: self.head,
+            _list: PhantomData,
+        }
+    }
+
+    /// Removes `element` from the list.
+    ///
+    /// # Safety
+    /// `element` must be in this list.
+    unsafe fn unlink(&mut self, element: NonNull<T>) {
+        // SAFETY: the caller guarantees that `element` is in this list, so it is valid
+        let link = unsafe { element.as_ref() }.link();
+        debug_assert!(link.is_linked());
+
+        match link.prev.get() {
+            // SAFETY: elements stay valid while they're in the list
+            Some(prev) => unsafe { prev.as_ref() }.link().next.set(link.next.get()),
+            None => self.head = link.next.get(),
+        }
+        match link.next.get() {
+            // SAFETY: elements stay valid while they're in the list
+            Some(next) => unsafe { next.as_ref() }.link().prev.set(link.prev.get()),
+            None => self.tail = link.prev.get(),
+        }
+        link.next.set(None);
+        link.prev.set(None);
+        link.linked.set(false);
+        self.len -= 1;
+    }
+}
+
+impl<T: Linked> Default for List<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Linked + fmt::Debug> fmt::Debug for List<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, T: Linked> IntoIterator for &'a List<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+/// An iterator over the elements of a [`List`].
+pub struct Iter<'a, T: Linked> {
+    next: Option<NonNull<T>>,
+    _list: PhantomData<&'a List<T>>,
+}
+
+impl<'a, T: Linked> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        // SAFETY: elements stay valid while they're in the list, which is borrowed by the iterator
+        let element = unsafe { self.next?.as_ref() };
+        self.next = element.link().next.get();
+        Some(element)
+    }
+}
+
+impl<T: Linked> fmt::Debug for Iter<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Iter").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Node {
+        link: Link<Node>,
+        value: u32,
+    }
+
+    // SAFETY: `link` is only used for `List<Node>`
+    unsafe impl Linked for Node {
+        fn link(&self) -> &Link<Self> {
+            &self.link
+        }
+    }
+
+    fn nodes<const N: usize>() -> [Node; N] {
+        let mut value = 0;
+        [(); N].map(|()| {
+            value += 1;
+            Node {
+                link: Link::new(),
+                value,
+            }
+        })
+    }
+
+    fn values<const N: usize>(list: &List<Node>) -> ([u32; N], usize) {
+        let mut values = [0; N];
+        let mut len = 0;
+        for (slot, node) in values.iter_mut().zip(list) {
+            *slot = node.value;
+            len += 1;
+        }
+        assert_eq!(len, list.len());
+        (values, len)
+    }
+
+    /// Returns the value of a popped node, which must be one of the test's nodes.
+    fn value(node: Option<NonNull<Node>>) -> u32 {
+        // SAFETY: the test's nodes outlive the lists they're popped from
+        unsafe { node.unwrap().as_ref() }.value
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let nodes = nodes::<3>();
+        let mut list = List::new();
+        assert!(list.is_empty() && list.front().is_none() && list.back().is_none());
+        // SAFETY: `nodes` outlives `list`
+        unsafe {
+            list.push_back(&nodes[1]);
+            list.push_front(&nodes[0]);
+            list.push_back(&nodes[2]);
+        }
+        assert_eq!(values::<3>(&list), ([1, 2, 3], 3));
+        assert!(nodes.iter().all(|node| node.link.is_linked()));
+
+        assert_eq!(value(list.pop_front()), 1);
+        assert_eq!(value(list.pop_back()), 3);
+        assert_eq!(list.front().unwrap().value, 2);
+        assert_eq!(list.back().unwrap().value, 2);
+        assert_eq!(value(list.pop_back()), 2);
+        assert!(list.pop_front().is_none() && list.pop_back().is_none());
+        assert!(nodes.iter().all(|node| !node.link.is_linked()));
+    }
+
+    #[test]
+    fn remove_anywhere() {
+        let nodes = nodes::<4>();
+        let mut list = List::new();
+        // SAFETY: `nodes` outlives `list`, and each removed node is in `list`
+        unsafe {
+            for node in &nodes {
+                list.push_back(node);
+            }
+            list.remove(&nodes[1]);
+            assert_eq!(values::<4>(&list), ([1, 3, 4, 0], 3));
+            list.remove(&nodes[0]);
+            assert_eq!(values::<4>(&list), ([3, 4, 0, 0], 2));
+            list.remove(&nodes[3]);
+            assert_eq!(values::<4>(&list), ([3, 0, 0, 0], 1));
+            list.remove(&nodes[2]);
+        }
+        assert!(list.is_empty() && list.front().is_none() && list.back().is_none());
+
+        // a removed element can be added again
+        // SAFETY: `nodes` outlives `list`
+        unsafe { list.push_front(&nodes[1]) };
+        assert_eq!(values::<4>(&list), ([2, 0, 0, 0], 1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn push_twice() {
+        let nodes = nodes::<1>();
+        let mut list = List::new();
+        // SAFETY: `nodes` outlives `list`
+        unsafe {
+            list.push_back(&nodes[0]);
+            list.push_front(&nodes[0]);
+        }
+    }
+}
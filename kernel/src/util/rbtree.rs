@@ -0,0 +1,871 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! An intrusive red-black tree, which doubles as an interval tree.
+//!
+//! Elements embed an [`RbLink`], and implement [`RbNode`] to return it and their key, so adding an
+//! element to an [`RbTree`] never allocates. As with the [intrusive list](super::list), the caller
+//! guarantees that elements stay put while they're in a tree.
+//!
+//! Each element covers the interval from its [`key`](RbNode::key) to its [`end`](RbNode::end), and
+//! each link records the greatest `end` in its subtree, so [`RbTree::find_overlapping`] and
+//! [`RbTree::for_each_overlapping`] find overlapping elements without visiting the whole tree.
+//! Elements which are only ordered, and not intervals, can leave `end` as the default, which is
+//! the same as `key`.
+//!
+//! Elements with equal keys are allowed, and are kept in the order they were inserted.
+
+use core::{cell::Cell, fmt, marker::PhantomData, ops::Range, ptr::NonNull};
+
+/// The links embedded in an element of an [`RbTree`].
+pub struct RbLink<T: RbNode> {
+    parent: Cell<Option<NonNull<T>>>,
+    left: Cell<Option<NonNull<T>>>,
+    right: Cell<Option<NonNull<T>>>,
+    red: Cell<bool>,
+    linked: Cell<bool>,
+    /// The greatest `end` of the elements in this element's subtree.
+    max_end: Cell<Option<T::Key>>,
+}
+
+// SAFETY: a link is only accessed by the tree which holds its element, through a mutable reference
+//         to the tree
+unsafe impl<T: RbNode> Send for RbLink<T> {}
+// SAFETY: a link is only accessed by the tree which holds its element, through a mutable reference
+//         to the tree
+unsafe impl<T: RbNode> Sync for RbLink<T> {}
+
+impl<T: RbNode> RbLink<T> {
+    /// Returns a link which is not in any tree.
+    pub const fn new() -> Self {
+        RbLink {
+            parent: Cell::new(None),
+            left: Cell::new(None),
+            right: Cell::new(None),
+            red: Cell::new(false),
+            linked: Cell::new(false),
+            max_end: Cell::new(None),
+        }
+    }
+
+    /// Returns `true` if the element is in a tree.
+    pub fn is_linked(&self) -> bool {
+        self.linked.get()
+    }
+}
+
+impl<T: RbNode> Default for RbLink<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: RbNode> fmt::Debug for RbLink<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RbLink")
+            .field("linked", &self.is_linked())
+            .finish_non_exhaustive()
+    }
+}
+
+/// An element of an [`RbTree`].
+///
+/// # Safety
+/// `link` must always return the same `RbLink`, which must be part of `self`, and must not be used
+/// by more than one tree at a time. `key` and `end` must not change while the element is in a tree.
+pub unsafe trait RbNode: Sized {
+    /// The type of the key elements are ordered by.
+    type Key: Ord + Copy;
+
+    /// Returns the element's link.
+    fn link(&self) -> &RbLink<Self>;
+
+    /// Returns the element's key, which is the start of its interval.
+    fn key(&self) -> Self::Key;
+
+    /// Returns the exclusive end of the element's interval.
+    fn end(&self) -> Self::Key {
+        self.key()
+    }
+}
+
+/// An intrusive red-black tree of elements of type `T`, ordered by key.
+pub struct RbTree<T: RbNode> {
+    root: Option<NonNull<T>>,
+    len: usize,
+    _marker: PhantomData<*const T>,
+}
+
+// SAFETY: the tree only hands out shared references to its elements, which may be sent between
+//         threads if `T` is `Sync`
+unsafe impl<T: RbNode + Sync> Send for RbTree<T> {}
+// SAFETY: the tree only hands out shared references to its elements, which may be shared between
+//         threads if `T` is `Sync`
+unsafe impl<T: RbNode + Sync> Sync for RbTree<T> {}
+
+impl<T: RbNode> RbTree<T> {
+    /// Returns an empty tree.
+    pub const fn new() -> Self {
+        RbTree {
+            root: None,
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the number of elements in the tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the tree is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the element with the smallest key.
+    pub fn first(&self) -> Option<&T> {
+        self.root.map(|root| node(leftmost(root)))
+    }
+
+    /// Returns the element with the largest key.
+    pub fn last(&self) -> Option<&T> {
+        self.root.map(|root| node(rightmost(root)))
+    }
+
+    /// Returns the first element with key `key`.
+    pub fn find(&self, key: T::Key) -> Option<&T> {
+        self.lower_bound(key).filter(|element| element.key() == key)
+    }
+
+    /// Returns the first element with a key greater than or equal to `key`.
+    pub fn lower_bound(&self, key: T::Key) -> Option<&T> {
+        let mut current = self.root;
+        let mut found = None;
+        while let Some(n) = current {
+            if node(n).key() >= key {
+                found = Some(n);
+                current = link(n).left.get();
+            } else {
+                current = link(n).right.get();
+            }
+        }
+        found.map(node)
+    }
+
+    /// Returns the last element with a key less than or equal to `key`.
+    ///
+    /// For non-overlapping intervals, this is the only element which may contain `key`.
+    pub fn find_le(&self, key: T::Key) -> Option<&T> {
+        let mut current = self.root;
+        let mut found = None;
+        while let Some(n) = current {
+            if node(n).key() <= key {
+                found = Some(n);
+                current = link(n).right.get();
+            } else {
+                current = link(n).left.get();
+            }
+        }
+        found.map(node)
+    }
+
+    /// Returns the element with the smallest key which overlaps `range`.
+    pub fn find_overlapping(&self, range: Range<T::Key>) -> Option<&T> {
+        let mut current = self.root;
+        while let Some(n) = current {
+            if max_end(n) <= range.start {
+                return None;
+            }
+            let element = node(n);
+            if element.key() >= range.end {
+                current = link(n).left.get();
+                continue;
+            }
+            // every element in the left subtree starts before `range` ends, so if any of them ends
+            // after `range` starts, the left subtree has an overlapping element
+            match link(n).left.get() {
+                Some(left) if max_end(left) > range.start => current = Some(left),
+                _ if element.end() > range.start => return Some(element),
+                _ => current = link(n).right.get(),
+            }
+        }
+        None
+    }
+
+    /// Calls `f` with each element which overlaps `range`, in order.
+    pub fn for_each_overlapping(&self, range: Range<T::Key>, mut f: impl FnMut(&T)) {
+        if let Some(root) = self.root {
+            overlapping(root, &range, &mut f);
+        }
+    }
+
+    /// Returns the element after `element`.
+    ///
+    /// # Safety
+    /// `element` must be in this tree.
+    pub unsafe fn next(&self, element: &T) -> Option<&T> {
+        successor(NonNull::from(element)).map(node)
+    }
+
+    /// Returns the element before `element`.
+    ///
+    /// # Safety
+    /// `element` must be in this tree.
+    pub unsafe fn prev(&self, element: &T) -> Option<&T> {
+        predecessor(NonNull::from(element)).map(node)
+    }
+
+    /// Returns an iterator over the elements, in order.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            next: self.root.map(leftmost),
+            _tree: PhantomData,
+        }
+    }
+
+    /// Adds `element` to the tree, after any elements with the same key.
+    ///
+    /// # Panics
+    /// Panics if `element` is already in a tree.
+    ///
+    /// # Safety
+    /// `element` must not be moved or dropped until it is removed from the tree.
+    pub unsafe fn insert(&mut self, element: &T) {
+        let new = element.link();
+        assert!(!new.is_linked(), "element is already in a tree");
+
+        let key = element.key();
+        let mut parent = None;
+        let mut current = self.root;
+        let mut is_left = false;
+        while let Some(n) = current {
+            parent = Some(n);
+            is_left = key < node(n).key();
+            current = if is_left {
+                link(n).left.get()
+            } else {
+                link(n).right.get()
+            };
+        }
+
+        let ptr = NonNull::from(element);
+        new.parent.set(parent);
+        new.left.set(None);
+        new.right.set(None);
+        new.red.set(true);
+        new.linked.set(true);
+        new.max_end.set(Some(element.end()));
+        match parent {
+            Some(p) if is_left => link(p).left.set(Some(ptr)),
+            Some(p) => link(p).right.set(Some(ptr)),
+            None => self.root = Some(ptr),
+        }
+        update_max_to_root(parent);
+
+        self.insert_fixup(ptr);
+        self.len += 1;
+    }
+
+    /// Removes `element` from the tree.
+    ///
+    /// # Safety
+    /// `element` must be in this tree.
+    pub unsafe fn remove(&mut self, element: &T) {
+        let z = NonNull::from(element);
+        let z_link = link(z);
+        debug_assert!(z_link.is_linked());
+
+        let mut removed_red = z_link.red.get();
+        let x;
+        let x_parent;
+        match (z_link.left.get(), z_link.right.get()) {
+            (None, right) => {
+                x = right;
+                x_parent = z_link.parent.get();
+                self.transplant(z, right);
+            }
+            (left, None) => {
+                x = left;
+                x_parent = z_link.parent.get();
+                self.transplant(z, left);
+            }
+            (Some(left), Some(right)) => {
+                // replace `z` with its successor, `y`
+                let y = leftmost(right);
+                let y_link = link(y);
+                removed_red = y_link.red.get();
+                x = y_link.right.get();
+                if y_link.parent.get() == Some(z) {
+                    x_parent = Some(y);
+                } else {
+                    x_parent = y_link.parent.get();
+                    self.transplant(y, x);
+                    y_link.right.set(Some(right));
+                    link(right).parent.set(Some(y));
+                }
+                self.transplant(z, Some(y));
+                y_link.left.set(Some(left));
+                link(left).parent.set(Some(y));
+                y_link.red.set(z_link.red.get());
+            }
+        }
+        update_max_to_root(x_parent);
+
+        if !removed_red {
+            self.remove_fixup(x, x_parent);
+        }
+
+        z_link.parent.set(None);
+        z_link.left.set(None);
+        z_link.right.set(None);
+        z_link.linked.set(false);
+        z_link.max_end.set(None);
+        self.len -= 1;
+    }
+
+    /// Restores the red-black properties after inserting `z`.
+    fn insert_fixup(&mut self, mut z: NonNull<T>) {
+        while let Some(p) = link(z).parent.get().filter(|&p| is_red(Some(p))) {
+            // the root is black, so a red parent has a parent
+            let g = link(p).parent.get().expect("red root");
+            if link(g).left.get() == Some(p) {
+                let uncle = link(g).right.get();
+                if is_red(uncle) {
+                    set_red(Some(p), false);
+                    set_red(uncle, false);
+                    set_red(Some(g), true);
+                    z = g;
+                } else {
+                    let p = if link(p).right.get() == Some(z) {
+                        self.rotate_left(p);
+                        z
+                    } else {
+                        p
+                    };
+                    set_red(Some(p), false);
+                    set_red(Some(g), true);
+                    self.rotate_right(g);
+                    break;
+                }
+            } else {
+                let uncle = link(g).left.get();
+                if is_red(uncle) {
+                    set_red(Some(p), false);
+                    set_red(uncle, false);
+                    set_red(Some(g), true);
+                    z = g;
+                } else {
+                    let p = if link(p).left.get() == Some(z) {
+                        self.rotate_right(p);
+                        z
+                    } else {
+                        p
+                    };
+                    set_red(Some(p), false);
+                    set_red(Some(g), true);
+                    self.rotate_left(g);
+                    break;
+                }
+            }
+        }
+        set_red(self.root, false);
+    }
+
+    /// Restores the red-black properties after removing a black element, where `x`, whose parent
+    /// is `parent`, took the removed element's place and is short one black element.
+    fn remove_fixup(&mut self, mut x: Option<NonNull<T>>, mut parent: Option<NonNull<T>>) {
+        while x != self.root && !is_red(x) {
+            // `x` isn't the root, so it has a parent, and since it's short one black element, it
+            // has a sibling
+            let p = parent.expect("non-root without a parent");
+            if link(p).left.get() == x {
+                let mut w = link(p).right.get().expect("missing sibling");
+                if is_red(Some(w)) {
+                    set_red(Some(w), false);
+                    set_red(Some(p), true);
+                    self.rotate_left(p);
+                    w = link(p).right.get().expect("missing sibling");
+                }
+                if !is_red(link(w).left.get()) && !is_red(link(w).right.get()) {
+                    set_red(Some(w), true);
+                    x = Some(p);
+                    parent = link(p).parent.get();
+                } else {
+                    if !is_red(link(w).right.get()) {
+                        set_red(link(w).left.get(), false);
+                        set_red(Some(w), true);
+                        self.rotate_right(w);
+                        w = link(p).right.get().expect("missing sibling");
+                    }
+                    set_red(Some(w), is_red(Some(p)));
+                    set_red(Some(p), false);
+                    set_red(link(w).right.get(), false);
+                    self.rotate_left(p);
+                    x = self.root;
+                    break;
+                }
+            } else {
+                let mut w = link(p).left.get().expect("missing sibling");
+                if is_red(Some(w)) {
+                    set_red(Some(w), false);
+                    set_red(Some(p), true);
+                    self.rotate_right(p);
+                    w = link(p).left.get().expect("missing sibling");
+                }
+                if !is_red(link(w).left.get()) && !is_red(link(w).right.get()) {
+                    set_red(Some(w), true);
+                    x = Some(p);
+                    parent = link(p).parent.get();
+                } else {
+                    if !is_red(link(w).left.get()) {
+                        set_red(link(w).right.get(), false);
+                        set_red(Some(w), true);
+                        self.rotate_left(w);
+                        w = link(p).left.get().expect("missing sibling");
+                    }
+                    set_red(Some(w), is_red(Some(p)));
+                    set_red(Some(p), false);
+                    set_red(link(w).left.get(), false);
+                    self.rotate_right(p);
+                    x = self.root;
+                    break;
+                }
+            }
+        }
+        set_red(x, false);
+    }
+
+    /// Rotates `x` down to the left, so its right child takes its place.
+    fn rotate_left(&mut self, x: NonNull<T>) {
+        let y = link(x).right.get().expect("rotating without a right child");
+        let inner = link(y).left.get();
+        link(x).right.set(inner);
+        if let Some(inner) = inner {
+            link(inner).parent.set(Some(x));
+        }
+        self.transplant(x, Some(y));
+        link(y).left.set(Some(x));
+        link(x).parent.set(Some(y));
+        update_max(x);
+        update_max(y);
+    }
+
+    /// Rotates `x` down to the right, so its left child takes its place.
+    fn rotate_right(&mut self, x: NonNull<T>) {
+        let y = link(x).left.get().expect("rotating without a left child");
+        let inner = link(y).right.get();
+        link(x).left.set(inner);
+        if let Some(inner) = inner {
+            link(inner).parent.set(Some(x));
+        }
+        self.transplant(x, Some(y));
+        link(y).right.set(Some(x));
+        link(x).parent.set(Some(y));
+        update_max(x);
+        update_max(y);
+    }
+
+    /// Puts `v` in `u`'s place under `u`'s parent.
+    fn transplant(&mut self, u: NonNull<T>, v: Option<NonNull<T>>) {
+        let parent = link(u).parent.get();
+        match parent {
+            Some(p) if link(p).left.get() == Some(u) => link(p).left.set(v),
+            Some(p) => link(p).right.set(v),
+            None => self.root = v,
+        }
+        if let Some(v) = v {
+            link(v).parent.set(parent);
+        }
+    }
+}
+
+impl<T: RbNode> Default for RbTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: RbNode + fmt::Debug> fmt::Debug for RbTree<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, T: RbNode> IntoIterator for &'a RbTree<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+/// An iterator over the elements of an [`RbTree`], in order.
+pub struct Iter<'a, T: RbNode> {
+    next: Option<NonNull<T>>,
+    _tree: PhantomData<&'a RbTree<T>>,
+}
+
+impl<'a, T: RbNode> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let current = self.next?;
+        self.next = successor(current);
+        Some(node(current))
+    }
+}
+
+impl<T: RbNode> fmt::Debug for Iter<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Iter").finish_non_exhaustive()
+    }
+}
+
+/// Returns the element `n` points to.
+///
+/// Only pointers to elements in a tree may be passed to this and the other helper functions below.
+fn node<'a, T: RbNode>(n: NonNull<T>) -> &'a T {
+    // SAFETY: elements stay valid while they're in a tree, and these helpers are only used on
+    //         elements in a tree
+    unsafe { n.as_ref() }
+}
+
+/// Returns the link of the element `n` points to.
+fn link<'a, T: RbNode>(n: NonNull<T>) -> &'a RbLink<T> {
+    node(n).link()
+}
+
+/// Returns `true` if `n` is a red element; missing elements are black.
+fn is_red<T: RbNode>(n: Option<NonNull<T>>) -> bool {
+    matches!(n, Some(n) if link(n).red.get())
+}
+
+/// Makes `n` red or black, if it's not missing.
+fn set_red<T: RbNode>(n: Option<NonNull<T>>, red: bool) {
+    if let Some(n) = n {
+        link(n).red.set(red);
+    }
+}
+
+/// Returns the greatest `end` in `n`'s subtree.
+fn max_end<T: RbNode>(n: NonNull<T>) -> T::Key {
+    link(n).max_end.get().unwrap_or_else(|| node(n).end())
+}
+
+/// Recalculates the greatest `end` in `n`'s subtree from its children.
+fn update_max<T: RbNode>(n: NonNull<T>) {
+    let l = link(n);
+    let max = [l.left.get(), l.right.get()]
+        .into_iter()
+        .flatten()
+        .map(max_end)
+        .fold(node(n).end(), core::cmp::max);
+    l.max_end.set(Some(max));
+}
+
+/// Recalculates the greatest `end` in the subtree of `n` and each of its ancestors.
+fn update_max_to_root<T: RbNode>(mut n: Option<NonNull<T>>) {
+    while let Some(current) = n {
+        update_max(current);
+        n = link(current).parent.get();
+    }
+}
+
+/// Returns the leftmost element in `n`'s subtree.
+fn leftmost<T: RbNode>(mut n: NonNull<T>) -> NonNull<T> {
+    while let Some(left) = link(n).left.get() {
+        n = left;
+    }
+    n
+}
+
+/// Returns the rightmost element in `n`'s subtree.
+fn rightmost<T: RbNode>(mut n: NonNull<T>) -> NonNull<T> {
+    while let Some(right) = link(n).right.get() {
+        n = right;
+    }
+    n
+}
+
+/// Returns the element after `n`.
+fn successor<T: RbNode>(mut n: NonNull<T>) -> Option<NonNull<T>> {
+    if let Some(right) = link(n).right.get() {
+        return Some(leftmost(right));
+    }
+    while let Some(parent) = link(n).parent.get() {
+        if link(parent).left.get() == Some(n) {
+            return Some(parent);
+        }
+        n = parent;
+    }
+    None
+}
+
+/// Returns the element before `n`.
+fn predecessor<T: RbNode>(mut n: NonNull<T>) -> Option<NonNull<T>> {
+    if let Some(left) = link(n).left.get() {
+        return Some(rightmost(left));
+    }
+    while let Some(parent) = link(n).parent.get() {
+        if link(parent).right.get() == Some(n) {
+            return Some(parent);
+        }
+        n = parent;
+    }
+    None
+}
+
+/// Calls `f` with each element in `n`'s subtree which overlaps `range`, in order.
+fn overlapping<T: RbNode>(n: NonNull<T>, range: &Range<T::Key>, f: &mut impl FnMut(&T)) {
+    if max_end(n) <= range.start {
+        return;
+    }
+    if let Some(left) = link(n).left.get() {
+        overlapping(left, range, f);
+    }
+    let element = node(n);
+    if element.key() >= range.end {
+        return;
+    }
+    if element.end() > range.start {
+        f(element);
+    }
+    if let Some(right) = link(n).right.get() {
+        overlapping(right, range, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The number of elements in the randomized tests.
+    const NODES: usize = 64;
+
+    struct Node {
+        link: RbLink<Node>,
+        key: u32,
+        end: u32,
+        /// The order the node was last inserted in, to check that equal keys keep that order.
+        seq: Cell<u32>,
+    }
+
+    // SAFETY: `link` is only used for `RbTree<Node>`, and `key` and `end` never change
+    unsafe impl RbNode for Node {
+        type Key = u32;
+
+        fn link(&self) -> &RbLink<Self> {
+            &self.link
+        }
+
+        fn key(&self) -> u32 {
+            self.key
+        }
+
+        fn end(&self) -> u32 {
+            self.end
+        }
+    }
+
+    /// A linear congruential generator, so the tests are repeatable.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, bound: u32) -> u32 {
+            self.0 = self
+                .0
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((self.0 >> 33) % u64::from(bound)) as u32
+        }
+    }
+
+    /// Returns nodes with random keys from a small range, so some keys repeat, and random lengths.
+    fn nodes(rng: &mut Lcg) -> [Node; NODES] {
+        [(); NODES].map(|()| {
+            let key = rng.next(40);
+            Node {
+                link: RbLink::new(),
+                key,
+                end: key + rng.next(8),
+                seq: Cell::new(0),
+            }
+        })
+    }
+
+    /// Checks the red-black and interval properties of `n`'s subtree, whose parent is `parent`,
+    /// returning its black height and its number of elements.
+    fn check_subtree(n: Option<NonNull<Node>>, parent: Option<NonNull<Node>>) -> (usize, usize) {
+        let n = match n {
+            Some(n) => n,
+            None => return (1, 0),
+        };
+        let l = link(n);
+        assert!(l.is_linked());
+        assert_eq!(l.parent.get(), parent, "wrong parent pointer");
+        if is_red(Some(n)) {
+            assert!(!is_red(parent), "red element with a red parent");
+        }
+        let (left_height, left_len) = check_subtree(l.left.get(), Some(n));
+        let (right_height, right_len) = check_subtree(l.right.get(), Some(n));
+        assert_eq!(left_height, right_height, "unequal black heights");
+
+        let expected_max = [l.left.get(), l.right.get()]
+            .into_iter()
+            .flatten()
+            .map(max_end)
+            .fold(node(n).end, core::cmp::max);
+        assert_eq!(max_end(n), expected_max, "stale max_end");
+
+        let height = left_height + usize::from(!is_red(Some(n)));
+        (height, left_len + right_len + 1)
+    }
+
+    /// Checks every invariant of `tree`, which holds the linked elements of `nodes`.
+    fn check(tree: &RbTree<Node>, nodes: &[Node]) {
+        assert!(!is_red(tree.root), "red root");
+        let (_, len) = check_subtree(tree.root, None);
+        assert_eq!(len, tree.len());
+        assert_eq!(len, nodes.iter().filter(|n| n.link.is_linked()).count());
+
+        let mut count = 0;
+        let mut prev: Option<&Node> = None;
+        for element in tree {
+            if let Some(prev) = prev {
+                assert!(prev.key <= element.key, "out of order");
+                if prev.key == element.key {
+                    assert!(
+                        prev.seq.get() < element.seq.get(),
+                        "equal keys out of order"
+                    );
+                }
+                // SAFETY: `element` is in `tree`
+                assert!(core::ptr::eq(unsafe { tree.prev(element) }.unwrap(), prev));
+            }
+            prev = Some(element);
+            count += 1;
+        }
+        assert_eq!(count, len);
+        if let Some(last) = prev {
+            assert!(core::ptr::eq(tree.last().unwrap(), last));
+            // SAFETY: `last` is in `tree`
+            assert!(unsafe { tree.next(last) }.is_none());
+        }
+    }
+
+    /// Checks the searches of `tree`, which holds the linked elements of `nodes`, against a linear
+    /// search of `nodes`.
+    fn check_searches(tree: &RbTree<Node>, nodes: &[Node]) {
+        let linked = || nodes.iter().filter(|n| n.link.is_linked());
+        for key in 0..50 {
+            let lower = linked().map(|n| n.key).filter(|&k| k >= key).min();
+            assert_eq!(tree.lower_bound(key).map(|n| n.key), lower);
+            let le = linked().map(|n| n.key).filter(|&k| k <= key).max();
+            assert_eq!(tree.find_le(key).map(|n| n.key), le);
+            assert_eq!(tree.find(key).is_some(), lower == Some(key));
+
+            let range = key..key + 3;
+            let overlaps = |n: &Node| n.key < range.end && n.end > range.start;
+            let first = linked().filter(|n| overlaps(n)).map(|n| n.key).min();
+            assert_eq!(tree.find_overlapping(range.clone()).map(|n| n.key), first);
+            let mut found = 0;
+            tree.for_each_overlapping(range.clone(), |n| {
+                assert!(overlaps(n));
+                found += 1;
+            });
+            assert_eq!(found, linked().filter(|n| overlaps(n)).count());
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let tree = RbTree::<Node>::new();
+        assert!(tree.is_empty());
+        assert!(tree.first().is_none() && tree.last().is_none());
+        assert!(tree.iter().next().is_none());
+        assert!(tree.find_overlapping(0..u32::MAX).is_none());
+    }
+
+    #[test]
+    fn sorted_inserts_then_removes() {
+        let mut key = 0;
+        let nodes = [(); NODES].map(|()| {
+            key += 1;
+            Node {
+                link: RbLink::new(),
+                key,
+                end: key + 1,
+                seq: Cell::new(0),
+            }
+        });
+        let mut tree = RbTree::new();
+        // SAFETY: `nodes` outlives `tree`, and each removed node is in `tree`
+        unsafe {
+            for node in &nodes {
+                tree.insert(node);
+                check(&tree, &nodes);
+            }
+            check_searches(&tree, &nodes);
+            for node in nodes.iter().rev() {
+                tree.remove(node);
+                check(&tree, &nodes);
+            }
+        }
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn random_inserts_and_removes() {
+        let mut rng = Lcg(0x5eed);
+        for _ in 0..8 {
+            let nodes = nodes(&mut rng);
+            let mut tree = RbTree::new();
+            for seq in 1..=1000 {
+                let node = &nodes[rng.next(NODES as u32) as usize];
+                // SAFETY: `nodes` outlives `tree`, and a node is only removed if it's in `tree`
+                unsafe {
+                    if node.link.is_linked() {
+                        tree.remove(node);
+                    } else {
+                        node.seq.set(seq);
+                        tree.insert(node);
+                    }
+                }
+                check(&tree, &nodes);
+                if seq % 100 == 0 {
+                    check_searches(&tree, &nodes);
+                }
+            }
+
+            while let Some(first) = tree.first() {
+                let first = NonNull::from(first);
+                // SAFETY: `first` is in `tree`
+                unsafe { tree.remove(node(first)) };
+                check(&tree, &nodes);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn insert_twice() {
+        let node = Node {
+            link: RbLink::new(),
+            key: 1,
+            end: 1,
+            seq: Cell::new(0),
+        };
+        let mut tree = RbTree::new();
+        // SAFETY: `node` outlives `tree`
+        unsafe {
+            tree.insert(&node);
+            tree.insert(&node);
+        }
+    }
+}
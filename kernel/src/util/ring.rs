@@ -0,0 +1,493 @@
+//  Copyright 2022 Michael Leany
+//
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+////////////////////////////////////////////////////////////////////////////////////////////////////
+//! Fixed-capacity, lock-free ring buffers.
+//!
+//! [`SpscRing`] has a single producer and a single consumer, such as an interrupt handler passing
+//! input to the code which processes it. [`MpscQueue`] allows any number of producers, such as log
+//! messages from interrupt handlers and normal code, but still a single consumer.
+//!
+//! Neither ever blocks, so both can be used from interrupt context: pushing to a full buffer
+//! returns the value instead, and popping from an empty buffer returns `None`.
+//!
+//! Since a ring buffer is normally a `static`, the producer and consumer ends are claimed at run
+//! time, instead of by splitting a mutable reference.
+//!
+//! ```ignore
+//! static INPUT: SpscRing<u8, 64> = SpscRing::new();
+//!
+//! // in the interrupt handler
+//! let _ = INPUT.producer().unwrap().push(byte);
+//! ```
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// A ring buffer with a single producer and a single consumer, which holds up to `N` values.
+pub struct SpscRing<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// The number of values ever popped. Only written by the consumer.
+    head: AtomicUsize,
+    /// The number of values ever pushed. Only written by the producer.
+    tail: AtomicUsize,
+    producer_claimed: AtomicBool,
+    consumer_claimed: AtomicBool,
+}
+
+// SAFETY: values are only moved into the ring by the single producer and out by the single
+//         consumer, and each slot is only accessed by one of them at a time
+unsafe impl<T: Send, const N: usize> Sync for SpscRing<T, N> {}
+
+impl<T, const N: usize> SpscRing<T, N> {
+    /// Returns an empty ring buffer.
+    ///
+    /// # Panics
+    /// Panics if `N` is zero.
+    pub const fn new() -> Self {
+        assert!(N > 0, "a ring buffer must have a capacity");
+        SpscRing {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            producer_claimed: AtomicBool::new(false),
+            consumer_claimed: AtomicBool::new(false),
+        }
+    }
+
+    /// Claims the producer end, returning `None` if it has already been claimed and not released.
+    pub fn producer(&self) -> Option<Producer<'_, T, N>> {
+        (!self.producer_claimed.swap(true, Ordering::Acquire)).then(|| Producer { ring: self })
+    }
+
+    /// Claims the consumer end, returning `None` if it has already been claimed and not released.
+    pub fn consumer(&self) -> Option<Consumer<'_, T, N>> {
+        (!self.consumer_claimed.swap(true, Ordering::Acquire)).then(|| Consumer { ring: self })
+    }
+
+    /// Returns the number of values in the ring.
+    ///
+    /// The result may be out of date by the time it is used, unless called by the producer or
+    /// consumer while the other end is idle.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    /// Returns `true` if the ring is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of values the ring can hold.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for SpscRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for SpscRing<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpscRing")
+            .field("len", &self.len())
+            .field("capacity", &N)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T, const N: usize> Drop for SpscRing<T, N> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let mut head = *self.head.get_mut();
+        while head != tail {
+            // SAFETY: the slots from `head` to `tail` hold values which haven't been popped
+            unsafe { self.slots[head % N].get_mut().assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+/// The producer end of an [`SpscRing`], which is released when dropped.
+pub struct Producer<'a, T, const N: usize> {
+    ring: &'a SpscRing<T, N>,
+}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// Pushes `value` onto the ring, or returns it if the ring is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.ring.head.load(Ordering::Acquire)) >= N {
+            return Err(value);
+        }
+
+        // SAFETY: the slot at `tail` is outside the consumer's range, and this is the only
+        //         producer
+        unsafe { (*self.ring.slots[tail % N].get()).write(value) };
+        self.ring
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> Drop for Producer<'_, T, N> {
+    fn drop(&mut self) {
+        self.ring.producer_claimed.store(false, Ordering::Release);
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for Producer<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Producer").field("ring", self.ring).finish()
+    }
+}
+
+/// The consumer end of an [`SpscRing`], which is released when dropped.
+pub struct Consumer<'a, T, const N: usize> {
+    ring: &'a SpscRing<T, N>,
+}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    /// Pops the oldest value from the ring, or returns `None` if the ring is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        if head == self.ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        // SAFETY: the slot at `head` was written by the producer before it advanced `tail`, and
+        //         this is the only consumer
+        let value = unsafe { (*self.ring.slots[head % N].get()).assume_init_read() };
+        self.ring
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Iterator for Consumer<'_, T, N> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.pop()
+    }
+}
+
+impl<T, const N: usize> Drop for Consumer<'_, T, N> {
+    fn drop(&mut self) {
+        self.ring.consumer_claimed.store(false, Ordering::Release);
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for Consumer<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Consumer").field("ring", self.ring).finish()
+    }
+}
+
+/// A slot in an [`MpscQueue`].
+struct Slot<T> {
+    /// The position in the queue the slot is ready for: `position` when it's ready to be written,
+    /// and `position + 1` when it's ready to be read.
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A queue with any number of producers and a single consumer, which holds up to `N` values.
+///
+/// This is Dmitry Vyukov's bounded queue, where each slot has a sequence number which tells
+/// producers and the consumer whether it is ready for them.
+pub struct MpscQueue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    /// The position of the next value to pop. Only written by the consumer.
+    head: AtomicUsize,
+    /// The position of the next value to push.
+    tail: AtomicUsize,
+    consumer_claimed: AtomicBool,
+}
+
+// SAFETY: a slot's value is only accessed by the producer or consumer which the slot's sequence
+//         number grants it to
+unsafe impl<T: Send, const N: usize> Sync for MpscQueue<T, N> {}
+
+impl<T, const N: usize> MpscQueue<T, N> {
+    /// Returns an empty queue.
+    ///
+    /// # Panics
+    /// Panics if `N` is less than two, since with a single slot, a value waiting to be popped would
+    /// have the sequence number of the next position to push.
+    pub const fn new() -> Self {
+        assert!(N > 1, "a queue must have a capacity of at least two");
+        let mut slots = [const {
+            Slot {
+                sequence: AtomicUsize::new(0),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            }
+        }; N];
+        let mut i = 0;
+        while i < N {
+            slots[i].sequence = AtomicUsize::new(i);
+            i += 1;
+        }
+
+        MpscQueue {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            consumer_claimed: AtomicBool::new(false),
+        }
+    }
+
+    /// Pushes `value` onto the queue, or returns it if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[tail % N];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+
+            match (sequence as isize).wrapping_sub(tail as isize) {
+                0 => match self.tail.compare_exchange_weak(
+                    tail,
+                    tail.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: claiming position `tail` gives this producer the slot, until it
+                        //         advances the slot's sequence number
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.sequence.store(tail.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => tail = current,
+                },
+                // the slot still holds a value from `N` positions ago
+                diff if diff < 0 => return Err(value),
+                // another producer claimed this position
+                _ => tail = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Claims the consumer end, returning `None` if it has already been claimed and not released.
+    pub fn consumer(&self) -> Option<QueueConsumer<'_, T, N>> {
+        (!self.consumer_claimed.swap(true, Ordering::Acquire))
+            .then(|| QueueConsumer { queue: self })
+    }
+
+    /// Returns the number of values in the queue, including any still being pushed.
+    ///
+    /// The result may be out of date by the time it is used.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    /// Returns `true` if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of values the queue can hold.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for MpscQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for MpscQueue<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MpscQueue")
+            .field("len", &self.len())
+            .field("capacity", &N)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T, const N: usize> Drop for MpscQueue<T, N> {
+    fn drop(&mut self) {
+        while let Some(mut consumer) = self.consumer() {
+            if consumer.pop().is_none() {
+                break;
+            }
+        }
+    }
+}
+
+/// The consumer end of an [`MpscQueue`], which is released when dropped.
+pub struct QueueConsumer<'a, T, const N: usize> {
+    queue: &'a MpscQueue<T, N>,
+}
+
+impl<T, const N: usize> QueueConsumer<'_, T, N> {
+    /// Pops the oldest value from the queue, or returns `None` if the queue is empty.
+    ///
+    /// A value which a producer is still in the middle of pushing is treated as not yet pushed.
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        let slot = &self.queue.slots[head % N];
+        if slot.sequence.load(Ordering::Acquire) != head.wrapping_add(1) {
+            return None;
+        }
+
+        // SAFETY: the slot's sequence number shows that a producer has finished writing it, and
+        //         this is the only consumer
+        let value = unsafe { (*slot.value.get()).assume_init_read() };
+        slot.sequence.store(head.wrapping_add(N), Ordering::Release);
+        self.queue
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Iterator for QueueConsumer<'_, T, N> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.pop()
+    }
+}
+
+impl<T, const N: usize> Drop for QueueConsumer<'_, T, N> {
+    fn drop(&mut self) {
+        self.queue.consumer_claimed.store(false, Ordering::Release);
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for QueueConsumer<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueueConsumer")
+            .field("queue", self.queue)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ends_claimed_once() {
+        let ring = SpscRing::<u8, 2>::new();
+        let producer = ring.producer().unwrap();
+        assert!(ring.producer().is_none());
+        let consumer = ring.consumer().unwrap();
+        assert!(ring.consumer().is_none());
+        drop((producer, consumer));
+        assert!(ring.producer().is_some() && ring.consumer().is_some());
+
+        let queue = MpscQueue::<u8, 2>::new();
+        let consumer = queue.consumer().unwrap();
+        assert!(queue.consumer().is_none());
+        drop(consumer);
+        assert!(queue.consumer().is_some());
+    }
+
+    #[test]
+    fn spsc_full_and_empty() {
+        let ring = SpscRing::<u32, 4>::new();
+        let (mut producer, mut consumer) = (ring.producer().unwrap(), ring.consumer().unwrap());
+        assert!(ring.is_empty());
+        assert_eq!(consumer.pop(), None);
+        for i in 0..4 {
+            assert_eq!(producer.push(i), Ok(()));
+        }
+        assert_eq!(ring.len(), ring.capacity());
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(consumer.pop(), Some(0));
+        assert_eq!(producer.push(4), Ok(()));
+        assert!((1..5).eq(&mut consumer));
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn spsc_wraparound() {
+        let ring = SpscRing::<usize, 3>::new();
+        let (mut producer, mut consumer) = (ring.producer().unwrap(), ring.consumer().unwrap());
+        for i in 0..10 {
+            assert_eq!(producer.push(2 * i), Ok(()));
+            assert_eq!(producer.push(2 * i + 1), Ok(()));
+            assert_eq!(consumer.pop(), Some(2 * i));
+            assert_eq!(consumer.pop(), Some(2 * i + 1));
+            assert_eq!(consumer.pop(), None);
+        }
+    }
+
+    #[test]
+    fn spsc_counter_overflow() {
+        let ring = SpscRing::<usize, 2>::new();
+        ring.head.store(usize::MAX - 1, Ordering::Relaxed);
+        ring.tail.store(usize::MAX - 1, Ordering::Relaxed);
+        let (mut producer, mut consumer) = (ring.producer().unwrap(), ring.consumer().unwrap());
+        for i in 0..4 {
+            assert_eq!(producer.push(i), Ok(()));
+            assert_eq!(ring.len(), 1);
+            assert_eq!(consumer.pop(), Some(i));
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn spsc_single_slot() {
+        let ring = SpscRing::<u8, 1>::new();
+        let (mut producer, mut consumer) = (ring.producer().unwrap(), ring.consumer().unwrap());
+        assert_eq!(producer.push(1), Ok(()));
+        assert_eq!(producer.push(2), Err(2));
+        assert_eq!(consumer.pop(), Some(1));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn mpsc_full_and_empty() {
+        let queue = MpscQueue::<u32, 4>::new();
+        let mut consumer = queue.consumer().unwrap();
+        assert!(queue.is_empty());
+        assert_eq!(consumer.pop(), None);
+        for i in 0..4 {
+            assert_eq!(queue.push(i), Ok(()));
+        }
+        assert_eq!(queue.len(), queue.capacity());
+        assert_eq!(queue.push(4), Err(4));
+        assert_eq!(consumer.pop(), Some(0));
+        assert_eq!(queue.push(4), Ok(()));
+        assert!((1..5).eq(&mut consumer));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn mpsc_wraparound() {
+        let queue = MpscQueue::<usize, 3>::new();
+        let mut consumer = queue.consumer().unwrap();
+        for i in 0..10 {
+            assert_eq!(queue.push(2 * i), Ok(()));
+            assert_eq!(queue.push(2 * i + 1), Ok(()));
+            assert_eq!(consumer.pop(), Some(2 * i));
+            assert_eq!(consumer.pop(), Some(2 * i + 1));
+            assert_eq!(consumer.pop(), None);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn mpsc_single_slot() {
+        MpscQueue::<u8, 1>::new();
+    }
+}